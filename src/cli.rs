@@ -7,9 +7,171 @@ pub struct Cli {
     ///Port to use in case of transport that allows it. Default is 6666
     pub port: u16,
 
-    #[arg(long, default_value = "Default::default()")]
+    #[arg(long, default_value = "std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST)")]
+    ///Address on which TCP transport accepts connections, 0.0.0.0 or :: accepts them on every interface. Default: 127.0.0.1
+    pub bind: std::net::IpAddr,
+
+    #[arg(long, default_value = "crate::db::DEFAULT_PATH.to_owned()")]
     ///Path on filesystem to store database. Default: dou_store_db
-    pub db: crate::db::Db,
+    pub db: String,
+
+    #[arg(long = "metrics-port")]
+    ///Port for HTTP listener serving Prometheus metrics on /metrics with liveness and readiness probes on /healthz and /readyz. Disabled by default.
+    pub metrics_port: Option<u16>,
+
+    #[arg(long = "admin-token")]
+    ///Token, which enables admin dashboard on /admin of metrics listener and admin methods, such as hooks and put_blob, and must be provided to use them. Disabled by default.
+    pub admin_token: Option<String>,
+
+    #[arg(long = "mdns-name")]
+    ///Instance name under which server is advertised over mDNS as _dou-store._tcp. Disabled by default.
+    pub mdns_name: Option<String>,
+
+    #[arg(long = "log-format", default_value = "Default::default()")]
+    ///Log output format: text or json, which writes one object per line. Default: text
+    pub log_format: crate::log::Format,
+
+    #[arg(long = "slow-request-ms", default_value = "1000")]
+    ///Requests taking longer than this number of milliseconds are logged as warnings. 0 disables it. Default: 1000
+    pub slow_request_ms: u64,
+
+    #[arg(long = "trace-spans")]
+    ///Log timing breakdown (parse, dispatch, db, serialize) of every request.
+    pub trace_spans: bool,
+
+    #[arg(long = "otlp-endpoint")]
+    ///OTLP/HTTP collector endpoint (e.g. http://127.0.0.1:4318) to export spans and metrics. Default: OTEL_EXPORTER_OTLP_ENDPOINT, if set.
+    pub otlp_endpoint: Option<String>,
+
+    #[arg(long = "otlp-interval", default_value = "10")]
+    ///Interval in seconds between OTLP exports. Default: 10
+    pub otlp_interval: u64,
+
+    #[arg(long = "worker-threads", default_value = "1")]
+    ///Number of IO threads. Multi-threaded scheduler is used when above 1. Default: 1
+    pub worker_threads: usize,
+
+    #[arg(long = "blocking-threads", default_value = "8")]
+    ///Maximum number of threads in IO runtime blocking pool. Default: 8
+    pub blocking_threads: usize,
+
+    #[arg(long = "noise-key")]
+    ///Hex encoded X25519 private key, which makes TCP transport require Noise XX handshake. Disabled by default.
+    pub noise_key: Option<String>,
+
+    #[arg(long = "noise-peers")]
+    ///Comma separated hex encoded public keys of clients allowed over Noise. Required, unless --noise-allow-any is set.
+    pub noise_peers: Option<String>,
+
+    #[arg(long = "noise-allow-any")]
+    ///Allow any client over Noise, authenticating only server.
+    pub noise_allow_any: bool,
+
+    #[arg(long = "max-connections-per-ip", default_value = "1")]
+    ///Maximum number of simultaneous TCP connections from single IP address. 0 disables it. Default: 1
+    pub max_connections_per_ip: usize,
+
+    #[arg(long = "max-invalid-frames", default_value = "0")]
+    ///Disconnect client after this number of consecutive invalid frames. 0 disables it. Default: 0
+    pub max_invalid_frames: usize,
+
+    #[arg(long = "max-key-len", default_value = "crate::server::key::DEFAULT_MAX_LEN")]
+    ///Maximum length of written keys in bytes. 0 disables it. Default: 256
+    pub max_key_len: usize,
+
+    #[arg(long = "key-chars", default_value = "crate::server::key::DEFAULT_CHARS.to_owned()")]
+    ///Characters allowed in written keys in addition to ASCII letters and digits. Default: -_./:
+    pub key_chars: String,
+
+    #[arg(long = "keepalive-secs", default_value = "0")]
+    ///Seconds without reads from client after which it is pinged, disconnecting it after two unanswered pings or a write stuck as long. 0 disables it. Default: 0
+    pub keepalive_secs: u64,
+
+    #[arg(long = "compression-threshold", default_value = "1024")]
+    ///Size in bytes from which responses are compressed for clients, which negotiated zstd feature. Default: 1024
+    pub compression_threshold: usize,
+
+    #[arg(long = "db-workers", default_value = "2")]
+    ///Number of threads performing database operations. Default: 2
+    pub db_workers: usize,
+
+    #[arg(long = "cache-size", default_value = "0")]
+    ///Capacity in bytes of in-memory cache of config values. 0 disables it. Default: 0
+    pub cache_size: usize,
+
+    #[arg(long = "hook-max-operations", default_value = "crate::server::hook::DEFAULT_MAX_OPERATIONS")]
+    ///Maximum number of operations performed by single run of hook script. 0 disables it. Default: 100000
+    pub hook_max_operations: u64,
+
+    #[arg(long = "hook-timeout-ms", default_value = "crate::server::hook::DEFAULT_TIMEOUT_MS")]
+    ///Maximum duration in milliseconds of single run of hook script. Default: 10
+    pub hook_timeout_ms: u64,
+
+    #[arg(long = "blob-threshold", default_value = "0")]
+    ///Size in bytes from which config values are stored once by their hash. 0 disables it. Default: 0
+    pub blob_threshold: usize,
+
+    #[arg(long, default_value = "Default::default()")]
+    ///Comma separated quotas of namespaces as <namespace>=<max_bytes>:<max_keys>, where 0 disables limit. Namespace is part of key before first '/'. Default: none
+    pub quotas: crate::server::quota::Quotas,
+
+    #[arg(long = "import-url")]
+    ///Endpoint of cluster to import from as http://host:port. Default: http://127.0.0.1:2379 for etcd and http://127.0.0.1:8500 for Consul
+    pub import_url: Option<String>,
+
+    #[arg(long = "import-prefix", default_value = "String::new()")]
+    ///Prefix of keys to import. Default: all keys
+    pub import_prefix: String,
+
+    #[arg(long = "env-whitelist", default_value = "String::new()")]
+    ///Comma separated environment variables, which config reads can substitute for ${VAR} via expand_env. Default: none
+    pub env_whitelist: String,
+
+    #[arg(long = "bridge-etcd")]
+    ///etcd endpoint as http://host:port, into which configs are mirrored. Disabled by default.
+    pub bridge_etcd: Option<String>,
+
+    #[arg(long = "bridge-consul")]
+    ///Consul endpoint as http://host:port, into which configs are mirrored. Disabled by default.
+    pub bridge_consul: Option<String>,
+
+    #[arg(long = "bridge-prefix", default_value = "String::new()")]
+    ///Prefix of keys, mirrored by bridge. Default: all keys
+    pub bridge_prefix: String,
+
+    #[arg(long = "bridge-remote-prefix")]
+    ///Prefix of remote keys, replacing bridge prefix. Default: the same as bridge prefix
+    pub bridge_remote_prefix: Option<String>,
+
+    #[arg(long = "bridge-pull-ms", default_value = "0")]
+    ///Interval in milliseconds of pulling remote changes back. 0 disables it. Default: 0
+    pub bridge_pull_ms: u64,
+
+    #[arg(long)]
+    ///Enable fault injection for testing of clients, configurable at runtime via chaos method with admin token. Never use it in production.
+    pub chaos: bool,
+
+    #[arg(long = "chaos-latency-ms", default_value = "0")]
+    ///Maximum latency in milliseconds randomly added to requests in chaos mode. Default: 0
+    pub chaos_latency_ms: u64,
+
+    #[arg(long = "chaos-drop-percent", default_value = "0")]
+    ///Percent of responses, which are dropped in chaos mode. Default: 0
+    pub chaos_drop_percent: u64,
+
+    #[arg(long = "chaos-error-percent", default_value = "0")]
+    ///Percent of requests, which fail with transient error in chaos mode. Default: 0
+    pub chaos_error_percent: u64,
+
+    #[arg(long)]
+    ///Address of upstream dou-store as host:port, from which keys missing locally are fetched and stored. Disabled by default.
+    pub upstream: Option<String>,
+
+    ///Mode to run instead of server: import-etcd or import-consul, which load keys of existing cluster into db and exit, or watch, which prints changes of keys on running server. On Windows also service-install or service-uninstall, which register server with the rest of arguments as service or remove it, and service, which is run by service control manager.
+    pub mode: Option<String>,
+
+    ///Argument of mode: prefix of keys to watch. Default: all keys
+    pub mode_arg: Option<String>,
 }
 
 impl Cli {
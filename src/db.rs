@@ -1,13 +1,40 @@
-use core::{fmt};
+///Path of database, used unless specified otherwise.
+pub const DEFAULT_PATH: &str = "dou_store_db";
 
 #[derive(Clone)]
 //Namespaces that we use.
 //
 //Generally `sled::Db` is light-weight, but we do not really need it
 //to write into namespaces.
+//It is only kept to query database-wide information.
 pub struct DbView {
+    db: sled::Db,
     pub config: sled::Tree,
     pub checksum: sled::Tree,
+    ///Keys, which are deleted once their writer disconnects.
+    pub ephemeral: sled::Tree,
+    ///Locks with their owner and expiration time.
+    pub locks: sled::Tree,
+    ///Messages of queues.
+    pub queues: sled::Tree,
+    ///Scripts run on reads and writes.
+    pub hooks: sled::Tree,
+    ///Deduplicated values by their hash.
+    pub blobs: sled::Tree,
+    ///Number of config keys, pointing at blob.
+    pub blob_refs: sled::Tree,
+    ///Bytes and keys used by every namespace.
+    pub usage: sled::Tree,
+    ///Values, which are written once their activation time comes.
+    pub pending: sled::Tree,
+    ///Results of writes by their idempotency key.
+    pub idempotency: sled::Tree,
+    ///Chunks of multipart uploads in progress.
+    pub uploads: sled::Tree,
+    ///Size from which values are stored as blobs, 0 disables it.
+    pub blob_threshold: usize,
+    ///Limits on usage of namespaces.
+    pub quotas: crate::server::quota::Quotas,
 }
 
 pub struct Db {
@@ -16,6 +43,37 @@ pub struct Db {
     view: DbView,
 }
 
+impl DbView {
+    #[inline]
+    ///Returns size of database on disk in bytes.
+    pub fn size_on_disk(&self) -> Result<u64, sled::Error> {
+        self.db.size_on_disk()
+    }
+
+    ///Returns value of config key, following pointer to blob.
+    pub fn get_config<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<sled::IVec>, sled::Error> {
+        match self.config.get(key)? {
+            Some(value) => match crate::server::blob::as_pointer(&value) {
+                Some(hash) => self.blobs.get(hash),
+                None => Ok(Some(value)),
+            },
+            None => Ok(None),
+        }
+    }
+
+    #[inline]
+    ///Writes all buffered changes to disk.
+    pub fn flush(&self) -> Result<usize, sled::Error> {
+        self.db.flush()
+    }
+
+    #[inline]
+    ///Returns unique identifier, which is greater than any previously generated one.
+    pub fn generate_id(&self) -> Result<u64, sled::Error> {
+        self.db.generate_id()
+    }
+}
+
 impl Db {
     pub fn open(path: &str) -> Result<Self, sled::Error> {
         let db = sled::Config::new().path(path)
@@ -24,54 +82,55 @@ impl Db {
                                     .use_compression(true)
                                     .flush_every_ms(Some(60_000))
                                     .open()?;
+        Self::with_db(db)
+    }
+
+    #[cfg(test)]
+    ///Opens database, which is removed once dropped.
+    pub fn temporary() -> Result<Self, sled::Error> {
+        Self::with_db(sled::Config::new().temporary(true).open()?)
+    }
 
+    fn with_db(db: sled::Db) -> Result<Self, sled::Error> {
         let config = db.open_tree("config")?;
         let checksum = db.open_tree("cheksum")?;
+        let ephemeral = db.open_tree("ephemeral")?;
+        let locks = db.open_tree("locks")?;
+        let queues = db.open_tree("queues")?;
+        let hooks = db.open_tree("hooks")?;
+        let blobs = db.open_tree("blobs")?;
+        let blob_refs = db.open_tree("blob_refs")?;
+        let usage = db.open_tree("usage")?;
+        let pending = db.open_tree("pending")?;
+        let idempotency = db.open_tree("idempotency")?;
+        let uploads = db.open_tree("uploads")?;
 
         Ok(Self {
-            db,
             view: DbView {
+                db: db.clone(),
                 config,
-                checksum
+                checksum,
+                ephemeral,
+                locks,
+                queues,
+                hooks,
+                blobs,
+                blob_refs,
+                usage,
+                pending,
+                idempotency,
+                uploads,
+                blob_threshold: 0,
+                quotas: Default::default(),
             },
+            db,
         })
     }
 
-    fn init(path: &str) -> Self {
-        match Self::open(path) {
-            Ok(result) => result,
-            Err(error) => {
-                eprintln!("Unable to initialize db at the default path. Error: {}", error);
-                std::process::exit(1);
-            }
-        }
-    }
-
     #[inline]
     pub fn view(&self) -> DbView {
         self.view.clone()
     }
 }
 
-impl fmt::Debug for Db {
-    #[inline]
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.write_str("Db")
-    }
-}
 
-impl core::str::FromStr for Db {
-    type Err = ();
-
-    #[inline]
-    fn from_str(path: &str) -> Result<Self, Self::Err> {
-        Ok(Self::init(path))
-    }
-}
-
-impl Default for Db {
-    #[inline]
-    fn default() -> Self {
-        Self::init("dou_store_db")
-    }
-}
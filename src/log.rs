@@ -0,0 +1,344 @@
+//! Logging macros on top of `rogu`, with optional JSON output.
+//!
+//! Text format is written by `rogu` itself, while JSON format emits one object per line.
+//! Once sink is set, such as Windows event log, text lines are passed to it instead.
+//!
+//! Macros accept optional structured fields before message:
+//!
+//! - `peer: <addr>` - client address;
+//! - `cid: <Option<&str>>` - correlation id of request;
+//! - `method: <&str>`, `duration: <Duration>` - processed request information.
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use std::io::Write as IoWrite;
+
+static JSON: AtomicBool = AtomicBool::new(false);
+static SINK: std::sync::OnceLock<Sink> = std::sync::OnceLock::new();
+
+///Receiver of log lines, replacing stdout and stderr.
+pub type Sink = fn(rogu::Level, &str);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+///Log output format
+pub enum Format {
+    ///Human readable text, as written by `rogu`
+    Text,
+    ///One JSON object per line
+    Json,
+}
+
+impl core::str::FromStr for Format {
+    type Err = ();
+
+    #[inline]
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        if text.eq_ignore_ascii_case("text") {
+            Ok(Format::Text)
+        } else if text.eq_ignore_ascii_case("json") {
+            Ok(Format::Json)
+        } else {
+            Err(())
+        }
+    }
+}
+
+impl Default for Format {
+    #[inline]
+    fn default() -> Self {
+        Format::Text
+    }
+}
+
+#[inline]
+pub fn set_format(format: Format) {
+    JSON.store(format == Format::Json, Ordering::Relaxed);
+}
+
+#[inline]
+pub fn is_json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+#[cfg(windows)]
+#[inline]
+///Sets sink of log lines, which is kept until exit.
+pub fn set_sink(sink: Sink) {
+    let _ = SINK.set(sink);
+}
+
+#[inline]
+pub fn sink() -> Option<Sink> {
+    SINK.get().copied()
+}
+
+///Writes log line in text form into sink.
+pub fn write_sink(sink: Sink, level: rogu::Level, fields: &Fields<'_>, args: fmt::Arguments<'_>) {
+    sink(level, &Text(fields, args).to_string())
+}
+
+///Optional structured fields of log line.
+pub struct Fields<'a> {
+    pub peer: Option<&'a dyn fmt::Display>,
+    pub correlation_id: Option<&'a str>,
+    pub method: Option<&'a str>,
+    pub duration: Option<Duration>,
+}
+
+impl Fields<'_> {
+    pub const EMPTY: Fields<'static> = Fields {
+        peer: None,
+        correlation_id: None,
+        method: None,
+        duration: None,
+    };
+}
+
+///Writes message with fields in text form, used as `rogu` message.
+pub struct Text<'a>(pub &'a Fields<'a>, pub fmt::Arguments<'a>);
+
+impl fmt::Display for Text<'_> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(peer) = self.0.peer {
+            write!(fmt, "{}: ", peer)?;
+        }
+        fmt.write_fmt(self.1)?;
+        if let Some(correlation_id) = self.0.correlation_id {
+            write!(fmt, " correlation_id={}", correlation_id)?;
+        }
+        if let Some(method) = self.0.method {
+            write!(fmt, " method={}", method)?;
+        }
+        if let Some(duration) = self.0.duration {
+            write!(fmt, " duration={:?}", duration)?;
+        }
+        Ok(())
+    }
+}
+
+const fn level_name(level: rogu::Level) -> &'static str {
+    match level {
+        rogu::Level::ERROR => "ERROR",
+        rogu::Level::WARN => "WARN",
+        rogu::Level::INFO => "INFO",
+        rogu::Level::DEBUG => "DEBUG",
+        rogu::Level::TRACE => "TRACE",
+        rogu::Level::NONE => "NONE",
+    }
+}
+
+///Writes time since UNIX epoch as RFC 3339 UTC timestamp with millisecond precision.
+fn write_timestamp(out: &mut String, now: Duration) {
+    let secs = now.as_secs();
+    let days = (secs / 86_400) as i64;
+    let day_secs = secs % 86_400;
+
+    //Civil from days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    let _ = write!(out, "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z", year, month, day, day_secs / 3600, day_secs % 3600 / 60, day_secs % 60, now.subsec_millis());
+}
+
+fn write_json_str(out: &mut String, text: &str) {
+    //Serializing str into String cannot fail.
+    if let Ok(text) = serde_json::to_string(text) {
+        out.push_str(&text);
+    }
+}
+
+///Formats log line as JSON object, terminated by new line.
+fn json_line(now: Duration, level: rogu::Level, location: &'static str, fields: &Fields<'_>, args: fmt::Arguments<'_>) -> String {
+    let mut line = String::with_capacity(128);
+
+    line.push_str("{\"timestamp\":\"");
+    write_timestamp(&mut line, now);
+    line.push_str("\",\"level\":\"");
+    line.push_str(level_name(level));
+    line.push_str("\",\"location\":");
+    write_json_str(&mut line, location);
+
+    if let Some(peer) = fields.peer {
+        line.push_str(",\"peer\":");
+        write_json_str(&mut line, &peer.to_string());
+    }
+    if let Some(correlation_id) = fields.correlation_id {
+        line.push_str(",\"correlation_id\":");
+        write_json_str(&mut line, correlation_id);
+    }
+    if let Some(method) = fields.method {
+        line.push_str(",\"method\":");
+        write_json_str(&mut line, method);
+    }
+    if let Some(duration) = fields.duration {
+        let _ = write!(line, ",\"duration_ms\":{}", duration.as_secs_f64() * 1000.0);
+    }
+
+    line.push_str(",\"message\":");
+    match args.as_str() {
+        Some(message) => write_json_str(&mut line, message),
+        None => write_json_str(&mut line, &args.to_string()),
+    }
+    line.push_str("}\n");
+    line
+}
+
+///Writes log line as JSON object.
+///
+///Error and warnings go to stderr, the rest into stdout.
+pub fn write_json(level: rogu::Level, location: &'static str, fields: &Fields<'_>, args: fmt::Arguments<'_>) {
+    let now = match std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+        Ok(now) => now,
+        Err(_) => Duration::from_secs(0),
+    };
+    let line = json_line(now, level, location, fields, args);
+
+    //Nothing to do if logging fails
+    let _ = match level {
+        rogu::Level::ERROR | rogu::Level::WARN => std::io::stderr().lock().write_all(line.as_bytes()),
+        _ => std::io::stdout().lock().write_all(line.as_bytes()),
+    };
+}
+
+macro_rules! log_line {
+    (@write $level:ident, $rogu:ident, $fields:ident, peer: $value:expr, $($rest:tt)+) => {{
+        $fields.peer = Some(&$value);
+        log_line!(@write $level, $rogu, $fields, $($rest)+)
+    }};
+    (@write $level:ident, $rogu:ident, $fields:ident, cid: $value:expr, $($rest:tt)+) => {{
+        $fields.correlation_id = $value;
+        log_line!(@write $level, $rogu, $fields, $($rest)+)
+    }};
+    (@write $level:ident, $rogu:ident, $fields:ident, method: $value:expr, $($rest:tt)+) => {{
+        $fields.method = Some($value);
+        log_line!(@write $level, $rogu, $fields, $($rest)+)
+    }};
+    (@write $level:ident, $rogu:ident, $fields:ident, duration: $value:expr, $($rest:tt)+) => {{
+        $fields.duration = Some($value);
+        log_line!(@write $level, $rogu, $fields, $($rest)+)
+    }};
+    (@write $level:ident, $rogu:ident, $fields:ident, $($arg:tt)+) => {
+        if let Some(sink) = $crate::log::sink() {
+            $crate::log::write_sink(sink, rogu::Level::$level, &$fields, format_args!($($arg)+));
+        } else if $crate::log::is_json() {
+            $crate::log::write_json(rogu::Level::$level, core::concat!(core::file!(), ":", core::line!()), &$fields, format_args!($($arg)+));
+        } else {
+            rogu::$rogu!("{}", $crate::log::Text(&$fields, format_args!($($arg)+)));
+        }
+    };
+    ($level:ident, $rogu:ident, $($arg:tt)+) => {
+        if rogu::is_enabled(rogu::Level::$level) {
+            #[allow(unused_mut)]
+            let mut fields = $crate::log::Fields::EMPTY;
+            log_line!(@write $level, $rogu, fields, $($arg)+);
+        }
+    };
+}
+
+///Writes error log
+macro_rules! error {
+    ($($arg:tt)+) => {
+        log_line!(ERROR, error, $($arg)+)
+    }
+}
+
+///Writes warn log
+macro_rules! warn {
+    ($($arg:tt)+) => {
+        log_line!(WARN, warn, $($arg)+)
+    }
+}
+
+//Following levels are disabled in release mode, same as `rogu` features in Cargo.toml.
+//Disabled macros still type check arguments, so that variables are considered used.
+
+#[cfg(debug_assertions)]
+///Writes info log
+macro_rules! info {
+    ($($arg:tt)+) => {
+        log_line!(INFO, info, $($arg)+)
+    }
+}
+
+#[cfg(not(debug_assertions))]
+///Writes info log
+macro_rules! info {
+    ($($arg:tt)+) => {
+        if false {
+            log_line!(INFO, info, $($arg)+)
+        }
+    }
+}
+
+#[cfg(debug_assertions)]
+///Writes trace log
+macro_rules! trace {
+    ($($arg:tt)+) => {
+        log_line!(TRACE, trace, $($arg)+)
+    }
+}
+
+#[cfg(not(debug_assertions))]
+///Writes trace log
+macro_rules! trace {
+    ($($arg:tt)+) => {
+        if false {
+            log_line!(TRACE, trace, $($arg)+)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_write_timestamp() {
+        let mut out = String::new();
+        write_timestamp(&mut out, Duration::from_millis(0));
+        assert_eq!(out, "1970-01-01T00:00:00.000Z");
+
+        out.clear();
+        write_timestamp(&mut out, Duration::from_millis(951_827_696_789));
+        assert_eq!(out, "2000-02-29T12:34:56.789Z");
+    }
+
+    #[test]
+    fn should_format_json_line() {
+        let fields = Fields {
+            peer: Some(&"127.0.0.1:1000"),
+            correlation_id: Some("cid"),
+            method: Some("get_config"),
+            duration: Some(Duration::from_millis(2)),
+        };
+        let line = json_line(Duration::from_secs(0), rogu::Level::WARN, "src/main.rs:1", &fields, format_args!("Slow \"{}\"", 1));
+
+        assert!(line.ends_with('\n'));
+        let line = serde_json::from_str::<serde_json::Value>(&line).expect("json");
+        assert_eq!(line, serde_json::json!({
+            "timestamp": "1970-01-01T00:00:00.000Z",
+            "level": "WARN",
+            "location": "src/main.rs:1",
+            "peer": "127.0.0.1:1000",
+            "correlation_id": "cid",
+            "method": "get_config",
+            "duration_ms": 2,
+            "message": "Slow \"1\"",
+        }));
+    }
+
+    #[test]
+    fn should_parse_format() {
+        assert_eq!("JSON".parse::<Format>(), Ok(Format::Json));
+        assert_eq!("text".parse::<Format>(), Ok(Format::Text));
+        assert!("xml".parse::<Format>().is_err());
+    }
+}
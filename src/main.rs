@@ -1,11 +1,20 @@
-#![no_main]
+#![cfg_attr(not(test), no_main)]
 
+#[macro_use]
+mod log;
+mod spans;
+mod otlp;
 mod protocol;
 mod cli;
 mod db;
 mod server;
+mod service;
 
+#[cfg(not(test))]
 c_ffi::c_main!(rust_main);
+//Test harness provides its own main, but everything reachable from ours is still used.
+#[cfg(test)]
+const _: fn(c_ffi::Args) -> bool = rust_main;
 
 fn rust_main(args: c_ffi::Args) -> bool {
     let args = match cli::Cli::new(args.into_iter().skip(1)) {
@@ -14,10 +23,152 @@ fn rust_main(args: c_ffi::Args) -> bool {
     };
 
     rogu::set_level(rogu::Level::INFO);
+    log::set_format(args.log_format);
 
-    let tcp = server::tcp::Tcp::new(args.port, args.db.view());
+    #[cfg(windows)]
+    match args.mode.as_deref() {
+        Some(service::RUN) => return service::run(args),
+        Some(service::INSTALL) => return service::install(),
+        Some(service::UNINSTALL) => return service::uninstall(),
+        _ => (),
+    }
+
+    run(args, terminated())
+}
+
+///Creates IO runtime, which is multi-threaded only with more than one worker thread.
+fn runtime(worker_threads: usize, blocking_threads: usize) -> std::io::Result<tokio::runtime::Runtime> {
+    let mut rt = match worker_threads {
+        0 | 1 => tokio::runtime::Builder::new_current_thread(),
+        worker_threads => {
+            let mut rt = tokio::runtime::Builder::new_multi_thread();
+            rt.worker_threads(worker_threads);
+            rt
+        }
+    };
+    rt.max_blocking_threads(blocking_threads.max(1)).enable_io().enable_time().build()
+}
+
+///Runs server until `stop` completes, returning whether it failed.
+fn run(args: cli::Cli, stop: impl core::future::Future<Output = ()>) -> bool {
+    //Watch is client of running server, which holds lock on db.
+    if args.mode.as_deref() == Some("watch") {
+        return server::watch::run(args.bind, args.port, args.mode_arg.as_deref().unwrap_or_default());
+    }
+
+    let db = match db::Db::open(&args.db) {
+        Ok(db) => db,
+        Err(error) => {
+            eprintln!("Unable to initialize db at '{}'. Error: {}", args.db, error);
+            return true;
+        }
+    };
+
+    let otlp_endpoint = match args.otlp_endpoint.as_ref() {
+        Some(endpoint) => Some(endpoint.clone()),
+        None => std::env::var(otlp::ENDPOINT_ENV).ok(),
+    };
+    let otlp = match otlp_endpoint {
+        Some(endpoint) => match otlp::Exporter::new(&endpoint, db.view()) {
+            Some(exporter) => Some(std::sync::Arc::new(exporter)),
+            None => {
+                eprintln!("Invalid OTLP endpoint '{}', only http:// is supported", endpoint);
+                return true;
+            }
+        },
+        None => None,
+    };
+
+    if args.trace_spans || otlp.is_some() {
+        if let Err(error) = tracing::subscriber::set_global_default(spans::SpanTimings::new(args.trace_spans, otlp.clone())) {
+            warn!("Unable to enable request tracing: {}", error);
+        }
+    }
+
+    let bridge = match (args.bridge_etcd.as_ref(), args.bridge_consul.as_ref()) {
+        (Some(_), Some(_)) => {
+            eprintln!("Only one of --bridge-etcd and --bridge-consul can be used");
+            return true;
+        },
+        (Some(url), None) | (None, Some(url)) => Some(server::bridge::Config {
+            kind: match args.bridge_etcd.is_some() {
+                true => server::import::Kind::Etcd,
+                false => server::import::Kind::Consul,
+            },
+            url: url.clone(),
+            prefix: args.bridge_prefix.clone(),
+            remote_prefix: args.bridge_remote_prefix.clone().unwrap_or_else(|| args.bridge_prefix.clone()),
+            pull_interval: match args.bridge_pull_ms {
+                0 => None,
+                ms => Some(core::time::Duration::from_millis(ms)),
+            },
+        }),
+        (None, None) => None,
+    };
 
-    let rt = match tokio::runtime::Builder::new_current_thread().max_blocking_threads(8).enable_io().build() {
+    let options = server::Options {
+        slow_request_threshold: match args.slow_request_ms {
+            0 => None,
+            ms => Some(core::time::Duration::from_millis(ms)),
+        },
+        cache_size: args.cache_size,
+        db_workers: args.db_workers,
+        key_rules: server::key::KeyRules {
+            max_len: args.max_key_len,
+            chars: args.key_chars,
+        },
+        hook_limits: server::hook::Limits {
+            max_operations: args.hook_max_operations,
+            timeout: core::time::Duration::from_millis(args.hook_timeout_ms),
+        },
+        blob_threshold: args.blob_threshold,
+        quotas: args.quotas.clone(),
+        bridge,
+        env: server::env::Environment::from_whitelist(&args.env_whitelist),
+        chaos: match args.chaos {
+            true => Some(server::chaos::Settings {
+                enabled: true,
+                latency_ms: args.chaos_latency_ms,
+                drop_percent: args.chaos_drop_percent,
+                error_percent: args.chaos_error_percent,
+            }),
+            false => None,
+        },
+        upstream: args.upstream.clone(),
+        admin_token: args.admin_token.clone(),
+    };
+    if let Some(mode) = args.mode.as_deref() {
+        return server::import::run(mode, args.import_url.as_deref(), &args.import_prefix, db.view(), &options);
+    }
+    let noise = match args.noise_key.as_deref() {
+        Some(key) => match server::noise::Config::new(key, args.noise_peers.as_deref(), args.noise_allow_any) {
+            Ok(noise) => {
+                info!("Noise public key: {}", noise.public_key());
+                Some(noise)
+            },
+            Err(error) => {
+                eprintln!("{}", error);
+                return true;
+            }
+        },
+        None => None,
+    };
+    let tcp_options = server::tcp::Options {
+        bind: args.bind,
+        max_connections_per_ip: args.max_connections_per_ip,
+        max_invalid_frames: args.max_invalid_frames,
+        keepalive: match args.keepalive_secs {
+            0 => None,
+            secs => Some(core::time::Duration::from_secs(secs)),
+        },
+        compression_threshold: args.compression_threshold,
+        noise,
+        admin_token: args.admin_token.clone(),
+    };
+    let handler = server::Handler::new(db.view(), options);
+    let tcp = server::tcp::Tcp::new(args.port, tcp_options, handler.clone());
+
+    let rt = match runtime(args.worker_threads, args.blocking_threads) {
         Ok(rt) => rt,
         Err(error) => {
             eprintln!("Unable to start IO loop: {}", error);
@@ -25,10 +176,110 @@ fn rust_main(args: c_ffi::Args) -> bool {
         }
     };
 
-    loop {
-        if !rt.block_on(tcp.start()) {
-            //We only exit with false when server unable to start.
-            std::thread::sleep(core::time::Duration::from_secs(1));
+    if let Some(port) = args.metrics_port {
+        let admin = args.admin_token.clone().map(|token| server::http::Admin {
+            token,
+            tcp: tcp.clone(),
+        });
+        let http = std::sync::Arc::new(server::http::Http::new(port, db.view(), admin));
+        rt.spawn(http.start());
+    }
+
+    let _mdns = match args.mdns_name.as_deref() {
+        Some(name) => match server::mdns::Advertisement::new(name, args.bind, args.port) {
+            Ok(mdns) => Some(mdns),
+            Err(error) => {
+                warn!("Unable to advertise over mDNS: {}", error);
+                None
+            }
+        },
+        None => None,
+    };
+
+    rt.spawn(handler.clone().expire_stale());
+    rt.spawn(handler.activate_scheduled());
+
+    if let Some(otlp) = otlp {
+        rt.spawn(otlp.run(core::time::Duration::from_secs(args.otlp_interval.max(1))));
+    }
+
+    let serve = async {
+        loop {
+            if !tcp.start().await {
+                //We only exit with false when server unable to start.
+                tokio::time::sleep(core::time::Duration::from_secs(1)).await;
+            }
+        }
+    };
+    rt.block_on(async {
+        tokio::select! {
+            _ = serve => (),
+            _ = stop => info!("Shutting down"),
         }
+    });
+
+    //Otherwise writes since last periodic flush are lost.
+    match db.view().flush() {
+        Ok(_) => false,
+        Err(error) => {
+            eprintln!("Unable to flush db: {}", error);
+            true
+        }
+    }
+}
+
+#[cfg(unix)]
+///Waits for request to terminate: SIGTERM, sent by supervisor such as launchd or systemd, or SIGINT.
+async fn terminated() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminate) => tokio::select! {
+            _ = terminate.recv() => (),
+            _ = interrupted() => (),
+        },
+        Err(error) => {
+            warn!("Unable to handle SIGTERM: {}", error);
+            interrupted().await
+        }
+    }
+}
+
+#[cfg(not(unix))]
+#[inline]
+///Waits for request to terminate: Ctrl-C or Ctrl-Break.
+async fn terminated() {
+    interrupted().await
+}
+
+async fn interrupted() {
+    if let Err(error) = tokio::signal::ctrl_c().await {
+        warn!("Unable to handle interruption: {}", error);
+        core::future::pending::<()>().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_runtime_options() {
+        let args = cli::Cli::new(["--worker-threads", "4", "--blocking-threads", "2"]).expect("args");
+        assert_eq!(args.worker_threads, 4);
+        assert_eq!(args.blocking_threads, 2);
+
+        let args = cli::Cli::new([]).expect("args");
+        assert_eq!(args.worker_threads, 1);
+        assert_eq!(args.blocking_threads, 8);
+    }
+
+    #[test]
+    fn should_use_multi_thread_runtime_only_for_several_workers() {
+        use tokio::runtime::RuntimeFlavor;
+
+        assert_eq!(runtime(0, 0).expect("runtime").handle().runtime_flavor(), RuntimeFlavor::CurrentThread);
+        assert_eq!(runtime(1, 8).expect("runtime").handle().runtime_flavor(), RuntimeFlavor::CurrentThread);
+        assert_eq!(runtime(2, 8).expect("runtime").handle().runtime_flavor(), RuntimeFlavor::MultiThread);
     }
 }
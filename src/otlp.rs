@@ -0,0 +1,306 @@
+//! OpenTelemetry export of spans and metrics, using OTLP/HTTP with JSON encoding.
+//!
+//! Only plain `http://` collector endpoints are supported.
+
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use core::time::Duration;
+
+use serde_json::{json, Value};
+use tokio::net::TcpStream;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+use crate::db;
+use crate::server::metrics::{METRICS, LATENCY_BUCKETS};
+
+const SERVICE_NAME: &str = "dou-store";
+///Environment variable, used if endpoint is not specified via CLI.
+pub const ENDPOINT_ENV: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+///Limit on number of spans waiting for export, anything above is dropped.
+const MAX_PENDING_SPANS: usize = 8192;
+
+///Finished span, ready for export.
+pub struct SpanRecord {
+    pub trace_id: u128,
+    pub span_id: u64,
+    pub parent_span_id: Option<u64>,
+    pub name: &'static str,
+    pub start: SystemTime,
+    pub end: SystemTime,
+    pub attributes: Vec<(&'static str, String)>,
+}
+
+#[inline]
+fn unix_nanos(time: SystemTime) -> String {
+    match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(time) => time.as_nanos().to_string(),
+        Err(_) => "0".to_owned(),
+    }
+}
+
+#[inline]
+fn str_attr(key: &str, value: &str) -> Value {
+    json!({"key": key, "value": {"stringValue": value}})
+}
+
+#[inline]
+fn resource() -> Value {
+    json!({"attributes": [str_attr("service.name", SERVICE_NAME)]})
+}
+
+///Collector endpoint, parsed from `http://host:port[/path]`
+struct Endpoint {
+    host: String,
+    path: String,
+}
+
+impl Endpoint {
+    fn parse(endpoint: &str) -> Option<Self> {
+        let endpoint = endpoint.strip_prefix("http://")?;
+        let (host, path) = match endpoint.find('/') {
+            Some(idx) => (&endpoint[..idx], endpoint[idx..].trim_end_matches('/')),
+            None => (endpoint, ""),
+        };
+
+        if host.is_empty() {
+            return None;
+        }
+
+        let host = match host.contains(':') {
+            true => host.to_owned(),
+            false => format!("{}:80", host),
+        };
+
+        Some(Self {
+            host,
+            path: path.to_owned(),
+        })
+    }
+
+    ///Sends JSON payload, returning status code of response.
+    async fn post(&self, path: &str, body: &[u8]) -> std::io::Result<u16> {
+        let socket = TcpStream::connect(self.host.as_str()).await?;
+        let mut socket = BufReader::new(socket);
+
+        let head = format!("POST {}{} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", self.path, path, self.host, body.len());
+        socket.get_mut().write_all(head.as_bytes()).await?;
+        socket.get_mut().write_all(body).await?;
+
+        let mut status = String::new();
+        socket.read_line(&mut status).await?;
+        match status.split_ascii_whitespace().nth(1).and_then(|code| code.parse().ok()) {
+            Some(code) => Ok(code),
+            None => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid HTTP response")),
+        }
+    }
+}
+
+pub struct Exporter {
+    endpoint: Endpoint,
+    started: SystemTime,
+    db: db::DbView,
+    spans: Mutex<Vec<SpanRecord>>,
+}
+
+impl Exporter {
+    ///Creates new exporter, returning `None` if endpoint is not valid.
+    pub fn new(endpoint: &str, db: db::DbView) -> Option<Self> {
+        Endpoint::parse(endpoint).map(|endpoint| Self {
+            endpoint,
+            started: SystemTime::now(),
+            db,
+            spans: Mutex::new(Vec::new()),
+        })
+    }
+
+    ///Queues span for export.
+    pub fn push_span(&self, span: SpanRecord) {
+        let mut spans = self.spans.lock().unwrap_or_else(|error| error.into_inner());
+        if spans.len() < MAX_PENDING_SPANS {
+            spans.push(span);
+        }
+    }
+
+    fn traces_payload(spans: Vec<SpanRecord>) -> Value {
+        let spans: Vec<Value> = spans.into_iter().map(|span| {
+            let attributes: Vec<Value> = span.attributes.iter().map(|(key, value)| str_attr(key, value)).collect();
+            let mut result = json!({
+                "traceId": format!("{:032x}", span.trace_id),
+                "spanId": format!("{:016x}", span.span_id),
+                "name": span.name,
+                //SPAN_KIND_SERVER for request itself and SPAN_KIND_INTERNAL for its stages
+                "kind": if span.parent_span_id.is_none() { 2 } else { 1 },
+                "startTimeUnixNano": unix_nanos(span.start),
+                "endTimeUnixNano": unix_nanos(span.end),
+                "attributes": attributes,
+            });
+            if let Some(parent) = span.parent_span_id {
+                result["parentSpanId"] = format!("{:016x}", parent).into();
+            }
+            result
+        }).collect();
+
+        json!({
+            "resourceSpans": [{
+                "resource": resource(),
+                "scopeSpans": [{
+                    "scope": {"name": SERVICE_NAME},
+                    "spans": spans,
+                }],
+            }],
+        })
+    }
+
+    fn metrics_payload(&self) -> Value {
+        let snapshot = METRICS.snapshot();
+        let start = unix_nanos(self.started);
+        let now = unix_nanos(SystemTime::now());
+
+        let requests: Vec<Value> = snapshot.requests.iter().map(|(method, stats)| json!({
+            "attributes": [str_attr("method", method)],
+            "startTimeUnixNano": start,
+            "timeUnixNano": now,
+            "asInt": stats.count.to_string(),
+        })).collect();
+
+        let durations: Vec<Value> = snapshot.requests.iter().map(|(method, stats)| {
+            //Last bucket is for values above all bounds
+            let above = stats.count - stats.buckets.iter().sum::<u64>();
+            let buckets: Vec<String> = stats.buckets.iter().chain(core::iter::once(&above)).map(|count| count.to_string()).collect();
+            json!({
+                "attributes": [str_attr("method", method)],
+                "startTimeUnixNano": start,
+                "timeUnixNano": now,
+                "count": stats.count.to_string(),
+                "sum": stats.duration_sum,
+                "bucketCounts": buckets,
+                "explicitBounds": LATENCY_BUCKETS,
+            })
+        }).collect();
+
+        let errors: Vec<Value> = snapshot.errors.iter().map(|(code, count)| json!({
+            "attributes": [str_attr("code", &code.to_string())],
+            "startTimeUnixNano": start,
+            "timeUnixNano": now,
+            "asInt": count.to_string(),
+        })).collect();
+
+        //AGGREGATION_TEMPORALITY_CUMULATIVE
+        let mut metrics = vec![
+            json!({"name": "dou_store.requests", "unit": "1", "sum": {"aggregationTemporality": 2, "isMonotonic": true, "dataPoints": requests}}),
+            json!({"name": "dou_store.request.duration", "unit": "s", "histogram": {"aggregationTemporality": 2, "dataPoints": durations}}),
+            json!({"name": "dou_store.errors", "unit": "1", "sum": {"aggregationTemporality": 2, "isMonotonic": true, "dataPoints": errors}}),
+            json!({"name": "dou_store.active_connections", "unit": "1", "gauge": {"dataPoints": [{"timeUnixNano": now, "asInt": snapshot.connections.to_string()}]}}),
+        ];
+
+        match self.db.size_on_disk() {
+            Ok(size) => metrics.push(json!({"name": "dou_store.db.size", "unit": "By", "gauge": {"dataPoints": [{"timeUnixNano": now, "asInt": size.to_string()}]}})),
+            Err(error) => warn!("Unable to get db size: {}", error),
+        }
+
+        json!({
+            "resourceMetrics": [{
+                "resource": resource(),
+                "scopeMetrics": [{
+                    "scope": {"name": SERVICE_NAME},
+                    "metrics": metrics,
+                }],
+            }],
+        })
+    }
+
+    async fn send(&self, path: &str, payload: &Value) {
+        let body = match serde_json::to_vec(payload) {
+            Ok(body) => body,
+            Err(error) => {
+                error!("Unable to serialize OTLP payload: {}", error);
+                return;
+            }
+        };
+
+        match self.endpoint.post(path, &body).await {
+            Ok(code) if (200..300).contains(&code) => (),
+            Ok(code) => warn!("OTLP collector rejected {} with status {}", path, code),
+            Err(error) => warn!("Unable to send {} to OTLP collector: {}", path, error),
+        }
+    }
+
+    ///Exports spans and metrics every `interval`.
+    pub async fn run(self: Arc<Self>, interval: Duration) {
+        info!("Export OTLP to http://{}{}", self.endpoint.host, self.endpoint.path);
+
+        let mut timer = tokio::time::interval(interval);
+        timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            timer.tick().await;
+
+            let spans = core::mem::take(&mut *self.spans.lock().unwrap_or_else(|error| error.into_inner()));
+            if !spans.is_empty() {
+                self.send("/v1/traces", &Self::traces_payload(spans)).await;
+            }
+
+            let metrics = self.metrics_payload();
+            self.send("/v1/metrics", &metrics).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_endpoint() {
+        let endpoint = Endpoint::parse("http://collector:4318/otlp/").expect("endpoint");
+        assert_eq!(endpoint.host, "collector:4318");
+        assert_eq!(endpoint.path, "/otlp");
+
+        let endpoint = Endpoint::parse("http://collector").expect("endpoint");
+        assert_eq!(endpoint.host, "collector:80");
+        assert_eq!(endpoint.path, "");
+
+        assert!(Endpoint::parse("https://collector:4318").is_none());
+        assert!(Endpoint::parse("http:///v1").is_none());
+    }
+
+    #[test]
+    fn should_encode_spans() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1);
+        let spans = vec![
+            SpanRecord {
+                trace_id: 1,
+                span_id: 2,
+                parent_span_id: None,
+                name: "request",
+                start,
+                end: start + Duration::from_millis(5),
+                attributes: vec![("method", "get_config".to_owned())],
+            },
+            SpanRecord {
+                trace_id: 1,
+                span_id: 3,
+                parent_span_id: Some(2),
+                name: "dispatch",
+                start,
+                end: start + Duration::from_millis(1),
+                attributes: Vec::new(),
+            },
+        ];
+
+        let payload = Exporter::traces_payload(spans);
+        let spans = &payload["resourceSpans"][0]["scopeSpans"][0]["spans"];
+        assert_eq!(spans[0], json!({
+            "traceId": "00000000000000000000000000000001",
+            "spanId": "0000000000000002",
+            "name": "request",
+            "kind": 2,
+            "startTimeUnixNano": "1000000000",
+            "endTimeUnixNano": "1005000000",
+            "attributes": [{"key": "method", "value": {"stringValue": "get_config"}}],
+        }));
+        assert_eq!(spans[1]["kind"], 1);
+        assert_eq!(spans[1]["parentSpanId"], "0000000000000002");
+        assert_eq!(payload["resourceSpans"][0]["resource"], resource());
+    }
+}
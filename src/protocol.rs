@@ -1,12 +1,204 @@
 //! Storage uses JSON-RPC protocol
 
-use std::collections::HashMap;
+use std::borrow::Cow;
+use core::fmt;
+
+use serde::{Deserialize, Deserializer};
+use serde::de::{MapAccess, Visitor};
+use serde_json::value::RawValue;
 
-pub type RequestPayload = HashMap<String, serde_json::Value>;
 ///Request
-pub type Request = json_rpc_types::Request<RequestPayload>;
+pub type Request<'a> = json_rpc_types::Request<RequestPayload<'a>>;
 ///Response
 pub type Response = json_rpc_types::Response<serde_json::Value, &'static str>;
 
 ///Character used to indicate end of message
 pub const EOT: u8 = 0x04;
+
+///Params field, carrying correlation id of request.
+///
+///Client may supply it to be able to correlate request across systems,
+///otherwise server generates one for its own logging.
+pub const CORRELATION_ID: &str = "correlation_id";
+
+#[derive(Deserialize)]
+///String, which borrows from input unless it contains escape sequences.
+struct Str<'a>(#[serde(borrow)] Cow<'a, str>);
+
+///Value of params field.
+pub enum Field<'a> {
+    Missing,
+    Str(Cow<'a, str>),
+    ///Any other JSON value, left unparsed.
+    Other(&'a RawValue),
+}
+
+///Request params, borrowing from the read buffer.
+///
+///Values are only parsed when requested by handler.
+#[derive(Default)]
+pub struct RequestPayload<'a> {
+    fields: Vec<(Cow<'a, str>, &'a RawValue)>,
+    ///Correlation id, either supplied by client or generated by server.
+    pub correlation_id: Option<Cow<'a, str>>,
+}
+
+impl<'a> RequestPayload<'a> {
+    #[inline]
+    ///Returns raw value of the field, if present.
+    pub fn get(&self, name: &str) -> Option<&'a RawValue> {
+        self.fields.iter().find(|(key, _)| key == name).map(|(_, value)| *value)
+    }
+
+    #[inline]
+    ///Returns whether boolean field is present and set to `true`.
+    pub fn flag(&self, name: &str) -> bool {
+        self.get(name).is_some_and(|value| value.get() == "true")
+    }
+
+    ///Returns value of the field, parsing it if it is a string.
+    pub fn field(&self, name: &str) -> Field<'a> {
+        match self.get(name) {
+            Some(value) => match serde_json::from_str::<Str<'a>>(value.get()) {
+                Ok(Str(value)) => Field::Str(value),
+                Err(_) => Field::Other(value),
+            },
+            None => Field::Missing,
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for RequestPayload<'de> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct PayloadVisitor;
+
+        impl<'de> Visitor<'de> for PayloadVisitor {
+            type Value = RequestPayload<'de>;
+
+            #[inline]
+            fn expecting(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+                fmt.write_str("params object")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                let mut payload = RequestPayload {
+                    fields: Vec::with_capacity(map.size_hint().unwrap_or(4)),
+                    correlation_id: None,
+                };
+
+                while let Some((Str(key), value)) = map.next_entry::<Str<'de>, &'de RawValue>()? {
+                    payload.fields.push((key, value));
+                }
+
+                if let Field::Str(cid) = payload.field(CORRELATION_ID) {
+                    payload.correlation_id = Some(cid);
+                }
+
+                Ok(payload)
+            }
+        }
+
+        deserializer.deserialize_map(PayloadVisitor)
+    }
+}
+
+#[inline]
+///Returns correlation id of request, if present.
+pub fn correlation_id<'a>(params: &'a RequestPayload<'_>) -> Option<&'a str> {
+    params.correlation_id.as_deref()
+}
+
+///Takes first complete message, sent by server, out of buffer.
+///
+///Server doesn't delimit its messages, so `None` is returned until whole JSON value is received.
+pub fn take_message(buf: &mut Vec<u8>) -> serde_json::Result<Option<serde_json::Value>> {
+    let mut messages = serde_json::Deserializer::from_slice(buf).into_iter::<serde_json::Value>();
+    match messages.next() {
+        Some(Ok(message)) => {
+            let end = messages.byte_offset();
+            buf.drain(..end);
+            Ok(Some(message))
+        },
+        Some(Err(error)) if !error.is_eof() => Err(error),
+        _ => Ok(None),
+    }
+}
+
+///Returns frame answering to request, which server sent on its own, such as keepalive ping.
+///
+///Returns `None` if message is not such request.
+pub fn answer_server_request(message: &serde_json::Value) -> Option<Vec<u8>> {
+    message.get("method")?;
+    let id = message.get("id")?;
+
+    let mut answer = format!(r#"{{"jsonrpc":"2.0","result":null,"id":{}}}"#, id).into_bytes();
+    answer.push(EOT);
+    Some(answer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_take_correlation_id_from_params() {
+        let params = serde_json::from_str::<RequestPayload>(r#"{"id":"key","correlation_id":"trace-1"}"#).expect("params");
+        assert_eq!(correlation_id(&params), Some("trace-1"));
+
+        let params = serde_json::from_str::<RequestPayload>(r#"{"id":"key","correlation_id":1}"#).expect("params");
+        assert_eq!(correlation_id(&params), None);
+
+        let params = serde_json::from_str::<RequestPayload>(r#"{"id":"key"}"#).expect("params");
+        assert_eq!(correlation_id(&params), None);
+    }
+
+    #[test]
+    fn should_borrow_fields_from_input() {
+        let input = r#"{"id":"plain","escaped":"line\nbreak","data":{"a":[1,2]},"is_set":true,"is_unset":1}"#;
+        let params = serde_json::from_str::<RequestPayload>(input).expect("params");
+
+        match params.field("id") {
+            Field::Str(Cow::Borrowed(key)) => assert_eq!(key, "plain"),
+            _ => panic!("id must be borrowed"),
+        }
+        match params.field("escaped") {
+            Field::Str(Cow::Owned(key)) => assert_eq!(key, "line\nbreak"),
+            _ => panic!("escaped string must be owned"),
+        }
+        match params.field("data") {
+            Field::Other(data) => assert_eq!(data.get(), r#"{"a":[1,2]}"#),
+            _ => panic!("data must be left unparsed"),
+        }
+        assert!(matches!(params.field("missing"), Field::Missing));
+
+        assert!(params.flag("is_set"));
+        assert!(!params.flag("is_unset"));
+        assert!(!params.flag("missing"));
+    }
+
+    #[test]
+    fn should_reject_params_other_than_object() {
+        assert!(serde_json::from_str::<RequestPayload>(r#"["id"]"#).is_err());
+        assert!(serde_json::from_str::<RequestPayload>(r#"{"id":"key""#).is_err());
+    }
+
+    #[test]
+    fn should_answer_only_server_requests() {
+        let answer = answer_server_request(&serde_json::json!({"jsonrpc": "2.0", "method": "ping", "id": "keepalive"})).expect("answer");
+        assert_eq!(answer, b"{\"jsonrpc\":\"2.0\",\"result\":null,\"id\":\"keepalive\"}\x04");
+
+        assert!(answer_server_request(&serde_json::json!({"jsonrpc": "2.0", "result": null, "id": 1})).is_none());
+        assert!(answer_server_request(&serde_json::json!({"jsonrpc": "2.0", "method": "watch"})).is_none());
+    }
+
+    #[test]
+    fn should_take_undelimited_messages() {
+        let mut buf = br#"{"id":1}{"id":"#.to_vec();
+        assert_eq!(take_message(&mut buf).expect("message"), Some(serde_json::json!({"id": 1})));
+        assert_eq!(take_message(&mut buf).expect("message"), None);
+
+        buf.extend_from_slice(b"2}");
+        assert_eq!(take_message(&mut buf).expect("message"), Some(serde_json::json!({"id": 2})));
+        assert!(buf.is_empty());
+    }
+}
@@ -0,0 +1,261 @@
+//! Content-addressed storage of values, deduplicating large configs.
+//!
+//! Values not smaller than threshold are stored once in blobs tree under their hash,
+//! while config tree keeps pointer to it: `POINTER_TAG` followed by hash.
+//! Tag is never valid start of utf-8 string, so pointer cannot be confused with plain value.
+//!
+//! Values are addressed by SHA-256, so that blob cannot be replaced by crafted value with the same hash.
+//!
+//! Every pointer is counted in blob refs tree and blob is removed once its last pointer is gone.
+//! Blobs, which are put directly, are pinned in refs tree and kept regardless of pointers,
+//! hence only admin may put them.
+
+use core::convert::TryFrom;
+use core::fmt::Write;
+
+use json_rpc_types::{Id, Version};
+use sled::transaction::{ConflictableTransactionResult, TransactionalTree};
+use sha2::{Digest, Sha256};
+
+use super::{int_err, internal_err, missing_response, RESULT};
+use crate::db;
+use crate::protocol::Response;
+
+///First byte of pointer to blob.
+const POINTER_TAG: u8 = 0xFF;
+///Length of pointer to blob.
+const POINTER_LEN: usize = 1 + HASH_LEN;
+const HASH_LEN: usize = 32;
+///Flag of refs count, set once blob is put directly.
+const PINNED: u64 = 1 << 63;
+
+pub type Hash = [u8; HASH_LEN];
+
+#[inline]
+pub fn hash(value: &[u8]) -> Hash {
+    Sha256::digest(value).into()
+}
+
+pub fn to_hex(hash: &Hash) -> String {
+    let mut result = String::with_capacity(HASH_LEN * 2);
+    for byte in hash.iter() {
+        let _ = write!(result, "{:02x}", byte);
+    }
+    result
+}
+
+pub fn from_hex(hash: &str) -> Option<Hash> {
+    if hash.len() != HASH_LEN * 2 {
+        return None;
+    }
+
+    let mut result = [0u8; HASH_LEN];
+    for (idx, byte) in result.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hash.get(idx * 2..idx * 2 + 2)?, 16).ok()?;
+    }
+    Some(result)
+}
+
+#[inline]
+///Returns hash of blob, if value is pointer to it.
+pub fn as_pointer(value: &[u8]) -> Option<Hash> {
+    match value.split_first() {
+        Some((&POINTER_TAG, hash)) => Hash::try_from(hash).ok(),
+        _ => None,
+    }
+}
+
+#[inline]
+fn pointer(hash: &Hash) -> [u8; POINTER_LEN] {
+    let mut pointer = [POINTER_TAG; POINTER_LEN];
+    pointer[1..].copy_from_slice(hash);
+    pointer
+}
+
+///Returns refs count of blob, including `PINNED` flag, or `default` if it is not counted.
+fn ref_count<E>(refs: &TransactionalTree, hash: &Hash, default: u64) -> ConflictableTransactionResult<u64, E> {
+    match refs.get(hash)? {
+        Some(count) => Ok(<[u8; 8]>::try_from(count.as_ref()).map(u64::from_be_bytes).unwrap_or(default)),
+        None => Ok(default),
+    }
+}
+
+fn add_ref<E>(blobs: &TransactionalTree, refs: &TransactionalTree, hash: &Hash, value: &[u8]) -> ConflictableTransactionResult<(), E> {
+    let count = ref_count(refs, hash, 0)?;
+    if blobs.get(hash)?.is_none() {
+        blobs.insert(&hash[..], value)?;
+    }
+    refs.insert(&hash[..], &(count + 1).to_be_bytes())?;
+    Ok(())
+}
+
+///Drops reference of value, previously stored in config, once it is pointer.
+pub fn release<E>(blobs: &TransactionalTree, refs: &TransactionalTree, value: &[u8]) -> ConflictableTransactionResult<(), E> {
+    let hash = match as_pointer(value) {
+        Some(hash) => hash,
+        None => return Ok(()),
+    };
+
+    let count = ref_count(refs, &hash, 1)?;
+    match count & !PINNED {
+        0 | 1 if count & PINNED == 0 => {
+            refs.remove(&hash[..])?;
+            blobs.remove(&hash[..])?;
+        },
+        0 => (),
+        _ => {
+            refs.insert(&hash[..], &(count - 1).to_be_bytes())?;
+        },
+    }
+    Ok(())
+}
+
+///Returns length of value of config key, following pointer to blob.
+pub fn stored_len<E>(config: &TransactionalTree, blobs: &TransactionalTree, key: &str) -> ConflictableTransactionResult<Option<usize>, E> {
+    match config.get(key.as_bytes())? {
+        Some(value) => match as_pointer(&value) {
+            Some(hash) => Ok(Some(blobs.get(hash)?.map_or(0, |blob| blob.len()))),
+            None => Ok(Some(value.len())),
+        },
+        None => Ok(None),
+    }
+}
+
+///Writes value of config key, storing it as blob if it is not smaller than threshold.
+///
+///Threshold 0 disables blobs.
+pub fn store<E>(config: &TransactionalTree, blobs: &TransactionalTree, refs: &TransactionalTree, threshold: usize, key: &str, value: &[u8]) -> ConflictableTransactionResult<(), E> {
+    let old = match threshold > 0 && value.len() >= threshold {
+        true => {
+            let hash = hash(value);
+            add_ref(blobs, refs, &hash, value)?;
+            config.insert(key.as_bytes(), &pointer(&hash)[..])?
+        },
+        false => config.insert(key.as_bytes(), value)?,
+    };
+
+    match old {
+        Some(old) => release(blobs, refs, &old),
+        None => Ok(()),
+    }
+}
+
+///Removes value of config key, returning whether it existed.
+pub fn remove<E>(config: &TransactionalTree, blobs: &TransactionalTree, refs: &TransactionalTree, key: &str) -> ConflictableTransactionResult<bool, E> {
+    match config.remove(key.as_bytes())? {
+        Some(old) => release(blobs, refs, &old).map(|_| true),
+        None => Ok(false),
+    }
+}
+
+///Stores blob, pinning it so that it is kept regardless of pointers.
+pub fn handle_put_blob_req(db: &db::DbView, value: &str, cid: Option<&str>, id: Option<Id>) -> Response {
+    use sled::Transactional;
+    use sled::transaction::TransactionError;
+
+    let hash = hash(value.as_bytes());
+
+    let result: Result<(), TransactionError<sled::Error>> = (&db.blobs, &db.blob_refs).transaction(|(blobs, refs)| {
+        //Blob with the same hash can be stored already, in which case it is the same value.
+        if blobs.get(hash)?.is_none() {
+            blobs.insert(&hash[..], value.as_bytes())?;
+        }
+        let count = ref_count(refs, &hash, 0)?;
+        refs.insert(&hash[..], &(count | PINNED).to_be_bytes())?;
+        Ok(())
+    });
+
+    match result {
+        Ok(()) => {
+            let mut payload = serde_json::map::Map::with_capacity(1);
+            payload.insert(RESULT.to_owned(), to_hex(&hash).into());
+            Response::result(Version::V2, payload.into(), id)
+        },
+        Err(error) => {
+            error!(cid: cid, "Unable to put blob: {}", error);
+            internal_err(int_err::BLOB_FAIL, id)
+        },
+    }
+}
+
+pub fn handle_get_blob_req(db: &db::DbView, hash: &Hash, explicit_missing: bool, cid: Option<&str>, id: Option<Id>) -> Response {
+    match db.blobs.get(hash) {
+        Ok(Some(value)) => match core::str::from_utf8(&value) {
+            Ok(value) => {
+                let mut payload = serde_json::map::Map::with_capacity(1);
+                payload.insert(RESULT.to_owned(), value.into());
+                Response::result(Version::V2, payload.into(), id)
+            },
+            Err(error) => {
+                error!(cid: cid, "Data corruption in blob {}. Unexpected non-utf8 value: {}", to_hex(hash), error);
+                internal_err(int_err::BLOB_RSP_CORRUPT, id)
+            },
+        },
+        Ok(None) if explicit_missing => missing_response(id),
+        Ok(None) => {
+            let mut payload = serde_json::map::Map::with_capacity(1);
+            payload.insert(RESULT.to_owned(), "".into());
+            Response::result(Version::V2, payload.into(), id)
+        },
+        Err(error) => {
+            error!(cid: cid, "Internal error accessing blobs tree: {}", error);
+            internal_err(int_err::BLOB_FAIL, id)
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sled::Transactional;
+    use sled::transaction::TransactionResult;
+
+    fn write(db: &db::DbView, key: &str, value: Option<&str>) {
+        let result: TransactionResult<(), ()> = (&db.config, &db.blobs, &db.blob_refs).transaction(|(config, blobs, refs)| match value {
+            Some(value) => store(config, blobs, refs, 1, key, value.as_bytes()),
+            None => remove(config, blobs, refs, key).map(|_| ()),
+        });
+        result.expect("write");
+    }
+
+    #[test]
+    fn should_convert_hash_to_hex() {
+        let hash = hash(b"value");
+        let hex = to_hex(&hash);
+        assert_eq!(hex.len(), HASH_LEN * 2);
+        assert_eq!(from_hex(&hex), Some(hash));
+        assert_eq!(from_hex(&hex[1..]), None);
+    }
+
+    #[test]
+    fn should_remove_blob_with_last_pointer() {
+        let db = db::Db::temporary().expect("open db").view();
+        let hash = hash(b"shared");
+
+        write(&db, "first", Some("shared"));
+        write(&db, "second", Some("shared"));
+        assert_eq!(db.get_config("first").expect("get").as_deref(), Some(&b"shared"[..]));
+
+        write(&db, "first", None);
+        assert!(db.blobs.get(hash).expect("get").is_some());
+        write(&db, "second", Some("other"));
+        assert!(db.blobs.get(hash).expect("get").is_none());
+        assert!(db.blob_refs.get(hash).expect("get").is_none());
+    }
+
+    #[test]
+    fn should_keep_blob_put_directly() {
+        let db = db::Db::temporary().expect("open db").view();
+        let hash = hash(b"pinned");
+
+        handle_put_blob_req(&db, "pinned", None, None).payload.expect("put blob");
+        write(&db, "key", Some("pinned"));
+        write(&db, "key", None);
+        assert_eq!(db.blobs.get(hash).expect("get").as_deref(), Some(&b"pinned"[..]));
+
+        write(&db, "key", Some("pinned"));
+        handle_put_blob_req(&db, "pinned", None, None).payload.expect("put blob");
+        write(&db, "key", None);
+        assert_eq!(db.blobs.get(hash).expect("get").as_deref(), Some(&b"pinned"[..]));
+    }
+}
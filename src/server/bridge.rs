@@ -0,0 +1,287 @@
+//! Mirroring of configs into external etcd or Consul prefix.
+//!
+//! Every mutation of config tree under local prefix is pushed to remote prefix, regardless of what
+//! caused it, by watching the tree. Keys, which existed before bridge started, are not pushed until changed.
+//!
+//! Optionally remote prefix is also polled and its changes are written back. Key is only deleted locally,
+//! once it disappears from remote prefix after being seen there, so that local writes, which are not
+//! pushed yet, are kept.
+
+use std::collections::HashSet;
+use std::io;
+use core::time::Duration;
+
+use super::import::{self, Endpoint, Kind};
+use super::{key, lease, worker};
+use crate::db;
+
+///Number of attempts to push single mutation.
+const PUSH_ATTEMPTS: usize = 3;
+///Delay before next attempt to push.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Clone)]
+pub struct Config {
+    pub kind: Kind,
+    pub url: String,
+    ///Mirrored prefix of local keys.
+    pub prefix: String,
+    ///Prefix of remote keys, replacing local prefix.
+    pub remote_prefix: String,
+    ///Interval of pulling remote changes back, disabled if `None`.
+    pub pull_interval: Option<Duration>,
+}
+
+struct Bridge {
+    kind: Kind,
+    endpoint: Endpoint,
+    prefix: String,
+    remote_prefix: String,
+}
+
+impl Bridge {
+    #[inline]
+    fn remote_key(&self, key: &[u8]) -> Vec<u8> {
+        let mut remote = self.remote_prefix.as_bytes().to_vec();
+        remote.extend_from_slice(&key[self.prefix.len().min(key.len())..]);
+        remote
+    }
+
+    #[inline]
+    fn local_key(&self, remote: &[u8]) -> Option<String> {
+        let rest = remote.strip_prefix(self.remote_prefix.as_bytes())?;
+        let rest = core::str::from_utf8(rest).ok()?;
+        Some(format!("{}{}", self.prefix, rest))
+    }
+
+    fn expect_ok(&self, status: u16) -> io::Result<()> {
+        match status {
+            200..=299 => Ok(()),
+            status => Err(io::Error::other(format!("Remote responded with status {}", status))),
+        }
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        let (status, _) = match self.kind {
+            Kind::Etcd => {
+                let body = serde_json::json!({
+                    "key": import::base64_encode(key),
+                    "value": import::base64_encode(value),
+                });
+                self.endpoint.request("POST", "/v3/kv/put", "", body.to_string().as_bytes())?
+            },
+            Kind::Consul => {
+                let key = String::from_utf8_lossy(key);
+                self.endpoint.request("PUT", &format!("/v1/kv/{}", key), &import::consul_headers(), value)?
+            },
+        };
+        self.expect_ok(status)
+    }
+
+    fn delete(&self, key: &[u8]) -> io::Result<()> {
+        let (status, _) = match self.kind {
+            Kind::Etcd => {
+                let body = serde_json::json!({
+                    "key": import::base64_encode(key),
+                });
+                self.endpoint.request("POST", "/v3/kv/deleterange", "", body.to_string().as_bytes())?
+            },
+            Kind::Consul => {
+                let key = String::from_utf8_lossy(key);
+                self.endpoint.request("DELETE", &format!("/v1/kv/{}", key), &import::consul_headers(), &[])?
+            },
+        };
+        self.expect_ok(status)
+    }
+
+    fn push(&self, db: &db::DbView, event: &sled::Event) -> io::Result<()> {
+        match event {
+            sled::Event::Insert { key, .. } => {
+                //Value is read again to follow pointer to blob.
+                match db.get_config(key) {
+                    Ok(Some(value)) => self.put(&self.remote_key(key), &value),
+                    Ok(None) => Ok(()),
+                    Err(error) => Err(io::Error::other(error)),
+                }
+            },
+            sled::Event::Remove { key } => self.delete(&self.remote_key(key)),
+        }
+    }
+
+    fn run_push(&self, db: db::DbView) {
+        let subscriber = db.config.watch_prefix(self.prefix.as_bytes());
+
+        for event in subscriber {
+            let key = String::from_utf8_lossy(event.key()).into_owned();
+            for attempt in 1..=PUSH_ATTEMPTS {
+                match self.push(&db, &event) {
+                    Ok(()) => break,
+                    Err(error) if attempt < PUSH_ATTEMPTS => {
+                        warn!("Bridge: Unable to push '{}', retrying: {}", key, error);
+                        std::thread::sleep(RETRY_DELAY);
+                    },
+                    Err(error) => error!("Bridge: Unable to push '{}': {}", key, error),
+                }
+            }
+        }
+    }
+
+    fn pull(&self, db: &db::DbView, worker: &worker::Worker, key_rules: &key::KeyRules, seen: &mut HashSet<String>) -> io::Result<()> {
+        let entries = self.kind.fetch(&self.endpoint, &self.remote_prefix)?;
+        let mut current = HashSet::with_capacity(entries.len());
+
+        for (remote, value) in entries {
+            let (key, value) = match (self.local_key(&remote), String::from_utf8(value)) {
+                (Some(key), Ok(value)) => (key, value),
+                _ => continue,
+            };
+            if key_rules.validate(&key).is_err() {
+                continue;
+            }
+
+            let is_changed = match db.get_config(&key) {
+                Ok(stored) => stored.as_deref() != Some(value.as_bytes()),
+                Err(error) => return Err(io::Error::other(error)),
+            };
+            if is_changed {
+                trace!("Bridge: Pulled '{}'", key);
+                worker.spawn(worker::Operation::SetConfig { key: key.clone(), value, lease: lease::Lease::Keep });
+            }
+            current.insert(key);
+        }
+
+        for key in seen.difference(&current) {
+            trace!("Bridge: Pulled removal of '{}'", key);
+            worker.spawn(worker::Operation::DeleteConfig { key: key.clone(), lease: lease::Lease::Keep });
+        }
+
+        *seen = current;
+        Ok(())
+    }
+
+    fn run_pull(&self, db: db::DbView, worker: worker::Worker, key_rules: key::KeyRules, interval: Duration) {
+        let mut seen = HashSet::new();
+        loop {
+            if let Err(error) = self.pull(&db, &worker, &key_rules, &mut seen) {
+                warn!("Bridge: Unable to pull from remote: {}", error);
+            }
+            std::thread::sleep(interval);
+        }
+    }
+}
+
+///Starts threads, mirroring configs as per `config`.
+pub fn start(config: Config, db: db::DbView, worker: worker::Worker, key_rules: key::KeyRules) {
+    let endpoint = match Endpoint::parse(&config.url) {
+        Some(endpoint) => endpoint,
+        None => {
+            error!("Bridge: Invalid URL '{}', only http://host:port is supported", config.url);
+            return;
+        }
+    };
+    let bridge = std::sync::Arc::new(Bridge {
+        kind: config.kind,
+        endpoint,
+        prefix: config.prefix,
+        remote_prefix: config.remote_prefix,
+    });
+    info!("Bridge: Mirror '{}' to {}", bridge.prefix, config.url);
+
+    let push = bridge.clone();
+    let push_db = db.clone();
+    if let Err(error) = std::thread::Builder::new().name("bridge-push".to_owned()).spawn(move || push.run_push(push_db)) {
+        error!("Bridge: Unable to start push thread: {}", error);
+    }
+
+    if let Some(interval) = config.pull_interval {
+        if let Err(error) = std::thread::Builder::new().name("bridge-pull".to_owned()).spawn(move || bridge.run_pull(db, worker, key_rules, interval)) {
+            error!("Bridge: Unable to start pull thread: {}", error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::import::tests::{serve, ok};
+
+    fn bridge(kind: Kind, endpoint: Endpoint) -> Bridge {
+        Bridge {
+            kind,
+            endpoint,
+            prefix: "app/".to_owned(),
+            remote_prefix: "legacy/".to_owned(),
+        }
+    }
+
+    #[test]
+    fn should_map_keys_between_prefixes() {
+        let bridge = bridge(Kind::Etcd, Endpoint::parse("http://127.0.0.1:1").expect("endpoint"));
+        assert_eq!(bridge.remote_key(b"app/key"), b"legacy/key");
+        assert_eq!(bridge.local_key(b"legacy/key").as_deref(), Some("app/key"));
+        assert_eq!(bridge.local_key(b"other/key"), None);
+        assert_eq!(bridge.local_key(b"legacy/\xFF"), None);
+    }
+
+    #[test]
+    fn should_push_mutations_to_etcd() {
+        let (endpoint, server) = serve(vec![ok("{}"), ok("{}"), "HTTP/1.1 500 Error\r\n\r\n".to_owned()]);
+        let bridge = bridge(Kind::Etcd, endpoint);
+
+        bridge.put(b"legacy/key", b"value").expect("put");
+        bridge.delete(b"legacy/key").expect("delete");
+        assert!(bridge.put(b"legacy/key", b"value").is_err());
+
+        let requests = server.join().expect("server");
+        assert!(requests[0].starts_with("POST /v3/kv/put HTTP/1.0\r\n"));
+        assert!(requests[0].contains(&import::base64_encode(b"legacy/key")));
+        assert!(requests[0].contains(&import::base64_encode(b"value")));
+        assert!(requests[1].starts_with("POST /v3/kv/deleterange HTTP/1.0\r\n"));
+    }
+
+    #[test]
+    fn should_push_mutations_to_consul() {
+        let (endpoint, server) = serve(vec![ok("true"), ok("true")]);
+        let bridge = bridge(Kind::Consul, endpoint);
+
+        bridge.put(b"legacy/key", b"value").expect("put");
+        bridge.delete(b"legacy/key").expect("delete");
+
+        let requests = server.join().expect("server");
+        assert!(requests[0].starts_with("PUT /v1/kv/legacy/key HTTP/1.0\r\n"));
+        assert!(requests[0].ends_with("\r\n\r\nvalue"));
+        assert!(requests[1].starts_with("DELETE /v1/kv/legacy/key HTTP/1.0\r\n"));
+    }
+
+    #[tokio::test]
+    async fn should_pull_only_removals_of_seen_keys() {
+        let db = db::Db::temporary().expect("open db").view();
+        let worker = worker::Worker::new(1, db.clone(), None);
+        super::super::handle_set_config_req(&db, None, "app/local", "value", lease::Lease::Keep, None, None).payload.expect("set");
+
+        let first = serde_json::json!([
+            {"Key": "legacy/a", "Value": import::base64_encode(b"1")},
+            {"Key": "legacy/b", "Value": import::base64_encode(b"2")},
+        ]);
+        let second = serde_json::json!([
+            {"Key": "legacy/b", "Value": import::base64_encode(b"2")},
+        ]);
+        let (endpoint, server) = serve(vec![ok(&first.to_string()), ok(&second.to_string())]);
+        let bridge = bridge(Kind::Consul, endpoint);
+        let mut seen = HashSet::new();
+
+        bridge.pull(&db, &worker, &key::KeyRules::default(), &mut seen).expect("pull");
+        //Single worker performs operations in order.
+        worker.call("wait", None, || ()).await;
+        assert_eq!(db.get_config("app/a").expect("get").as_deref(), Some(&b"1"[..]));
+        assert_eq!(db.get_config("app/b").expect("get").as_deref(), Some(&b"2"[..]));
+
+        bridge.pull(&db, &worker, &key::KeyRules::default(), &mut seen).expect("pull");
+        worker.call("wait", None, || ()).await;
+        assert_eq!(db.get_config("app/a").expect("get"), None);
+        assert_eq!(db.get_config("app/b").expect("get").as_deref(), Some(&b"2"[..]));
+        assert_eq!(db.get_config("app/local").expect("get").as_deref(), Some(&b"value"[..]));
+
+        server.join().expect("server");
+    }
+}
@@ -0,0 +1,166 @@
+//! LRU cache of config values, limited by total size of keys and values.
+
+use std::sync::{Arc, Mutex};
+use std::collections::{BTreeMap, HashMap};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+struct Entry {
+    value: sled::IVec,
+    //Position within LRU order
+    tick: u64,
+}
+
+#[derive(Default)]
+struct State {
+    size: usize,
+    tick: u64,
+    entries: HashMap<Arc<str>, Entry>,
+    //Least recently used first
+    order: BTreeMap<u64, Arc<str>>,
+}
+
+impl State {
+    fn remove(&mut self, key: &str) {
+        if let Some((key, entry)) = self.entries.remove_entry(key) {
+            self.order.remove(&entry.tick);
+            self.size -= key.len() + entry.value.len();
+        }
+    }
+}
+
+pub struct Cache {
+    capacity: usize,
+    //Incremented on every invalidation, to prevent caching of value read before it.
+    generation: AtomicU64,
+    state: Mutex<State>,
+}
+
+impl Cache {
+    ///Creates new cache, holding up to `capacity` bytes.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            generation: AtomicU64::new(0),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    #[inline]
+    ///Returns current generation, which must be acquired before reading value from db.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    pub fn get(&self, key: &str) -> Option<sled::IVec> {
+        let mut state = self.state.lock().unwrap_or_else(|error| error.into_inner());
+        let state = &mut *state;
+
+        state.tick += 1;
+        let tick = state.tick;
+        let (key, entry) = state.entries.get_key_value(key)?;
+        let key = key.clone();
+        let value = entry.value.clone();
+        let old_tick = entry.tick;
+
+        state.order.remove(&old_tick);
+        state.order.insert(tick, key.clone());
+        if let Some(entry) = state.entries.get_mut(&key) {
+            entry.tick = tick;
+        }
+
+        Some(value)
+    }
+
+    ///Stores value, unless it was invalidated since `generation`.
+    pub fn insert(&self, key: &str, value: sled::IVec, generation: u64) {
+        let size = key.len() + value.len();
+        if size > self.capacity {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap_or_else(|error| error.into_inner());
+        if self.generation() != generation {
+            return;
+        }
+
+        state.remove(key);
+        while state.size + size > self.capacity {
+            match state.order.pop_first() {
+                Some((_, oldest)) => state.remove(&oldest),
+                None => break,
+            }
+        }
+
+        state.tick += 1;
+        let tick = state.tick;
+        let key: Arc<str> = key.into();
+        state.order.insert(tick, key.clone());
+        state.entries.insert(key, Entry {
+            value,
+            tick,
+        });
+        state.size += size;
+    }
+
+    ///Removes value, must be called after value is modified in db.
+    pub fn invalidate(&self, key: &str) {
+        let mut state = self.state.lock().unwrap_or_else(|error| error.into_inner());
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        state.remove(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_evict_least_recently_used() {
+        let cache = Cache::new(8);
+        cache.insert("a", "11".into(), cache.generation());
+        cache.insert("b", "22".into(), cache.generation());
+
+        //Access makes "a" most recently used.
+        assert_eq!(cache.get("a").as_deref(), Some(&b"11"[..]));
+        cache.insert("c", "33".into(), cache.generation());
+
+        assert_eq!(cache.get("a").as_deref(), Some(&b"11"[..]));
+        assert_eq!(cache.get("b"), None);
+        assert_eq!(cache.get("c").as_deref(), Some(&b"33"[..]));
+    }
+
+    #[test]
+    fn should_skip_values_over_capacity() {
+        let cache = Cache::new(4);
+        cache.insert("key", "value".into(), cache.generation());
+        assert_eq!(cache.get("key"), None);
+    }
+
+    #[test]
+    fn should_not_cache_value_read_before_invalidation() {
+        let cache = Cache::new(64);
+        cache.insert("key", "old".into(), cache.generation());
+
+        let generation = cache.generation();
+        cache.invalidate("key");
+        assert_eq!(cache.get("key"), None);
+
+        cache.insert("key", "stale".into(), generation);
+        assert_eq!(cache.get("key"), None);
+
+        cache.insert("key", "new".into(), cache.generation());
+        assert_eq!(cache.get("key").as_deref(), Some(&b"new"[..]));
+    }
+
+    #[test]
+    fn should_account_size_of_replaced_value() {
+        let cache = Cache::new(6);
+        cache.insert("a", "11".into(), cache.generation());
+        cache.insert("a", "1".into(), cache.generation());
+        cache.insert("b", "2".into(), cache.generation());
+
+        assert_eq!(cache.state.lock().unwrap_or_else(|error| error.into_inner()).size, 4);
+        assert_eq!(cache.get("a").as_deref(), Some(&b"1"[..]));
+        assert_eq!(cache.get("b").as_deref(), Some(&b"2"[..]));
+    }
+}
@@ -0,0 +1,165 @@
+//! Fault injection, exercising retries and timeouts of clients.
+//!
+//! Once server is started with `--chaos`, every request, except `chaos` itself, can be delayed,
+//! answered with transient error or have its response lost.
+//! Faults are changed at runtime via `chaos` method, which is unavailable otherwise and requires admin token.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+
+use json_rpc_types::{Id, Version, Error, ErrorCode};
+
+use super::int_err;
+use crate::protocol::Response;
+
+#[derive(Clone, Copy, Default)]
+pub struct Settings {
+    pub enabled: bool,
+    ///Maximum injected latency, actual one is random up to it.
+    pub latency_ms: u64,
+    ///Percent of requests, which responses are never sent.
+    pub drop_percent: u64,
+    ///Percent of requests, which fail with transient error.
+    pub error_percent: u64,
+}
+
+impl Settings {
+    pub fn to_json(self) -> serde_json::Value {
+        let mut result = serde_json::Map::with_capacity(4);
+        result.insert("enabled".to_owned(), self.enabled.into());
+        result.insert("latency_ms".to_owned(), self.latency_ms.into());
+        result.insert("drop_percent".to_owned(), self.drop_percent.into());
+        result.insert("error_percent".to_owned(), self.error_percent.into());
+        result.into()
+    }
+}
+
+pub struct Chaos {
+    enabled: AtomicBool,
+    latency_ms: AtomicU64,
+    drop_percent: AtomicU64,
+    error_percent: AtomicU64,
+    //State of xorshift generator
+    rng: AtomicU64,
+}
+
+impl Chaos {
+    pub fn new(settings: Settings) -> Self {
+        let seed = match std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH) {
+            Ok(now) => now.as_nanos() as u64,
+            Err(_) => 0,
+        };
+
+        let result = Self {
+            enabled: AtomicBool::new(false),
+            latency_ms: AtomicU64::new(0),
+            drop_percent: AtomicU64::new(0),
+            error_percent: AtomicU64::new(0),
+            //Zero state would generate only zeros.
+            rng: AtomicU64::new(seed | 1),
+        };
+        result.set(settings);
+        result
+    }
+
+    pub fn set(&self, settings: Settings) {
+        self.latency_ms.store(settings.latency_ms, Ordering::Relaxed);
+        self.drop_percent.store(settings.drop_percent.min(100), Ordering::Relaxed);
+        self.error_percent.store(settings.error_percent.min(100), Ordering::Relaxed);
+        self.enabled.store(settings.enabled, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> Settings {
+        Settings {
+            enabled: self.enabled.load(Ordering::Relaxed),
+            latency_ms: self.latency_ms.load(Ordering::Relaxed),
+            drop_percent: self.drop_percent.load(Ordering::Relaxed),
+            error_percent: self.error_percent.load(Ordering::Relaxed),
+        }
+    }
+
+    ///Returns random number in range `0..bound`.
+    fn random(&self, bound: u64) -> u64 {
+        let mut state = self.rng.load(Ordering::Relaxed);
+        loop {
+            let mut next = state;
+            next ^= next << 13;
+            next ^= next >> 7;
+            next ^= next << 17;
+            match self.rng.compare_exchange_weak(state, next, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => return next % bound,
+                Err(current) => state = current,
+            }
+        }
+    }
+
+    #[inline]
+    fn roll(&self, percent: u64) -> bool {
+        percent > 0 && self.random(100) < percent
+    }
+
+    ///Delays request, returning error response, if request is to fail.
+    pub async fn inject(&self, id: Option<Id>) -> Option<Response> {
+        if !self.enabled.load(Ordering::Relaxed) {
+            return None;
+        }
+
+        let latency_ms = self.latency_ms.load(Ordering::Relaxed);
+        if latency_ms > 0 {
+            tokio::time::sleep(Duration::from_millis(self.random(latency_ms + 1))).await;
+        }
+
+        match self.roll(self.error_percent.load(Ordering::Relaxed)) {
+            true => Some(Response::error(Version::V2, Error::from_code(ErrorCode::ServerError(int_err::CHAOS_FAULT)).set_data("Injected fault, retry request"), id)),
+            false => None,
+        }
+    }
+
+    #[inline]
+    ///Returns whether response should be lost.
+    pub fn is_lost(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed) && self.roll(self.drop_percent.load(Ordering::Relaxed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_clamp_percents() {
+        let chaos = Chaos::new(Settings { enabled: true, latency_ms: 5, drop_percent: 150, error_percent: 101 });
+        let settings = chaos.get();
+        assert!(settings.enabled);
+        assert_eq!(settings.latency_ms, 5);
+        assert_eq!(settings.drop_percent, 100);
+        assert_eq!(settings.error_percent, 100);
+    }
+
+    #[test]
+    fn should_generate_numbers_within_bound() {
+        let chaos = Chaos::new(Settings::default());
+        for _ in 0..1000 {
+            assert!(chaos.random(3) < 3);
+        }
+        assert!(!chaos.roll(0));
+        assert!(chaos.roll(100));
+    }
+
+    #[tokio::test]
+    async fn should_inject_faults_only_once_enabled() {
+        let chaos = Chaos::new(Settings { enabled: false, latency_ms: 0, drop_percent: 100, error_percent: 100 });
+        assert!(chaos.inject(Some(Id::Num(1))).await.is_none());
+        assert!(!chaos.is_lost());
+
+        chaos.set(Settings { enabled: true, ..chaos.get() });
+        let response = chaos.inject(Some(Id::Num(1))).await.expect("fault");
+        assert_eq!(response.payload.expect_err("fault").code.code(), int_err::CHAOS_FAULT);
+        assert_eq!(response.id, Some(Id::Num(1)));
+        assert!(chaos.is_lost());
+
+        chaos.set(Settings { enabled: true, latency_ms: 1, drop_percent: 0, error_percent: 0 });
+        assert!(chaos.inject(None).await.is_none());
+        assert!(!chaos.is_lost());
+    }
+}
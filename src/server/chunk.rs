@@ -0,0 +1,264 @@
+//! Chunked transfer of large values.
+//!
+//! Client opts into chunked download by setting `chunked: true` in params of `config`,
+//! which makes values above `CHUNK_SIZE` to be sent as sequence of responses with the same id,
+//! each carrying result `{seq, last, data}`.
+//!
+//! Upload is done by sending `set_config` for every chunk with `seq` and `last` params.
+//! Intermediate chunks are acknowledged with their `seq`, while last one is answered as normal `set_config`.
+//! Uploads belong to connection, which started them, and are discarded once it is closed.
+//! All uploads of connection together are limited to `MAX_SESSION_SIZE`.
+
+use std::sync::Mutex;
+use std::collections::HashMap;
+use std::time::Instant;
+use core::time::Duration;
+
+use json_rpc_types::Version;
+
+use super::{DATA, RESULT, SEQ, LAST, CHUNKED};
+use crate::protocol::{Field, RequestPayload, Response};
+
+///Maximum size of data in single chunk.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+///Uploads without new chunks for this long are discarded.
+const UPLOAD_TIMEOUT: Duration = Duration::from_secs(60);
+///Maximum size of uploaded value.
+pub const MAX_UPLOAD_SIZE: usize = 64 * 1024 * 1024;
+///Maximum size of all values, which single session uploads at once.
+pub const MAX_SESSION_SIZE: usize = 2 * MAX_UPLOAD_SIZE;
+
+#[inline]
+///Returns whether client asked for chunked response.
+pub fn is_requested(params: &RequestPayload<'_>) -> bool {
+    params.flag(CHUNKED)
+}
+
+///Returns `seq` and `last` params of uploaded chunk, if request is chunk.
+pub fn upload_params(params: &RequestPayload<'_>) -> Result<Option<(u64, bool)>, &'static str> {
+    let seq = match params.get(SEQ) {
+        Some(seq) => match serde_json::from_str::<u64>(seq.get()) {
+            Ok(seq) => seq,
+            Err(_) => return Err("Params field 'seq' must be unsigned integer"),
+        },
+        None => return Ok(None),
+    };
+
+    let last = match params.field(LAST) {
+        Field::Missing => false,
+        Field::Other(value) => match value.get() {
+            "true" => true,
+            "false" => false,
+            _ => return Err("Params field 'last' must be boolean"),
+        },
+        Field::Str(_) => return Err("Params field 'last' must be boolean"),
+    };
+
+    Ok(Some((seq, last)))
+}
+
+///Takes out string result of response, if it should be split into chunks.
+pub fn take_data(response: &mut Response) -> Option<String> {
+    match &mut response.payload {
+        Ok(serde_json::Value::Object(result)) => match result.get(RESULT) {
+            Some(serde_json::Value::String(data)) if data.len() > CHUNK_SIZE => match result.remove(RESULT) {
+                Some(serde_json::Value::String(data)) => Some(data),
+                _ => None,
+            },
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+///Splits data into chunks of at most `CHUNK_SIZE` bytes, respecting char boundaries.
+pub fn split(mut data: &str) -> impl Iterator<Item = &str> {
+    core::iter::from_fn(move || {
+        if data.is_empty() {
+            return None;
+        }
+
+        let mut end = data.len().min(CHUNK_SIZE);
+        while !data.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        let (chunk, rest) = data.split_at(end);
+        data = rest;
+        Some(chunk)
+    })
+}
+
+///Creates response for chunk, copying the rest of result from `response`.
+pub fn response(response: &Response, seq: usize, last: bool, data: &str) -> Response {
+    let mut payload = match &response.payload {
+        Ok(serde_json::Value::Object(result)) => result.clone(),
+        _ => serde_json::Map::with_capacity(3),
+    };
+    payload.insert(SEQ.to_owned(), seq.into());
+    payload.insert(LAST.to_owned(), last.into());
+    payload.insert(DATA.to_owned(), data.into());
+
+    Response::result(Version::V2, payload.into(), response.id.clone())
+}
+
+struct Upload {
+    next_seq: u64,
+    data: String,
+    updated: Instant,
+}
+
+#[derive(Default)]
+///Chunked uploads in progress.
+pub struct Uploads {
+    //Session and key to upload
+    inner: Mutex<HashMap<(u64, String), Upload>>,
+}
+
+impl Uploads {
+    ///Adds chunk of session, returning full value when last chunk is received.
+    ///
+    ///Chunk with `seq` 0 always starts new upload.
+    pub fn push(&self, session: u64, key: &str, seq: u64, last: bool, data: &str) -> Result<Option<String>, &'static str> {
+        let mut uploads = self.inner.lock().unwrap_or_else(|error| error.into_inner());
+        uploads.retain(|_, upload| upload.updated.elapsed() < UPLOAD_TIMEOUT);
+
+        let key = (session, key.to_owned());
+        //Size of other uploads of session.
+        let staged: usize = uploads.iter().filter(|(other, _)| other.0 == session && other.1 != key.1).map(|(_, upload)| upload.data.len()).sum();
+        if seq == 0 {
+            if last {
+                uploads.remove(&key);
+                return Ok(Some(data.to_owned()));
+            }
+            if staged + data.len() > MAX_SESSION_SIZE {
+                uploads.remove(&key);
+                return Err("Uploads of connection exceed maximum size, upload is aborted");
+            }
+
+            uploads.insert(key, Upload {
+                next_seq: 1,
+                data: data.to_owned(),
+                updated: Instant::now(),
+            });
+            return Ok(None);
+        }
+
+        match uploads.get_mut(&key) {
+            Some(upload) if upload.next_seq == seq && upload.data.len() + data.len() > MAX_UPLOAD_SIZE => {
+                uploads.remove(&key);
+                Err("Uploaded value exceeds maximum size, upload is aborted")
+            },
+            Some(upload) if upload.next_seq == seq && staged + upload.data.len() + data.len() > MAX_SESSION_SIZE => {
+                uploads.remove(&key);
+                Err("Uploads of connection exceed maximum size, upload is aborted")
+            },
+            Some(upload) if upload.next_seq == seq => {
+                upload.data.push_str(data);
+                if last {
+                    return Ok(uploads.remove(&key).map(|upload| upload.data));
+                }
+
+                upload.next_seq += 1;
+                upload.updated = Instant::now();
+                Ok(None)
+            },
+            Some(_) => {
+                uploads.remove(&key);
+                Err("Unexpected chunk 'seq', upload is aborted")
+            },
+            None => Err("Unexpected chunk 'seq', upload is not started"),
+        }
+    }
+
+    ///Discards all uploads of session.
+    pub fn close(&self, session: u64) {
+        self.inner.lock().unwrap_or_else(|error| error.into_inner()).retain(|(owner, _), _| *owner != session);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_split_at_char_boundaries() {
+        let data = "é".repeat(CHUNK_SIZE);
+        let chunks: Vec<&str> = split(&data).collect();
+
+        assert!(chunks.iter().all(|chunk| chunk.len() <= CHUNK_SIZE));
+        assert_eq!(chunks.concat(), data);
+        assert_eq!(split("").count(), 0);
+    }
+
+    #[test]
+    fn should_assemble_chunks_in_order() {
+        let uploads = Uploads::default();
+
+        assert_eq!(uploads.push(1, "key", 0, false, "a"), Ok(None));
+        assert_eq!(uploads.push(1, "key", 1, false, "b"), Ok(None));
+        assert_eq!(uploads.push(1, "key", 2, true, "c"), Ok(Some("abc".to_owned())));
+        assert!(uploads.push(1, "key", 3, true, "d").is_err());
+    }
+
+    #[test]
+    fn should_abort_upload_on_unexpected_seq() {
+        let uploads = Uploads::default();
+
+        assert_eq!(uploads.push(1, "key", 0, false, "a"), Ok(None));
+        assert!(uploads.push(1, "key", 2, false, "c").is_err());
+        assert!(uploads.push(1, "key", 1, true, "b").is_err());
+    }
+
+    #[test]
+    fn should_keep_uploads_of_sessions_apart() {
+        let uploads = Uploads::default();
+
+        assert_eq!(uploads.push(1, "key", 0, false, "a"), Ok(None));
+        assert_eq!(uploads.push(2, "key", 0, false, "x"), Ok(None));
+        assert_eq!(uploads.push(2, "key", 1, true, "y"), Ok(Some("xy".to_owned())));
+        assert_eq!(uploads.push(1, "key", 1, true, "b"), Ok(Some("ab".to_owned())));
+    }
+
+    #[test]
+    fn should_discard_uploads_of_closed_session() {
+        let uploads = Uploads::default();
+
+        assert_eq!(uploads.push(1, "key", 0, false, "a"), Ok(None));
+        assert_eq!(uploads.push(2, "key", 0, false, "x"), Ok(None));
+        uploads.close(1);
+
+        assert!(uploads.push(1, "key", 1, true, "b").is_err());
+        assert_eq!(uploads.push(2, "key", 1, true, "y"), Ok(Some("xy".to_owned())));
+    }
+
+    #[test]
+    fn should_abort_upload_over_max_size() {
+        let uploads = Uploads::default();
+        let chunk = "a".repeat(MAX_UPLOAD_SIZE / 2 + 1);
+
+        assert_eq!(uploads.push(1, "key", 0, false, &chunk), Ok(None));
+        assert!(uploads.push(1, "key", 1, false, &chunk).is_err());
+        assert!(uploads.push(1, "key", 2, true, "a").is_err());
+    }
+
+    #[test]
+    fn should_abort_upload_over_max_size_of_session() {
+        let uploads = Uploads::default();
+        let chunk = "a".repeat(MAX_UPLOAD_SIZE / 2);
+
+        for key in ["first", "second", "third"] {
+            assert_eq!(uploads.push(1, key, 0, false, &chunk), Ok(None));
+        }
+        assert_eq!(uploads.push(1, "fourth", 0, false, &chunk), Ok(None));
+        assert_eq!(uploads.push(1, "fifth", 0, false, "a"), Err("Uploads of connection exceed maximum size, upload is aborted"));
+        assert_eq!(uploads.push(1, "first", 1, false, "a"), Err("Uploads of connection exceed maximum size, upload is aborted"));
+        assert!(uploads.push(1, "first", 2, true, "a").is_err());
+
+        //Aborted upload frees its space, while other sessions have their own limit.
+        assert_eq!(uploads.push(1, "fifth", 0, false, "a"), Ok(None));
+        assert_eq!(uploads.push(2, "key", 0, false, &chunk), Ok(None));
+        //Restarted upload replaces its previous chunks.
+        assert_eq!(uploads.push(1, "second", 0, false, &chunk), Ok(None));
+    }
+}
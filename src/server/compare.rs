@@ -0,0 +1,170 @@
+//! Structured difference between stored config and new document.
+//!
+//! Values are compared as JSON, recursing into objects and arrays, while anything else,
+//! including value which is not JSON, is compared as a whole.
+//! Every change is reported with JSON pointer to its location:
+//!
+//! - `{"op": "add", "path": ..., "value": ...}`;
+//! - `{"op": "remove", "path": ..., "old": ...}`;
+//! - `{"op": "replace", "path": ..., "old": ..., "value": ...}`.
+
+use json_rpc_types::{Id, Version};
+use serde_json::Value;
+
+use super::{int_err, internal_err, RESULT};
+use crate::db;
+use crate::protocol::Response;
+
+///Document, which stored value is compared against.
+pub enum Target {
+    Data(String),
+    Key(String),
+}
+
+#[inline]
+fn parse(value: &[u8]) -> Value {
+    match serde_json::from_slice(value) {
+        Ok(value) => value,
+        Err(_) => Value::String(String::from_utf8_lossy(value).into_owned()),
+    }
+}
+
+///Escapes segment of JSON pointer.
+fn push_segment(path: &str, segment: &str) -> String {
+    let mut result = String::with_capacity(path.len() + segment.len() + 1);
+    result.push_str(path);
+    result.push('/');
+    for ch in segment.chars() {
+        match ch {
+            '~' => result.push_str("~0"),
+            '/' => result.push_str("~1"),
+            ch => result.push(ch),
+        }
+    }
+    result
+}
+
+fn change(op: &str, path: &str, old: Option<Value>, value: Option<Value>) -> Value {
+    let mut result = serde_json::Map::with_capacity(4);
+    result.insert("op".to_owned(), op.into());
+    result.insert("path".to_owned(), path.into());
+    if let Some(old) = old {
+        result.insert("old".to_owned(), old);
+    }
+    if let Some(value) = value {
+        result.insert("value".to_owned(), value);
+    }
+    result.into()
+}
+
+fn diff(path: &str, old: Option<Value>, new: Option<Value>, changes: &mut Vec<Value>) {
+    match (old, new) {
+        (None, None) => (),
+        (None, Some(new)) => changes.push(change("add", path, None, Some(new))),
+        (Some(old), None) => changes.push(change("remove", path, Some(old), None)),
+        (Some(Value::Object(mut old)), Some(Value::Object(new))) => {
+            for (name, new) in new {
+                let old = old.remove(&name);
+                diff(&push_segment(path, &name), old, Some(new), changes);
+            }
+            for (name, old) in old {
+                diff(&push_segment(path, &name), Some(old), None, changes);
+            }
+        },
+        (Some(Value::Array(old)), Some(Value::Array(new))) => {
+            let mut old = old.into_iter();
+            let mut new = new.into_iter();
+            let mut idx = 0usize;
+            loop {
+                match (old.next(), new.next()) {
+                    (None, None) => break,
+                    (old, new) => diff(&push_segment(path, &idx.to_string()), old, new, changes),
+                }
+                idx += 1;
+            }
+        },
+        (Some(old), Some(new)) => if old != new {
+            changes.push(change("replace", path, Some(old), Some(new)));
+        },
+    }
+}
+
+///Returns changes between versions of value, where `None` is missing value.
+pub fn changes(old: Option<&[u8]>, new: Option<&[u8]>) -> Vec<Value> {
+    let mut changes = Vec::new();
+    diff("", old.map(parse), new.map(parse), &mut changes);
+    changes
+}
+
+pub fn handle_diff_config_req(db: &db::DbView, key: &str, target: &Target, cid: Option<&str>, id: Option<Id>) -> Response {
+    let old = match db.get_config(key) {
+        Ok(old) => old.map(|old| parse(&old)),
+        Err(error) => {
+            error!(cid: cid, "Internal error accessing config tree: {}", error);
+            return internal_err(int_err::DIFF_CONFIG_FAIL_GET, id);
+        }
+    };
+    let new = match target {
+        Target::Data(data) => Some(parse(data.as_bytes())),
+        Target::Key(other) => match db.get_config(other) {
+            Ok(new) => new.map(|new| parse(&new)),
+            Err(error) => {
+                error!(cid: cid, "Internal error accessing config tree: {}", error);
+                return internal_err(int_err::DIFF_CONFIG_FAIL_GET, id);
+            }
+        },
+    };
+
+    let mut changes = Vec::new();
+    diff("", old, new, &mut changes);
+
+    let mut payload = serde_json::map::Map::with_capacity(1);
+    payload.insert(RESULT.to_owned(), changes.into());
+    Response::result(Version::V2, payload.into(), id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{handle_set_config_req, lease::Lease};
+    use serde_json::json;
+
+    #[test]
+    fn should_report_changes_with_json_pointers() {
+        let old = br#"{"db":{"host":"localhost","port":5432},"a/b":1,"tags":[1,2],"debug":true}"#;
+        let new = br#"{"db":{"host":"db.prod","port":5432},"a/b":2,"tags":[1],"name":"app"}"#;
+
+        assert_eq!(changes(Some(old), Some(new)), [
+            json!({"op": "replace", "path": "/a~1b", "old": 1, "value": 2}),
+            json!({"op": "replace", "path": "/db/host", "old": "localhost", "value": "db.prod"}),
+            json!({"op": "add", "path": "/name", "value": "app"}),
+            json!({"op": "remove", "path": "/tags/1", "old": 2}),
+            json!({"op": "remove", "path": "/debug", "old": true}),
+        ]);
+    }
+
+    #[test]
+    fn should_compare_values_other_than_json_as_whole() {
+        assert_eq!(changes(Some(b"plain"), Some(b"plain")), Vec::<Value>::new());
+        assert_eq!(changes(Some(b"plain"), Some(b"{\"a\":1}")), [json!({"op": "replace", "path": "", "old": "plain", "value": {"a": 1}})]);
+        assert_eq!(changes(None, Some(b"1")), [json!({"op": "add", "path": "", "value": 1})]);
+        assert_eq!(changes(Some(b"1"), None), [json!({"op": "remove", "path": "", "old": 1})]);
+        assert_eq!(changes(None, None), Vec::<Value>::new());
+    }
+
+    #[test]
+    fn should_compare_against_other_key() {
+        let db = db::Db::temporary().expect("open db").view();
+        handle_set_config_req(&db, None, "old", r#"{"a":1}"#, Lease::Keep, None, None).payload.expect("set");
+        handle_set_config_req(&db, None, "new", r#"{"a":2}"#, Lease::Keep, None, None).payload.expect("set");
+
+        let response = handle_diff_config_req(&db, "old", &Target::Key("new".to_owned()), None, None);
+        assert_eq!(response.payload.expect("diff")[RESULT], json!([{"op": "replace", "path": "/a", "old": 1, "value": 2}]));
+
+        let response = handle_diff_config_req(&db, "old", &Target::Key("missing".to_owned()), None, None);
+        assert_eq!(response.payload.expect("diff")[RESULT], json!([{"op": "remove", "path": "", "old": {"a": 1}}]));
+
+        let response = handle_diff_config_req(&db, "old", &Target::Data(r#"{"a":1}"#.to_owned()), None, None);
+        assert_eq!(response.payload.expect("diff")[RESULT], json!([]));
+    }
+}
@@ -0,0 +1,97 @@
+//! Substitution of `${VAR}` placeholders with environment variables on `config` reads.
+//!
+//! Only variables from server's whitelist are substituted, with values read once on start.
+//! Any other placeholder is left as it is.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+///Start of placeholder.
+const START: &str = "${";
+///End of placeholder.
+const END: char = '}';
+
+#[derive(Clone, Default)]
+///Whitelisted environment variables.
+pub struct Environment {
+    vars: Arc<HashMap<String, String>>,
+}
+
+impl Environment {
+    ///Reads variables from comma separated list of names, skipping unset ones.
+    pub fn from_whitelist(names: &str) -> Self {
+        let mut vars = HashMap::new();
+        for name in names.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            match std::env::var(name) {
+                Ok(value) => {
+                    vars.insert(name.to_owned(), value);
+                },
+                Err(_) => warn!("Whitelisted environment variable '{}' is not set", name),
+            }
+        }
+
+        Self {
+            vars: Arc::new(vars),
+        }
+    }
+
+    ///Substitutes placeholders within value.
+    pub fn expand(&self, value: &str) -> String {
+        let mut result = String::with_capacity(value.len());
+        let mut rest = value;
+
+        while let Some(start) = rest.find(START) {
+            let name_start = start + START.len();
+            let end = match rest[name_start..].find(END) {
+                Some(end) => name_start + end,
+                None => break,
+            };
+
+            result.push_str(&rest[..start]);
+            match self.vars.get(&rest[name_start..end]) {
+                Some(var) => result.push_str(var),
+                None => result.push_str(&rest[start..=end]),
+            }
+            rest = &rest[end + 1..];
+        }
+
+        result.push_str(rest);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn environment(vars: &[(&str, &str)]) -> Environment {
+        Environment {
+            vars: Arc::new(vars.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()),
+        }
+    }
+
+    #[test]
+    fn should_substitute_only_whitelisted_vars() {
+        let env = environment(&[("HOST", "localhost"), ("PORT", "80")]);
+        assert_eq!(env.expand("http://${HOST}:${PORT}/"), "http://localhost:80/");
+        assert_eq!(env.expand("${HOST}${OTHER}"), "localhost${OTHER}");
+        assert_eq!(env.expand("${}"), "${}");
+        assert_eq!(env.expand("no placeholders"), "no placeholders");
+    }
+
+    #[test]
+    fn should_keep_unterminated_placeholder() {
+        let env = environment(&[("HOST", "localhost")]);
+        assert_eq!(env.expand("${HOST} ${HOST"), "localhost ${HOST");
+        assert_eq!(env.expand("$HOST}"), "$HOST}");
+    }
+
+    #[test]
+    fn should_skip_unset_vars_of_whitelist() {
+        let path = std::env::var("PATH").expect("PATH");
+        let env = Environment::from_whitelist(" PATH, ,DOU_STORE_UNSET_VAR");
+        assert_eq!(env.vars.len(), 1);
+        assert_eq!(env.expand("${PATH}"), path);
+        assert_eq!(env.expand("${DOU_STORE_UNSET_VAR}"), "${DOU_STORE_UNSET_VAR}");
+    }
+}
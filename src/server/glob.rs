@@ -0,0 +1,203 @@
+//! Matching of keys against glob pattern.
+//!
+//! Pattern supports:
+//!
+//! - `?` - any single character, except `/`;
+//! - `*` - any number of characters, except `/`, hence it stays within single segment of key;
+//! - `**` - any number of any characters.
+//!
+//! Only keys under literal prefix of pattern are scanned, so patterns starting with wildcard scan all keys.
+//! Matching takes time proportional to product of pattern and key lengths, regardless of pattern.
+
+use json_rpc_types::{Id, Version};
+
+use super::{int_err, internal_err, key_item, RESULT};
+use crate::db;
+use crate::protocol::Response;
+
+///Maximum number of keys matched by single request.
+pub const MAX_MATCHES: usize = 1000;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Token {
+    Char(char),
+    Single,
+    Segment,
+    Any,
+}
+
+pub struct Pattern {
+    tokens: Vec<Token>,
+    prefix: String,
+}
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Self {
+        let mut tokens = Vec::with_capacity(pattern.len());
+        let mut chars = pattern.chars().peekable();
+        while let Some(ch) = chars.next() {
+            let token = match ch {
+                '?' => Token::Single,
+                '*' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    Token::Any
+                },
+                '*' => Token::Segment,
+                ch => Token::Char(ch),
+            };
+            //Consecutive wildcards are the same as the widest of them.
+            match (tokens.last_mut(), token) {
+                (Some(last @ Token::Segment), Token::Any) => *last = Token::Any,
+                (Some(Token::Any), Token::Segment | Token::Any) | (Some(Token::Segment), Token::Segment) => (),
+                _ => tokens.push(token),
+            }
+        }
+
+        let prefix = tokens.iter().map_while(|token| match token {
+            Token::Char(ch) => Some(*ch),
+            _ => None,
+        }).collect();
+
+        Self {
+            tokens,
+            prefix,
+        }
+    }
+
+    ///Returns whether key matches whole pattern.
+    pub fn matches(&self, key: &str) -> bool {
+        let key: Vec<char> = key.chars().collect();
+        //Whether rest of pattern from token matches rest of key from position, filled backwards row by row.
+        let mut next = vec![false; key.len() + 1];
+        let mut current = vec![false; key.len() + 1];
+        next[key.len()] = true;
+
+        for token in self.tokens.iter().rev() {
+            current[key.len()] = matches!(token, Token::Segment | Token::Any) && next[key.len()];
+            for idx in (0..key.len()).rev() {
+                let ch = key[idx];
+                current[idx] = match token {
+                    Token::Char(expected) => ch == *expected && next[idx + 1],
+                    Token::Single => ch != '/' && next[idx + 1],
+                    Token::Segment => next[idx] || (ch != '/' && current[idx + 1]),
+                    Token::Any => next[idx] || current[idx + 1],
+                };
+            }
+            core::mem::swap(&mut current, &mut next);
+        }
+
+        next[0]
+    }
+}
+
+fn match_keys(db: &db::DbView, pattern: &Pattern, with_values: bool) -> Result<serde_json::Map<String, serde_json::Value>, sled::Error> {
+    let mut keys = Vec::new();
+    let mut is_truncated = false;
+
+    for entry in db.checksum.scan_prefix(pattern.prefix.as_bytes()) {
+        let (key, checksum) = entry?;
+        let key = match core::str::from_utf8(&key) {
+            Ok(key) if pattern.matches(key) => key,
+            _ => continue,
+        };
+        if keys.len() >= MAX_MATCHES {
+            is_truncated = true;
+            break;
+        }
+
+        keys.push(key_item(db, key, &checksum, with_values)?);
+    }
+
+    let mut result = serde_json::Map::with_capacity(2);
+    result.insert("keys".to_owned(), keys.into());
+    result.insert("truncated".to_owned(), is_truncated.into());
+    Ok(result)
+}
+
+pub fn handle_match_keys_req(db: &db::DbView, pattern: &str, with_values: bool, cid: Option<&str>, id: Option<Id>) -> Response {
+    match match_keys(db, &Pattern::new(pattern), with_values) {
+        Ok(result) => {
+            let mut payload = serde_json::map::Map::with_capacity(1);
+            payload.insert(RESULT.to_owned(), result.into());
+            Response::result(Version::V2, payload.into(), id)
+        },
+        Err(error) => {
+            error!(cid: cid, "Internal error matching keys of config tree: {}", error);
+            internal_err(int_err::CONFIG_FAIL_GET, id)
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xxhash_rust::xxh3::xxh3_64;
+    use super::super::{handle_set_config_req, lease::Lease};
+
+    #[test]
+    fn should_match_wildcards() {
+        let pattern = Pattern::new("app/*/db?");
+        assert!(pattern.matches("app/prod/db1"));
+        assert!(pattern.matches("app//db1"));
+        assert!(!pattern.matches("app/prod/eu/db1"));
+        assert!(!pattern.matches("app/prod/db"));
+        assert!(!pattern.matches("app/prod/db/"));
+
+        let pattern = Pattern::new("app/**/db");
+        assert!(pattern.matches("app/prod/eu/db"));
+        assert!(pattern.matches("app//db"));
+        assert!(!pattern.matches("app/db"));
+
+        assert!(Pattern::new("**").matches(""));
+        assert!(Pattern::new("*").matches("key"));
+        assert!(!Pattern::new("*").matches("app/key"));
+        assert!(Pattern::new("ключ/*").matches("ключ/значение"));
+        assert!(!Pattern::new("key").matches("key/"));
+    }
+
+    #[test]
+    fn should_merge_consecutive_wildcards() {
+        assert!(Pattern::new("a***b").tokens == Pattern::new("a**b").tokens);
+        assert!(Pattern::new("a*****b").tokens == Pattern::new("a**b").tokens);
+        assert!(Pattern::new("a**b").matches("a/x/b"));
+        assert!(Pattern::new("a***b").matches("a/x/b"));
+        assert!(!Pattern::new("a*?b").matches("ab"));
+        assert!(Pattern::new("a*?b").matches("axb"));
+    }
+
+    #[test]
+    fn should_scan_only_literal_prefix() {
+        assert_eq!(Pattern::new("app/*/db").prefix, "app/");
+        assert_eq!(Pattern::new("app?").prefix, "app");
+        assert_eq!(Pattern::new("**/db").prefix, "");
+    }
+
+    #[test]
+    fn should_match_stored_keys() {
+        let db = db::Db::temporary().expect("open db").view();
+        for key in ["app/prod/db", "app/test/db", "app/prod/eu/db", "other/db"] {
+            handle_set_config_req(&db, None, key, key, Lease::Keep, None, None).payload.expect("set");
+        }
+
+        let payload = handle_match_keys_req(&db, "app/*/db", false, None, None).payload.expect("match");
+        assert_eq!(payload[RESULT], serde_json::json!({
+            "keys": [{"id": "app/prod/db", "checksum": xxh3_64(b"app/prod/db")}, {"id": "app/test/db", "checksum": xxh3_64(b"app/test/db")}],
+            "truncated": false,
+        }));
+
+        let payload = handle_match_keys_req(&db, "**/eu/*", true, None, None).payload.expect("match");
+        assert_eq!(payload[RESULT]["keys"], serde_json::json!([{"id": "app/prod/eu/db", "checksum": xxh3_64(b"app/prod/eu/db"), "data": "app/prod/eu/db"}]));
+    }
+
+    #[test]
+    fn should_truncate_matches() {
+        let db = db::Db::temporary().expect("open db").view();
+        for idx in 0..=MAX_MATCHES {
+            db.checksum.insert(format!("key{:05}", idx), &0u64.to_be_bytes()).expect("insert");
+        }
+
+        let payload = handle_match_keys_req(&db, "key*", false, None, None).payload.expect("match");
+        assert_eq!(payload[RESULT]["keys"].as_array().expect("keys").len(), MAX_MATCHES);
+        assert_eq!(payload[RESULT]["truncated"], true);
+    }
+}
@@ -0,0 +1,90 @@
+//! Readiness of instance to serve requests.
+//!
+//! Liveness only requires process to respond, as `ping` does, while readiness also requires
+//! db to be readable and flushable. Recovery of sled is finished by the time db is opened,
+//! so instance never serves while recovering.
+//!
+//! Db is read on every probe, while result of flush is reused for `FLUSH_INTERVAL`,
+//! so that frequent probes do not keep writing to disk.
+
+use std::sync::Mutex;
+use std::time::Instant;
+use core::time::Duration;
+
+use json_rpc_types::{Id, Version, Error, ErrorCode};
+
+use super::{int_err, bool_response};
+use crate::db;
+use crate::protocol::Response;
+
+///Interval, during which result of last flush is reused.
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+struct Flush {
+    at: Instant,
+    is_ok: bool,
+}
+
+static LAST_FLUSH: Mutex<Option<Flush>> = Mutex::new(None);
+
+///Flushes db, unless it is flushed recently, returning whether flush succeeded.
+fn flush(db: &db::DbView) -> bool {
+    //Concurrent probes wait for flush in progress, reusing its result.
+    let mut last = LAST_FLUSH.lock().unwrap_or_else(|error| error.into_inner());
+    if let Some(last) = last.as_ref().filter(|last| last.at.elapsed() < FLUSH_INTERVAL) {
+        return last.is_ok;
+    }
+
+    let is_ok = match db.flush() {
+        Ok(_) => true,
+        Err(error) => {
+            error!("Readiness check: Unable to flush db: {}", error);
+            false
+        }
+    };
+    *last = Some(Flush {
+        at: Instant::now(),
+        is_ok,
+    });
+    is_ok
+}
+
+///Returns reason, why instance cannot serve requests.
+pub fn check(db: &db::DbView) -> Result<(), &'static str> {
+    if let Err(error) = db.config.first() {
+        error!("Readiness check: Unable to read config tree: {}", error);
+        return Err("Unable to read db");
+    }
+    if !flush(db) {
+        return Err("Unable to flush db");
+    }
+    Ok(())
+}
+
+pub fn handle_ready_req(db: &db::DbView, id: Option<Id>) -> Response {
+    match check(db) {
+        Ok(()) => bool_response(true, id),
+        Err(reason) => Response::error(Version::V2, Error::from_code(ErrorCode::ServerError(int_err::NOT_READY)).set_data(reason), id),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn last_flush_at() -> Option<Instant> {
+        LAST_FLUSH.lock().unwrap_or_else(|error| error.into_inner()).as_ref().map(|last| last.at)
+    }
+
+    #[test]
+    fn should_reuse_recent_flush() {
+        let db = db::Db::temporary().expect("open db").view();
+
+        assert_eq!(check(&db), Ok(()));
+        let flushed_at = last_flush_at();
+        assert!(flushed_at.is_some());
+
+        assert_eq!(check(&db), Ok(()));
+        assert_eq!(last_flush_at(), flushed_at);
+    }
+}
@@ -0,0 +1,317 @@
+//! Rhai scripts, run on reads and writes of keys with matching prefix.
+//!
+//! Script sees `key` and `value` of operation.
+//! On write it can return new value as string, reject write by returning `false` or throwing,
+//! and derive writes of other keys by setting them in `derived` map.
+//! Derived writes do not run hooks themselves.
+//! On read it can return string to replace value sent to client.
+//! Returning `()` keeps value as it is.
+//!
+//! Hooks are stored in dedicated tree and compiled on start.
+//! Only admin can change hooks, while scripts run on db workers, so that they never block connections.
+
+use std::sync::{RwLock, RwLockReadGuard};
+use std::collections::BTreeMap;
+use std::cell::Cell;
+use std::time::Instant;
+use core::time::Duration;
+
+///Default maximum number of operations, which script can perform.
+pub const DEFAULT_MAX_OPERATIONS: u64 = 100_000;
+///Default maximum duration in milliseconds of script.
+pub const DEFAULT_TIMEOUT_MS: u64 = 10;
+//Number of operations between checks of script's deadline.
+const DEADLINE_CHECK_INTERVAL: u64 = 256;
+
+const KEY: &str = "key";
+const VALUE: &str = "value";
+const DERIVED: &str = "derived";
+
+thread_local! {
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+#[derive(Clone)]
+///Limits of every script run.
+pub struct Limits {
+    pub max_operations: u64,
+    pub timeout: Duration,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_operations: DEFAULT_MAX_OPERATIONS,
+            timeout: Duration::from_millis(DEFAULT_TIMEOUT_MS),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Event {
+    Read,
+    Write,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+///Hook, as it is stored.
+pub struct Definition {
+    pub prefix: String,
+    pub event: Event,
+    pub script: String,
+}
+
+struct Hook {
+    definition: Definition,
+    ast: rhai::AST,
+}
+
+///Result of write hooks.
+pub struct Written {
+    pub value: String,
+    ///Writes of other keys, requested by hooks.
+    pub derived: Vec<(String, String)>,
+}
+
+pub enum SetError {
+    Compile(String),
+    Db(sled::Error),
+}
+
+pub struct Hooks {
+    engine: rhai::Engine,
+    timeout: Duration,
+    tree: sled::Tree,
+    //Ordered by name, which is also order of execution.
+    hooks: RwLock<BTreeMap<String, Hook>>,
+}
+
+impl Hooks {
+    ///Creates hooks out of stored definitions, skipping ones that fail to compile.
+    pub fn new(tree: sled::Tree, limits: Limits) -> Self {
+        let mut engine = rhai::Engine::new();
+        engine.set_max_operations(limits.max_operations);
+        engine.on_progress(|operations| match operations % DEADLINE_CHECK_INTERVAL {
+            0 => match DEADLINE.with(Cell::get) {
+                Some(deadline) if Instant::now() >= deadline => Some("Script exceeded time limit".into()),
+                _ => None,
+            },
+            _ => None,
+        });
+        engine.on_print(|_text| info!("Hook: {}", _text));
+        engine.on_debug(|_text, _, _| trace!("Hook: {}", _text));
+
+        let mut hooks = BTreeMap::new();
+        for hook in tree.iter() {
+            let (name, definition) = match hook {
+                Ok(hook) => hook,
+                Err(error) => {
+                    error!("Unable to load hooks: {}", error);
+                    break;
+                },
+            };
+            let name = String::from_utf8_lossy(&name).into_owned();
+            let definition = match serde_json::from_slice::<Definition>(&definition) {
+                Ok(definition) => definition,
+                Err(error) => {
+                    error!("Data corruption in hook '{}': {}", name, error);
+                    continue;
+                },
+            };
+            match engine.compile(&definition.script) {
+                Ok(ast) => {
+                    hooks.insert(name, Hook { definition, ast });
+                },
+                Err(error) => error!("Unable to compile hook '{}': {}", name, error),
+            }
+        }
+
+        Self {
+            engine,
+            timeout: limits.timeout,
+            tree,
+            hooks: RwLock::new(hooks),
+        }
+    }
+
+    #[inline]
+    fn read(&self) -> RwLockReadGuard<'_, BTreeMap<String, Hook>> {
+        self.hooks.read().unwrap_or_else(|error| error.into_inner())
+    }
+
+    ///Returns whether any hook runs on event of key.
+    pub fn matches(&self, event: Event, key: &str) -> bool {
+        self.read().values().any(|hook| hook.definition.event == event && key.starts_with(&hook.definition.prefix))
+    }
+
+    ///Compiles and stores hook, replacing previous one with the same name.
+    pub fn set(&self, name: &str, definition: Definition) -> Result<(), SetError> {
+        let ast = self.engine.compile(&definition.script).map_err(|error| SetError::Compile(error.to_string()))?;
+        let stored = match serde_json::to_vec(&definition) {
+            Ok(stored) => stored,
+            Err(_) => unreachable!(),
+        };
+        self.tree.insert(name, stored).map_err(SetError::Db)?;
+
+        self.hooks.write().unwrap_or_else(|error| error.into_inner()).insert(name.to_owned(), Hook { definition, ast });
+        Ok(())
+    }
+
+    ///Removes hook, returning whether it existed.
+    pub fn remove(&self, name: &str) -> Result<bool, sled::Error> {
+        self.tree.remove(name)?;
+        Ok(self.hooks.write().unwrap_or_else(|error| error.into_inner()).remove(name).is_some())
+    }
+
+    ///Returns description of all hooks.
+    pub fn list(&self) -> serde_json::Value {
+        let hooks = self.read();
+        let mut result = Vec::with_capacity(hooks.len());
+        for (name, hook) in hooks.iter() {
+            let mut info = match serde_json::to_value(&hook.definition) {
+                Ok(serde_json::Value::Object(info)) => info,
+                _ => unreachable!(),
+            };
+            info.insert("id".to_owned(), name.as_str().into());
+            result.push(serde_json::Value::Object(info));
+        }
+        result.into()
+    }
+
+    fn run(&self, hook: &Hook, scope: &mut rhai::Scope<'_>) -> Result<rhai::Dynamic, String> {
+        DEADLINE.with(|deadline| deadline.set(Some(Instant::now() + self.timeout)));
+        let result = self.engine.eval_ast_with_scope::<rhai::Dynamic>(scope, &hook.ast);
+        DEADLINE.with(|deadline| deadline.set(None));
+        result.map_err(|error| error.to_string())
+    }
+
+    ///Runs write hooks of key, returning value to write or reason of rejection.
+    pub fn on_write(&self, key: &str, mut value: String) -> Result<Written, String> {
+        let mut derived = Vec::new();
+
+        for (name, hook) in self.read().iter() {
+            if hook.definition.event != Event::Write || !key.starts_with(&hook.definition.prefix) {
+                continue;
+            }
+
+            let mut scope = rhai::Scope::new();
+            scope.push(KEY, key.to_owned());
+            scope.push(VALUE, value.clone());
+            scope.push(DERIVED, rhai::Map::new());
+
+            let result = self.run(hook, &mut scope).map_err(|error| format!("Hook '{}' failed: {}", name, error))?;
+            if result.is_string() {
+                value = result.into_string().unwrap_or_default();
+            } else if let Ok(false) = result.as_bool() {
+                return Err(format!("Hook '{}' rejected write", name));
+            } else if !result.is_unit() && result.as_bool().is_err() {
+                return Err(format!("Hook '{}' returned {}, expected string, bool or ()", name, result.type_name()));
+            }
+
+            for (derived_key, derived_value) in scope.get_value::<rhai::Map>(DERIVED).unwrap_or_default() {
+                match derived_value.into_string() {
+                    Ok(derived_value) => derived.push((derived_key.into(), derived_value)),
+                    Err(kind) => return Err(format!("Hook '{}' derived '{}' of type {}, expected string", name, derived_key, kind)),
+                }
+            }
+        }
+
+        Ok(Written {
+            value,
+            derived,
+        })
+    }
+
+    ///Runs read hooks of key, returning value to send or reason of failure.
+    pub fn on_read(&self, key: &str, mut value: String) -> Result<String, String> {
+        for (name, hook) in self.read().iter() {
+            if hook.definition.event != Event::Read || !key.starts_with(&hook.definition.prefix) {
+                continue;
+            }
+
+            let mut scope = rhai::Scope::new();
+            scope.push(KEY, key.to_owned());
+            scope.push(VALUE, value.clone());
+
+            let result = self.run(hook, &mut scope).map_err(|error| format!("Hook '{}' failed: {}", name, error))?;
+            if result.is_string() {
+                value = result.into_string().unwrap_or_default();
+            } else if !result.is_unit() {
+                return Err(format!("Hook '{}' returned {}, expected string or ()", name, result.type_name()));
+            }
+        }
+
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn definition(prefix: &str, event: Event, script: &str) -> Definition {
+        Definition {
+            prefix: prefix.to_owned(),
+            event,
+            script: script.to_owned(),
+        }
+    }
+
+    fn hooks() -> Hooks {
+        Hooks::new(crate::db::Db::temporary().expect("open db").view().hooks.clone(), Limits::default())
+    }
+
+    #[test]
+    fn should_run_write_hooks_of_matching_keys() {
+        let hooks = hooks();
+        assert!(hooks.set("a", definition("app/", Event::Write, r#"derived["audit/" + key] = value; value + "!""#)).is_ok());
+        assert!(hooks.set("b", definition("app/locked", Event::Write, "false")).is_ok());
+
+        assert!(hooks.matches(Event::Write, "app/key"));
+        assert!(!hooks.matches(Event::Read, "app/key"));
+        assert!(!hooks.matches(Event::Write, "other"));
+
+        let written = hooks.on_write("app/key", "value".to_owned()).expect("write");
+        assert_eq!(written.value, "value!");
+        assert_eq!(written.derived, [("audit/app/key".to_owned(), "value".to_owned())]);
+
+        assert_eq!(hooks.on_write("app/locked", "value".to_owned()).err().as_deref(), Some("Hook 'b' rejected write"));
+        assert_eq!(hooks.on_write("other", "value".to_owned()).ok().map(|written| written.value).as_deref(), Some("value"));
+    }
+
+    #[test]
+    fn should_run_read_hooks_of_matching_keys() {
+        let hooks = hooks();
+        assert!(hooks.set("upper", definition("app/", Event::Read, "value.to_upper()")).is_ok());
+        assert!(hooks.set("keep", definition("app/", Event::Read, "()")).is_ok());
+
+        assert_eq!(hooks.on_read("app/key", "value".to_owned()), Ok("VALUE".to_owned()));
+        assert_eq!(hooks.on_read("other", "value".to_owned()), Ok("value".to_owned()));
+
+        assert!(hooks.set("number", definition("app/", Event::Read, "1")).is_ok());
+        assert!(hooks.on_read("app/key", "value".to_owned()).is_err());
+    }
+
+    #[test]
+    fn should_stop_script_over_limits() {
+        let db = crate::db::Db::temporary().expect("open db").view();
+        let hooks = Hooks::new(db.hooks.clone(), Limits { max_operations: 0, timeout: Duration::from_millis(5) });
+        assert!(hooks.set("loop", definition("", Event::Read, "loop {}")).is_ok());
+
+        assert!(hooks.on_read("key", "value".to_owned()).is_err());
+    }
+
+    #[test]
+    fn should_load_stored_hooks() {
+        let db = crate::db::Db::temporary().expect("open db").view();
+        let hooks = Hooks::new(db.hooks.clone(), Limits::default());
+        assert!(matches!(hooks.set("invalid", definition("", Event::Read, "value +")), Err(SetError::Compile(_))));
+        assert!(hooks.set("first", definition("app/", Event::Read, "()")).is_ok());
+        assert!(hooks.set("second", definition("app/", Event::Write, "()")).is_ok());
+        assert_eq!(hooks.remove("second").ok(), Some(true));
+
+        let hooks = Hooks::new(db.hooks.clone(), Limits::default());
+        assert_eq!(hooks.list(), serde_json::json!([{"id": "first", "prefix": "app/", "event": "read", "script": "()"}]));
+    }
+}
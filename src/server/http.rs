@@ -0,0 +1,415 @@
+//! Minimal HTTP listener for operational endpoints.
+//!
+//! - `GET /metrics` - metrics in Prometheus format;
+//! - `GET /healthz` - liveness, responding as long as process serves requests;
+//! - `GET /readyz` - readiness, responding with 503 if db cannot serve requests.
+//!
+//! When admin token is configured, it also serves dashboard under `/admin`.
+//! Dashboard page itself is static, while its API requires `Authorization: Bearer <token>`:
+//!
+//! - `GET /admin/keys?prefix=<prefix>` - lists keys with their checksums;
+//! - `POST /admin/rpc` - executes JSON-RPC request, same as on TCP listener.
+
+use std::sync::Arc;
+
+use core::convert::TryFrom;
+
+use tokio::net::{TcpStream, TcpListener};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+use super::{ErrorKindExt, LOCAL_HOST};
+use super::metrics::METRICS;
+use super::{health, tcp};
+use crate::db;
+use crate::protocol::Request;
+
+///Limit on size of request line and headers.
+const MAX_HEAD_SIZE: u64 = 8 * 1024;
+///Limit on size of request body.
+const MAX_BODY_SIZE: u64 = 4 * 1024 * 1024;
+///Maximum number of keys listed by dashboard at once.
+const MAX_LISTED_KEYS: usize = 1000;
+
+const DASHBOARD: &str = include_str!("dashboard.html");
+
+const STATUS_OK: &str = "200 OK";
+const STATUS_NOT_FOUND: &str = "404 Not Found";
+const STATUS_BAD_REQUEST: &str = "400 Bad Request";
+const STATUS_METHOD_NOT_ALLOWED: &str = "405 Method Not Allowed";
+const STATUS_UNAUTHORIZED: &str = "401 Unauthorized";
+const STATUS_PAYLOAD_TOO_LARGE: &str = "413 Payload Too Large";
+const STATUS_INTERNAL_ERROR: &str = "500 Internal Server Error";
+const STATUS_SERVICE_UNAVAILABLE: &str = "503 Service Unavailable";
+
+const CONTENT_TEXT: &str = "text/plain; charset=utf-8";
+const CONTENT_PROMETHEUS: &str = "text/plain; version=0.0.4; charset=utf-8";
+const CONTENT_HTML: &str = "text/html; charset=utf-8";
+const CONTENT_JSON: &str = "application/json";
+
+///Admin dashboard.
+pub struct Admin {
+    ///Token, which API requests must present as `Authorization: Bearer <token>`.
+    pub token: String,
+    ///Server, which executes requests of dashboard.
+    pub tcp: tcp::Tcp,
+}
+
+impl Admin {
+    fn is_authorized(&self, authorization: Option<&str>) -> bool {
+        let token = match authorization.and_then(|authorization| authorization.strip_prefix("Bearer ")) {
+            Some(token) => token.trim(),
+            None => return false,
+        };
+
+        super::is_token_eq(token, &self.token)
+    }
+}
+
+#[derive(Default)]
+struct Head {
+    authorization: Option<String>,
+    content_length: u64,
+}
+
+fn from_hex_digit(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}
+
+///Decodes percent encoded component of query.
+fn percent_decode(value: &str) -> Option<String> {
+    let mut result = Vec::with_capacity(value.len());
+    let mut bytes = value.bytes();
+
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'%' => {
+                let high = from_hex_digit(bytes.next()?)?;
+                let low = from_hex_digit(bytes.next()?)?;
+                result.push(high << 4 | low);
+            },
+            b'+' => result.push(b' '),
+            byte => result.push(byte),
+        }
+    }
+
+    String::from_utf8(result).ok()
+}
+
+fn query_param(query: &str, name: &str) -> Option<String> {
+    query.split('&').filter_map(|pair| pair.split_once('=')).find(|(key, _)| *key == name).and_then(|(_, value)| percent_decode(value))
+}
+
+pub struct Http {
+    port: u16,
+    db: db::DbView,
+    admin: Option<Admin>,
+}
+
+impl Http {
+    pub fn new(port: u16, db: db::DbView, admin: Option<Admin>) -> Self {
+        Self {
+            port,
+            db,
+            admin,
+        }
+    }
+
+    async fn respond(socket: &mut TcpStream, status: &str, content_type: &str, body: &[u8]) {
+        let head = format!("HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", status, content_type, body.len());
+
+        let result = match socket.write_all(head.as_bytes()).await {
+            Ok(()) => socket.write_all(body).await,
+            Err(error) => Err(error),
+        };
+
+        if let Err(_error) = result {
+            trace!("HTTP: Unable to send response: {}", _error);
+        }
+    }
+
+    fn metrics(&self) -> String {
+        let db_size = match self.db.size_on_disk() {
+            Ok(size) => Some(size),
+            Err(error) => {
+                warn!("Unable to get db size: {}", error);
+                None
+            }
+        };
+
+        let mut body = String::new();
+        //Writing into String cannot fail
+        let _ = METRICS.write_prometheus(&mut body, db_size);
+        body
+    }
+
+    fn keys(&self, prefix: &str) -> Result<String, sled::Error> {
+        let mut keys = Vec::new();
+        let mut is_truncated = false;
+
+        for entry in self.db.checksum.scan_prefix(prefix) {
+            if keys.len() >= MAX_LISTED_KEYS {
+                is_truncated = true;
+                break;
+            }
+
+            let (key, checksum) = entry?;
+            let mut item = serde_json::Map::with_capacity(2);
+            item.insert("id".to_owned(), String::from_utf8_lossy(&key).into_owned().into());
+            //Checksum is sent as string, because JavaScript numbers cannot hold every u64.
+            let checksum = <[u8; 8]>::try_from(checksum.as_ref()).map(|checksum| u64::from_be_bytes(checksum).to_string()).ok();
+            item.insert("checksum".to_owned(), checksum.into());
+            keys.push(serde_json::Value::from(item));
+        }
+
+        let mut result = serde_json::Map::with_capacity(2);
+        result.insert("keys".to_owned(), keys.into());
+        result.insert("truncated".to_owned(), is_truncated.into());
+        match serde_json::to_string(&result) {
+            Ok(result) => Ok(result),
+            Err(_) => unreachable!(),
+        }
+    }
+
+    async fn handle_admin(&self, admin: &Admin, socket: &mut TcpStream, method: &str, target: &str, head: &Head, body: &[u8]) {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        if path == "/admin" || path == "/admin/" {
+            return match method {
+                "GET" => Self::respond(socket, STATUS_OK, CONTENT_HTML, DASHBOARD.as_bytes()).await,
+                _ => Self::respond(socket, STATUS_METHOD_NOT_ALLOWED, CONTENT_TEXT, b"Method Not Allowed").await,
+            };
+        }
+
+        if !admin.is_authorized(head.authorization.as_deref()) {
+            return Self::respond(socket, STATUS_UNAUTHORIZED, CONTENT_TEXT, b"Unauthorized").await;
+        }
+
+        match (method, path) {
+            ("GET", "/admin/keys") => match self.keys(&query_param(query, "prefix").unwrap_or_default()) {
+                Ok(body) => Self::respond(socket, STATUS_OK, CONTENT_JSON, body.as_bytes()).await,
+                Err(error) => {
+                    error!("Dashboard: Unable to list keys: {}", error);
+                    Self::respond(socket, STATUS_INTERNAL_ERROR, CONTENT_TEXT, b"Unable to list keys").await
+                }
+            },
+            ("POST", "/admin/rpc") => match serde_json::from_slice::<Request>(body) {
+                Ok(request) => {
+                    let response = admin.tcp.execute(request).await;
+                    match serde_json::to_vec(&response) {
+                        Ok(body) => Self::respond(socket, STATUS_OK, CONTENT_JSON, &body).await,
+                        Err(_) => unreachable!(),
+                    }
+                },
+                Err(_) => Self::respond(socket, STATUS_BAD_REQUEST, CONTENT_TEXT, b"Invalid JSON-RPC request").await,
+            },
+            (_, "/admin/keys") | (_, "/admin/rpc") => Self::respond(socket, STATUS_METHOD_NOT_ALLOWED, CONTENT_TEXT, b"Method Not Allowed").await,
+            _ => Self::respond(socket, STATUS_NOT_FOUND, CONTENT_TEXT, b"Not Found").await,
+        }
+    }
+
+    async fn handle_client(self: Arc<Self>, socket: TcpStream, addr: std::net::SocketAddr) {
+        let mut reader = BufReader::new(socket).take(MAX_HEAD_SIZE);
+        let mut request_line = String::new();
+
+        match reader.read_line(&mut request_line).await {
+            Ok(0) => return,
+            Ok(_) => (),
+            Err(_error) => {
+                trace!(peer: addr, "HTTP error: {}", _error);
+                return;
+            }
+        }
+
+        let mut head = Head::default();
+        let mut header = String::new();
+        loop {
+            header.clear();
+            match reader.read_line(&mut header).await {
+                Ok(0) => break,
+                Ok(_) => match header.trim_end().split_once(':') {
+                    Some((name, value)) => if name.eq_ignore_ascii_case("authorization") {
+                        head.authorization = Some(value.trim().to_owned());
+                    } else if name.eq_ignore_ascii_case("content-length") {
+                        head.content_length = value.trim().parse().unwrap_or(0);
+                    },
+                    None => if header.trim_end().is_empty() {
+                        break;
+                    },
+                },
+                Err(_error) => {
+                    trace!(peer: addr, "HTTP error: {}", _error);
+                    return;
+                }
+            }
+        }
+
+        let mut body = Vec::new();
+        if head.content_length > 0 {
+            if head.content_length > MAX_BODY_SIZE {
+                let mut socket = reader.into_inner().into_inner();
+                return Self::respond(&mut socket, STATUS_PAYLOAD_TOO_LARGE, CONTENT_TEXT, b"Payload Too Large").await;
+            }
+
+            reader.set_limit(head.content_length);
+            if let Err(_error) = reader.read_to_end(&mut body).await {
+                trace!(peer: addr, "HTTP error: {}", _error);
+                return;
+            }
+        }
+
+        let mut socket = reader.into_inner().into_inner();
+        let mut parts = request_line.split_ascii_whitespace();
+        let (method, target) = match (parts.next(), parts.next()) {
+            (Some(method), Some(target)) => (method, target),
+            _ => return Self::respond(&mut socket, STATUS_BAD_REQUEST, CONTENT_TEXT, b"Bad Request").await,
+        };
+        let path = target.split_once('?').map_or(target, |(path, _)| path);
+
+        if let Some(admin) = self.admin.as_ref() {
+            if path == "/admin" || path.starts_with("/admin/") {
+                return self.handle_admin(admin, &mut socket, method, target, &head, &body).await;
+            }
+        }
+
+        if method != "GET" {
+            return Self::respond(&mut socket, STATUS_METHOD_NOT_ALLOWED, CONTENT_TEXT, b"Method Not Allowed").await;
+        }
+
+        match path {
+            "/metrics" => {
+                let body = self.metrics();
+                Self::respond(&mut socket, STATUS_OK, CONTENT_PROMETHEUS, body.as_bytes()).await
+            },
+            "/healthz" => Self::respond(&mut socket, STATUS_OK, CONTENT_TEXT, b"OK").await,
+            "/readyz" => {
+                let db = self.db.clone();
+                //Flush blocks until data is on disk.
+                match tokio::task::spawn_blocking(move || health::check(&db)).await {
+                    Ok(Ok(())) => Self::respond(&mut socket, STATUS_OK, CONTENT_TEXT, b"OK").await,
+                    Ok(Err(reason)) => Self::respond(&mut socket, STATUS_SERVICE_UNAVAILABLE, CONTENT_TEXT, reason.as_bytes()).await,
+                    Err(_) => Self::respond(&mut socket, STATUS_SERVICE_UNAVAILABLE, CONTENT_TEXT, b"Unable to check db").await,
+                }
+            },
+            _ => Self::respond(&mut socket, STATUS_NOT_FOUND, CONTENT_TEXT, b"Not Found").await,
+        }
+    }
+
+    pub async fn start(self: Arc<Self>) -> bool {
+        let serv = match TcpListener::bind((LOCAL_HOST, self.port)).await {
+            Ok(serv) => serv,
+            Err(error) => {
+                warn!("Unable to start HTTP server on {}:{}. Error: {}", LOCAL_HOST, self.port, error);
+                return false;
+            }
+        };
+
+        info!("Start HTTP on {}:{}", LOCAL_HOST, self.port);
+
+        loop {
+            let (socket, addr) = match serv.accept().await {
+                Ok(res) => res,
+                Err(error) => {
+                    if error.kind().is_accept_error_ok() {
+                        continue;
+                    } else {
+                        warn!("HTTP Server Error: {}", error);
+                        return false
+                    }
+                }
+            };
+
+            tokio::spawn(self.clone().handle_client(socket, addr));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn http(db: db::DbView) -> Arc<Http> {
+        let options = tcp::Options {
+            bind: LOCAL_HOST,
+            max_connections_per_ip: 0,
+            max_invalid_frames: 0,
+            keepalive: None,
+            compression_threshold: usize::MAX,
+            noise: None,
+            admin_token: Some("secret".to_owned()),
+        };
+        let admin = Admin {
+            token: "secret".to_owned(),
+            tcp: tcp::Tcp::new(0, options, super::super::Handler::new(db.clone(), Default::default())),
+        };
+        Arc::new(Http::new(0, db, Some(admin)))
+    }
+
+    ///Sends raw HTTP request, returning status line and body of response.
+    async fn request(http: &Arc<Http>, request: &str) -> (String, String) {
+        let listener = TcpListener::bind((LOCAL_HOST, 0)).await.expect("bind");
+        let mut client = TcpStream::connect(listener.local_addr().expect("addr")).await.expect("connect");
+        let (socket, addr) = listener.accept().await.expect("accept");
+        let task = tokio::spawn(http.clone().handle_client(socket, addr));
+
+        client.write_all(request.as_bytes()).await.expect("write");
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.expect("read");
+        task.await.expect("task");
+
+        let (head, body) = response.split_once("\r\n\r\n").expect("head");
+        (head.lines().next().unwrap_or_default().to_owned(), body.to_owned())
+    }
+
+    #[test]
+    fn should_decode_query_params() {
+        assert_eq!(query_param("prefix=app%2Fconfig+v1&limit=1", "prefix").as_deref(), Some("app/config v1"));
+        assert_eq!(query_param("prefix=", "prefix").as_deref(), Some(""));
+        assert_eq!(query_param("limit=1", "prefix"), None);
+        assert_eq!(query_param("prefix=%2", "prefix"), None);
+        assert_eq!(query_param("prefix=%ff", "prefix"), None);
+    }
+
+    #[tokio::test]
+    async fn should_require_token_for_dashboard_api() {
+        let db = db::Db::temporary().expect("open db").view();
+        let http = http(db);
+
+        let (status, body) = request(&http, "GET /admin HTTP/1.1\r\n\r\n").await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(body, DASHBOARD);
+
+        let (status, _) = request(&http, "GET /admin/keys HTTP/1.1\r\n\r\n").await;
+        assert_eq!(status, "HTTP/1.1 401 Unauthorized");
+        let (status, _) = request(&http, "GET /admin/keys HTTP/1.1\r\nAuthorization: Bearer secreT\r\n\r\n").await;
+        assert_eq!(status, "HTTP/1.1 401 Unauthorized");
+        let (status, _) = request(&http, "GET /admin/keys HTTP/1.1\r\nAuthorization: secret\r\n\r\n").await;
+        assert_eq!(status, "HTTP/1.1 401 Unauthorized");
+    }
+
+    #[tokio::test]
+    async fn should_execute_rpc_and_list_keys_of_dashboard() {
+        let db = db::Db::temporary().expect("open db").view();
+        let http = http(db);
+
+        let rpc = r#"{"jsonrpc":"2.0","method":"set_config","params":{"id":"app/key","data":"value"},"id":1}"#;
+        let (status, body) = request(&http, &format!("POST /admin/rpc HTTP/1.1\r\nauthorization: Bearer secret\r\nContent-Length: {}\r\n\r\n{}", rpc.len(), rpc)).await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert!(serde_json::from_str::<serde_json::Value>(&body).expect("json").get("result").is_some());
+
+        let (status, body) = request(&http, "GET /admin/keys?prefix=app%2F HTTP/1.1\r\nAuthorization: Bearer secret\r\n\r\n").await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        let keys = serde_json::from_str::<serde_json::Value>(&body).expect("json");
+        assert_eq!(keys["truncated"], false);
+        assert_eq!(keys["keys"][0]["id"], "app/key");
+        assert!(keys["keys"][0]["checksum"].is_string());
+
+        let (status, _) = request(&http, "POST /admin/rpc HTTP/1.1\r\nAuthorization: Bearer secret\r\nContent-Length: 7\r\n\r\ninvalid").await;
+        assert_eq!(status, "HTTP/1.1 400 Bad Request");
+        let (status, _) = request(&http, "GET /admin/rpc HTTP/1.1\r\nAuthorization: Bearer secret\r\n\r\n").await;
+        assert_eq!(status, "HTTP/1.1 405 Method Not Allowed");
+    }
+}
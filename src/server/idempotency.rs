@@ -0,0 +1,238 @@
+//! Idempotency keys of writes.
+//!
+//! `set_config` and `delete_config` with `idempotency_key` are performed at most once per key:
+//! successful result is remembered and returned as it is to retries, regardless of current state.
+//! Failed requests are not remembered, so that retry performs them again.
+//!
+//! Results are stored under idempotency key together with their expiration time and fingerprint of request,
+//! so that key cannot be reused for different request. Expired results are removed periodically,
+//! together with oldest ones over the limit.
+//!
+//! Retry, which arrives while request with the same key is still in progress, is rejected.
+
+use std::sync::Mutex;
+use std::collections::HashSet;
+use core::convert::TryFrom;
+use core::time::Duration;
+
+use json_rpc_types::{Id, Version, Error, ErrorCode};
+
+use super::{int_err, internal_err, missing_response, unix_time_ms, RESULT};
+use crate::db;
+use crate::protocol::Response;
+
+///Time during which result is remembered.
+pub const TTL_MS: u64 = 10 * 60_000;
+///Maximum number of remembered results, which are kept once expired ones are removed.
+pub const MAX_KEYS: usize = 10_000;
+///Interval between removals of expired results.
+pub const SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Default)]
+///Idempotency keys of requests in progress.
+pub struct InFlight {
+    keys: Mutex<HashSet<String>>,
+}
+
+impl InFlight {
+    ///Marks key as in progress until guard is dropped, returning `None` if it is already in progress.
+    pub fn begin(&self, key: &str) -> Option<Guard<'_>> {
+        match self.keys.lock().unwrap_or_else(|error| error.into_inner()).insert(key.to_owned()) {
+            true => Some(Guard {
+                in_flight: self,
+                key: key.to_owned(),
+            }),
+            false => None,
+        }
+    }
+}
+
+pub struct Guard<'a> {
+    in_flight: &'a InFlight,
+    key: String,
+}
+
+impl Drop for Guard<'_> {
+    fn drop(&mut self) {
+        self.in_flight.keys.lock().unwrap_or_else(|error| error.into_inner()).remove(&self.key);
+    }
+}
+
+#[inline]
+pub fn in_progress(id: Option<Id>) -> Response {
+    Response::error(Version::V2, Error::from_code(ErrorCode::ServerError(int_err::IDEMPOTENCY_CONFLICT)).set_data("Request with the same idempotency key is in progress"), id)
+}
+
+///Encodes record as expiration time and fingerprint in big endian, followed by result.
+fn encode(expires_at: u64, fingerprint: u64, result: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(16 + result.len());
+    record.extend_from_slice(&expires_at.to_be_bytes());
+    record.extend_from_slice(&fingerprint.to_be_bytes());
+    record.extend_from_slice(result);
+    record
+}
+
+fn decode(record: &[u8]) -> Option<(u64, u64, &[u8])> {
+    let (expires_at, record) = record.split_at_checked(8)?;
+    let (fingerprint, result) = record.split_at_checked(8)?;
+    let expires_at = <[u8; 8]>::try_from(expires_at).ok()?;
+    let fingerprint = <[u8; 8]>::try_from(fingerprint).ok()?;
+    Some((u64::from_be_bytes(expires_at), u64::from_be_bytes(fingerprint), result))
+}
+
+///Returns remembered result of request, or `null` result if there is none.
+pub fn handle_recall_req(db: &db::DbView, key: &str, fingerprint: u64, cid: Option<&str>, id: Option<Id>) -> Response {
+    let record = match db.idempotency.get(key) {
+        Ok(record) => record,
+        Err(error) => {
+            error!(cid: cid, "Internal error accessing idempotency tree: {}", error);
+            return internal_err(int_err::IDEMPOTENCY_FAIL, id);
+        }
+    };
+
+    //Corrupted record is treated as expired one.
+    match record.as_ref().and_then(|record| decode(record)) {
+        Some((expires_at, _, _)) if expires_at <= unix_time_ms() => missing_response(id),
+        Some((_, stored, _)) if stored != fingerprint => {
+            Response::error(Version::V2, Error::from_code(ErrorCode::ServerError(int_err::IDEMPOTENCY_CONFLICT)).set_data("Idempotency key is already used by another request"), id)
+        },
+        Some((_, _, result)) => match serde_json::from_slice::<serde_json::Value>(result) {
+            Ok(result) => {
+                trace!(cid: cid, "Replaying result of idempotency key '{}'", key);
+                let mut payload = serde_json::map::Map::with_capacity(1);
+                payload.insert(RESULT.to_owned(), result);
+                Response::result(Version::V2, payload.into(), id)
+            },
+            Err(error) => {
+                error!(cid: cid, "Data corruption in result of idempotency key '{}': {}", key, error);
+                internal_err(int_err::IDEMPOTENCY_FAIL, id)
+            },
+        },
+        None => missing_response(id),
+    }
+}
+
+pub fn handle_remember_req(db: &db::DbView, key: &str, fingerprint: u64, result: &serde_json::Value, cid: Option<&str>, id: Option<Id>) -> Response {
+    let result = match serde_json::to_vec(result) {
+        Ok(result) => result,
+        Err(_) => unreachable!(),
+    };
+
+    match db.idempotency.insert(key, encode(unix_time_ms().saturating_add(TTL_MS), fingerprint, &result)) {
+        Ok(_) => Response::result(Version::V2, Default::default(), id),
+        Err(error) => {
+            //Write is already done, so retry would only repeat it.
+            error!(cid: cid, "Unable to remember result of idempotency key '{}': {}", key, error);
+            internal_err(int_err::IDEMPOTENCY_FAIL, id)
+        }
+    }
+}
+
+fn expire(tree: &sled::Tree, now: u64) -> sled::Result<usize> {
+    let mut expired = 0;
+    let mut remembered = Vec::new();
+
+    for entry in tree.iter() {
+        let (key, record) = entry?;
+        match decode(&record) {
+            Some((expires_at, _, _)) if expires_at > now => remembered.push((expires_at, key)),
+            _ => {
+                tree.remove(key)?;
+                expired += 1;
+            },
+        }
+    }
+
+    if remembered.len() > MAX_KEYS {
+        remembered.sort_unstable_by_key(|(expires_at, _)| *expires_at);
+        for (_, key) in remembered.drain(..remembered.len() - MAX_KEYS) {
+            tree.remove(key)?;
+            expired += 1;
+        }
+    }
+
+    Ok(expired)
+}
+
+///Removes results expired at `now` and oldest ones over limit.
+pub fn handle_expire_req(db: &db::DbView, now: u64, id: Option<Id>) -> Response {
+    match expire(&db.idempotency, now) {
+        Ok(expired) => {
+            if expired > 0 {
+                trace!("Removed {} results of idempotency keys", expired);
+            }
+            Response::result(Version::V2, Default::default(), id)
+        },
+        Err(error) => {
+            error!("Unable to remove expired results of idempotency keys: {}", error);
+            internal_err(int_err::IDEMPOTENCY_FAIL, id)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_decode_encoded_record() {
+        let record = encode(1, 2, b"{}");
+        assert_eq!(decode(&record), Some((1, 2, &b"{}"[..])));
+        assert_eq!(decode(&record[..16]), Some((1, 2, &b""[..])));
+        assert_eq!(decode(&record[..15]), None);
+    }
+
+    #[test]
+    fn should_reject_key_in_progress() {
+        let in_flight = InFlight::default();
+        let guard = in_flight.begin("key").expect("begin");
+        assert!(in_flight.begin("key").is_none());
+        assert!(in_flight.begin("other").is_some());
+
+        drop(guard);
+        assert!(in_flight.begin("key").is_some());
+    }
+
+    #[test]
+    fn should_replay_result_only_to_same_request() {
+        let db = db::Db::temporary().expect("open db").view();
+        let payload = handle_recall_req(&db, "key", 1, None, None).payload.expect("recall");
+        assert_eq!(payload[RESULT], serde_json::Value::Null);
+
+        handle_remember_req(&db, "key", 1, &serde_json::json!(42), None, None).payload.expect("remember");
+        let payload = handle_recall_req(&db, "key", 1, None, None).payload.expect("recall");
+        assert_eq!(payload[RESULT], 42);
+
+        let error = handle_recall_req(&db, "key", 2, None, None).payload.expect_err("conflict");
+        assert_eq!(error.code.code(), int_err::IDEMPOTENCY_CONFLICT);
+    }
+
+    #[test]
+    fn should_treat_expired_and_corrupted_records_as_missing() {
+        let db = db::Db::temporary().expect("open db").view();
+        db.idempotency.insert("expired", encode(1, 1, b"42")).expect("insert");
+        db.idempotency.insert("corrupted", &b"short"[..]).expect("insert");
+
+        for key in ["expired", "corrupted"] {
+            let payload = handle_recall_req(&db, key, 1, None, None).payload.expect("recall");
+            assert_eq!(payload[RESULT], serde_json::Value::Null);
+        }
+    }
+
+    #[test]
+    fn should_expire_results_and_oldest_over_limit() {
+        let db = db::Db::temporary().expect("open db").view();
+        db.idempotency.insert("expired", encode(10, 1, b"1")).expect("insert");
+        db.idempotency.insert("corrupted", &b"short"[..]).expect("insert");
+        for idx in 0..=MAX_KEYS as u64 {
+            db.idempotency.insert(format!("key{}", idx), encode(100 + idx, 1, b"1")).expect("insert");
+        }
+
+        handle_expire_req(&db, 10, None).payload.expect("expire");
+        assert_eq!(db.idempotency.len(), MAX_KEYS);
+        assert!(!db.idempotency.contains_key("expired").expect("get"));
+        assert!(!db.idempotency.contains_key("corrupted").expect("get"));
+        assert!(!db.idempotency.contains_key("key0").expect("get"));
+        assert!(db.idempotency.contains_key("key1").expect("get"));
+    }
+}
@@ -0,0 +1,395 @@
+//! Import of keys from existing etcd or Consul cluster.
+//!
+//! etcd is read via JSON gateway of v3 API (`/v3/kv/range`), while Consul via its KV API (`/v1/kv`).
+//! Token for Consul is taken from `CONSUL_HTTP_TOKEN` environment variable, if set.
+//! Keys are written the same way as by `set_config`, so key rules and quotas apply to them.
+//!
+//! Only plain `http://` endpoints are supported.
+
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use core::time::Duration;
+
+use serde_json::Value;
+
+use super::{handle_set_config_req, lease, quota, Options};
+use crate::db;
+
+pub const DEFAULT_ETCD_URL: &str = "http://127.0.0.1:2379";
+pub const DEFAULT_CONSUL_URL: &str = "http://127.0.0.1:8500";
+///Environment variable with ACL token of Consul.
+const CONSUL_TOKEN_ENV: &str = "CONSUL_HTTP_TOKEN";
+///Number of keys requested from etcd at once.
+const ETCD_PAGE_SIZE: u64 = 1000;
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+///Keys with their values, read from cluster.
+pub type Entries = Vec<(Vec<u8>, Vec<u8>)>;
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub fn base64_encode(data: &[u8]) -> String {
+    let mut result = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], chunk.get(1).copied().unwrap_or(0), chunk.get(2).copied().unwrap_or(0)];
+        let triple = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+        for idx in 0..4 {
+            match idx <= chunk.len() {
+                true => result.push(BASE64[(triple >> (18 - idx * 6)) as usize & 0x3F] as char),
+                false => result.push('='),
+            }
+        }
+    }
+    result
+}
+
+fn base64_decode(data: &str) -> Option<Vec<u8>> {
+    let data = data.trim_end_matches('=').as_bytes();
+    let mut result = Vec::with_capacity(data.len() * 3 / 4);
+    let mut acc = 0u32;
+    let mut bits = 0u32;
+
+    for byte in data {
+        let value = BASE64.iter().position(|ch| ch == byte)? as u32;
+        acc = acc << 6 | value;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            result.push((acc >> bits) as u8);
+        }
+    }
+    Some(result)
+}
+
+#[inline]
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+///Cluster endpoint, parsed from `http://host:port`
+pub struct Endpoint {
+    host: String,
+}
+
+impl Endpoint {
+    pub fn parse(url: &str) -> Option<Self> {
+        let host = url.strip_prefix("http://")?.trim_end_matches('/');
+        if host.is_empty() || host.contains('/') {
+            return None;
+        }
+
+        let host = match host.contains(':') {
+            true => host.to_owned(),
+            false => format!("{}:80", host),
+        };
+        Some(Self {
+            host,
+        })
+    }
+
+    ///Performs request, returning status code and body of response.
+    ///
+    ///HTTP/1.0 is used, so that response is never chunked.
+    pub fn request(&self, method: &str, path: &str, headers: &str, body: &[u8]) -> io::Result<(u16, Vec<u8>)> {
+        let mut socket = TcpStream::connect(self.host.as_str())?;
+        socket.set_read_timeout(Some(TIMEOUT))?;
+        socket.set_write_timeout(Some(TIMEOUT))?;
+
+        let head = format!("{} {} HTTP/1.0\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}\r\n", method, path, self.host, body.len(), headers);
+        socket.write_all(head.as_bytes())?;
+        socket.write_all(body)?;
+
+        let mut response = Vec::new();
+        socket.read_to_end(&mut response)?;
+
+        let head_end = response.windows(4).position(|window| window == b"\r\n\r\n").ok_or_else(|| invalid_data("Invalid HTTP response"))?;
+        let status = core::str::from_utf8(&response[..head_end]).ok().and_then(|head| head.split_ascii_whitespace().nth(1)).and_then(|code| code.parse().ok());
+        match status {
+            Some(status) => Ok((status, response.split_off(head_end + 4))),
+            None => Err(invalid_data("Invalid HTTP response")),
+        }
+    }
+}
+
+///Returns end of range, which covers all keys with prefix.
+fn prefix_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xFF {
+            end.push(last + 1);
+            return end;
+        }
+    }
+    //Range from prefix to `\0` covers all keys.
+    vec![0]
+}
+
+fn fetch_etcd(endpoint: &Endpoint, prefix: &str) -> io::Result<Entries> {
+    let range_end = base64_encode(&prefix_end(prefix.as_bytes()));
+    let mut key = match prefix.is_empty() {
+        true => vec![0],
+        false => prefix.as_bytes().to_vec(),
+    };
+    let mut result = Vec::new();
+
+    loop {
+        let request = serde_json::json!({
+            "key": base64_encode(&key),
+            "range_end": range_end,
+            "limit": ETCD_PAGE_SIZE,
+        });
+        let (status, body) = endpoint.request("POST", "/v3/kv/range", "", request.to_string().as_bytes())?;
+        if status != 200 {
+            return Err(io::Error::other(format!("etcd responded with status {}", status)));
+        }
+
+        let body: Value = serde_json::from_slice(&body).map_err(invalid_data)?;
+        let kvs = body.get("kvs").and_then(Value::as_array).map(Vec::as_slice).unwrap_or_default();
+        for kv in kvs {
+            let key = kv.get("key").and_then(Value::as_str).and_then(base64_decode);
+            //Empty value is omitted by gateway.
+            let value = kv.get("value").and_then(Value::as_str).map_or(Some(Vec::new()), base64_decode);
+            match (key, value) {
+                (Some(key), Some(value)) => result.push((key, value)),
+                _ => return Err(invalid_data("etcd responded with invalid key-value")),
+            }
+        }
+
+        match (body.get("more").and_then(Value::as_bool).unwrap_or(false), result.last()) {
+            //Next page starts right after last key.
+            (true, Some((last, _))) => {
+                key = last.clone();
+                key.push(0);
+            },
+            _ => break Ok(result),
+        }
+    }
+}
+
+fn fetch_consul(endpoint: &Endpoint, prefix: &str) -> io::Result<Entries> {
+    let (status, body) = endpoint.request("GET", &format!("/v1/kv/{}?recurse=true", prefix), &consul_headers(), &[])?;
+    match status {
+        200 => (),
+        //No keys under prefix.
+        404 => return Ok(Vec::new()),
+        status => return Err(io::Error::other(format!("Consul responded with status {}", status))),
+    }
+
+    let body: Vec<Value> = serde_json::from_slice(&body).map_err(invalid_data)?;
+    let mut result = Vec::with_capacity(body.len());
+    for entry in body {
+        let key = match entry.get("Key").and_then(Value::as_str) {
+            Some(key) => key.as_bytes().to_vec(),
+            None => return Err(invalid_data("Consul responded with entry without key")),
+        };
+        let value = match entry.get("Value") {
+            Some(Value::String(value)) => base64_decode(value).ok_or_else(|| invalid_data("Consul responded with invalid value"))?,
+            //Folders have no value.
+            _ => continue,
+        };
+        result.push((key, value));
+    }
+    Ok(result)
+}
+
+#[derive(Clone, Copy)]
+///Kind of external store.
+pub enum Kind {
+    Etcd,
+    Consul,
+}
+
+impl Kind {
+    #[inline]
+    pub const fn default_url(self) -> &'static str {
+        match self {
+            Kind::Etcd => DEFAULT_ETCD_URL,
+            Kind::Consul => DEFAULT_CONSUL_URL,
+        }
+    }
+
+    #[inline]
+    ///Reads all keys under prefix.
+    pub fn fetch(self, endpoint: &Endpoint, prefix: &str) -> io::Result<Entries> {
+        match self {
+            Kind::Etcd => fetch_etcd(endpoint, prefix),
+            Kind::Consul => fetch_consul(endpoint, prefix),
+        }
+    }
+}
+
+///Returns headers, needed to access Consul.
+pub fn consul_headers() -> String {
+    match std::env::var(CONSUL_TOKEN_ENV) {
+        Ok(token) => format!("X-Consul-Token: {}\r\n", token),
+        Err(_) => String::new(),
+    }
+}
+
+///Imports keys for `mode`, returning `true` on failure.
+pub fn run(mode: &str, url: Option<&str>, prefix: &str, mut db: db::DbView, options: &Options) -> bool {
+    let kind = match mode {
+        "import-etcd" => Kind::Etcd,
+        "import-consul" => Kind::Consul,
+        mode => {
+            eprintln!("Unknown mode '{}', expected import-etcd, import-consul or watch", mode);
+            return true;
+        }
+    };
+    let url = url.unwrap_or(kind.default_url());
+    let endpoint = match Endpoint::parse(url) {
+        Some(endpoint) => endpoint,
+        None => {
+            eprintln!("Invalid import URL '{}', only http://host:port is supported", url);
+            return true;
+        }
+    };
+
+    let entries = match kind.fetch(&endpoint, prefix) {
+        Ok(entries) => entries,
+        Err(error) => {
+            eprintln!("Unable to read keys from {}: {}", url, error);
+            return true;
+        }
+    };
+
+    db.blob_threshold = options.blob_threshold;
+    db.quotas = options.quotas.clone();
+    if let Err(error) = quota::init_usage(&db) {
+        eprintln!("Unable to calculate usage of namespaces: {}", error);
+        return true;
+    }
+    let mut imported = 0usize;
+    let mut skipped = 0usize;
+
+    for (key, value) in entries {
+        let (key, value) = match (String::from_utf8(key), String::from_utf8(value)) {
+            (Ok(key), Ok(value)) => (key, value),
+            (key, _) => {
+                warn!("Skipping '{}': key and value must be utf-8", key.as_deref().unwrap_or("<non-utf8>"));
+                skipped += 1;
+                continue;
+            }
+        };
+        if let Err(error) = options.key_rules.validate(&key) {
+            warn!("Skipping '{}': {}", key, error);
+            skipped += 1;
+            continue;
+        }
+
+        //Errors are logged by handler.
+        match handle_set_config_req(&db, None, &key, &value, lease::Lease::Detach, None, None).payload {
+            Ok(_) => imported += 1,
+            Err(_) => skipped += 1,
+        }
+    }
+
+    info!("Imported {} keys from {}, skipped {}", imported, url, skipped);
+    if let Err(error) = db.flush() {
+        eprintln!("Unable to flush db: {}", error);
+        return true;
+    }
+    false
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+
+    ///Serves responses in order, one per connection, returning received requests once done.
+    pub(crate) fn serve(responses: Vec<String>) -> (Endpoint, std::thread::JoinHandle<Vec<String>>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind");
+        let endpoint = Endpoint::parse(&format!("http://{}", listener.local_addr().expect("addr"))).expect("endpoint");
+        let server = std::thread::spawn(move || {
+            let mut requests = Vec::new();
+            for response in responses {
+                let (mut socket, _) = listener.accept().expect("accept");
+                let mut request = Vec::new();
+                let mut buffer = [0u8; 4096];
+                loop {
+                    let size = socket.read(&mut buffer).expect("read");
+                    request.extend_from_slice(&buffer[..size]);
+                    if let Some(head_end) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+                        let head = String::from_utf8_lossy(&request[..head_end]).into_owned();
+                        let len = head.lines().find_map(|line| line.strip_prefix("Content-Length: ")).and_then(|len| len.parse::<usize>().ok()).unwrap_or(0);
+                        if request.len() >= head_end + 4 + len {
+                            break;
+                        }
+                    }
+                }
+                requests.push(String::from_utf8(request).expect("utf-8"));
+                socket.write_all(response.as_bytes()).expect("write");
+            }
+            requests
+        });
+        (endpoint, server)
+    }
+
+    pub(crate) fn ok(body: &str) -> String {
+        format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", body.len(), body)
+    }
+
+    #[test]
+    fn should_roundtrip_base64() {
+        for data in [&b""[..], b"f", b"fo", b"foo", b"foob", b"\xFF\x00\x80"] {
+            assert_eq!(base64_decode(&base64_encode(data)).as_deref(), Some(data));
+        }
+        assert_eq!(base64_encode(b"foob"), "Zm9vYg==");
+        assert_eq!(base64_decode("Zm9v!"), None);
+    }
+
+    #[test]
+    fn should_parse_only_plain_http_endpoints() {
+        assert_eq!(Endpoint::parse("http://localhost:2379/").expect("parse").host, "localhost:2379");
+        assert_eq!(Endpoint::parse("http://localhost").expect("parse").host, "localhost:80");
+        assert!(Endpoint::parse("https://localhost:2379").is_none());
+        assert!(Endpoint::parse("http://localhost:2379/v3").is_none());
+        assert!(Endpoint::parse("http://").is_none());
+    }
+
+    #[test]
+    fn should_cover_all_keys_with_prefix() {
+        assert_eq!(prefix_end(b"app/"), b"app0");
+        assert_eq!(prefix_end(b"a\xFF\xFF"), b"b");
+        assert_eq!(prefix_end(b"\xFF"), [0]);
+        assert_eq!(prefix_end(b""), [0]);
+    }
+
+    #[test]
+    fn should_fetch_etcd_by_pages() {
+        let first = serde_json::json!({
+            "kvs": [{"key": base64_encode(b"app/a"), "value": base64_encode(b"1")}, {"key": base64_encode(b"app/b")}],
+            "more": true,
+        });
+        let second = serde_json::json!({
+            "kvs": [{"key": base64_encode(b"app/c"), "value": base64_encode(b"3")}],
+        });
+        let (endpoint, server) = serve(vec![ok(&first.to_string()), ok(&second.to_string())]);
+
+        let entries = Kind::Etcd.fetch(&endpoint, "app/").expect("fetch");
+        assert_eq!(entries, [(b"app/a".to_vec(), b"1".to_vec()), (b"app/b".to_vec(), Vec::new()), (b"app/c".to_vec(), b"3".to_vec())]);
+
+        let requests = server.join().expect("server");
+        assert!(requests[0].starts_with("POST /v3/kv/range HTTP/1.0\r\n"));
+        assert!(requests[0].contains(&base64_encode(b"app/")));
+        assert!(requests[0].contains(&base64_encode(b"app0")));
+        //Second page starts right after last key.
+        assert!(requests[1].contains(&base64_encode(b"app/b\0")));
+    }
+
+    #[test]
+    fn should_fetch_consul_without_folders() {
+        let body = serde_json::json!([
+            {"Key": "app/", "Value": null},
+            {"Key": "app/a", "Value": base64_encode(b"1")},
+        ]);
+        let (endpoint, server) = serve(vec![ok(&body.to_string()), "HTTP/1.1 404 Not Found\r\n\r\n".to_owned(), "HTTP/1.1 500 Error\r\n\r\n".to_owned()]);
+
+        assert_eq!(Kind::Consul.fetch(&endpoint, "app/").expect("fetch"), [(b"app/a".to_vec(), b"1".to_vec())]);
+        assert!(Kind::Consul.fetch(&endpoint, "none/").expect("fetch").is_empty());
+        assert!(Kind::Consul.fetch(&endpoint, "app/").is_err());
+
+        let requests = server.join().expect("server");
+        assert!(requests[0].starts_with("GET /v1/kv/app/?recurse=true HTTP/1.0\r\n"));
+    }
+}
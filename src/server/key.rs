@@ -0,0 +1,95 @@
+//! Constraints on keys, enforced on writes.
+
+///Prefix reserved for server's own metadata.
+pub const INTERNAL_PREFIX: &str = "__internal/";
+///Default characters allowed in keys, in addition to ASCII alphanumerics.
+pub const DEFAULT_CHARS: &str = "-_./:";
+///Default maximum length of key in bytes.
+pub const DEFAULT_MAX_LEN: usize = 256;
+
+#[derive(Clone)]
+pub struct KeyRules {
+    ///Maximum length of key in bytes, 0 disables it.
+    pub max_len: usize,
+    ///Characters allowed in addition to ASCII alphanumerics.
+    pub chars: String,
+}
+
+impl Default for KeyRules {
+    fn default() -> Self {
+        Self {
+            max_len: DEFAULT_MAX_LEN,
+            chars: DEFAULT_CHARS.to_owned(),
+        }
+    }
+}
+
+impl KeyRules {
+    ///Checks that client is allowed to write key, returning reason otherwise.
+    pub fn validate(&self, key: &str) -> Result<(), &'static str> {
+        if key.is_empty() {
+            return Err("Key must not be empty");
+        }
+
+        if self.max_len > 0 && key.len() > self.max_len {
+            return Err("Key exceeds maximum length");
+        }
+
+        if key.starts_with(INTERNAL_PREFIX) {
+            return Err("Key uses reserved prefix '__internal/'");
+        }
+
+        if !key.chars().all(|ch| ch.is_ascii_alphanumeric() || self.chars.contains(ch)) {
+            return Err("Key contains disallowed characters");
+        }
+
+        Ok(())
+    }
+
+    ///Checks that client is allowed to remove key, returning reason otherwise.
+    ///
+    ///Only reserved prefix is enforced, as other rules might be introduced after key is written.
+    pub fn validate_removal(&self, key: &str) -> Result<(), &'static str> {
+        match key.starts_with(INTERNAL_PREFIX) {
+            true => Err("Key uses reserved prefix '__internal/'"),
+            false => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_accept_key_within_rules() {
+        let rules = KeyRules::default();
+
+        assert_eq!(rules.validate("app/prod:db-url_1.json"), Ok(()));
+    }
+
+    #[test]
+    fn should_reject_key_outside_rules() {
+        let rules = KeyRules {
+            max_len: 8,
+            chars: "/".to_owned(),
+        };
+
+        assert!(rules.validate("").is_err());
+        assert!(rules.validate("too/long/key").is_err());
+        assert!(rules.validate("a b").is_err());
+        assert!(rules.validate("a:b").is_err());
+        assert!(KeyRules::default().validate("__internal/key").is_err());
+    }
+
+    #[test]
+    fn should_allow_removal_of_key_outside_rules() {
+        let rules = KeyRules {
+            max_len: 4,
+            chars: String::new(),
+        };
+
+        assert_eq!(rules.validate_removal("legacy key with spaces"), Ok(()));
+        assert!(rules.validate_removal("__internal/key").is_err());
+    }
+}
@@ -0,0 +1,124 @@
+//! Layering of configs, merging ordered list of keys into single document.
+//!
+//! Every layer must be JSON object, which is merged recursively into previous layers,
+//! so that later layer overrides only fields it sets. Missing keys are skipped.
+
+use json_rpc_types::{Id, Version, Error, ErrorCode};
+use xxhash_rust::xxh3::xxh3_64;
+
+use super::{int_err, internal_err, template, DATA, RESULT};
+use crate::db;
+use crate::protocol::Response;
+
+///Maximum number of layers in single request.
+pub const MAX_LAYERS: usize = 64;
+
+type Object = serde_json::Map<String, serde_json::Value>;
+
+fn merge(base: &mut Object, layer: Object) {
+    for (name, value) in layer {
+        match (base.get_mut(&name), value) {
+            (Some(serde_json::Value::Object(base)), serde_json::Value::Object(value)) => merge(base, value),
+            (_, value) => {
+                base.insert(name, value);
+            },
+        }
+    }
+}
+
+#[inline]
+fn not_object(id: Option<Id>) -> Response {
+    Response::error(Version::V2, Error::from_code(ErrorCode::ServerError(int_err::RESOLVE_NOT_OBJECT)).set_data("Layer is not JSON object"), id)
+}
+
+pub fn handle_resolve_req(db: &db::DbView, keys: &[String], resolve: bool, cid: Option<&str>, id: Option<Id>) -> Response {
+    let mut document = Object::new();
+
+    for key in keys {
+        let value = match db.get_config(key) {
+            Ok(Some(value)) => value,
+            Ok(None) => continue,
+            Err(error) => {
+                error!(cid: cid, "Internal error accessing config tree: {}", error);
+                return internal_err(int_err::RESOLVE_FAIL_GET, id);
+            }
+        };
+
+        let layer = match resolve && template::has_refs(&value) {
+            true => match core::str::from_utf8(&value).map(|value| template::resolve(db, key, value)) {
+                Ok(Ok(resolved)) => serde_json::from_str::<Object>(&resolved),
+                Ok(Err(error)) => {
+                    warn!(cid: cid, "Unable to resolve '{}': {}", key, error);
+                    return Response::error(Version::V2, Error::from_code(ErrorCode::ServerError(int_err::TEMPLATE_FAIL)).set_data(error.reason()), id);
+                },
+                Err(_) => return not_object(id),
+            },
+            false => serde_json::from_slice::<Object>(&value),
+        };
+
+        match layer {
+            Ok(layer) => merge(&mut document, layer),
+            Err(error) => {
+                warn!(cid: cid, "Layer '{}' is not JSON object: {}", key, error);
+                return not_object(id);
+            }
+        }
+    }
+
+    let data = match serde_json::to_string(&document) {
+        Ok(data) => data,
+        Err(_) => unreachable!(),
+    };
+
+    let mut result = serde_json::map::Map::with_capacity(2);
+    result.insert("checksum".to_owned(), xxh3_64(data.as_bytes()).into());
+    result.insert(DATA.to_owned(), data.into());
+
+    let mut payload = serde_json::map::Map::with_capacity(1);
+    payload.insert(RESULT.to_owned(), result.into());
+    Response::result(Version::V2, payload.into(), id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{handle_set_config_req, lease::Lease};
+
+    fn resolve(db: &db::DbView, keys: &[&str]) -> Response {
+        let keys: Vec<String> = keys.iter().map(|key| (*key).to_owned()).collect();
+        handle_resolve_req(db, &keys, true, None, None)
+    }
+
+    #[test]
+    fn should_merge_layers_in_order() {
+        let db = db::Db::temporary().expect("open db").view();
+        handle_set_config_req(&db, None, "base", r#"{"db":{"host":"localhost","port":5432},"debug":false,"tags":[1,2]}"#, Lease::Keep, None, None).payload.expect("set");
+        handle_set_config_req(&db, None, "prod", r#"{"db":{"host":"db.prod"},"tags":[3]}"#, Lease::Keep, None, None).payload.expect("set");
+
+        let mut result = resolve(&db, &["base", "missing", "prod"]).payload.expect("resolve")[RESULT].take();
+        let data = result[DATA].take();
+        let data = data.as_str().expect("data");
+        assert_eq!(serde_json::from_str::<serde_json::Value>(data).expect("json"), serde_json::json!({
+            "db": {"host": "db.prod", "port": 5432},
+            "debug": false,
+            "tags": [3],
+        }));
+        assert_eq!(result["checksum"], xxh3_64(data.as_bytes()));
+    }
+
+    #[test]
+    fn should_reject_layer_other_than_object() {
+        let db = db::Db::temporary().expect("open db").view();
+        handle_set_config_req(&db, None, "base", r#"{"debug":false}"#, Lease::Keep, None, None).payload.expect("set");
+        handle_set_config_req(&db, None, "list", "[1,2]", Lease::Keep, None, None).payload.expect("set");
+
+        let error = resolve(&db, &["base", "list"]).payload.expect_err("not object");
+        assert_eq!(error.code.code(), int_err::RESOLVE_NOT_OBJECT);
+    }
+
+    #[test]
+    fn should_resolve_empty_document_without_layers() {
+        let db = db::Db::temporary().expect("open db").view();
+        assert_eq!(resolve(&db, &["missing"]).payload.expect("resolve")[RESULT][DATA], "{}");
+    }
+}
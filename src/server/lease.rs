@@ -0,0 +1,110 @@
+//! Leases of ephemeral keys, which are deleted once connection of their writer is closed.
+//!
+//! Ephemeral keys are also stored in db, so that keys of connections, which were alive when
+//! server stopped, are deleted on next start.
+
+use std::sync::Mutex;
+use std::collections::HashMap;
+
+use crate::db;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+///Change of key's lease, done together with write.
+pub enum Lease {
+    Keep,
+    Attach,
+    Detach,
+}
+
+#[derive(Default)]
+///Owners of ephemeral keys.
+pub struct Leases {
+    //Key to id of owning session
+    owners: Mutex<HashMap<String, u64>>,
+}
+
+impl Leases {
+    #[inline]
+    ///Makes session owner of key.
+    pub fn attach(&self, key: &str, session: u64) {
+        self.owners.lock().unwrap_or_else(|error| error.into_inner()).insert(key.to_owned(), session);
+    }
+
+    #[inline]
+    ///Removes lease of key, returning whether key was ephemeral.
+    pub fn detach(&self, key: &str) -> bool {
+        self.owners.lock().unwrap_or_else(|error| error.into_inner()).remove(key).is_some()
+    }
+
+    ///Removes all leases of session, returning their keys.
+    pub fn expire(&self, session: u64) -> Vec<String> {
+        let mut owners = self.owners.lock().unwrap_or_else(|error| error.into_inner());
+        let keys: Vec<String> = owners.iter().filter(|(_, owner)| **owner == session).map(|(key, _)| key.clone()).collect();
+        for key in keys.iter() {
+            owners.remove(key);
+        }
+        keys
+    }
+}
+
+///Deletes ephemeral keys, left since last run.
+pub fn expire_stored(db: &db::DbView) {
+    let mut expired = 0usize;
+
+    for key in db.ephemeral.iter().keys() {
+        let key = match key {
+            Ok(key) => String::from_utf8_lossy(&key).into_owned(),
+            Err(error) => {
+                error!("Unable to delete ephemeral key: {}", error);
+                return;
+            }
+        };
+
+        //Errors are logged by handler.
+        match super::handle_delete_config_req(db, None, &key, Lease::Detach, None, None).payload {
+            Ok(_) => expired += 1,
+            Err(_) => return,
+        }
+    }
+
+    if expired > 0 {
+        info!("Deleted {} ephemeral keys of previous run", expired);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_expire_only_leases_of_session() {
+        let leases = Leases::default();
+        leases.attach("first", 1);
+        leases.attach("second", 1);
+        leases.attach("other", 2);
+        leases.attach("moved", 1);
+        leases.attach("moved", 2);
+
+        assert!(leases.detach("second"));
+        assert!(!leases.detach("second"));
+
+        assert_eq!(leases.expire(1), ["first"]);
+        assert!(leases.expire(1).is_empty());
+
+        let mut keys = leases.expire(2);
+        keys.sort_unstable();
+        assert_eq!(keys, ["moved", "other"]);
+    }
+
+    #[test]
+    fn should_delete_ephemeral_keys_of_previous_run() {
+        let db = db::Db::temporary().expect("open db").view();
+        super::super::handle_set_config_req(&db, None, "ephemeral", "value", Lease::Attach, None, None).payload.expect("set");
+        super::super::handle_set_config_req(&db, None, "durable", "value", Lease::Keep, None, None).payload.expect("set");
+
+        expire_stored(&db);
+        assert_eq!(db.get_config("ephemeral").expect("get"), None);
+        assert_eq!(db.get_config("durable").expect("get").as_deref(), Some(&b"value"[..]));
+        assert!(db.ephemeral.is_empty());
+    }
+}
@@ -0,0 +1,135 @@
+//! Locks with lease timeout, stored in dedicated tree.
+//!
+//! Lock is record of owner's token and expiration time, modified only with compare and swap.
+//! Expired lock can be acquired by anyone.
+
+use core::convert::TryFrom;
+
+use json_rpc_types::{Id, Version};
+
+use super::{int_err, internal_err, unix_time_ms, RESULT};
+use crate::db;
+use crate::protocol::Response;
+
+///Lease time of lock if client doesn't specify it.
+pub const DEFAULT_TTL_MS: u64 = 30_000;
+
+#[derive(Clone, Copy)]
+pub enum LockOp {
+    Acquire,
+    Release,
+    Renew,
+}
+
+impl LockOp {
+    #[inline]
+    pub const fn name(self) -> &'static str {
+        match self {
+            LockOp::Acquire => "lock_acquire",
+            LockOp::Release => "lock_release",
+            LockOp::Renew => "lock_renew",
+        }
+    }
+}
+
+///Encodes record as expiration time in big endian followed by owner.
+fn encode(owner: &str, expires_at: u64) -> Vec<u8> {
+    let mut record = Vec::with_capacity(8 + owner.len());
+    record.extend_from_slice(&expires_at.to_be_bytes());
+    record.extend_from_slice(owner.as_bytes());
+    record
+}
+
+fn decode(record: &[u8]) -> Option<(&[u8], u64)> {
+    let (expires_at, owner) = record.split_at_checked(8)?;
+    let expires_at = <[u8; 8]>::try_from(expires_at).ok()?;
+    Some((owner, u64::from_be_bytes(expires_at)))
+}
+
+///Performs operation, returning whether it succeeded.
+fn execute(tree: &sled::Tree, op: LockOp, name: &str, owner: &str, ttl_ms: u64) -> sled::Result<bool> {
+    loop {
+        let current = tree.get(name)?;
+        let now = unix_time_ms();
+        //Corrupted record is treated as expired lock.
+        let (is_owner, is_expired) = match current.as_ref().and_then(|record| decode(record)) {
+            Some((current_owner, expires_at)) => (current_owner == owner.as_bytes(), expires_at <= now),
+            None => (false, true),
+        };
+
+        let new = match op {
+            LockOp::Acquire if is_owner || is_expired => Some(encode(owner, now.saturating_add(ttl_ms))),
+            LockOp::Renew if is_owner && !is_expired => Some(encode(owner, now.saturating_add(ttl_ms))),
+            LockOp::Release if is_owner => None,
+            _ => return Ok(false),
+        };
+
+        match tree.compare_and_swap(name, current, new)? {
+            Ok(()) => return Ok(true),
+            //Lock is modified concurrently, so decide again.
+            Err(_) => continue,
+        }
+    }
+}
+
+pub fn handle_lock_req(db: &db::DbView, op: LockOp, name: &str, owner: &str, ttl_ms: u64, cid: Option<&str>, id: Option<Id>) -> Response {
+    match execute(&db.locks, op, name, owner, ttl_ms) {
+        Ok(result) => {
+            let mut payload = serde_json::map::Map::with_capacity(1);
+            payload.insert(RESULT.to_owned(), result.into());
+            Response::result(Version::V2, payload.into(), id)
+        },
+        Err(error) => {
+            error!(cid: cid, "Unable to {} '{}': {}", op.name(), name, error);
+            internal_err(int_err::LOCK_FAIL, id)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lock(db: &db::DbView, op: LockOp, owner: &str, ttl_ms: u64) -> bool {
+        execute(&db.locks, op, "lock", owner, ttl_ms).expect("lock")
+    }
+
+    #[test]
+    fn should_grant_lock_to_single_owner() {
+        let db = db::Db::temporary().expect("open db").view();
+
+        assert!(lock(&db, LockOp::Acquire, "first", DEFAULT_TTL_MS));
+        assert!(lock(&db, LockOp::Acquire, "first", DEFAULT_TTL_MS));
+        assert!(!lock(&db, LockOp::Acquire, "second", DEFAULT_TTL_MS));
+        assert!(!lock(&db, LockOp::Renew, "second", DEFAULT_TTL_MS));
+        assert!(!lock(&db, LockOp::Release, "second", DEFAULT_TTL_MS));
+
+        assert!(lock(&db, LockOp::Renew, "first", DEFAULT_TTL_MS));
+        assert!(lock(&db, LockOp::Release, "first", DEFAULT_TTL_MS));
+        assert!(!lock(&db, LockOp::Release, "first", DEFAULT_TTL_MS));
+        assert!(lock(&db, LockOp::Acquire, "second", DEFAULT_TTL_MS));
+    }
+
+    #[test]
+    fn should_let_anyone_acquire_expired_lock() {
+        let db = db::Db::temporary().expect("open db").view();
+
+        assert!(lock(&db, LockOp::Acquire, "first", 0));
+        assert!(!lock(&db, LockOp::Renew, "first", DEFAULT_TTL_MS));
+        assert!(lock(&db, LockOp::Acquire, "second", DEFAULT_TTL_MS));
+        assert!(!lock(&db, LockOp::Release, "first", DEFAULT_TTL_MS));
+
+        //Corrupted record is treated as expired lock.
+        db.locks.insert("lock", &b"short"[..]).expect("insert");
+        assert!(lock(&db, LockOp::Acquire, "first", DEFAULT_TTL_MS));
+    }
+
+    #[test]
+    fn should_answer_with_result() {
+        let db = db::Db::temporary().expect("open db").view();
+
+        let response = handle_lock_req(&db, LockOp::Acquire, "lock", "owner", DEFAULT_TTL_MS, None, Some(Id::Num(1)));
+        assert_eq!(response.payload.expect("acquire")[RESULT], true);
+        assert_eq!(response.id, Some(Id::Num(1)));
+    }
+}
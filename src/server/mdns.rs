@@ -0,0 +1,97 @@
+//! Advertisement of server over mDNS/DNS-SD as `_dou-store._tcp`.
+//!
+//! Service is advertised only on interfaces, which TCP transport accepts connections on:
+//! unspecified address advertises every interface of its family with addresses updated as they change,
+//! while specific address advertises only interface, which has it.
+
+use std::net::IpAddr;
+
+use super::session;
+
+///Type of advertised service.
+const SERVICE_TYPE: &str = "_dou-store._tcp.local.";
+
+///Keeps service advertised until dropped.
+pub struct Advertisement {
+    daemon: mdns_sd::ServiceDaemon,
+}
+
+impl Advertisement {
+    ///Starts advertising instance `name` with TCP `port`, on which connections are accepted at `addr`.
+    pub fn new(name: &str, addr: IpAddr, port: u16) -> Result<Self, mdns_sd::Error> {
+        let daemon = mdns_sd::ServiceDaemon::new()?;
+
+        let (disabled, enabled) = interfaces(addr);
+        if let Some(disabled) = disabled {
+            daemon.disable_interface(disabled)?;
+        }
+        if let Some(enabled) = enabled {
+            daemon.enable_interface(enabled)?;
+        }
+        daemon.register(service(name, addr, port)?)?;
+
+        Ok(Self {
+            daemon,
+        })
+    }
+}
+
+///Returns interfaces to disable and then enable, so that only ones with `addr` are advertised on.
+fn interfaces(addr: IpAddr) -> (Option<mdns_sd::IfKind>, Option<mdns_sd::IfKind>) {
+    match addr {
+        IpAddr::V4(unspecified) if unspecified.is_unspecified() => (Some(mdns_sd::IfKind::IPv6), None),
+        IpAddr::V6(unspecified) if unspecified.is_unspecified() => (None, None),
+        IpAddr::V4(addr) if addr.is_loopback() => (Some(mdns_sd::IfKind::All), Some(mdns_sd::IfKind::LoopbackV4)),
+        IpAddr::V6(addr) if addr.is_loopback() => (Some(mdns_sd::IfKind::All), Some(mdns_sd::IfKind::LoopbackV6)),
+        addr => (Some(mdns_sd::IfKind::All), Some(mdns_sd::IfKind::Addr(addr))),
+    }
+}
+
+///Describes service, which announces addresses of interfaces automatically if `addr` is unspecified.
+fn service(name: &str, addr: IpAddr, port: u16) -> Result<mdns_sd::ServiceInfo, mdns_sd::Error> {
+    let version = session::PROTOCOL_VERSION.to_string();
+    let properties = [("version", version.as_str()), ("framing", session::FRAMING), ("encoding", session::ENCODING)];
+    let host = format!("{}.local.", name);
+
+    match addr.is_unspecified() {
+        true => mdns_sd::ServiceInfo::new(SERVICE_TYPE, name, &host, (), port, &properties[..]).map(mdns_sd::ServiceInfo::enable_addr_auto),
+        false => mdns_sd::ServiceInfo::new(SERVICE_TYPE, name, &host, addr, port, &properties[..]),
+    }
+}
+
+impl Drop for Advertisement {
+    fn drop(&mut self) {
+        let _ = self.daemon.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn should_advertise_only_interfaces_of_bound_address() {
+        use mdns_sd::IfKind;
+
+        assert!(matches!(interfaces(IpAddr::V4(Ipv4Addr::UNSPECIFIED)), (Some(IfKind::IPv6), None)));
+        assert!(matches!(interfaces("::".parse().expect("addr")), (None, None)));
+        assert!(matches!(interfaces(IpAddr::V4(Ipv4Addr::LOCALHOST)), (Some(IfKind::All), Some(IfKind::LoopbackV4))));
+        assert!(matches!(interfaces("::1".parse().expect("addr")), (Some(IfKind::All), Some(IfKind::LoopbackV6))));
+        assert!(matches!(interfaces("192.0.2.2".parse().expect("addr")), (Some(IfKind::All), Some(IfKind::Addr(addr))) if addr == IpAddr::from([192, 0, 2, 2])));
+    }
+
+    #[test]
+    fn should_describe_service() {
+        let info = service("store", IpAddr::from([192, 0, 2, 2]), 6666).expect("service");
+        assert_eq!(info.get_fullname(), "store._dou-store._tcp.local.");
+        assert_eq!(info.get_hostname(), "store.local.");
+        assert_eq!(info.get_addresses_v4().into_iter().collect::<Vec<_>>(), [&Ipv4Addr::new(192, 0, 2, 2)]);
+        assert_eq!(info.get_property_val_str("version"), Some("1"));
+        assert_eq!(info.get_property_val_str("framing"), Some(session::FRAMING));
+        assert_eq!(info.get_property_val_str("encoding"), Some(session::ENCODING));
+
+        let info = service("store", IpAddr::V4(Ipv4Addr::UNSPECIFIED), 6666).expect("service");
+        assert!(info.get_addresses_v4().is_empty());
+    }
+}
@@ -0,0 +1,179 @@
+//! Server metrics, exported in Prometheus text format or over OTLP.
+
+use std::sync::Mutex;
+use std::collections::BTreeMap;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+///Upper bounds of latency histogram buckets, in seconds.
+pub const LATENCY_BUCKETS: [f64; 10] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+///Name under which requests for unknown methods are accounted.
+pub const UNKNOWN_METHOD: &str = "unknown";
+
+#[derive(Default, Clone)]
+pub struct MethodStats {
+    pub count: u64,
+    pub duration_sum: f64,
+    ///Non-cumulative, converted into cumulative on Prometheus output.
+    pub buckets: [u64; LATENCY_BUCKETS.len()],
+}
+
+///Copy of current metrics values.
+pub struct Snapshot {
+    pub connections: u64,
+    pub requests: Vec<(String, MethodStats)>,
+    pub errors: Vec<(i64, u64)>,
+}
+
+pub struct Metrics {
+    connections: AtomicU64,
+    requests: Mutex<BTreeMap<String, MethodStats>>,
+    errors: Mutex<BTreeMap<i64, u64>>,
+}
+
+///Global metrics storage.
+pub static METRICS: Metrics = Metrics::new();
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            connections: AtomicU64::new(0),
+            requests: Mutex::new(BTreeMap::new()),
+            errors: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    #[inline]
+    pub fn connection_open(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn connection_close(&self) {
+        self.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn record_request(&self, method: &str, duration: Duration) {
+        let duration = duration.as_secs_f64();
+        let mut requests = self.requests.lock().unwrap_or_else(|error| error.into_inner());
+
+        let stats = match requests.get_mut(method) {
+            Some(stats) => stats,
+            None => requests.entry(method.to_owned()).or_default(),
+        };
+
+        stats.count += 1;
+        stats.duration_sum += duration;
+        if let Some(idx) = LATENCY_BUCKETS.iter().position(|bound| duration <= *bound) {
+            stats.buckets[idx] += 1;
+        }
+    }
+
+    pub fn record_error(&self, code: i64) {
+        let mut errors = self.errors.lock().unwrap_or_else(|error| error.into_inner());
+        *errors.entry(code).or_insert(0) += 1;
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let requests = self.requests.lock().unwrap_or_else(|error| error.into_inner());
+        let requests = requests.iter().map(|(method, stats)| (method.clone(), stats.clone())).collect();
+        let errors = self.errors.lock().unwrap_or_else(|error| error.into_inner());
+        let errors = errors.iter().map(|(code, count)| (*code, *count)).collect();
+
+        Snapshot {
+            connections: self.connections.load(Ordering::Relaxed),
+            requests,
+            errors,
+        }
+    }
+
+    ///Writes all metrics in Prometheus text format.
+    ///
+    ///`db_size` is provided by caller as it requires access to storage.
+    pub fn write_prometheus<W: Write>(&self, out: &mut W, db_size: Option<u64>) -> fmt::Result {
+        {
+            let requests = self.requests.lock().unwrap_or_else(|error| error.into_inner());
+
+            out.write_str("# HELP dou_store_requests_total Number of processed requests.\n")?;
+            out.write_str("# TYPE dou_store_requests_total counter\n")?;
+            for (method, stats) in requests.iter() {
+                writeln!(out, "dou_store_requests_total{{method=\"{}\"}} {}", method, stats.count)?;
+            }
+
+            out.write_str("# HELP dou_store_request_duration_seconds Time spent processing request.\n")?;
+            out.write_str("# TYPE dou_store_request_duration_seconds histogram\n")?;
+            for (method, stats) in requests.iter() {
+                let mut cumulative = 0;
+                for (bound, count) in LATENCY_BUCKETS.iter().zip(stats.buckets.iter()) {
+                    cumulative += count;
+                    writeln!(out, "dou_store_request_duration_seconds_bucket{{method=\"{}\",le=\"{}\"}} {}", method, bound, cumulative)?;
+                }
+                writeln!(out, "dou_store_request_duration_seconds_bucket{{method=\"{}\",le=\"+Inf\"}} {}", method, stats.count)?;
+                writeln!(out, "dou_store_request_duration_seconds_sum{{method=\"{}\"}} {}", method, stats.duration_sum)?;
+                writeln!(out, "dou_store_request_duration_seconds_count{{method=\"{}\"}} {}", method, stats.count)?;
+            }
+        }
+
+        {
+            let errors = self.errors.lock().unwrap_or_else(|error| error.into_inner());
+
+            out.write_str("# HELP dou_store_errors_total Number of error responses.\n")?;
+            out.write_str("# TYPE dou_store_errors_total counter\n")?;
+            for (code, count) in errors.iter() {
+                writeln!(out, "dou_store_errors_total{{code=\"{}\"}} {}", code, count)?;
+            }
+        }
+
+        out.write_str("# HELP dou_store_active_connections Number of connected clients.\n")?;
+        out.write_str("# TYPE dou_store_active_connections gauge\n")?;
+        writeln!(out, "dou_store_active_connections {}", self.connections.load(Ordering::Relaxed))?;
+
+        if let Some(db_size) = db_size {
+            out.write_str("# HELP dou_store_db_size_bytes Size of database on disk.\n")?;
+            out.write_str("# TYPE dou_store_db_size_bytes gauge\n")?;
+            writeln!(out, "dou_store_db_size_bytes {}", db_size)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_write_cumulative_histogram() {
+        let metrics = Metrics::new();
+        metrics.connection_open();
+        metrics.record_request("get_config", Duration::from_micros(50));
+        metrics.record_request("get_config", Duration::from_millis(3));
+        metrics.record_request("get_config", Duration::from_secs(10));
+        metrics.record_error(-32600);
+
+        let mut out = String::new();
+        metrics.write_prometheus(&mut out, Some(4096)).expect("write");
+
+        assert!(out.contains("dou_store_requests_total{method=\"get_config\"} 3\n"));
+        assert!(out.contains("dou_store_request_duration_seconds_bucket{method=\"get_config\",le=\"0.0001\"} 1\n"));
+        assert!(out.contains("dou_store_request_duration_seconds_bucket{method=\"get_config\",le=\"0.001\"} 1\n"));
+        assert!(out.contains("dou_store_request_duration_seconds_bucket{method=\"get_config\",le=\"0.005\"} 2\n"));
+        assert!(out.contains("dou_store_request_duration_seconds_bucket{method=\"get_config\",le=\"5\"} 2\n"));
+        assert!(out.contains("dou_store_request_duration_seconds_bucket{method=\"get_config\",le=\"+Inf\"} 3\n"));
+        assert!(out.contains("dou_store_request_duration_seconds_count{method=\"get_config\"} 3\n"));
+        assert!(out.contains("dou_store_errors_total{code=\"-32600\"} 1\n"));
+        assert!(out.contains("dou_store_active_connections 1\n"));
+        assert!(out.contains("dou_store_db_size_bytes 4096\n"));
+    }
+
+    #[test]
+    fn should_omit_db_size_if_unknown() {
+        let mut out = String::new();
+        Metrics::new().write_prometheus(&mut out, None).expect("write");
+
+        assert!(out.contains("dou_store_active_connections 0\n"));
+        assert!(!out.contains("dou_store_db_size_bytes"));
+    }
+}
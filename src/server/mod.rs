@@ -1,40 +1,290 @@
-use std::net;
+use std::{io, net};
+use std::sync::{Arc, OnceLock};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::time::{Instant, SystemTime};
+use core::convert::TryFrom;
+use core::future::Future;
+use core::time::Duration;
+use core::sync::atomic::{AtomicU64, Ordering};
 
-use rogu::error;
 use json_rpc_types::{Id, Error, Version, ErrorCode};
-use xxhash_rust::xxh3::xxh3_64;
+use xxhash_rust::xxh3::{xxh3_64, xxh3_64_with_seed};
 use xxhash_rust::const_xxh3::xxh3_64 as const_xxh3_64;
 
 use crate::db;
-use crate::protocol::{Request, RequestPayload, Response};
+use crate::protocol::{self, Field, Request, RequestPayload, Response};
 
 //methods
 const PING: u64 = const_xxh3_64(b"ping");
+const HELLO: u64 = const_xxh3_64(b"hello");
 const CHECKSUM: u64 = const_xxh3_64(b"cheksum");
 const CONFIG: u64 = const_xxh3_64(b"config");
 const SET_CONFIG: u64 = const_xxh3_64(b"set_config");
+const DELETE_CONFIG: u64 = const_xxh3_64(b"delete_config");
+const SET_EPHEMERAL: u64 = const_xxh3_64(b"set_ephemeral");
+const LOCK_ACQUIRE: u64 = const_xxh3_64(b"lock_acquire");
+const LOCK_RELEASE: u64 = const_xxh3_64(b"lock_release");
+const LOCK_RENEW: u64 = const_xxh3_64(b"lock_renew");
+const QUEUE_PUSH: u64 = const_xxh3_64(b"queue_push");
+const QUEUE_POP: u64 = const_xxh3_64(b"queue_pop");
+const QUEUE_ACK: u64 = const_xxh3_64(b"queue_ack");
+const QUEUE_LEN: u64 = const_xxh3_64(b"queue_len");
+const PUBLISH: u64 = const_xxh3_64(b"publish");
+const SUBSCRIBE_CHANNEL: u64 = const_xxh3_64(b"subscribe_channel");
+const UNSUBSCRIBE_CHANNEL: u64 = const_xxh3_64(b"unsubscribe_channel");
+const SET_HOOK: u64 = const_xxh3_64(b"set_hook");
+const DELETE_HOOK: u64 = const_xxh3_64(b"delete_hook");
+const HOOKS: u64 = const_xxh3_64(b"hooks");
+const RESOLVE: u64 = const_xxh3_64(b"resolve");
+const DIFF: u64 = const_xxh3_64(b"diff");
+const TREE_CHECKSUM: u64 = const_xxh3_64(b"tree_checksum");
+const PUT_BLOB: u64 = const_xxh3_64(b"put_blob");
+const GET_BLOB_BY_HASH: u64 = const_xxh3_64(b"get_blob_by_hash");
+const USAGE: u64 = const_xxh3_64(b"usage");
+const DIFF_CONFIG: u64 = const_xxh3_64(b"diff_config");
+const CHAOS: u64 = const_xxh3_64(b"chaos");
+const READ_SNAPSHOT: u64 = const_xxh3_64(b"read_snapshot");
+const WATCH: u64 = const_xxh3_64(b"watch");
+const UNWATCH: u64 = const_xxh3_64(b"unwatch");
+const READY: u64 = const_xxh3_64(b"ready");
+const MATCH_KEYS: u64 = const_xxh3_64(b"match_keys");
+const RANGE: u64 = const_xxh3_64(b"range");
+const UPLOAD_BEGIN: u64 = const_xxh3_64(b"upload_begin");
+const UPLOAD_CHUNK: u64 = const_xxh3_64(b"upload_chunk");
+const UPLOAD_COMMIT: u64 = const_xxh3_64(b"upload_commit");
+///Methods without side effects, which are skipped when sent as notification.
+const READ_ONLY: [u64; 15] = [
+    PING, CHECKSUM, CONFIG, QUEUE_LEN, HOOKS, RESOLVE, DIFF, TREE_CHECKSUM, GET_BLOB_BY_HASH,
+    USAGE, DIFF_CONFIG, READ_SNAPSHOT, READY, MATCH_KEYS, RANGE,
+];
+//admin methods, handled by transport
+const CONNECTIONS: u64 = const_xxh3_64(b"connections");
+const KICK: u64 = const_xxh3_64(b"kick");
 
 //params
-const ID: &'static str = "id";
-const DATA: &'static str = "data";
-const RESULT: &'static str = "result";
+const ID: &str = "id";
+const IDS: &str = "ids";
+const DATA: &str = "data";
+const RESULT: &str = "result";
+const SEQ: &str = "seq";
+const LAST: &str = "last";
+const CHUNKED: &str = "chunked";
+///Flag to return `null` result for missing key, instead of empty value.
+const EXPLICIT_MISSING: &str = "explicit_missing";
+const VERSION: &str = "version";
+const FEATURES: &str = "features";
+const OWNER: &str = "owner";
+const TTL_MS: &str = "ttl_ms";
+const VISIBILITY_MS: &str = "visibility_ms";
+const PREFIX: &str = "prefix";
+const EVENT: &str = "event";
+const SCRIPT: &str = "script";
+///Flag to return value without resolving references.
+const RAW: &str = "raw";
+const CHECKSUMS: &str = "checksums";
+const NAMESPACE: &str = "namespace";
+///Flag to only validate write, without persisting it.
+const VALIDATE: &str = "validate";
+const OTHER: &str = "other";
+const ACTIVATE_AT: &str = "activate_at";
+///Flag to substitute whitelisted environment variables within value.
+const EXPAND_ENV: &str = "expand_env";
+const ENABLED: &str = "enabled";
+const LATENCY_MS: &str = "latency_ms";
+const DROP_PERCENT: &str = "drop_percent";
+const ERROR_PERCENT: &str = "error_percent";
+const PATTERN: &str = "pattern";
+///Token, which authorizes admin methods.
+const ADMIN_TOKEN: &str = "admin_token";
+///Flag to return values together with keys.
+const VALUES: &str = "values";
+const FROM: &str = "from";
+const TO: &str = "to";
+const ORDER: &str = "order";
+const LIMIT: &str = "limit";
+const IDEMPOTENCY_KEY: &str = "idempotency_key";
+const UPLOAD: &str = "upload";
 
 const LOCAL_HOST: net::IpAddr = net::IpAddr::V4(net::Ipv4Addr::new(127, 0, 0, 1));
 
 mod int_err {
     pub const CHECKSUM_FAIL_GET: i64 = 1;
+    pub const CHECKSUM_RSP_CORRUPT: i64 = 2;
     pub const CONFIG_FAIL_GET: i64 = 10;
     pub const CONFIG_RSP_CORRUPT: i64 = 20;
     pub const SET_CONFIG_FAIL: i64 = 30;
     pub const SET_CONFIG_SERDE_FAIL: i64 = 31;
+    pub const DELETE_CONFIG_FAIL: i64 = 40;
+    pub const INVALID_KEY: i64 = 50;
+    pub const LOCK_FAIL: i64 = 60;
+    pub const QUEUE_FAIL: i64 = 70;
+    pub const QUEUE_RSP_CORRUPT: i64 = 71;
+    pub const HOOK_REJECTED: i64 = 80;
+    pub const HOOK_FAIL: i64 = 81;
+    pub const HOOK_STORE_FAIL: i64 = 82;
+    pub const TEMPLATE_FAIL: i64 = 90;
     pub const TASK_SPAWN_FAIL: i64 = 100;
+    pub const TASK_PANIC: i64 = 101;
+    pub const RESOLVE_FAIL_GET: i64 = 110;
+    pub const RESOLVE_NOT_OBJECT: i64 = 111;
+    pub const DIFF_FAIL_GET: i64 = 120;
+    pub const DIFF_RSP_CORRUPT: i64 = 121;
+    pub const BLOB_FAIL: i64 = 130;
+    pub const BLOB_RSP_CORRUPT: i64 = 131;
+    pub const QUOTA_EXCEEDED: i64 = 140;
+    pub const USAGE_FAIL_GET: i64 = 141;
+    pub const DIFF_CONFIG_FAIL_GET: i64 = 150;
+    pub const CHAOS_FAULT: i64 = 160;
+    pub const UPSTREAM_FAIL: i64 = 170;
+    pub const NOT_READY: i64 = 180;
+    pub const IDEMPOTENCY_FAIL: i64 = 190;
+    pub const IDEMPOTENCY_CONFLICT: i64 = 191;
+    pub const UPLOAD_FAIL: i64 = 200;
+    pub const UPLOAD_RSP_CORRUPT: i64 = 201;
+    pub const UNAUTHORIZED: i64 = 210;
 }
 
 pub mod tcp;
+pub mod http;
+pub mod metrics;
+pub mod pool;
+pub mod cache;
+pub mod worker;
+pub mod chunk;
+pub mod key;
+pub mod session;
+pub mod lease;
+pub mod lock;
+pub mod queue;
+pub mod pubsub;
+pub mod hook;
+pub mod template;
+pub mod layer;
+pub mod sync;
+pub mod blob;
+pub mod noise;
+pub mod mdns;
+pub mod quota;
+pub mod compare;
+pub mod schedule;
+pub mod import;
+pub mod bridge;
+pub mod env;
+pub mod chaos;
+pub mod upstream;
+pub mod snapshot;
+pub mod watch;
+pub mod health;
+pub mod glob;
+pub mod range;
+pub mod idempotency;
+pub mod multipart;
+
+trait ErrorKindExt {
+    ///Returns true whether error can be ignored in context of `TcpListener::accept`
+    fn is_accept_error_ok(&self) -> bool;
+}
+
+impl ErrorKindExt for io::ErrorKind {
+    #[inline(always)]
+    fn is_accept_error_ok(&self) -> bool {
+        *self == io::ErrorKind::ConnectionAborted ||
+        *self == io::ErrorKind::ConnectionRefused ||
+        *self == io::ErrorKind::ConnectionReset ||
+        *self == io::ErrorKind::NotConnected ||
+        *self == io::ErrorKind::WouldBlock ||
+        *self == io::ErrorKind::TimedOut ||
+        *self == io::ErrorKind::Interrupted
+    }
+}
+
+///Generates correlation id for request, which client didn't supply.
+fn generate_correlation_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    static SEED: OnceLock<u64> = OnceLock::new();
+
+    let seed = *SEED.get_or_init(|| match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(now) => now.as_nanos() as u64,
+        Err(_) => 0,
+    });
+    let counter = COUNTER.fetch_add(1, Ordering::Relaxed);
+
+    format!("{:016x}", xxh3_64_with_seed(&counter.to_le_bytes(), seed))
+}
+
+#[inline]
+///Returns current time as milliseconds since unix epoch.
+fn unix_time_ms() -> u64 {
+    match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(now) => now.as_millis() as u64,
+        Err(_) => 0,
+    }
+}
+
+///Business logic of the server, decoupled from transport.
+///
+///Transport is responsible for framing and serialization, while handler only needs to produce
+///response for every request it is given.
+pub trait RequestHandler: Send + Sync + 'static {
+    ///Processes request of client's `session`, returning response to be sent back to the client.
+    fn handle_request(&self, session: &session::Session, request: Request<'_>) -> impl Future<Output = Response> + Send;
+
+    #[inline]
+    ///Called once client's connection is closed, including when its task panics.
+    fn close_session(&self, _session: &session::Session) {
+    }
+
+    #[inline]
+    ///Called after request is processed to determine whether its response must not be sent.
+    fn is_lost(&self, _method: &str) -> bool {
+        false
+    }
+}
+
+#[derive(Clone, Default)]
+///Options of default handler.
+pub struct Options {
+    ///Requests taking longer are logged as warnings.
+    pub slow_request_threshold: Option<Duration>,
+    ///Capacity of config cache in bytes, 0 disables it.
+    pub cache_size: usize,
+    ///Number of threads performing db operations.
+    pub db_workers: usize,
+    ///Constraints on keys of writes.
+    pub key_rules: key::KeyRules,
+    ///Limits of hook scripts.
+    pub hook_limits: hook::Limits,
+    ///Size in bytes from which values are deduplicated by their hash, 0 disables it.
+    pub blob_threshold: usize,
+    ///Limits on usage of namespaces.
+    pub quotas: quota::Quotas,
+    ///Mirroring of configs into external store.
+    pub bridge: Option<bridge::Config>,
+    ///Environment variables, which can be substituted within values.
+    pub env: env::Environment,
+    ///Initial faults of chaos mode, disabled if `None`.
+    pub chaos: Option<chaos::Settings>,
+    ///Address of dou-store, from which missing keys are read through.
+    pub upstream: Option<String>,
+    ///Token, which must be supplied to admin methods, disabled if `None`.
+    pub admin_token: Option<String>,
+}
 
 #[derive(Clone)]
-struct Handler {
-    db: db::DbView,
+///Default handler, serving requests out of db.
+pub struct Handler {
+    options: Options,
+    cache: Option<Arc<cache::Cache>>,
+    worker: worker::Worker,
+    uploads: Arc<chunk::Uploads>,
+    leases: Arc<lease::Leases>,
+    channels: Arc<pubsub::Channels>,
+    hooks: Arc<hook::Hooks>,
+    chaos: Option<Arc<chaos::Chaos>>,
+    upstream: Option<Arc<upstream::Upstream>>,
+    watchers: Arc<watch::Watchers>,
+    in_flight: Arc<idempotency::InFlight>,
 }
 
 #[inline]
@@ -42,11 +292,62 @@ fn invalid_req(msg: &'static str, id: Option<Id>) -> Response {
     Response::error(Version::V2, Error::from_code(ErrorCode::InvalidRequest).set_data(msg), id)
 }
 
+#[inline]
+fn invalid_key(msg: &'static str, id: Option<Id>) -> Response {
+    Response::error(Version::V2, Error::from_code(ErrorCode::ServerError(int_err::INVALID_KEY)).set_data(msg), id)
+}
+
+#[inline]
+fn unauthorized(msg: &'static str, id: Option<Id>) -> Response {
+    Response::error(Version::V2, Error::from_code(ErrorCode::ServerError(int_err::UNAUTHORIZED)).set_data(msg), id)
+}
+
+///Compares whole tokens, so that time doesn't depend on position of first mismatch.
+fn is_token_eq(left: &str, right: &str) -> bool {
+    left.len() == right.len() && left.bytes().zip(right.bytes()).fold(0, |diff, (left, right)| diff | (left ^ right)) == 0
+}
+
+///Checks that params carry `expected` admin token, returning response of rejection otherwise.
+///
+///Every request is rejected, if there is no admin token.
+fn authorize(expected: Option<&str>, params: Option<&RequestPayload<'_>>, id: &Option<Id>) -> Result<(), Response> {
+    let expected = match expected {
+        Some(expected) => expected,
+        None => return Err(unauthorized("Method requires admin token, which is not configured", id.clone())),
+    };
+
+    match params.map_or(Field::Missing, |params| params.field(ADMIN_TOKEN)) {
+        Field::Str(token) if is_token_eq(&token, expected) => Ok(()),
+        _ => Err(unauthorized("Params field 'admin_token' must be admin token", id.clone())),
+    }
+}
+
+#[inline]
+fn hook_rejected(id: Option<Id>) -> Response {
+    Response::error(Version::V2, Error::from_code(ErrorCode::ServerError(int_err::HOOK_REJECTED)).set_data("Rejected by hook"), id)
+}
+
 #[inline]
 const fn internal_err(err: i64, id: Option<Id>) -> Response {
     Response::error(Version::V2, Error::from_code(ErrorCode::ServerError(err)), id)
 }
 
+#[inline]
+fn missing_response(id: Option<Id>) -> Response {
+    let mut payload = serde_json::map::Map::with_capacity(1);
+    payload.insert(RESULT.to_owned(), serde_json::Value::Null);
+    Response::result(Version::V2, payload.into(), id)
+}
+
+#[inline]
+///Returns whether response is of missing key, requested with `explicit_missing`.
+fn is_missing(response: &Response) -> bool {
+    match &response.payload {
+        Ok(serde_json::Value::Object(result)) => result.get(RESULT).is_some_and(serde_json::Value::is_null),
+        _ => false,
+    }
+}
+
 #[inline]
 fn checksum_response(num: u64, id: Option<Id>) -> Response {
     let mut payload = serde_json::map::Map::with_capacity(1);
@@ -54,11 +355,24 @@ fn checksum_response(num: u64, id: Option<Id>) -> Response {
     Response::result(Version::V2, payload.into(), id)
 }
 
-fn config_response(data: &[u8], id: Option<Id>) -> Response {
+///Describes listed key with its checksum and, if requested, value.
+fn key_item(db: &db::DbView, key: &str, checksum: &[u8], with_values: bool) -> Result<serde_json::Value, sled::Error> {
+    let mut item = serde_json::Map::with_capacity(3);
+    item.insert(ID.to_owned(), key.into());
+    let checksum = <[u8; 8]>::try_from(checksum).map(u64::from_be_bytes).ok();
+    item.insert("checksum".to_owned(), checksum.into());
+    if with_values {
+        let value = db.get_config(key)?.map(|value| String::from_utf8_lossy(&value).into_owned());
+        item.insert(DATA.to_owned(), value.into());
+    }
+    Ok(item.into())
+}
+
+fn config_response(data: &[u8], cid: Option<&str>, id: Option<Id>) -> Response {
     let data = match core::str::from_utf8(data) {
         Ok(data) => data,
         Err(error) => {
-            error!("Data corruption in config. Unexpected non-utf8 config: {}", error);
+            error!(cid: cid, "Data corruption in config. Unexpected non-utf8 config: {}", error);
             return internal_err(int_err::CONFIG_RSP_CORRUPT, id)
         }
     };
@@ -68,142 +382,1547 @@ fn config_response(data: &[u8], id: Option<Id>) -> Response {
     Response::result(Version::V2, payload.into(), id)
 }
 
+fn hello_response(session: &session::Session, params: Option<&RequestPayload<'_>>, id: Option<Id>) -> Response {
+    if let Some(version) = params.and_then(|params| params.get(VERSION)) {
+        if serde_json::from_str::<u64>(version.get()).is_err() {
+            return invalid_req("Params field 'version' must be unsigned integer", id);
+        }
+    }
+
+    let features = match params.and_then(|params| params.get(FEATURES)) {
+        Some(features) => match serde_json::from_str::<Vec<Cow<'_, str>>>(features.get()) {
+            Ok(features) => session.negotiate(features.iter().map(|feature| feature.as_ref())),
+            Err(_) => return invalid_req("Params field 'features' must be array of strings", id),
+        },
+        None => session.negotiate(core::iter::empty()),
+    };
+
+    let mut result = serde_json::map::Map::with_capacity(5);
+    result.insert(VERSION.to_owned(), session::PROTOCOL_VERSION.into());
+    result.insert("framing".to_owned(), session::FRAMING.into());
+    result.insert("encoding".to_owned(), session::ENCODING.into());
+    result.insert(FEATURES.to_owned(), features.into());
+    result.insert("supported".to_owned(), session::Session::supported().collect::<Vec<_>>().into());
+
+    let mut payload = serde_json::map::Map::with_capacity(1);
+    payload.insert(RESULT.to_owned(), result.into());
+    Response::result(Version::V2, payload.into(), id)
+}
+
+#[inline]
+fn bool_response(result: bool, id: Option<Id>) -> Response {
+    let mut payload = serde_json::map::Map::with_capacity(1);
+    payload.insert(RESULT.to_owned(), result.into());
+    Response::result(Version::V2, payload.into(), id)
+}
 
 #[inline]
-fn handle_set_config_req(db: db::DbView, params: RequestPayload, id: Option<Id>) -> Response {
-    let key = match params.get(ID) {
-        Some(serde_json::Value::String(value)) => value,
-        Some(_) => return invalid_req("Params field 'id' must be a string", id),
-        None => return invalid_req("Params is missing field 'id'", id),
+fn key_param<'a>(params: &RequestPayload<'a>, id: &Option<Id>) -> Result<Cow<'a, str>, Response> {
+    match params.field(ID) {
+        Field::Str(key) => Ok(key),
+        Field::Other(_) => Err(invalid_req("Params field 'id' must be a string", id.clone())),
+        Field::Missing => Err(invalid_req("Params is missing field 'id'", id.clone())),
+    }
+}
+
+fn keys_param(params: &RequestPayload<'_>, id: &Option<Id>) -> Result<Vec<String>, Response> {
+    let keys = match params.get(IDS).map(|keys| serde_json::from_str::<Vec<String>>(keys.get())) {
+        Some(Ok(keys)) => keys,
+        Some(Err(_)) => return Err(invalid_req("Params field 'ids' must be array of strings", id.clone())),
+        None => return Err(invalid_req("Params is missing field 'ids'", id.clone())),
     };
 
-    match params.get(DATA) {
-        Some(serde_json::Value::String(value)) => set_config_response(db, key, value, id),
+    match keys.len() > layer::MAX_LAYERS {
+        true => Err(invalid_req("Params field 'ids' has too many keys", id.clone())),
+        false => Ok(keys),
+    }
+}
+
+#[inline]
+fn data_param<'a>(params: &RequestPayload<'a>, id: &Option<Id>) -> Result<Cow<'a, str>, Response> {
+    match params.field(DATA) {
+        Field::Str(value) => Ok(value),
         //We prefer user to serialize, but accept object too.
-        Some(serde_json::Value::Object(value)) => match serde_json::to_string(value) {
-            Ok(value) => set_config_response(db, key, &value, id),
-            Err(error) => {
-                error!("Internal error serializing json: {}", error);
-                internal_err(int_err::SET_CONFIG_SERDE_FAIL, id)
+        Field::Other(value) if value.get().starts_with('{') => {
+            match serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(value.get()).and_then(|value| serde_json::to_string(&value)) {
+                Ok(value) => Ok(Cow::Owned(value)),
+                Err(error) => {
+                    error!(cid: protocol::correlation_id(params), "Internal error serializing json: {}", error);
+                    Err(internal_err(int_err::SET_CONFIG_SERDE_FAIL, id.clone()))
+                },
+            }
+        },
+        Field::Other(_) => Err(invalid_req("Params field 'data' must be a string or object", id.clone())),
+        Field::Missing => Err(invalid_req("Params is missing field 'data'", id.clone())),
+    }
+}
+
+fn hook_definition(params: &RequestPayload<'_>, id: &Option<Id>) -> Result<hook::Definition, Response> {
+    let prefix = match params.field(PREFIX) {
+        Field::Str(prefix) => prefix.into_owned(),
+        Field::Other(_) => return Err(invalid_req("Params field 'prefix' must be a string", id.clone())),
+        Field::Missing => String::new(),
+    };
+    let event = match params.get(EVENT).map(|event| serde_json::from_str::<hook::Event>(event.get())) {
+        Some(Ok(event)) => event,
+        Some(Err(_)) => return Err(invalid_req("Params field 'event' must be 'read' or 'write'", id.clone())),
+        None => return Err(invalid_req("Params is missing field 'event'", id.clone())),
+    };
+    let script = match params.field(SCRIPT) {
+        Field::Str(script) => script.into_owned(),
+        Field::Other(_) => return Err(invalid_req("Params field 'script' must be a string", id.clone())),
+        Field::Missing => return Err(invalid_req("Params is missing field 'script'", id.clone())),
+    };
+
+    Ok(hook::Definition {
+        prefix,
+        event,
+        script,
+    })
+}
+
+///Extracts owner and lease time of lock operation.
+fn lock_params(params: &RequestPayload<'_>, id: &Option<Id>) -> Result<(String, u64), Response> {
+    let owner = match params.field(OWNER) {
+        Field::Str(owner) if !owner.is_empty() => owner.into_owned(),
+        Field::Str(_) => return Err(invalid_req("Params field 'owner' must not be empty", id.clone())),
+        Field::Other(_) => return Err(invalid_req("Params field 'owner' must be a string", id.clone())),
+        Field::Missing => return Err(invalid_req("Params is missing field 'owner'", id.clone())),
+    };
+
+    let ttl_ms = match params.get(TTL_MS) {
+        Some(ttl_ms) => match serde_json::from_str::<u64>(ttl_ms.get()) {
+            Ok(0) | Err(_) => return Err(invalid_req("Params field 'ttl_ms' must be positive integer", id.clone())),
+            Ok(ttl_ms) => ttl_ms,
+        },
+        None => lock::DEFAULT_TTL_MS,
+    };
+
+    Ok((owner, ttl_ms))
+}
+
+///Applies changes of chaos faults out of params to `current` ones.
+fn chaos_settings(params: &RequestPayload<'_>, mut current: chaos::Settings, id: &Option<Id>) -> Result<chaos::Settings, Response> {
+    if let Some(enabled) = params.get(ENABLED) {
+        current.enabled = match serde_json::from_str::<bool>(enabled.get()) {
+            Ok(enabled) => enabled,
+            Err(_) => return Err(invalid_req("Params field 'enabled' must be boolean", id.clone())),
+        };
+    }
+    if let Some(latency_ms) = params.get(LATENCY_MS) {
+        current.latency_ms = match serde_json::from_str::<u64>(latency_ms.get()) {
+            Ok(latency_ms) => latency_ms,
+            Err(_) => return Err(invalid_req("Params field 'latency_ms' must be unsigned integer", id.clone())),
+        };
+    }
+    if let Some(drop_percent) = params.get(DROP_PERCENT) {
+        current.drop_percent = match serde_json::from_str::<u64>(drop_percent.get()) {
+            Ok(drop_percent) if drop_percent <= 100 => drop_percent,
+            _ => return Err(invalid_req("Params field 'drop_percent' must be integer within 0..=100", id.clone())),
+        };
+    }
+    if let Some(error_percent) = params.get(ERROR_PERCENT) {
+        current.error_percent = match serde_json::from_str::<u64>(error_percent.get()) {
+            Ok(error_percent) if error_percent <= 100 => error_percent,
+            _ => return Err(invalid_req("Params field 'error_percent' must be integer within 0..=100", id.clone())),
+        };
+    }
+
+    Ok(current)
+}
+
+///Extracts range query out of params.
+fn range_query(params: &RequestPayload<'_>, id: &Option<Id>) -> Result<range::Query, Response> {
+    let from = match params.field(FROM) {
+        Field::Str(from) => Some(from.into_owned()),
+        Field::Other(_) => return Err(invalid_req("Params field 'from' must be a string", id.clone())),
+        Field::Missing => None,
+    };
+    let to = match params.field(TO) {
+        Field::Str(to) => Some(to.into_owned()),
+        Field::Other(_) => return Err(invalid_req("Params field 'to' must be a string", id.clone())),
+        Field::Missing => None,
+    };
+    if let (Some(from), Some(to)) = (from.as_ref(), to.as_ref()) {
+        if from > to {
+            return Err(invalid_req("Params field 'from' must not be greater than 'to'", id.clone()));
+        }
+    }
+
+    let descending = match params.field(ORDER) {
+        Field::Str(order) if order == "asc" => false,
+        Field::Str(order) if order == "desc" => true,
+        Field::Missing => false,
+        _ => return Err(invalid_req("Params field 'order' must be 'asc' or 'desc'", id.clone())),
+    };
+    let limit = match params.get(LIMIT) {
+        Some(limit) => match serde_json::from_str::<usize>(limit.get()) {
+            Ok(0) | Err(_) => return Err(invalid_req("Params field 'limit' must be positive integer", id.clone())),
+            Ok(limit) => limit.min(range::MAX_LIMIT),
+        },
+        None => range::MAX_LIMIT,
+    };
+
+    Ok(range::Query {
+        from,
+        to,
+        descending,
+        limit,
+        with_values: params.flag(VALUES),
+    })
+}
+
+#[inline]
+fn upload_param(params: &RequestPayload<'_>, id: &Option<Id>) -> Result<u64, Response> {
+    match params.get(UPLOAD).map(|upload| serde_json::from_str::<u64>(upload.get())) {
+        Some(Ok(upload)) => Ok(upload),
+        Some(Err(_)) => Err(invalid_req("Params field 'upload' must be unsigned integer", id.clone())),
+        None => Err(invalid_req("Params is missing field 'upload'", id.clone())),
+    }
+}
+
+///Extracts queue operation out of params.
+fn queue_op(method: u64, params: &RequestPayload<'_>, id: &Option<Id>) -> Result<queue::QueueOp, Response> {
+    match method {
+        QUEUE_PUSH => data_param(params, id).map(|data| queue::QueueOp::Push(data.into_owned())),
+        QUEUE_POP => match params.get(VISIBILITY_MS) {
+            Some(visibility_ms) => match serde_json::from_str::<u64>(visibility_ms.get()) {
+                Ok(visibility_ms) => Ok(queue::QueueOp::Pop { visibility_ms }),
+                Err(_) => Err(invalid_req("Params field 'visibility_ms' must be unsigned integer", id.clone())),
             },
+            None => Ok(queue::QueueOp::Pop { visibility_ms: 0 }),
+        },
+        QUEUE_ACK => match params.get(SEQ).map(|seq| serde_json::from_str::<u64>(seq.get())) {
+            Some(Ok(seq)) => Ok(queue::QueueOp::Ack(seq)),
+            Some(Err(_)) => Err(invalid_req("Params field 'seq' must be unsigned integer", id.clone())),
+            None => Err(invalid_req("Params is missing field 'seq'", id.clone())),
+        },
+        _ => Ok(queue::QueueOp::Len),
+    }
+}
+
+///Writes JSON value without whitespace and with keys of objects in sorted order.
+fn write_canonical(out: &mut Vec<u8>, value: &serde_json::Value) {
+    match value {
+        serde_json::Value::Object(object) => {
+            let mut fields: Vec<_> = object.iter().collect();
+            fields.sort_unstable_by_key(|(key, _)| *key);
+
+            out.push(b'{');
+            for (idx, (key, value)) in fields.into_iter().enumerate() {
+                if idx > 0 {
+                    out.push(b',');
+                }
+                //Serializing into Vec cannot fail.
+                let _ = serde_json::to_writer(&mut *out, key);
+                out.push(b':');
+                write_canonical(out, value);
+            }
+            out.push(b'}');
+        },
+        serde_json::Value::Array(items) => {
+            out.push(b'[');
+            for (idx, item) in items.iter().enumerate() {
+                if idx > 0 {
+                    out.push(b',');
+                }
+                write_canonical(out, item);
+            }
+            out.push(b']');
+        },
+        value => {
+            let _ = serde_json::to_writer(&mut *out, value);
+        },
+    }
+}
+
+///Identifies write by its method, key and value, so that idempotency key cannot be reused for another write.
+///
+///Key and value are compared as JSON, regardless of whitespace and order of keys.
+fn request_fingerprint(method: &str, params: &RequestPayload<'_>) -> u64 {
+    let mut request = Vec::new();
+    request.extend_from_slice(method.as_bytes());
+
+    for field in [ID, DATA] {
+        request.push(0);
+        if let Some(value) = params.get(field) {
+            match serde_json::from_str::<serde_json::Value>(value.get()) {
+                Ok(value) => write_canonical(&mut request, &value),
+                Err(_) => request.extend_from_slice(value.get().as_bytes()),
+            }
+        }
+    }
+    xxh3_64(&request)
+}
+
+///Writes config within transaction over checksum, config, ephemeral, blobs, blob refs and usage trees.
+fn write_config(db: &db::DbView, trees: [&sled::transaction::TransactionalTree; 6], key: &str, value: &str, hash: u64, lease: lease::Lease) -> sled::transaction::ConflictableTransactionResult<(), quota::Exceeded> {
+    let [checksum, config, ephemeral, blobs, blob_refs, usage] = trees;
+
+    let old_len = blob::stored_len(config, blobs, key)?;
+    quota::on_write(usage, &db.quotas, key, old_len, value.len())?;
+    checksum.insert(key.as_bytes(), &hash.to_be_bytes())?;
+    blob::store(config, blobs, blob_refs, db.blob_threshold, key, value.as_bytes())?;
+    match lease {
+        lease::Lease::Keep => (),
+        lease::Lease::Attach => {
+            ephemeral.insert(key.as_bytes(), &[])?;
+        },
+        lease::Lease::Detach => {
+            ephemeral.remove(key.as_bytes())?;
         },
-        Some(_) => invalid_req("Params field 'data' must be a string or object", id),
-        None => invalid_req("Params is missing field 'data'", id),
     }
+    Ok(())
 }
 
-fn set_config_response(db: db::DbView, key: &str, value: &str, id: Option<Id>) -> Response {
+fn handle_set_config_req(db: &db::DbView, cache: Option<&cache::Cache>, key: &str, value: &str, lease: lease::Lease, cid: Option<&str>, id: Option<Id>) -> Response {
     use sled::Transactional;
     use sled::transaction::TransactionError;
 
     let hash = xxh3_64(value.as_bytes());
 
-    let result: Result<(), TransactionError<bool>> = (&db.checksum, &db.config).transaction(|(checksum, config)| {
-        checksum.insert(key.as_bytes(), &hash.to_be_bytes())?;
-        config.insert(key.as_bytes(), value.as_bytes())?;
-        Ok(())
+    let result: Result<(), TransactionError<quota::Exceeded>> = (&db.checksum, &db.config, &db.ephemeral, &db.blobs, &db.blob_refs, &db.usage).transaction(|(checksum, config, ephemeral, blobs, blob_refs, usage)| {
+        write_config(db, [checksum, config, ephemeral, blobs, blob_refs, usage], key, value, hash, lease)
     });
 
     match result {
-        Ok(_) => checksum_response(hash, id),
+        Ok(_) => {
+            if let Some(cache) = cache {
+                cache.invalidate(key);
+            }
+            checksum_response(hash, id)
+        },
+        Err(TransactionError::Abort(exceeded)) => {
+            warn!(cid: cid, "Rejected write of '{}': {}", key, exceeded);
+            exceeded.response(id)
+        },
+        Err(error) => {
+            error!(cid: cid, "Unable to set config: {}", error);
+            internal_err(int_err::SET_CONFIG_FAIL, id)
+        }
+    }
+}
+
+///Runs checks of write, which happen within db, returning checksum value would have.
+fn handle_validate_config_req(db: &db::DbView, key: &str, value: &str, cid: Option<&str>, id: Option<Id>) -> Response {
+    let old_len = match db.get_config(key) {
+        Ok(old) => old.map(|old| old.len()),
         Err(error) => {
-            error!("Unable to set config: {}", error);
+            error!(cid: cid, "Internal error accessing config tree: {}", error);
             return internal_err(int_err::SET_CONFIG_FAIL, id);
         }
+    };
+
+    match quota::check_write(db, key, old_len, value.len()) {
+        Ok(Ok(())) => checksum_response(xxh3_64(value.as_bytes()), id),
+        Ok(Err(exceeded)) => exceeded.response(id),
+        Err(error) => {
+            error!(cid: cid, "Internal error accessing usage tree: {}", error);
+            internal_err(int_err::SET_CONFIG_FAIL, id)
+        }
     }
 }
 
+fn handle_delete_config_req(db: &db::DbView, cache: Option<&cache::Cache>, key: &str, lease: lease::Lease, cid: Option<&str>, id: Option<Id>) -> Response {
+    use sled::Transactional;
+    use sled::transaction::TransactionError;
 
-#[inline]
-fn handle_checksum_req(db: db::DbView, params: RequestPayload, id: Option<Id>) -> Response {
-    match params.get(ID) {
-        Some(serde_json::Value::String(value)) => match db.checksum.get(&value) {
-            Ok(Some(value)) => {
-                let mut bytes = [0u8; 8];
-                bytes.clone_from_slice(&value);
-                checksum_response(u64::from_be_bytes(bytes), id)
+    let result: Result<bool, TransactionError<bool>> = (&db.checksum, &db.config, &db.ephemeral, &db.blobs, &db.blob_refs, &db.usage).transaction(|(checksum, config, ephemeral, blobs, blob_refs, usage)| {
+        if lease == lease::Lease::Detach {
+            ephemeral.remove(key.as_bytes())?;
+        }
+        if let Some(old_len) = blob::stored_len(config, blobs, key)? {
+            quota::on_remove(usage, key, old_len)?;
+        }
+        checksum.remove(key.as_bytes())?;
+        blob::remove(config, blobs, blob_refs, key)
+    });
+
+    match result {
+        Ok(is_removed) => {
+            if let Some(cache) = cache {
+                cache.invalidate(key);
+            }
+            let mut payload = serde_json::map::Map::with_capacity(1);
+            payload.insert(RESULT.to_owned(), is_removed.into());
+            Response::result(Version::V2, payload.into(), id)
+        },
+        Err(error) => {
+            error!(cid: cid, "Unable to delete config: {}", error);
+            internal_err(int_err::DELETE_CONFIG_FAIL, id)
+        }
+    }
+}
+
+fn handle_checksum_req(db: &db::DbView, key: &str, explicit_missing: bool, cid: Option<&str>, id: Option<Id>) -> Response {
+    match db.checksum.get(key) {
+        Ok(Some(value)) => match <[u8; 8]>::try_from(value.as_ref()) {
+            Ok(bytes) => checksum_response(u64::from_be_bytes(bytes), id),
+            Err(_) => {
+                error!(cid: cid, "Data corruption in checksum of '{}'. Unexpected length {}, expected 8", key, value.len());
+                internal_err(int_err::CHECKSUM_RSP_CORRUPT, id)
             },
-            Ok(None) => checksum_response(0, id),
-            Err(error) => {
-                error!("Internal error accessing checksum tree: {}", error);
-                internal_err(int_err::CHECKSUM_FAIL_GET, id)
+        },
+        Ok(None) if explicit_missing => missing_response(id),
+        Ok(None) => checksum_response(0, id),
+        Err(error) => {
+            error!(cid: cid, "Internal error accessing checksum tree: {}", error);
+            internal_err(int_err::CHECKSUM_FAIL_GET, id)
+        }
+    }
+}
+
+///Resolves references within value of `config` response.
+fn resolve_response(db: &db::DbView, key: &str, mut response: Response, cid: Option<&str>) -> Response {
+    if let Ok(serde_json::Value::Object(result)) = &mut response.payload {
+        if let Some(serde_json::Value::String(value)) = result.get_mut(RESULT) {
+            match template::resolve(db, key, value) {
+                Ok(resolved) => *value = resolved,
+                Err(error) => {
+                    warn!(cid: cid, "Unable to resolve '{}': {}", key, error);
+                    return Response::error(Version::V2, Error::from_code(ErrorCode::ServerError(int_err::TEMPLATE_FAIL)).set_data(error.reason()), response.id);
+                }
+            }
+        }
+    }
+    response
+}
+
+fn handle_config_req(db: &db::DbView, cache: Option<&cache::Cache>, key: &str, explicit_missing: bool, resolve: bool, cid: Option<&str>, id: Option<Id>) -> Response {
+    let generation = cache.as_ref().map(|cache| cache.generation());
+
+    match db.get_config(key) {
+        Ok(Some(value)) => {
+            let has_refs = resolve && template::has_refs(&value);
+            let response = config_response(&value, cid, id);
+            if let (Some(cache), Some(generation), Ok(_)) = (cache, generation, &response.payload) {
+                cache.insert(key, value, generation);
+            }
+            match has_refs {
+                true => resolve_response(db, key, response, cid),
+                false => response,
             }
         },
-        Some(_) => invalid_req("Params field 'id' must be a string", id),
-        None => invalid_req("Params is missing field 'id'", id),
+        Ok(None) if explicit_missing => missing_response(id),
+        Ok(None) => config_response(&[], cid, id),
+        Err(error) => {
+            error!(cid: cid, "Internal error accessing config tree: {}", error);
+            internal_err(int_err::CONFIG_FAIL_GET, id)
+        },
+    }
+}
+
+impl Handler {
+    pub fn new(mut db: db::DbView, options: Options) -> Self {
+        db.blob_threshold = options.blob_threshold;
+        db.quotas = options.quotas.clone();
+        if let Err(error) = quota::init_usage(&db) {
+            error!("Unable to calculate usage of namespaces: {}", error);
+        }
+        let cache = match options.cache_size {
+            0 => None,
+            size => Some(Arc::new(cache::Cache::new(size))),
+        };
+
+        lease::expire_stored(&db);
+        multipart::discard_stored(&db);
+
+        let hooks = Arc::new(hook::Hooks::new(db.hooks.clone(), options.hook_limits.clone()));
+        let worker = worker::Worker::new(options.db_workers, db.clone(), cache.clone());
+        let watchers = Arc::new(watch::Watchers::new(db.clone()));
+        if let Some(config) = options.bridge.clone() {
+            bridge::start(config, db, worker.clone(), options.key_rules.clone());
+        }
+
+        let chaos = options.chaos.map(|settings| {
+            warn!("Chaos mode is enabled, requests are subject to injected faults");
+            Arc::new(chaos::Chaos::new(settings))
+        });
+
+        Self {
+            hooks,
+            worker,
+            chaos,
+            watchers,
+            upstream: options.upstream.clone().map(|addr| Arc::new(upstream::Upstream::new(addr))),
+            uploads: Arc::new(chunk::Uploads::default()),
+            leases: Arc::new(lease::Leases::default()),
+            in_flight: Arc::new(idempotency::InFlight::default()),
+            channels: Arc::new(pubsub::Channels::default()),
+            options,
+            cache,
+        }
     }
 }
 
+///Returns threshold with key and correlation id of request, only needed to report it once it is slow.
+fn slow_request_info(threshold: Option<Duration>, params: Option<&RequestPayload<'_>>) -> Option<(Duration, Option<String>, Option<String>)> {
+    match (threshold, params) {
+        (Some(threshold), Some(params)) => {
+            let key = match params.field(ID) {
+                Field::Str(key) => Some(key.into_owned()),
+                _ => None,
+            };
+            Some((threshold, key, protocol::correlation_id(params).map(ToOwned::to_owned)))
+        },
+        (Some(threshold), None) => Some((threshold, None, None)),
+        (None, _) => None,
+    }
+}
 
-#[inline]
-fn handle_config_req(db: db::DbView, params: RequestPayload, id: Option<Id>) -> Response {
-    match params.get(ID) {
-        Some(serde_json::Value::String(value)) => match db.config.get(&value) {
-            Ok(Some(value)) => config_response(&value, id),
-            Ok(None) => config_response(&[], id),
+impl RequestHandler for Handler {
+    async fn handle_request(&self, session: &session::Session, request: Request<'_>) -> Response {
+        let method = request.method;
+        let slow_info = slow_request_info(self.options.slow_request_threshold, request.params.as_ref());
+        let start = Instant::now();
+
+        let response = self.dispatch(session, request).await;
+
+        let elapsed = start.elapsed();
+        if let Some((threshold, key, cid)) = slow_info {
+            if elapsed >= threshold {
+                warn!(cid: cid.as_deref(), method: method.as_str(), duration: elapsed, "Slow request for key '{}'", key.as_deref().unwrap_or_default());
+            }
+        }
+
+        let method = match &response.payload {
             Err(error) => {
-                error!("Internal error accessing config tree: {}", error);
-                internal_err(int_err::CONFIG_FAIL_GET, id)
+                metrics::METRICS.record_error(error.code.code());
+                match error.code {
+                    ErrorCode::MethodNotFound => metrics::UNKNOWN_METHOD,
+                    _ => method.as_str(),
+                }
             },
-        },
-        Some(_) => invalid_req("Params field 'id' must be a string", id),
-        None => invalid_req("Params is missing field 'id'", id),
+            Ok(_) => method.as_str(),
+        };
+        metrics::METRICS.record_request(method, elapsed);
+
+        response
+    }
+
+    fn close_session(&self, session: &session::Session) {
+        self.channels.close(session.id());
+        self.watchers.close(session.id());
+        self.uploads.close(session.id());
+        self.worker.spawn(worker::Operation::DiscardUploads { session: session.id() });
+        for key in self.leases.expire(session.id()) {
+            info!("Lease of '{}' expired", key);
+            self.worker.spawn(worker::Operation::DeleteConfig { key, lease: lease::Lease::Detach });
+        }
+    }
+
+    #[inline]
+    fn is_lost(&self, method: &str) -> bool {
+        match &self.chaos {
+            Some(chaos) => method != "chaos" && chaos.is_lost(),
+            None => false,
+        }
     }
 }
 
 impl Handler {
-    pub const fn new(db: db::DbView) -> Self {
-        Self {
-            db,
+    #[inline]
+    ///Checks that request carries admin token, returning response of rejection otherwise.
+    fn authorize(&self, params: Option<&RequestPayload<'_>>, id: &Option<Id>) -> Result<(), Response> {
+        authorize(self.options.admin_token.as_deref(), params, id)
+    }
+
+    ///Applies read hooks to value of `config` response.
+    async fn read_hooks(&self, key: &str, mut response: Response, cid: Option<&str>) -> Response {
+        if !self.hooks.matches(hook::Event::Read, key) {
+            return response;
+        }
+
+        if let Ok(serde_json::Value::Object(result)) = &mut response.payload {
+            if let Some(serde_json::Value::String(value)) = result.get_mut(RESULT) {
+                let (hooks, hooked_key, hooked) = (self.hooks.clone(), key.to_owned(), core::mem::take(value));
+                match self.worker.call("read_hooks", cid, move || hooks.on_read(&hooked_key, hooked)).await {
+                    Some(Ok(hooked)) => *value = hooked,
+                    Some(Err(error)) => {
+                        error!(cid: cid, "Read of '{}': {}", key, error);
+                        return internal_err(int_err::HOOK_FAIL, response.id);
+                    },
+                    None => return internal_err(int_err::TASK_SPAWN_FAIL, response.id),
+                }
+            }
+        }
+        response
+    }
+
+    ///Substitutes environment variables within value of `config` response.
+    fn expand_env(&self, mut response: Response) -> Response {
+        if let Ok(serde_json::Value::Object(result)) = &mut response.payload {
+            if let Some(serde_json::Value::String(value)) = result.get_mut(RESULT) {
+                *value = self.options.env.expand(value);
+            }
         }
+        response
     }
 
-    async fn handle_request(&self, request: Request) -> Response {
-        match xxh3_64(request.method.as_str().as_bytes()) {
+    ///Runs write hooks, returning response of rejection if write is not allowed.
+    async fn write_hooks(&self, key: &str, value: String, cid: Option<&str>, id: &Option<Id>) -> Result<hook::Written, Response> {
+        if !self.hooks.matches(hook::Event::Write, key) {
+            return Ok(hook::Written {
+                value,
+                derived: Vec::new(),
+            });
+        }
+
+        let (hooks, hooked_key) = (self.hooks.clone(), key.to_owned());
+        match self.worker.call("write_hooks", cid, move || hooks.on_write(&hooked_key, value)).await {
+            Some(Ok(written)) => Ok(written),
+            Some(Err(error)) => {
+                warn!(cid: cid, "Write of '{}': {}", key, error);
+                Err(hook_rejected(id.clone()))
+            },
+            None => Err(internal_err(int_err::TASK_SPAWN_FAIL, id.clone())),
+        }
+    }
+
+    ///Promotes scheduled configs once they are due, announcing them to subscribers.
+    pub async fn activate_scheduled(self) {
+        let mut interval = tokio::time::interval(schedule::POLL_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+
+            let response = self.worker.run(worker::Operation::Activate { now: unix_time_ms() }, None, None).await;
+            let activated = match response.payload {
+                Ok(serde_json::Value::Object(mut payload)) => match payload.remove(RESULT) {
+                    Some(serde_json::Value::Array(activated)) => activated,
+                    _ => continue,
+                },
+                _ => continue,
+            };
+
+            for item in activated {
+                if let Some(key) = schedule::activated_key(&item) {
+                    info!("Activated scheduled config '{}'", key);
+                    self.leases.detach(key);
+                }
+                self.channels.publish(schedule::CHANNEL, &item.to_string());
+            }
+        }
+    }
+
+    ///Removes expired results of idempotency keys and expired uploads.
+    pub async fn expire_stale(self) {
+        let mut interval = tokio::time::interval(idempotency::SWEEP_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+            self.worker.run(worker::Operation::ExpireIdempotent { now: unix_time_ms() }, None, None).await;
+            self.worker.run(worker::Operation::ExpireUploads { now: unix_time_ms() }, None, None).await;
+        }
+    }
+
+    ///Fetches missing key from upstream, storing it locally.
+    ///
+    ///Returns response of write on success, with checksum of value, or `None` if upstream has no such key.
+    async fn read_through(&self, key: &str, cid: Option<&str>, id: Option<Id>) -> Option<Response> {
+        let upstream = self.upstream.as_ref()?;
+        //Key cannot be stored locally, so act as if upstream has no such key.
+        if self.options.key_rules.validate(key).is_err() {
+            return None;
+        }
+
+        match upstream.fetch(key, cid).await {
+            Ok(Some(value)) => {
+                trace!(cid: cid, "Read '{}' through upstream", key);
+                Some(self.worker.run(worker::Operation::SetConfig { key: key.to_owned(), value, lease: lease::Lease::Keep }, cid, id).await)
+            },
+            Ok(None) => None,
+            Err(error) => {
+                error!(cid: cid, "Unable to read '{}' from upstream {}: {}", key, upstream.addr(), error);
+                Some(internal_err(int_err::UPSTREAM_FAIL, id))
+            },
+        }
+    }
+
+    ///Queues writes, derived by hooks.
+    fn write_derived(&self, derived: Vec<(String, String)>, cid: Option<&str>) {
+        for (key, value) in derived {
+            if let Err(error) = self.options.key_rules.validate(&key) {
+                warn!(cid: cid, "Skipping derived write of '{}': {}", key, error);
+                continue;
+            }
+            let lease = match self.leases.detach(&key) {
+                true => lease::Lease::Detach,
+                false => lease::Lease::Keep,
+            };
+            self.worker.spawn(worker::Operation::SetConfig { key, value, lease });
+        }
+    }
+
+    async fn dispatch(&self, session: &session::Session, request: Request<'_>) -> Response {
+        let method = xxh3_64(request.method.as_str().as_bytes());
+        //Only writes make sense without response.
+        if request.is_notification() && READ_ONLY.contains(&method) {
+            return Response::result(Version::V2, Default::default(), None);
+        }
+
+        if let Some(chaos) = self.chaos.as_ref().filter(|_| method != CHAOS) {
+            if let Some(response) = chaos.inject(request.id.clone()).await {
+                return response;
+            }
+        }
+
+        if method == SET_CONFIG || method == DELETE_CONFIG {
+            match request.params.as_ref().map(|params| (params.field(IDEMPOTENCY_KEY), params)) {
+                Some((Field::Str(key), params)) if !key.is_empty() => {
+                    let key = key.into_owned();
+                    let fingerprint = request_fingerprint(request.method.as_str(), params);
+                    return self.idempotent(session, method, key, fingerprint, request).await;
+                },
+                Some((Field::Missing, _)) | None => (),
+                Some(_) => return invalid_req("Params field 'idempotency_key' must be non-empty string", request.id),
+            }
+        }
+
+        self.route(session, method, request).await
+    }
+
+    ///Performs write at most once per idempotency key, returning its remembered result to retries.
+    async fn idempotent(&self, session: &session::Session, method: u64, key: String, fingerprint: u64, request: Request<'_>) -> Response {
+        let _guard = match self.in_flight.begin(&key) {
+            Some(guard) => guard,
+            None => return idempotency::in_progress(request.id),
+        };
+        let cid = request.params.as_ref().and_then(protocol::correlation_id).map(ToOwned::to_owned);
+        let cid = cid.as_deref();
+
+        let recalled = self.worker.run(worker::Operation::Recall { key: key.clone(), fingerprint }, cid, request.id.clone()).await;
+        if !is_missing(&recalled) {
+            return recalled;
+        }
+
+        let response = self.route(session, method, request).await;
+        if let Ok(serde_json::Value::Object(payload)) = &response.payload {
+            if let Some(result) = payload.get(RESULT) {
+                //Guard is only released once result is remembered, so that retry cannot repeat write.
+                self.worker.run(worker::Operation::Remember { key, fingerprint, result: result.clone() }, cid, None).await;
+            }
+        }
+        response
+    }
+
+    async fn route(&self, session: &session::Session, method: u64, request: Request<'_>) -> Response {
+        match method {
             PING => Response::result(Version::V2, Default::default(), request.id),
+            READY => self.worker.run(worker::Operation::Ready, request.params.as_ref().and_then(protocol::correlation_id), request.id).await,
+            HELLO => hello_response(session, request.params.as_ref(), request.id),
             CHECKSUM => match request.params {
                 Some(params) => {
-                    let id = request.id.clone();
-                    let db = self.db.clone();
-                    match tokio::task::spawn_blocking(move || handle_checksum_req(db, params, id)).await {
-                        Ok(result) => result,
-                        Err(error) => {
-                            error!("Failed to execute handle_checksum_req task: {}", error);
-                            internal_err(int_err::TASK_SPAWN_FAIL, request.id)
-                        }
+                    let key = match key_param(&params, &request.id) {
+                        Ok(key) => key.into_owned(),
+                        Err(response) => return response,
+                    };
+                    let cid = protocol::correlation_id(&params);
+                    let explicit_missing = session.has(session::feature::EXPLICIT_MISSING) || params.flag(EXPLICIT_MISSING);
+                    let response = self.worker.run(worker::Operation::Checksum { key: key.clone(), explicit_missing: explicit_missing || self.upstream.is_some() }, cid, request.id).await;
+                    if self.upstream.is_none() || !is_missing(&response) {
+                        return response;
+                    }
+
+                    match self.read_through(&key, cid, response.id.clone()).await {
+                        Some(response) => response,
+                        None if explicit_missing => response,
+                        None => checksum_response(0, response.id),
                     }
                 },
                 None => invalid_req("Missing params", request.id),
             },
             CONFIG => match request.params {
                 Some(params) => {
-                    let id = request.id.clone();
-                    let db = self.db.clone();
-                    match tokio::task::spawn_blocking(move || handle_config_req(db, params, id)).await {
-                        Ok(result) => result,
-                        Err(error) => {
-                            error!("Failed to execute handle_config_req task: {}", error);
-                            internal_err(int_err::TASK_SPAWN_FAIL, request.id)
+                    let key = match key_param(&params, &request.id) {
+                        Ok(key) => key,
+                        Err(response) => return response,
+                    };
+                    let cid = protocol::correlation_id(&params);
+                    let resolve = !params.flag(RAW);
+                    let expand_env = params.flag(EXPAND_ENV);
+                    if let Some(value) = self.cache.as_ref().and_then(|cache| cache.get(&key)) {
+                        //Referenced keys are not cached, so they need to be read from db.
+                        if !resolve || !template::has_refs(&value) {
+                            let response = self.read_hooks(&key, config_response(&value, cid, request.id), cid).await;
+                            return match expand_env {
+                                true => self.expand_env(response),
+                                false => response,
+                            };
                         }
                     }
+
+                    let explicit_missing = session.has(session::feature::EXPLICIT_MISSING) || params.flag(EXPLICIT_MISSING);
+                    let operation = worker::Operation::Config {
+                        key: key.to_string(),
+                        explicit_missing: explicit_missing || self.upstream.is_some(),
+                        resolve,
+                    };
+                    let mut response = self.worker.run(operation, cid, request.id).await;
+                    if self.upstream.is_some() && is_missing(&response) {
+                        response = match self.read_through(&key, cid, response.id.clone()).await {
+                            Some(stored) if stored.payload.is_ok() => {
+                                let operation = worker::Operation::Config { key: key.to_string(), explicit_missing, resolve };
+                                self.worker.run(operation, cid, stored.id).await
+                            },
+                            Some(error) => error,
+                            None if explicit_missing => response,
+                            None => config_response(&[], cid, response.id),
+                        };
+                    }
+                    let response = self.read_hooks(&key, response, cid).await;
+                    match expand_env {
+                        true => self.expand_env(response),
+                        false => response,
+                    }
                 },
                 None => invalid_req("Missing params", request.id),
             },
             SET_CONFIG => match request.params {
                 Some(params) => {
-                    let id = request.id.clone();
-                    let db = self.db.clone();
-                    match tokio::task::spawn_blocking(move || handle_set_config_req(db, params, id)).await {
+                    let key = match key_param(&params, &request.id) {
+                        Ok(key) => key.into_owned(),
+                        Err(response) => return response,
+                    };
+                    if let Err(error) = self.options.key_rules.validate(&key) {
+                        return invalid_key(error, request.id);
+                    }
+                    let activate_at = match params.get(ACTIVATE_AT).map(|activate_at| serde_json::from_str::<u64>(activate_at.get())) {
+                        Some(Ok(activate_at)) => Some(activate_at),
+                        Some(Err(_)) => return invalid_req("Params field 'activate_at' must be unsigned integer", request.id),
+                        None => None,
+                    };
+                    let value = match data_param(&params, &request.id) {
+                        Ok(value) => value,
+                        Err(response) => return response,
+                    };
+                    let value = match chunk::upload_params(&params) {
+                        Ok(Some((seq, last))) => match self.uploads.push(session.id(), &key, seq, last, &value) {
+                            Ok(Some(value)) => value,
+                            Ok(None) => {
+                                let mut payload = serde_json::map::Map::with_capacity(1);
+                                payload.insert(RESULT.to_owned(), seq.into());
+                                return Response::result(Version::V2, payload.into(), request.id);
+                            },
+                            Err(error) => return invalid_req(error, request.id),
+                        },
+                        Ok(None) => value.into_owned(),
+                        Err(error) => return invalid_req(error, request.id),
+                    };
+                    let cid = protocol::correlation_id(&params);
+                    let hook::Written { value, derived } = match self.write_hooks(&key, value, cid, &request.id).await {
+                        Ok(written) => written,
+                        Err(response) => return response,
+                    };
+                    if params.flag(VALIDATE) {
+                        return self.worker.run(worker::Operation::ValidateConfig { key, value }, cid, request.id).await;
+                    }
+                    //Value, which is already due, is simply written.
+                    if let Some(activate_at) = activate_at.filter(|activate_at| *activate_at > unix_time_ms()) {
+                        return self.worker.run(worker::Operation::Schedule { key, value, activate_at }, cid, request.id).await;
+                    }
+                    let lease = match self.leases.detach(&key) {
+                        true => lease::Lease::Detach,
+                        false => lease::Lease::Keep,
+                    };
+                    let response = self.worker.run(worker::Operation::SetConfig { key, value, lease }, cid, request.id).await;
+                    if response.payload.is_ok() {
+                        self.write_derived(derived, cid);
+                    }
+                    response
+                },
+                None => invalid_req("Missing params", request.id),
+            },
+            SET_EPHEMERAL => match request.params {
+                Some(params) => {
+                    let key = match key_param(&params, &request.id) {
+                        Ok(key) => key.into_owned(),
+                        Err(response) => return response,
+                    };
+                    if let Err(error) = self.options.key_rules.validate(&key) {
+                        return invalid_key(error, request.id);
+                    }
+                    let value = match data_param(&params, &request.id) {
+                        Ok(value) => value.into_owned(),
+                        Err(response) => return response,
+                    };
+                    let cid = protocol::correlation_id(&params);
+                    let hook::Written { value, derived } = match self.write_hooks(&key, value, cid, &request.id).await {
+                        Ok(written) => written,
+                        Err(response) => return response,
+                    };
+                    if params.flag(VALIDATE) {
+                        return self.worker.run(worker::Operation::ValidateConfig { key, value }, cid, request.id).await;
+                    }
+
+                    let operation = worker::Operation::SetConfig { key: key.clone(), value, lease: lease::Lease::Attach };
+                    let response = self.worker.run(operation, cid, request.id).await;
+                    if response.payload.is_ok() {
+                        self.leases.attach(&key, session.id());
+                        self.write_derived(derived, cid);
+                    }
+                    response
+                },
+                None => invalid_req("Missing params", request.id),
+            },
+            DELETE_CONFIG => match request.params {
+                Some(params) => {
+                    let key = match key_param(&params, &request.id) {
+                        Ok(key) => key.into_owned(),
+                        Err(response) => return response,
+                    };
+                    if let Err(error) = self.options.key_rules.validate_removal(&key) {
+                        return invalid_key(error, request.id);
+                    }
+                    let lease = match self.leases.detach(&key) {
+                        true => lease::Lease::Detach,
+                        false => lease::Lease::Keep,
+                    };
+                    self.worker.run(worker::Operation::DeleteConfig { key, lease }, protocol::correlation_id(&params), request.id).await
+                },
+                None => invalid_req("Missing params", request.id),
+            },
+            LOCK_ACQUIRE | LOCK_RELEASE | LOCK_RENEW => match request.params {
+                Some(params) => {
+                    let name = match key_param(&params, &request.id) {
+                        Ok(name) => name.into_owned(),
+                        Err(response) => return response,
+                    };
+                    if method == LOCK_ACQUIRE {
+                        if let Err(error) = self.options.key_rules.validate(&name) {
+                            return invalid_key(error, request.id);
+                        }
+                    }
+                    let (owner, ttl_ms) = match lock_params(&params, &request.id) {
                         Ok(result) => result,
-                        Err(error) => {
-                            error!("Failed to execute handle_set_config_req task: {}", error);
-                            internal_err(int_err::TASK_SPAWN_FAIL, request.id)
+                        Err(response) => return response,
+                    };
+                    let op = match method {
+                        LOCK_ACQUIRE => lock::LockOp::Acquire,
+                        LOCK_RELEASE => lock::LockOp::Release,
+                        _ => lock::LockOp::Renew,
+                    };
+                    self.worker.run(worker::Operation::Lock { op, name, owner, ttl_ms }, protocol::correlation_id(&params), request.id).await
+                },
+                None => invalid_req("Missing params", request.id),
+            },
+            QUEUE_PUSH | QUEUE_POP | QUEUE_ACK | QUEUE_LEN => match request.params {
+                Some(params) => {
+                    let name = match key_param(&params, &request.id) {
+                        Ok(name) => name.into_owned(),
+                        Err(response) => return response,
+                    };
+                    if method == QUEUE_PUSH {
+                        if let Err(error) = self.options.key_rules.validate(&name) {
+                            return invalid_key(error, request.id);
+                        }
+                    }
+                    let op = match queue_op(method, &params, &request.id) {
+                        Ok(op) => op,
+                        Err(response) => return response,
+                    };
+                    self.worker.run(worker::Operation::Queue { op, name }, protocol::correlation_id(&params), request.id).await
+                },
+                None => invalid_req("Missing params", request.id),
+            },
+            PUBLISH => match request.params {
+                Some(params) => {
+                    let channel = match key_param(&params, &request.id) {
+                        Ok(channel) => channel,
+                        Err(response) => return response,
+                    };
+                    if let Err(error) = self.options.key_rules.validate(&channel) {
+                        return invalid_key(error, request.id);
+                    }
+                    let data = match data_param(&params, &request.id) {
+                        Ok(data) => data,
+                        Err(response) => return response,
+                    };
+
+                    let mut payload = serde_json::map::Map::with_capacity(1);
+                    payload.insert(RESULT.to_owned(), self.channels.publish(&channel, &data).into());
+                    Response::result(Version::V2, payload.into(), request.id)
+                },
+                None => invalid_req("Missing params", request.id),
+            },
+            SUBSCRIBE_CHANNEL | UNSUBSCRIBE_CHANNEL => match request.params {
+                Some(params) => {
+                    let channel = match key_param(&params, &request.id) {
+                        Ok(channel) => channel,
+                        Err(response) => return response,
+                    };
+                    if method == SUBSCRIBE_CHANNEL {
+                        if let Err(error) = self.options.key_rules.validate(&channel) {
+                            return invalid_key(error, request.id);
                         }
                     }
+                    let result = match method {
+                        SUBSCRIBE_CHANNEL => self.channels.subscribe(&channel, session),
+                        _ => self.channels.unsubscribe(&channel, session.id()),
+                    };
+                    bool_response(result, request.id)
+                },
+                None => invalid_req("Missing params", request.id),
+            },
+            WATCH | UNWATCH => {
+                let params = request.params.unwrap_or_default();
+                let prefix = match params.field(PREFIX) {
+                    Field::Str(prefix) => prefix,
+                    Field::Other(_) => return invalid_req("Params field 'prefix' must be a string", request.id),
+                    Field::Missing => Cow::Borrowed(""),
+                };
+                let result = match method {
+                    WATCH => self.watchers.watch(&prefix, session),
+                    _ => self.watchers.unwatch(&prefix, session.id()),
+                };
+                bool_response(result, request.id)
+            },
+            RESOLVE => match request.params {
+                Some(params) => {
+                    let keys = match keys_param(&params, &request.id) {
+                        Ok(keys) => keys,
+                        Err(response) => return response,
+                    };
+                    self.worker.run(worker::Operation::Resolve { keys, resolve: !params.flag(RAW) }, protocol::correlation_id(&params), request.id).await
+                },
+                None => invalid_req("Missing params", request.id),
+            },
+            MATCH_KEYS => match request.params {
+                Some(params) => {
+                    let pattern = match params.field(PATTERN) {
+                        Field::Str(pattern) => pattern.into_owned(),
+                        Field::Other(_) => return invalid_req("Params field 'pattern' must be a string", request.id),
+                        Field::Missing => return invalid_req("Params is missing field 'pattern'", request.id),
+                    };
+                    self.worker.run(worker::Operation::MatchKeys { pattern, with_values: params.flag(VALUES) }, protocol::correlation_id(&params), request.id).await
+                },
+                None => invalid_req("Missing params", request.id),
+            },
+            UPLOAD_BEGIN => match request.params {
+                Some(params) => {
+                    let key = match key_param(&params, &request.id) {
+                        Ok(key) => key.into_owned(),
+                        Err(response) => return response,
+                    };
+                    if let Err(error) = self.options.key_rules.validate(&key) {
+                        return invalid_key(error, request.id);
+                    }
+                    self.worker.run(worker::Operation::UploadBegin { session: session.id(), key }, protocol::correlation_id(&params), request.id).await
                 },
                 None => invalid_req("Missing params", request.id),
             },
+            UPLOAD_CHUNK => match request.params {
+                Some(params) => {
+                    let upload = match upload_param(&params, &request.id) {
+                        Ok(upload) => upload,
+                        Err(response) => return response,
+                    };
+                    let seq = match params.get(SEQ).map(|seq| serde_json::from_str::<u64>(seq.get())) {
+                        Some(Ok(seq)) => seq,
+                        Some(Err(_)) => return invalid_req("Params field 'seq' must be unsigned integer", request.id),
+                        None => return invalid_req("Params is missing field 'seq'", request.id),
+                    };
+                    //Chunk of object would not join with others into valid json.
+                    let data = match params.field(DATA) {
+                        Field::Str(data) => data.into_owned(),
+                        Field::Other(_) => return invalid_req("Params field 'data' must be a string", request.id),
+                        Field::Missing => return invalid_req("Params is missing field 'data'", request.id),
+                    };
+                    self.worker.run(worker::Operation::UploadChunk { session: session.id(), upload, seq, data }, protocol::correlation_id(&params), request.id).await
+                },
+                None => invalid_req("Missing params", request.id),
+            },
+            UPLOAD_COMMIT => match request.params {
+                Some(params) => {
+                    let upload = match upload_param(&params, &request.id) {
+                        Ok(upload) => upload,
+                        Err(response) => return response,
+                    };
+                    let cid = protocol::correlation_id(&params);
+                    let assembled = self.worker.run(worker::Operation::UploadAssemble { session: session.id(), upload }, cid, request.id).await;
+                    let (mut assembled, id) = match multipart::take_assembled(upload, assembled) {
+                        Ok(assembled) => assembled,
+                        Err(response) => return response,
+                    };
+                    let value = core::mem::take(&mut assembled.value);
+                    let derived = match self.write_hooks(&assembled.key, value, cid, &id).await {
+                        Ok(hook::Written { value, derived }) => {
+                            assembled.value = value;
+                            derived
+                        },
+                        Err(response) => return response,
+                    };
+                    let lease = match self.leases.detach(&assembled.key) {
+                        true => lease::Lease::Detach,
+                        false => lease::Lease::Keep,
+                    };
+                    let response = self.worker.run(worker::Operation::UploadCommit { assembled, lease }, cid, id).await;
+                    if response.payload.is_ok() {
+                        self.write_derived(derived, cid);
+                    }
+                    response
+                },
+                None => invalid_req("Missing params", request.id),
+            },
+            RANGE => {
+                let params = request.params.unwrap_or_default();
+                let query = match range_query(&params, &request.id) {
+                    Ok(query) => query,
+                    Err(response) => return response,
+                };
+                self.worker.run(worker::Operation::Range { query }, protocol::correlation_id(&params), request.id).await
+            },
+            READ_SNAPSHOT => match request.params {
+                Some(params) => {
+                    let keys = match keys_param(&params, &request.id) {
+                        Ok(keys) => keys,
+                        Err(response) => return response,
+                    };
+                    self.worker.run(worker::Operation::ReadSnapshot { keys }, protocol::correlation_id(&params), request.id).await
+                },
+                None => invalid_req("Missing params", request.id),
+            },
+            DIFF => match request.params {
+                Some(params) => {
+                    let checksums = match params.get(CHECKSUMS).map(|checksums| serde_json::from_str::<BTreeMap<String, u64>>(checksums.get())) {
+                        Some(Ok(checksums)) if checksums.len() > sync::MAX_KEYS => return invalid_req("Params field 'checksums' has too many keys", request.id),
+                        Some(Ok(checksums)) => checksums,
+                        Some(Err(_)) => return invalid_req("Params field 'checksums' must be object of unsigned integers", request.id),
+                        None => return invalid_req("Params is missing field 'checksums'", request.id),
+                    };
+                    let prefix = match params.field(PREFIX) {
+                        Field::Str(prefix) => Some(prefix.into_owned()),
+                        Field::Other(_) => return invalid_req("Params field 'prefix' must be a string", request.id),
+                        Field::Missing => None,
+                    };
+                    self.worker.run(worker::Operation::Diff { checksums, prefix }, protocol::correlation_id(&params), request.id).await
+                },
+                None => invalid_req("Missing params", request.id),
+            },
+            TREE_CHECKSUM => {
+                let params = request.params.unwrap_or_default();
+                let prefix = match params.field(PREFIX) {
+                    Field::Str(prefix) => prefix.into_owned(),
+                    Field::Other(_) => return invalid_req("Params field 'prefix' must be a string", request.id),
+                    Field::Missing => String::new(),
+                };
+                self.worker.run(worker::Operation::TreeChecksum { prefix }, protocol::correlation_id(&params), request.id).await
+            },
+            PUT_BLOB => match request.params {
+                Some(params) => {
+                    //Pinned blobs are never released, so only admin may store them.
+                    if let Err(response) = self.authorize(Some(&params), &request.id) {
+                        return response;
+                    }
+                    let value = match data_param(&params, &request.id) {
+                        Ok(value) => value.into_owned(),
+                        Err(response) => return response,
+                    };
+                    self.worker.run(worker::Operation::PutBlob { value }, protocol::correlation_id(&params), request.id).await
+                },
+                None => invalid_req("Missing params", request.id),
+            },
+            GET_BLOB_BY_HASH => match request.params {
+                Some(params) => {
+                    let hash = match key_param(&params, &request.id) {
+                        Ok(hash) => match blob::from_hex(&hash) {
+                            Some(hash) => hash,
+                            None => return invalid_req("Params field 'id' must be hash of blob", request.id),
+                        },
+                        Err(response) => return response,
+                    };
+                    let explicit_missing = session.has(session::feature::EXPLICIT_MISSING) || params.flag(EXPLICIT_MISSING);
+                    self.worker.run(worker::Operation::GetBlob { hash, explicit_missing }, protocol::correlation_id(&params), request.id).await
+                },
+                None => invalid_req("Missing params", request.id),
+            },
+            DIFF_CONFIG => match request.params {
+                Some(params) => {
+                    let key = match key_param(&params, &request.id) {
+                        Ok(key) => key.into_owned(),
+                        Err(response) => return response,
+                    };
+                    //Data is compared in the same form as it would be written.
+                    let target = match (params.get(DATA).is_some(), params.field(OTHER)) {
+                        (true, _) => match data_param(&params, &request.id) {
+                            Ok(data) => compare::Target::Data(data.into_owned()),
+                            Err(response) => return response,
+                        },
+                        (false, Field::Str(other)) => compare::Target::Key(other.into_owned()),
+                        (false, Field::Other(_)) => return invalid_req("Params field 'other' must be a string", request.id),
+                        (false, Field::Missing) => return invalid_req("Params is missing field 'data' or 'other'", request.id),
+                    };
+                    self.worker.run(worker::Operation::DiffConfig { key, target }, protocol::correlation_id(&params), request.id).await
+                },
+                None => invalid_req("Missing params", request.id),
+            },
+            USAGE => {
+                let params = request.params.unwrap_or_default();
+                let namespace = match params.field(NAMESPACE) {
+                    Field::Str(namespace) => Some(namespace.into_owned()),
+                    Field::Other(_) => return invalid_req("Params field 'namespace' must be a string", request.id),
+                    Field::Missing => None,
+                };
+                self.worker.run(worker::Operation::Usage { namespace }, protocol::correlation_id(&params), request.id).await
+            },
+            SET_HOOK => match request.params {
+                Some(params) => {
+                    if let Err(response) = self.authorize(Some(&params), &request.id) {
+                        return response;
+                    }
+                    let name = match key_param(&params, &request.id) {
+                        Ok(name) => name.into_owned(),
+                        Err(response) => return response,
+                    };
+                    let definition = match hook_definition(&params, &request.id) {
+                        Ok(definition) => definition,
+                        Err(response) => return response,
+                    };
+                    let cid = protocol::correlation_id(&params);
+                    let (hooks, hook_name) = (self.hooks.clone(), name.clone());
+                    match self.worker.call("set_hook", cid, move || hooks.set(&hook_name, definition)).await {
+                        Some(Ok(())) => bool_response(true, request.id),
+                        Some(Err(hook::SetError::Compile(error))) => {
+                            warn!(cid: cid, "Unable to compile hook '{}': {}", name, error);
+                            invalid_req("Params field 'script' must be valid rhai script", request.id)
+                        },
+                        Some(Err(hook::SetError::Db(error))) => {
+                            error!(cid: cid, "Unable to store hook '{}': {}", name, error);
+                            internal_err(int_err::HOOK_STORE_FAIL, request.id)
+                        },
+                        None => internal_err(int_err::TASK_SPAWN_FAIL, request.id),
+                    }
+                },
+                None => invalid_req("Missing params", request.id),
+            },
+            DELETE_HOOK => match request.params {
+                Some(params) => {
+                    if let Err(response) = self.authorize(Some(&params), &request.id) {
+                        return response;
+                    }
+                    let name = match key_param(&params, &request.id) {
+                        Ok(name) => name.into_owned(),
+                        Err(response) => return response,
+                    };
+                    let cid = protocol::correlation_id(&params);
+                    let (hooks, hook_name) = (self.hooks.clone(), name.clone());
+                    match self.worker.call("delete_hook", cid, move || hooks.remove(&hook_name)).await {
+                        Some(Ok(is_removed)) => bool_response(is_removed, request.id),
+                        Some(Err(error)) => {
+                            error!(cid: cid, "Unable to delete hook '{}': {}", name, error);
+                            internal_err(int_err::HOOK_STORE_FAIL, request.id)
+                        },
+                        None => internal_err(int_err::TASK_SPAWN_FAIL, request.id),
+                    }
+                },
+                None => invalid_req("Missing params", request.id),
+            },
+            CHAOS => match &self.chaos {
+                Some(chaos) => {
+                    let params = request.params.unwrap_or_default();
+                    if let Err(response) = self.authorize(Some(&params), &request.id) {
+                        return response;
+                    }
+                    let settings = match chaos_settings(&params, chaos.get(), &request.id) {
+                        Ok(settings) => settings,
+                        Err(response) => return response,
+                    };
+                    chaos.set(settings);
+                    info!(cid: protocol::correlation_id(&params), "Chaos mode {}: latency_ms={} drop_percent={} error_percent={}", if settings.enabled { "enabled" } else { "disabled" }, settings.latency_ms, settings.drop_percent, settings.error_percent);
+
+                    let mut payload = serde_json::map::Map::with_capacity(1);
+                    payload.insert(RESULT.to_owned(), settings.to_json());
+                    Response::result(Version::V2, payload.into(), request.id)
+                },
+                None => invalid_req("Chaos mode is disabled", request.id),
+            },
+            HOOKS => {
+                let mut payload = serde_json::map::Map::with_capacity(1);
+                payload.insert(RESULT.to_owned(), self.hooks.list());
+                Response::result(Version::V2, payload.into(), request.id)
+            },
             _ => Response::error(Version::V2, Error::from_code(ErrorCode::MethodNotFound), request.id),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(params: &str) -> RequestPayload<'_> {
+        serde_json::from_str(params).expect("params")
+    }
+
+    fn handler() -> Handler {
+        Handler::new(db::Db::temporary().expect("open db").view(), Options::default())
+    }
+
+    ///Performs request within client's session, returning response as JSON.
+    async fn call_in(handler: &Handler, session: &session::Session, request: &str) -> serde_json::Value {
+        let request = serde_json::from_str::<Request>(request).expect("request");
+        serde_json::to_value(handler.handle_request(session, request).await).expect("response")
+    }
+
+    ///Performs request within its own session.
+    async fn call(handler: &Handler, request: &str) -> serde_json::Value {
+        let (session, _outbox) = session::Session::new();
+        call_in(handler, &session, request).await
+    }
+
+    #[test]
+    fn should_fingerprint_request_regardless_of_formatting() {
+        let fingerprint = request_fingerprint("set_config", &params(r#"{"id":"key","data":{"a":1,"b":[1,{"c":2,"d":3}]}}"#));
+
+        assert_eq!(request_fingerprint("set_config", &params(r#"{ "data": { "b": [1, {"d": 3, "c": 2}], "a": 1 }, "id": "key" }"#)), fingerprint);
+        assert_ne!(request_fingerprint("set_config", &params(r#"{"id":"key","data":{"a":2,"b":[1,{"c":2,"d":3}]}}"#)), fingerprint);
+        assert_ne!(request_fingerprint("set_config", &params(r#"{"id":"key","data":{"a":1,"b":[{"c":2,"d":3},1]}}"#)), fingerprint);
+        assert_ne!(request_fingerprint("delete_config", &params(r#"{"id":"key","data":{"a":1,"b":[1,{"c":2,"d":3}]}}"#)), fingerprint);
+        assert_ne!(request_fingerprint("set_config", &params(r#"{"id":"key"}"#)), request_fingerprint("set_config", &params(r#"{"id":"key","data":""}"#)));
+    }
+
+    #[test]
+    fn should_compare_tokens() {
+        assert!(is_token_eq("secret", "secret"));
+        assert!(!is_token_eq("secret", "secreT"));
+        assert!(!is_token_eq("secret", "secret2"));
+        assert!(!is_token_eq("", "secret"));
+    }
+
+    #[test]
+    fn should_generate_unique_correlation_ids() {
+        let first = generate_correlation_id();
+        let second = generate_correlation_id();
+
+        assert_eq!(first.len(), 16);
+        assert!(first.bytes().all(|byte| byte.is_ascii_hexdigit()));
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn should_collect_slow_request_info_only_with_threshold() {
+        let threshold = Duration::from_millis(10);
+        let payload = params(r#"{"id":"key","correlation_id":"trace-1"}"#);
+
+        assert_eq!(slow_request_info(Some(threshold), Some(&payload)), Some((threshold, Some("key".to_owned()), Some("trace-1".to_owned()))));
+        assert_eq!(slow_request_info(Some(threshold), Some(&params(r#"{"id":1}"#))), Some((threshold, None, None)));
+        assert_eq!(slow_request_info(Some(threshold), None), Some((threshold, None, None)));
+        assert_eq!(slow_request_info(None, Some(&payload)), None);
+    }
+
+    #[tokio::test]
+    async fn should_return_null_for_missing_key_only_if_requested() {
+        let handler = handler();
+
+        let config = call(&handler, r#"{"jsonrpc":"2.0","method":"config","params":{"id":"missing"},"id":1}"#).await;
+        assert_eq!(config["result"]["result"], "");
+        let checksum = call(&handler, r#"{"jsonrpc":"2.0","method":"cheksum","params":{"id":"missing"},"id":1}"#).await;
+        assert_eq!(checksum["result"]["result"], 0);
+
+        let config = call(&handler, r#"{"jsonrpc":"2.0","method":"config","params":{"id":"missing","explicit_missing":true},"id":1}"#).await;
+        assert_eq!(config["result"], serde_json::json!({"result": null}));
+        let checksum = call(&handler, r#"{"jsonrpc":"2.0","method":"cheksum","params":{"id":"missing","explicit_missing":true},"id":1}"#).await;
+        assert_eq!(checksum["result"], serde_json::json!({"result": null}));
+
+        call(&handler, r#"{"jsonrpc":"2.0","method":"set_config","params":{"id":"empty","data":""},"id":1}"#).await;
+        let config = call(&handler, r#"{"jsonrpc":"2.0","method":"config","params":{"id":"empty","explicit_missing":true},"id":1}"#).await;
+        assert_eq!(config["result"]["result"], "");
+    }
+
+    #[test]
+    fn should_report_corrupted_checksum() {
+        let db = db::Db::temporary().expect("open db").view();
+        db.checksum.insert("short", &[1, 2, 3][..]).expect("insert");
+        db.checksum.insert("valid", &42u64.to_be_bytes()[..]).expect("insert");
+
+        let response = handle_checksum_req(&db, "short", false, None, Some(Id::Num(1)));
+        assert_eq!(response.payload.expect_err("corrupted").code.code(), int_err::CHECKSUM_RSP_CORRUPT);
+        assert_eq!(response.id, Some(Id::Num(1)));
+
+        let response = handle_checksum_req(&db, "valid", false, None, None);
+        assert_eq!(response.payload.expect("checksum")[RESULT], 42);
+    }
+
+    #[tokio::test]
+    async fn should_apply_features_negotiated_by_hello() {
+        let handler = handler();
+        let (session, _outbox) = session::Session::new();
+
+        let hello = call_in(&handler, &session, r#"{"jsonrpc":"2.0","method":"hello","params":{"version":1,"features":["explicit_missing","unknown"]},"id":1}"#).await;
+        assert_eq!(hello["result"]["result"], serde_json::json!({
+            "version": session::PROTOCOL_VERSION,
+            "framing": "eot",
+            "encoding": "json",
+            "features": ["explicit_missing"],
+            "supported": ["explicit_missing", "chunked", "zstd"],
+        }));
+
+        let config = call_in(&handler, &session, r#"{"jsonrpc":"2.0","method":"config","params":{"id":"missing"},"id":2}"#).await;
+        assert_eq!(config["result"], serde_json::json!({"result": null}));
+
+        let hello = call_in(&handler, &session, r#"{"jsonrpc":"2.0","method":"hello","params":{"features":"zstd"},"id":3}"#).await;
+        assert_eq!(hello["error"]["code"], -32600);
+        let hello = call_in(&handler, &session, r#"{"jsonrpc":"2.0","method":"hello","params":{"version":-1},"id":4}"#).await;
+        assert_eq!(hello["error"]["code"], -32600);
+    }
+
+    #[tokio::test]
+    async fn should_delete_ephemeral_key_once_session_is_closed() {
+        let handler = handler();
+        let (session, _outbox) = session::Session::new();
+
+        call_in(&handler, &session, r#"{"jsonrpc":"2.0","method":"set_ephemeral","params":{"id":"ephemeral","data":"value"},"id":1}"#).await;
+        call_in(&handler, &session, r#"{"jsonrpc":"2.0","method":"set_ephemeral","params":{"id":"durable","data":"value"},"id":2}"#).await;
+        call_in(&handler, &session, r#"{"jsonrpc":"2.0","method":"set_config","params":{"id":"durable","data":"value"},"id":3}"#).await;
+        handler.close_session(&session);
+
+        let config = call(&handler, r#"{"jsonrpc":"2.0","method":"config","params":{"id":"ephemeral","explicit_missing":true},"id":4}"#).await;
+        assert_eq!(config["result"]["result"], serde_json::Value::Null);
+        let config = call(&handler, r#"{"jsonrpc":"2.0","method":"config","params":{"id":"durable"},"id":5}"#).await;
+        assert_eq!(config["result"]["result"], "value");
+    }
+
+    #[tokio::test]
+    async fn should_validate_write_without_persisting_it() {
+        let options = Options {
+            quotas: "app=16:1".parse().expect("quotas"),
+            ..Options::default()
+        };
+        let handler = Handler::new(db::Db::temporary().expect("open db").view(), options);
+
+        let validated = call(&handler, r#"{"jsonrpc":"2.0","method":"set_config","params":{"id":"app/a","data":"value","validate":true},"id":1}"#).await;
+        assert_eq!(validated["result"]["result"], xxh3_64(b"value"));
+        let config = call(&handler, r#"{"jsonrpc":"2.0","method":"config","params":{"id":"app/a","explicit_missing":true},"id":2}"#).await;
+        assert_eq!(config["result"]["result"], serde_json::Value::Null);
+
+        let validated = call(&handler, r#"{"jsonrpc":"2.0","method":"set_ephemeral","params":{"id":"app/a","data":"value over quota","validate":true},"id":3}"#).await;
+        assert_eq!(validated["error"]["code"], int_err::QUOTA_EXCEEDED);
+        let validated = call(&handler, r#"{"jsonrpc":"2.0","method":"set_config","params":{"id":"bad key","data":"value","validate":true},"id":4}"#).await;
+        assert_eq!(validated["error"]["code"], int_err::INVALID_KEY);
+
+        let written = call(&handler, r#"{"jsonrpc":"2.0","method":"set_config","params":{"id":"app/a","data":"value"},"id":5}"#).await;
+        assert_eq!(written["result"]["result"], xxh3_64(b"value"));
+    }
+
+    #[tokio::test]
+    async fn should_diff_config_against_object_data() {
+        let handler = handler();
+        call(&handler, r#"{"jsonrpc":"2.0","method":"set_config","params":{"id":"key","data":{"a":1}},"id":1}"#).await;
+
+        let diff = call(&handler, r#"{"jsonrpc":"2.0","method":"diff_config","params":{"id":"key","data":{"a":2}},"id":2}"#).await;
+        assert_eq!(diff["result"]["result"], serde_json::json!([{"op": "replace", "path": "/a", "old": 1, "value": 2}]));
+        let diff = call(&handler, r#"{"jsonrpc":"2.0","method":"diff_config","params":{"id":"key","data":"{\"a\":1}"},"id":3}"#).await;
+        assert_eq!(diff["result"]["result"], serde_json::json!([]));
+    }
+
+    #[tokio::test]
+    async fn should_expand_env_only_on_request() {
+        let options = Options {
+            env: env::Environment::from_whitelist("PATH"),
+            ..Options::default()
+        };
+        let handler = Handler::new(db::Db::temporary().expect("open db").view(), options);
+        call(&handler, r#"{"jsonrpc":"2.0","method":"set_config","params":{"id":"key","data":"${PATH}"},"id":1}"#).await;
+
+        let config = call(&handler, r#"{"jsonrpc":"2.0","method":"config","params":{"id":"key","expand_env":true},"id":2}"#).await;
+        assert_eq!(config["result"]["result"], std::env::var("PATH").expect("PATH"));
+        let config = call(&handler, r#"{"jsonrpc":"2.0","method":"config","params":{"id":"key"},"id":3}"#).await;
+        assert_eq!(config["result"]["result"], "${PATH}");
+    }
+
+    #[tokio::test]
+    async fn should_change_chaos_faults_only_by_admin() {
+        let handler = handler();
+        let chaos = call(&handler, r#"{"jsonrpc":"2.0","method":"chaos","params":{"enabled":true},"id":1}"#).await;
+        assert_eq!(chaos["error"]["data"], "Chaos mode is disabled");
+
+        let options = Options {
+            admin_token: Some("secret".to_owned()),
+            chaos: Some(chaos::Settings::default()),
+            ..Options::default()
+        };
+        let handler = Handler::new(db::Db::temporary().expect("open db").view(), options);
+        let chaos = call(&handler, r#"{"jsonrpc":"2.0","method":"chaos","params":{"enabled":true},"id":2}"#).await;
+        assert_eq!(chaos["error"]["code"], int_err::UNAUTHORIZED);
+        let chaos = call(&handler, r#"{"jsonrpc":"2.0","method":"chaos","params":{"admin_token":"secret","error_percent":101},"id":3}"#).await;
+        assert_eq!(chaos["error"]["data"], "Params field 'error_percent' must be integer within 0..=100");
+
+        let chaos = call(&handler, r#"{"jsonrpc":"2.0","method":"chaos","params":{"admin_token":"secret","enabled":true,"error_percent":100},"id":4}"#).await;
+        assert_eq!(chaos["result"]["result"], serde_json::json!({"enabled": true, "latency_ms": 0, "drop_percent": 0, "error_percent": 100}));
+        let config = call(&handler, r#"{"jsonrpc":"2.0","method":"config","params":{"id":"key"},"id":5}"#).await;
+        assert_eq!(config["error"]["code"], int_err::CHAOS_FAULT);
+
+        //Faults are never injected into chaos itself, so that they can be disabled.
+        let chaos = call(&handler, r#"{"jsonrpc":"2.0","method":"chaos","params":{"admin_token":"secret","enabled":false},"id":6}"#).await;
+        assert_eq!(chaos["result"]["result"]["enabled"], false);
+        let config = call(&handler, r#"{"jsonrpc":"2.0","method":"config","params":{"id":"key"},"id":7}"#).await;
+        assert!(config.get("error").is_none());
+    }
+
+    #[tokio::test]
+    async fn should_put_blob_only_by_admin() {
+        let handler = handler();
+        let put = call(&handler, r#"{"jsonrpc":"2.0","method":"put_blob","params":{"data":"value"},"id":1}"#).await;
+        assert_eq!(put["error"]["data"], "Method requires admin token, which is not configured");
+
+        let options = Options {
+            admin_token: Some("secret".to_owned()),
+            ..Options::default()
+        };
+        let handler = Handler::new(db::Db::temporary().expect("open db").view(), options);
+        let put = call(&handler, r#"{"jsonrpc":"2.0","method":"put_blob","params":{"data":"value"},"id":2}"#).await;
+        assert_eq!(put["error"]["code"], int_err::UNAUTHORIZED);
+        let put = call(&handler, r#"{"jsonrpc":"2.0","method":"put_blob","params":{"admin_token":"wrong","data":"value"},"id":3}"#).await;
+        assert_eq!(put["error"]["code"], int_err::UNAUTHORIZED);
+
+        let put = call(&handler, r#"{"jsonrpc":"2.0","method":"put_blob","params":{"admin_token":"secret","data":"value"},"id":4}"#).await;
+        let hash = put["result"]["result"].as_str().expect("hash").to_owned();
+        assert_eq!(hash, blob::to_hex(&blob::hash(b"value")));
+        let get = call(&handler, &format!(r#"{{"jsonrpc":"2.0","method":"get_blob_by_hash","params":{{"id":"{}"}},"id":5}}"#, hash)).await;
+        assert_eq!(get["result"]["result"], "value");
+    }
+
+    #[test]
+    fn should_validate_range_query() {
+        let query = range_query(&params(r#"{"from":"a","to":"b","order":"desc","limit":5000,"values":true}"#), &None).expect("query");
+        assert_eq!((query.from.as_deref(), query.to.as_deref()), (Some("a"), Some("b")));
+        assert!(query.descending);
+        assert!(query.with_values);
+        assert_eq!(query.limit, range::MAX_LIMIT);
+
+        let query = range_query(&params(r#"{}"#), &None).expect("query");
+        assert_eq!((query.from, query.to), (None, None));
+        assert!(!query.descending);
+
+        for (params_str, message) in [
+            (r#"{"from":"b","to":"a"}"#, "Params field 'from' must not be greater than 'to'"),
+            (r#"{"from":1}"#, "Params field 'from' must be a string"),
+            (r#"{"order":"up"}"#, "Params field 'order' must be 'asc' or 'desc'"),
+            (r#"{"limit":0}"#, "Params field 'limit' must be positive integer"),
+        ] {
+            let response = range_query(&params(params_str), &None).err().expect("invalid");
+            assert_eq!(response.payload.expect_err("invalid").data, Some(message));
+        }
+    }
+
+    #[tokio::test]
+    async fn should_perform_write_once_per_idempotency_key() {
+        let handler = handler();
+        let first = call(&handler, r#"{"jsonrpc":"2.0","method":"set_config","params":{"id":"key","data":"first","idempotency_key":"write"},"id":1}"#).await;
+        call(&handler, r#"{"jsonrpc":"2.0","method":"set_config","params":{"id":"key","data":"second"},"id":2}"#).await;
+
+        //Retry replays remembered result without writing again.
+        let retry = call(&handler, r#"{"jsonrpc":"2.0","method":"set_config","params":{"id":"key","data":"first","idempotency_key":"write"},"id":3}"#).await;
+        assert_eq!(first["result"]["result"], xxhash_rust::xxh3::xxh3_64(b"first"));
+        assert_eq!(retry["result"], first["result"]);
+        let config = call(&handler, r#"{"jsonrpc":"2.0","method":"config","params":{"id":"key"},"id":4}"#).await;
+        assert_eq!(config["result"]["result"], "second");
+
+        let conflict = call(&handler, r#"{"jsonrpc":"2.0","method":"delete_config","params":{"id":"key","idempotency_key":"write"},"id":5}"#).await;
+        assert_eq!(conflict["error"]["code"], int_err::IDEMPOTENCY_CONFLICT);
+        let invalid = call(&handler, r#"{"jsonrpc":"2.0","method":"delete_config","params":{"id":"key","idempotency_key":""},"id":6}"#).await;
+        assert_eq!(invalid["error"]["data"], "Params field 'idempotency_key' must be non-empty string");
+    }
+}
@@ -0,0 +1,546 @@
+//! Multipart upload of large values, staged in db.
+//!
+//! - `upload_begin` with `id` of key starts upload, returning its id;
+//! - `upload_chunk` with `upload`, `seq` and `data` stores chunk, acknowledging it with its `seq`;
+//! - `upload_commit` with `upload` writes chunks joined in order of `seq` as value of key, returning its checksum.
+//!
+//! Chunks must be sent in order starting from 0, but already sent chunk can be sent again to replace it.
+//! Chunk is limited to `MAX_CHUNK_SIZE`, while all chunks of upload are limited to `MAX_SIZE`.
+//! Upload is staged in uploads tree under its id, with state under id alone and chunks under id followed by `seq`,
+//! and is removed together with write of value on commit.
+//!
+//! Upload belongs to session, which began it, so that only this session can send its chunks and commit it,
+//! while session can have at most `MAX_SESSION_UPLOADS` uploads at once.
+//! Quota of key's namespace is checked against staged size as chunks arrive, before value is written.
+//!
+//! Uploads are discarded once their session is closed, as well as periodically once there are no new chunks for `TIMEOUT_MS`.
+//! Uploads of previous run are discarded on start, as there are no sessions left to finish them.
+
+use core::convert::TryFrom;
+
+use json_rpc_types::{Id, Version};
+use xxhash_rust::xxh3::xxh3_64;
+
+use super::{int_err, internal_err, invalid_req, checksum_response, write_config, unix_time_ms, quota, cache, lease, ID, DATA, SEQ, RESULT};
+use crate::db;
+use crate::protocol::Response;
+
+///Uploads without new chunks for this long are discarded.
+pub const TIMEOUT_MS: u64 = 10 * 60_000;
+///Maximum size of single chunk.
+pub const MAX_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+///Maximum size of staged chunks of single upload.
+pub const MAX_SIZE: u64 = super::chunk::MAX_UPLOAD_SIZE as u64;
+///Maximum number of uploads, which single session can have at once.
+pub const MAX_SESSION_UPLOADS: usize = 4;
+
+const NOT_STARTED: &str = "Upload is not started or expired";
+
+///State of upload.
+struct Upload<'a> {
+    updated_at: u64,
+    next_seq: u64,
+    ///Total size of staged chunks.
+    size: u64,
+    ///Id of session, which began upload.
+    session: u64,
+    key: &'a [u8],
+}
+
+impl<'a> Upload<'a> {
+    ///Encodes state as update time, next `seq`, size and session in big endian, followed by key.
+    fn encode(&self) -> Vec<u8> {
+        let mut record = Vec::with_capacity(32 + self.key.len());
+        record.extend_from_slice(&self.updated_at.to_be_bytes());
+        record.extend_from_slice(&self.next_seq.to_be_bytes());
+        record.extend_from_slice(&self.size.to_be_bytes());
+        record.extend_from_slice(&self.session.to_be_bytes());
+        record.extend_from_slice(self.key);
+        record
+    }
+
+    fn decode(record: &'a [u8]) -> Option<Self> {
+        let (updated_at, record) = record.split_at_checked(8)?;
+        let (next_seq, record) = record.split_at_checked(8)?;
+        let (size, record) = record.split_at_checked(8)?;
+        let (session, key) = record.split_at_checked(8)?;
+        Some(Self {
+            updated_at: u64::from_be_bytes(<[u8; 8]>::try_from(updated_at).ok()?),
+            next_seq: u64::from_be_bytes(<[u8; 8]>::try_from(next_seq).ok()?),
+            size: u64::from_be_bytes(<[u8; 8]>::try_from(size).ok()?),
+            session: u64::from_be_bytes(<[u8; 8]>::try_from(session).ok()?),
+            key,
+        })
+    }
+
+    #[inline]
+    fn is_expired(&self, now: u64) -> bool {
+        self.updated_at.saturating_add(TIMEOUT_MS) <= now
+    }
+
+    #[inline]
+    ///Returns whether upload can be continued by session at `now`.
+    fn is_open(&self, session: u64, now: u64) -> bool {
+        self.session == session && !self.is_expired(now)
+    }
+}
+
+#[inline]
+fn chunk_key(upload: u64, seq: u64) -> [u8; 16] {
+    let mut result = [0u8; 16];
+    result[..8].copy_from_slice(&upload.to_be_bytes());
+    result[8..].copy_from_slice(&seq.to_be_bytes());
+    result
+}
+
+///Removes uploads, which are not to be kept, together with chunks, which are left without upload.
+fn discard<F: Fn(&Upload<'_>) -> bool>(tree: &sled::Tree, is_kept: F) -> sled::Result<usize> {
+    let mut discarded = 0;
+    let mut start = 0u64;
+
+    while let Some(entry) = tree.range(start.to_be_bytes()..).next() {
+        let (key, record) = entry?;
+        let upload = match key.get(..8).and_then(|upload| <[u8; 8]>::try_from(upload).ok()) {
+            Some(upload) => u64::from_be_bytes(upload),
+            None => {
+                tree.remove(key)?;
+                continue;
+            }
+        };
+
+        let is_alive = key.len() == 8 && Upload::decode(&record).is_some_and(|state| is_kept(&state));
+        if !is_alive {
+            for chunk in tree.scan_prefix(upload.to_be_bytes()).keys() {
+                tree.remove(chunk?)?;
+            }
+            discarded += 1;
+        }
+
+        match upload.checked_add(1) {
+            Some(next) => start = next,
+            None => break,
+        }
+    }
+
+    Ok(discarded)
+}
+
+///Discards uploads of previous run.
+pub fn discard_stored(db: &db::DbView) {
+    match discard(&db.uploads, |_| false) {
+        Ok(0) => (),
+        Ok(discarded) => info!("Discarded {} uploads of previous run", discarded),
+        Err(error) => error!("Unable to discard uploads of previous run: {}", error),
+    }
+}
+
+///Checks whether value of staged size would fit into quota of key's namespace.
+fn check_quota(db: &db::DbView, key: &[u8], size: u64) -> sled::Result<Result<(), quota::Exceeded>> {
+    let key = String::from_utf8_lossy(key);
+    if db.quotas.get(quota::namespace(&key)).is_none() {
+        return Ok(Ok(()));
+    }
+
+    let old_len = db.get_config(key.as_bytes())?.map(|old| old.len());
+    quota::check_write(db, &key, old_len, usize::try_from(size).unwrap_or(usize::MAX))
+}
+
+pub fn handle_begin_req(db: &db::DbView, session: u64, key: &str, cid: Option<&str>, id: Option<Id>) -> Response {
+    let now = unix_time_ms();
+    let mut open = 0;
+    for record in db.uploads.iter() {
+        match record {
+            Ok((upload, record)) => if upload.len() == 8 && Upload::decode(&record).is_some_and(|state| state.is_open(session, now)) {
+                open += 1;
+            },
+            Err(error) => {
+                error!(cid: cid, "Internal error accessing uploads tree: {}", error);
+                return internal_err(int_err::UPLOAD_FAIL, id);
+            }
+        }
+    }
+    if open >= MAX_SESSION_UPLOADS {
+        return invalid_req("Session has too many uploads in progress", id);
+    }
+
+    match check_quota(db, key.as_bytes(), 0) {
+        Ok(Ok(())) => (),
+        Ok(Err(exceeded)) => return exceeded.response(id),
+        Err(error) => {
+            error!(cid: cid, "Internal error accessing usage tree: {}", error);
+            return internal_err(int_err::UPLOAD_FAIL, id);
+        }
+    }
+
+    let state = Upload {
+        updated_at: now,
+        next_seq: 0,
+        size: 0,
+        session,
+        key: key.as_bytes(),
+    };
+    let result = db.generate_id().and_then(|upload| db.uploads.insert(upload.to_be_bytes(), state.encode()).map(|_| upload));
+    match result {
+        Ok(upload) => {
+            let mut payload = serde_json::map::Map::with_capacity(1);
+            payload.insert(RESULT.to_owned(), upload.into());
+            Response::result(Version::V2, payload.into(), id)
+        },
+        Err(error) => {
+            error!(cid: cid, "Unable to begin upload of '{}': {}", key, error);
+            internal_err(int_err::UPLOAD_FAIL, id)
+        }
+    }
+}
+
+///Checks whether upload would still fit into quota of its key, once chunk is stored.
+fn check_chunk_quota(db: &db::DbView, upload: u64, seq: u64, len: usize) -> sled::Result<Result<(), quota::Exceeded>> {
+    //Missing upload is rejected by transaction.
+    let record = match db.uploads.get(upload.to_be_bytes())? {
+        Some(record) => record,
+        None => return Ok(Ok(())),
+    };
+    let state = match Upload::decode(&record) {
+        Some(state) => state,
+        None => return Ok(Ok(())),
+    };
+
+    let replaced = db.uploads.get(chunk_key(upload, seq))?.map_or(0, |chunk| chunk.len() as u64);
+    check_quota(db, state.key, state.size.saturating_sub(replaced).saturating_add(len as u64))
+}
+
+pub fn handle_chunk_req(db: &db::DbView, session: u64, upload: u64, seq: u64, data: &str, cid: Option<&str>, id: Option<Id>) -> Response {
+    use sled::transaction::{ConflictableTransactionError, TransactionError};
+
+    if data.len() > MAX_CHUNK_SIZE {
+        return invalid_req("Chunk exceeds maximum size", id);
+    }
+
+    //Transaction cannot read other trees, so quota is checked up front, while commit checks it again.
+    match check_chunk_quota(db, upload, seq, data.len()) {
+        Ok(Ok(())) => (),
+        Ok(Err(exceeded)) => {
+            warn!(cid: cid, "Rejected chunk {} of upload {}: {}", seq, upload, exceeded);
+            return exceeded.response(id);
+        },
+        Err(error) => {
+            error!(cid: cid, "Internal error accessing usage tree: {}", error);
+            return internal_err(int_err::UPLOAD_FAIL, id);
+        }
+    }
+
+    let now = unix_time_ms();
+    let result: Result<(), TransactionError<&'static str>> = db.uploads.transaction(|tree| {
+        let record = tree.get(upload.to_be_bytes())?;
+        //Upload of another session is indistinguishable from missing one.
+        let mut state = match record.as_ref().and_then(|record| Upload::decode(record)) {
+            Some(state) if state.is_open(session, now) => state,
+            _ => return Err(ConflictableTransactionError::Abort(NOT_STARTED)),
+        };
+        if seq > state.next_seq {
+            return Err(ConflictableTransactionError::Abort("Unexpected chunk 'seq', chunks must be sent in order"));
+        }
+
+        //Chunk, which is sent again, replaces previous one.
+        let replaced = tree.insert(&chunk_key(upload, seq), data.as_bytes())?.map_or(0, |chunk| chunk.len() as u64);
+        state.size = state.size.saturating_sub(replaced).saturating_add(data.len() as u64);
+        if state.size > MAX_SIZE {
+            return Err(ConflictableTransactionError::Abort("Upload exceeds maximum size"));
+        }
+        state.updated_at = now;
+        state.next_seq = state.next_seq.max(seq.saturating_add(1));
+        tree.insert(&upload.to_be_bytes(), state.encode())?;
+        Ok(())
+    });
+
+    match result {
+        Ok(()) => {
+            let mut payload = serde_json::map::Map::with_capacity(1);
+            payload.insert(RESULT.to_owned(), seq.into());
+            Response::result(Version::V2, payload.into(), id)
+        },
+        Err(TransactionError::Abort(error)) => invalid_req(error, id),
+        Err(TransactionError::Storage(error)) => {
+            error!(cid: cid, "Unable to store chunk {} of upload {}: {}", seq, upload, error);
+            internal_err(int_err::UPLOAD_FAIL, id)
+        }
+    }
+}
+
+///Joins chunks of upload, returning result `{id, seq, data}` with key, number of chunks and value.
+pub fn handle_assemble_req(db: &db::DbView, session: u64, upload: u64, cid: Option<&str>, id: Option<Id>) -> Response {
+    let record = match db.uploads.get(upload.to_be_bytes()) {
+        Ok(record) => record,
+        Err(error) => {
+            error!(cid: cid, "Internal error accessing uploads tree: {}", error);
+            return internal_err(int_err::UPLOAD_FAIL, id);
+        }
+    };
+    let state = match record.as_ref().and_then(|record| Upload::decode(record)) {
+        Some(state) if state.is_open(session, unix_time_ms()) => state,
+        _ => return invalid_req(NOT_STARTED, id),
+    };
+
+    let mut value = Vec::new();
+    for seq in 0..state.next_seq {
+        match db.uploads.get(chunk_key(upload, seq)) {
+            Ok(Some(chunk)) => value.extend_from_slice(&chunk),
+            Ok(None) => {
+                error!(cid: cid, "Data corruption in upload {}. Missing chunk {}", upload, seq);
+                return internal_err(int_err::UPLOAD_RSP_CORRUPT, id);
+            },
+            Err(error) => {
+                error!(cid: cid, "Internal error accessing uploads tree: {}", error);
+                return internal_err(int_err::UPLOAD_FAIL, id);
+            },
+        }
+    }
+
+    let (key, value) = match (core::str::from_utf8(state.key), String::from_utf8(value)) {
+        (Ok(key), Ok(value)) => (key, value),
+        _ => {
+            error!(cid: cid, "Data corruption in upload {}. Unexpected non-utf8 key or value", upload);
+            return internal_err(int_err::UPLOAD_RSP_CORRUPT, id);
+        }
+    };
+
+    let mut result = serde_json::map::Map::with_capacity(3);
+    result.insert(ID.to_owned(), key.into());
+    result.insert(SEQ.to_owned(), state.next_seq.into());
+    result.insert(DATA.to_owned(), value.into());
+
+    let mut payload = serde_json::map::Map::with_capacity(1);
+    payload.insert(RESULT.to_owned(), result.into());
+    Response::result(Version::V2, payload.into(), id)
+}
+
+///Value of upload, ready to be committed.
+pub struct Assembled {
+    pub upload: u64,
+    ///Number of chunks, joined into value.
+    pub chunks: u64,
+    pub key: String,
+    pub value: String,
+}
+
+///Takes assembled upload out of result of `handle_assemble_req`, returning it with id of response.
+pub fn take_assembled(upload: u64, response: Response) -> Result<(Assembled, Option<Id>), Response> {
+    let mut result = match response.payload {
+        Ok(serde_json::Value::Object(mut payload)) => match payload.remove(RESULT) {
+            Some(serde_json::Value::Object(result)) => result,
+            _ => return Err(internal_err(int_err::UPLOAD_RSP_CORRUPT, response.id)),
+        },
+        Ok(_) => return Err(internal_err(int_err::UPLOAD_RSP_CORRUPT, response.id)),
+        Err(error) => return Err(Response::error(Version::V2, error, response.id)),
+    };
+
+    match (result.remove(ID), result.get(SEQ).and_then(serde_json::Value::as_u64), result.remove(DATA)) {
+        (Some(serde_json::Value::String(key)), Some(chunks), Some(serde_json::Value::String(value))) => Ok((Assembled { upload, chunks, key, value }, response.id)),
+        _ => Err(internal_err(int_err::UPLOAD_RSP_CORRUPT, response.id)),
+    }
+}
+
+enum Rejected {
+    Exceeded(quota::Exceeded),
+    Modified,
+}
+
+///Writes value of upload, removing upload unless it is modified since it was assembled.
+pub fn handle_commit_req(db: &db::DbView, cache: Option<&cache::Cache>, assembled: &Assembled, lease: lease::Lease, cid: Option<&str>, id: Option<Id>) -> Response {
+    use sled::Transactional;
+    use sled::transaction::{ConflictableTransactionError, TransactionError};
+
+    let (upload, chunks, key, value) = (assembled.upload, assembled.chunks, assembled.key.as_str(), assembled.value.as_str());
+
+    let hash = xxh3_64(value.as_bytes());
+    let result: Result<(), TransactionError<Rejected>> = (&db.checksum, &db.config, &db.ephemeral, &db.blobs, &db.blob_refs, &db.usage, &db.uploads).transaction(|(checksum, config, ephemeral, blobs, blob_refs, usage, uploads)| {
+        let record = uploads.remove(&upload.to_be_bytes())?;
+        match record.as_ref().and_then(|record| Upload::decode(record)) {
+            Some(state) if state.next_seq == chunks && state.key == key.as_bytes() => (),
+            _ => return Err(ConflictableTransactionError::Abort(Rejected::Modified)),
+        }
+        for seq in 0..chunks {
+            uploads.remove(&chunk_key(upload, seq))?;
+        }
+
+        write_config(db, [checksum, config, ephemeral, blobs, blob_refs, usage], key, value, hash, lease).map_err(|error| match error {
+            ConflictableTransactionError::Abort(exceeded) => ConflictableTransactionError::Abort(Rejected::Exceeded(exceeded)),
+            ConflictableTransactionError::Storage(error) => ConflictableTransactionError::Storage(error),
+            _ => ConflictableTransactionError::Conflict,
+        })
+    });
+
+    match result {
+        Ok(()) => {
+            if let Some(cache) = cache {
+                cache.invalidate(key);
+            }
+            checksum_response(hash, id)
+        },
+        Err(TransactionError::Abort(Rejected::Exceeded(exceeded))) => {
+            warn!(cid: cid, "Rejected upload of '{}': {}", key, exceeded);
+            exceeded.response(id)
+        },
+        Err(TransactionError::Abort(Rejected::Modified)) => invalid_req("Upload is modified or expired before it is committed", id),
+        Err(TransactionError::Storage(error)) => {
+            error!(cid: cid, "Unable to commit upload of '{}': {}", key, error);
+            internal_err(int_err::UPLOAD_FAIL, id)
+        }
+    }
+}
+
+///Discards uploads of closed session.
+pub fn handle_discard_req(db: &db::DbView, session: u64, id: Option<Id>) -> Response {
+    match discard(&db.uploads, |state| state.session != session) {
+        Ok(discarded) => {
+            if discarded > 0 {
+                trace!("Discarded {} uploads of closed session", discarded);
+            }
+            Response::result(Version::V2, Default::default(), id)
+        },
+        Err(error) => {
+            error!("Unable to discard uploads of closed session: {}", error);
+            internal_err(int_err::UPLOAD_FAIL, id)
+        }
+    }
+}
+
+///Discards uploads without new chunks for `TIMEOUT_MS` at `now`.
+pub fn handle_expire_req(db: &db::DbView, now: u64, id: Option<Id>) -> Response {
+    match discard(&db.uploads, |state| !state.is_expired(now)) {
+        Ok(expired) => {
+            if expired > 0 {
+                info!("Discarded {} expired uploads", expired);
+            }
+            Response::result(Version::V2, Default::default(), id)
+        },
+        Err(error) => {
+            error!("Unable to discard expired uploads: {}", error);
+            internal_err(int_err::UPLOAD_FAIL, id)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SESSION: u64 = 1;
+
+    fn begin(db: &db::DbView, key: &str) -> u64 {
+        let result = handle_begin_req(db, SESSION, key, None, None).payload.expect("begin");
+        result[RESULT].as_u64().expect("upload id")
+    }
+
+    fn staged_size(db: &db::DbView, upload: u64) -> u64 {
+        let record = db.uploads.get(upload.to_be_bytes()).expect("get").expect("upload");
+        Upload::decode(&record).expect("state").size
+    }
+
+    #[test]
+    fn should_encode_state() {
+        let state = Upload {
+            updated_at: 1,
+            next_seq: 2,
+            size: 3,
+            session: 4,
+            key: b"key",
+        };
+        let record = state.encode();
+        let decoded = Upload::decode(&record).expect("decode");
+        assert_eq!((decoded.updated_at, decoded.next_seq, decoded.size, decoded.session, decoded.key), (1, 2, 3, 4, &b"key"[..]));
+        assert!(Upload::decode(&record[..28]).is_none());
+    }
+
+    #[test]
+    fn should_count_staged_size_of_replaced_chunks() {
+        let db = db::Db::temporary().expect("open db").view();
+        let upload = begin(&db, "key");
+
+        handle_chunk_req(&db, SESSION, upload, 0, "abcd", None, None).payload.expect("chunk");
+        handle_chunk_req(&db, SESSION, upload, 1, "ef", None, None).payload.expect("chunk");
+        assert_eq!(staged_size(&db, upload), 6);
+
+        handle_chunk_req(&db, SESSION, upload, 0, "a", None, None).payload.expect("chunk");
+        assert_eq!(staged_size(&db, upload), 3);
+        assert!(handle_chunk_req(&db, SESSION, upload, 3, "g", None, None).payload.is_err());
+    }
+
+    #[test]
+    fn should_reject_too_large_chunk() {
+        let db = db::Db::temporary().expect("open db").view();
+        let upload = begin(&db, "key");
+
+        let chunk = "a".repeat(MAX_CHUNK_SIZE + 1);
+        assert!(handle_chunk_req(&db, SESSION, upload, 0, &chunk, None, None).payload.is_err());
+        assert_eq!(staged_size(&db, upload), 0);
+        assert!(db.uploads.get(chunk_key(upload, 0)).expect("get").is_none());
+    }
+
+    fn error(response: Response) -> (i64, Option<&'static str>) {
+        let error = response.payload.expect_err("error");
+        (error.code.code(), error.data)
+    }
+
+    #[test]
+    fn should_reject_upload_of_another_session() {
+        let db = db::Db::temporary().expect("open db").view();
+        let upload = begin(&db, "key");
+        handle_chunk_req(&db, SESSION, upload, 0, "value", None, None).payload.expect("chunk");
+
+        assert_eq!(error(handle_chunk_req(&db, SESSION + 1, upload, 0, "other", None, None)).1, Some(NOT_STARTED));
+        assert_eq!(error(handle_chunk_req(&db, SESSION + 1, upload, 1, "other", None, None)).1, Some(NOT_STARTED));
+        assert_eq!(error(handle_assemble_req(&db, SESSION + 1, upload, None, None)).1, Some(NOT_STARTED));
+
+        let assembled = handle_assemble_req(&db, SESSION, upload, None, None).payload.expect("assemble");
+        assert_eq!(assembled[RESULT]["data"], "value");
+    }
+
+    #[test]
+    fn should_limit_uploads_of_session() {
+        let db = db::Db::temporary().expect("open db").view();
+        for _ in 0..MAX_SESSION_UPLOADS {
+            begin(&db, "key");
+        }
+
+        assert_eq!(error(handle_begin_req(&db, SESSION, "key", None, None)).1, Some("Session has too many uploads in progress"));
+        handle_begin_req(&db, SESSION + 1, "key", None, None).payload.expect("begin");
+
+        handle_discard_req(&db, SESSION, None).payload.expect("discard");
+        assert_eq!(db.uploads.len(), 1);
+        begin(&db, "key");
+    }
+
+    #[test]
+    fn should_check_quota_of_staged_chunks() {
+        let mut db = db::Db::temporary().expect("open db").view();
+        db.quotas = "app=16:1,full=100:1".parse().expect("quotas");
+        super::super::handle_set_config_req(&db, None, "full/key", "value", lease::Lease::Keep, None, None).payload.expect("set");
+
+        assert_eq!(error(handle_begin_req(&db, SESSION, "full/other", None, None)).0, int_err::QUOTA_EXCEEDED);
+        //Existing key can still be replaced.
+        begin(&db, "full/key");
+
+        let upload = begin(&db, "app/key");
+        handle_chunk_req(&db, SESSION, upload, 0, "12345678", None, None).payload.expect("chunk");
+        assert_eq!(error(handle_chunk_req(&db, SESSION, upload, 1, "90", None, None)).0, int_err::QUOTA_EXCEEDED);
+        assert_eq!(staged_size(&db, upload), 8);
+        assert!(db.uploads.get(chunk_key(upload, 1)).expect("get").is_none());
+    }
+
+    #[test]
+    fn should_discard_expired_and_stored_uploads() {
+        let db = db::Db::temporary().expect("open db").view();
+        let upload = begin(&db, "key");
+        handle_chunk_req(&db, SESSION, upload, 0, "value", None, None).payload.expect("chunk");
+
+        handle_expire_req(&db, unix_time_ms(), None).payload.expect("expire");
+        assert_eq!(db.uploads.len(), 2);
+        handle_expire_req(&db, unix_time_ms() + TIMEOUT_MS, None).payload.expect("expire");
+        assert!(db.uploads.is_empty());
+
+        begin(&db, "key");
+        discard_stored(&db);
+        assert!(db.uploads.is_empty());
+    }
+}
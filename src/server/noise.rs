@@ -0,0 +1,306 @@
+//! Encryption of TCP transport with Noise protocol.
+//!
+//! Client performs `XX` handshake, authenticating both sides with their static keys,
+//! after which every Noise message is sent prefixed by its length as big endian `u16`.
+//! Frames of JSON-RPC protocol are carried within decrypted stream as usual.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use core::convert::TryFrom;
+use core::fmt::Write;
+use core::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+///Noise protocol, used for handshake.
+const PATTERN: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+///Maximum size of Noise message.
+const MAX_MESSAGE: usize = 65535;
+///Size of authentication tag, appended to every encrypted message.
+const TAG_LEN: usize = 16;
+///Maximum size of data in single message.
+const MAX_PAYLOAD: usize = MAX_MESSAGE - TAG_LEN;
+const KEY_LEN: usize = 32;
+///Time given to client to complete handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub type Key = [u8; KEY_LEN];
+
+fn parse_key(key: &str) -> Option<Key> {
+    let key = key.trim();
+    if key.len() != KEY_LEN * 2 {
+        return None;
+    }
+
+    let mut result = [0u8; KEY_LEN];
+    for (idx, byte) in result.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(key.get(idx * 2..idx * 2 + 2)?, 16).ok()?;
+    }
+    Some(result)
+}
+
+fn to_hex(key: &[u8]) -> String {
+    let mut result = String::with_capacity(key.len() * 2);
+    for byte in key.iter() {
+        let _ = write!(result, "{:02x}", byte);
+    }
+    result
+}
+
+#[derive(Clone)]
+///Static keys of Noise transport.
+pub struct Config {
+    private_key: Key,
+    ///Public keys of clients, allowed to connect. Any client is allowed if empty, which must be requested explicitly.
+    peers: Vec<Key>,
+}
+
+impl Config {
+    ///Creates config out of hex encoded private key and comma separated hex encoded public keys of peers.
+    ///
+    ///Without peers any client is allowed only if `allow_any` is set.
+    pub fn new(private_key: &str, peers: Option<&str>, allow_any: bool) -> Result<Self, &'static str> {
+        let private_key = parse_key(private_key).ok_or("Noise key must be 32 bytes in hex")?;
+        let mut result = Vec::new();
+        for peer in peers.unwrap_or_default().split(',').filter(|peer| !peer.trim().is_empty()) {
+            result.push(parse_key(peer).ok_or("Noise peer must be 32 bytes public key in hex")?);
+        }
+        if result.is_empty() && !allow_any {
+            return Err("Noise requires public keys of clients in --noise-peers, unless any client is allowed with --noise-allow-any");
+        }
+
+        Ok(Self {
+            private_key,
+            peers: result,
+        })
+    }
+
+    ///Returns hex encoded public key of server, which clients need to authenticate it.
+    pub fn public_key(&self) -> String {
+        use snow::resolvers::CryptoResolver;
+
+        match snow::resolvers::DefaultResolver.resolve_dh(&snow::params::DHChoice::Curve25519) {
+            Some(mut dh) => {
+                dh.set(&self.private_key);
+                to_hex(dh.pubkey())
+            },
+            None => unreachable!(),
+        }
+    }
+}
+
+#[inline]
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+async fn read_message<S: AsyncRead + Unpin>(socket: &mut S, buf: &mut [u8]) -> io::Result<usize> {
+    let len = socket.read_u16().await? as usize;
+    socket.read_exact(&mut buf[..len]).await?;
+    Ok(len)
+}
+
+async fn write_message<S: AsyncWrite + Unpin>(socket: &mut S, message: &[u8]) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(2 + message.len());
+    frame.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    frame.extend_from_slice(message);
+    socket.write_all(&frame).await
+}
+
+async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(mut socket: S, config: &Config) -> io::Result<NoiseStream<S>> {
+    let params = match PATTERN.parse() {
+        Ok(params) => params,
+        Err(_) => unreachable!(),
+    };
+    let mut state = snow::Builder::new(params).local_private_key(&config.private_key).build_responder().map_err(invalid_data)?;
+    let mut message = vec![0u8; MAX_MESSAGE];
+    let mut payload = vec![0u8; MAX_MESSAGE];
+
+    // -> e
+    let len = read_message(&mut socket, &mut message).await?;
+    state.read_message(&message[..len], &mut payload).map_err(invalid_data)?;
+    // <- e, ee, s, es
+    let len = state.write_message(&[], &mut message).map_err(invalid_data)?;
+    write_message(&mut socket, &message[..len]).await?;
+    // -> s, se
+    let len = read_message(&mut socket, &mut message).await?;
+    state.read_message(&message[..len], &mut payload).map_err(invalid_data)?;
+
+    if !config.peers.is_empty() {
+        let is_allowed = state.get_remote_static().and_then(|key| Key::try_from(key).ok()).is_some_and(|key| config.peers.contains(&key));
+        if !is_allowed {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "Client's key is not allowed"));
+        }
+    }
+
+    let state = state.into_transport_mode().map_err(invalid_data)?;
+    Ok(NoiseStream {
+        socket,
+        state,
+        incoming: Vec::with_capacity(MAX_MESSAGE + 2),
+        decrypted: Vec::new(),
+        decrypted_pos: 0,
+        outgoing: Vec::new(),
+        outgoing_pos: 0,
+        accepted: 0,
+    })
+}
+
+///Performs handshake as responder.
+pub async fn accept<S: AsyncRead + AsyncWrite + Unpin>(socket: S, config: &Config) -> io::Result<NoiseStream<S>> {
+    match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake(socket, config)).await {
+        Ok(result) => result,
+        Err(_) => Err(io::ErrorKind::TimedOut.into()),
+    }
+}
+
+///Stream, which encrypts and decrypts data with established Noise session.
+pub struct NoiseStream<S> {
+    socket: S,
+    state: snow::TransportState,
+    //Raw data, read until complete message is available.
+    incoming: Vec<u8>,
+    decrypted: Vec<u8>,
+    decrypted_pos: usize,
+    //Encrypted message, which is being written.
+    outgoing: Vec<u8>,
+    outgoing_pos: usize,
+    //Size of data in outgoing message.
+    accepted: usize,
+}
+
+impl<S> NoiseStream<S> {
+    ///Returns length of complete message at the start of incoming data.
+    fn complete_message(&self) -> Option<usize> {
+        match self.incoming.get(..2) {
+            Some(len) => {
+                let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+                match self.incoming.len() >= 2 + len {
+                    true => Some(len),
+                    false => None,
+                }
+            },
+            None => None,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for NoiseStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            if this.decrypted_pos < this.decrypted.len() {
+                let len = buf.remaining().min(this.decrypted.len() - this.decrypted_pos);
+                buf.put_slice(&this.decrypted[this.decrypted_pos..this.decrypted_pos + len]);
+                this.decrypted_pos += len;
+                return Poll::Ready(Ok(()));
+            }
+
+            if let Some(len) = this.complete_message() {
+                this.decrypted.resize(len, 0);
+                let decrypted = this.state.read_message(&this.incoming[2..2 + len], &mut this.decrypted).map_err(invalid_data)?;
+                this.decrypted.truncate(decrypted);
+                this.decrypted_pos = 0;
+                this.incoming.drain(..2 + len);
+                continue;
+            }
+
+            let mut chunk = [0u8; 8 * 1024];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            match Pin::new(&mut this.socket).poll_read(cx, &mut chunk) {
+                Poll::Ready(Ok(())) if chunk.filled().is_empty() => return match this.incoming.is_empty() {
+                    true => Poll::Ready(Ok(())),
+                    false => Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into())),
+                },
+                Poll::Ready(Ok(())) => this.incoming.extend_from_slice(chunk.filled()),
+                Poll::Ready(Err(error)) => return Poll::Ready(Err(error)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> NoiseStream<S> {
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.outgoing_pos < self.outgoing.len() {
+            match Pin::new(&mut self.socket).poll_write(cx, &self.outgoing[self.outgoing_pos..]) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                Poll::Ready(Ok(written)) => self.outgoing_pos += written,
+                Poll::Ready(Err(error)) => return Poll::Ready(Err(error)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        self.outgoing.clear();
+        self.outgoing_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for NoiseStream<S> {
+    //Data is reported as written only once its message is written, so caller must retry with the same data.
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        if this.outgoing.is_empty() {
+            let len = buf.len().min(MAX_PAYLOAD);
+            this.outgoing.resize(2 + len + TAG_LEN, 0);
+            let encrypted = this.state.write_message(&buf[..len], &mut this.outgoing[2..]).map_err(invalid_data)?;
+            this.outgoing.truncate(2 + encrypted);
+            this.outgoing[..2].copy_from_slice(&(encrypted as u16).to_be_bytes());
+            this.accepted = len;
+        }
+
+        match this.poll_drain(cx) {
+            Poll::Ready(Ok(())) => Poll::Ready(Ok(this.accepted)),
+            Poll::Ready(Err(error)) => Poll::Ready(Err(error)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_drain(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.socket).poll_flush(cx),
+            result => result,
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_drain(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.socket).poll_shutdown(cx),
+            result => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "0101010101010101010101010101010101010101010101010101010101010101";
+    const PEER: &str = "0202020202020202020202020202020202020202020202020202020202020202";
+
+    #[test]
+    fn should_require_peers_unless_any_is_allowed() {
+        assert!(Config::new(KEY, None, false).is_err());
+        assert!(Config::new(KEY, Some(" , "), false).is_err());
+        assert!(Config::new(KEY, None, true).is_ok_and(|config| config.peers.is_empty()));
+
+        let config = Config::new(KEY, Some(PEER), false).expect("config");
+        assert_eq!(config.peers, [[2u8; KEY_LEN]]);
+    }
+
+    #[test]
+    fn should_reject_invalid_keys() {
+        assert!(Config::new(&KEY[1..], Some(PEER), false).is_err());
+        assert!(Config::new(KEY, Some("zz"), false).is_err());
+        assert_eq!(parse_key(KEY).map(|key| to_hex(&key)).as_deref(), Some(KEY));
+    }
+}
@@ -0,0 +1,93 @@
+//! Shared pool of reusable buffers.
+//!
+//! Connections only hold buffers while processing request, so idle connections cost nothing
+//! beyond socket itself, while buffers grown by huge requests are not kept around.
+
+use std::sync::Mutex;
+use core::ops::{Deref, DerefMut};
+
+///Maximum number of buffers kept in pool.
+const MAX_BUFFERS: usize = 256;
+///Buffers grown above this capacity are freed instead of returning to the pool.
+const MAX_CAPACITY: usize = 64 * 1024;
+///Capacity of newly allocated buffer.
+const INITIAL_CAPACITY: usize = 1024;
+
+static POOL: Mutex<Vec<Vec<u8>>> = Mutex::new(Vec::new());
+
+///Buffer, which returns to the pool on drop.
+pub struct Buffer {
+    inner: Vec<u8>,
+}
+
+///Takes buffer out of pool, allocating new one if pool is empty.
+pub fn get() -> Buffer {
+    let buffer = POOL.lock().unwrap_or_else(|error| error.into_inner()).pop();
+
+    Buffer {
+        inner: buffer.unwrap_or_else(|| Vec::with_capacity(INITIAL_CAPACITY)),
+    }
+}
+
+impl Deref for Buffer {
+    type Target = Vec<u8>;
+
+    #[inline(always)]
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl DerefMut for Buffer {
+    #[inline(always)]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        if self.inner.capacity() > MAX_CAPACITY {
+            return;
+        }
+
+        let mut buffer = core::mem::take(&mut self.inner);
+        buffer.clear();
+
+        let mut pool = POOL.lock().unwrap_or_else(|error| error.into_inner());
+        if pool.len() < MAX_BUFFERS {
+            pool.push(buffer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Pool is shared with other tests, so only properties of every pooled buffer are checked.
+
+    #[test]
+    fn should_return_cleared_buffers() {
+        let mut buffer = get();
+        buffer.extend_from_slice(b"request");
+        drop(buffer);
+
+        for _ in 0..4 {
+            let buffer = get();
+            assert!(buffer.is_empty());
+            assert!(buffer.capacity() >= INITIAL_CAPACITY);
+        }
+    }
+
+    #[test]
+    fn should_free_grown_buffers() {
+        let mut buffer = get();
+        buffer.resize(MAX_CAPACITY + 1, 0);
+        drop(buffer);
+
+        let pool = POOL.lock().unwrap_or_else(|error| error.into_inner());
+        assert!(pool.len() <= MAX_BUFFERS);
+        assert!(pool.iter().all(|buffer| buffer.is_empty() && buffer.capacity() <= MAX_CAPACITY));
+    }
+}
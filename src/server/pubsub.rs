@@ -0,0 +1,134 @@
+//! Channels, fanning out messages to subscribed connections without persisting them.
+//!
+//! Message is delivered as JSON-RPC notification `message` with params `{channel, data}`.
+//! Subscriber, which doesn't read fast enough, misses messages once its outbox is full.
+
+use std::sync::Mutex;
+use std::collections::HashMap;
+
+use super::session::{Message, Outbox, Session};
+
+///Method of notification, carrying published message.
+const METHOD: &str = "message";
+
+#[derive(Default)]
+pub struct Channels {
+    //Channel to subscribed sessions
+    subscribers: Mutex<HashMap<String, Vec<(u64, Outbox)>>>,
+}
+
+impl Channels {
+    #[inline]
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Vec<(u64, Outbox)>>> {
+        self.subscribers.lock().unwrap_or_else(|error| error.into_inner())
+    }
+
+    ///Subscribes session to channel, returning whether it wasn't subscribed already.
+    pub fn subscribe(&self, channel: &str, session: &Session) -> bool {
+        let mut subscribers = self.lock();
+        let subscribers = subscribers.entry(channel.to_owned()).or_default();
+        if subscribers.iter().any(|(id, _)| *id == session.id()) {
+            return false;
+        }
+        subscribers.push((session.id(), session.outbox().clone()));
+        true
+    }
+
+    ///Unsubscribes session from channel, returning whether it was subscribed.
+    pub fn unsubscribe(&self, channel: &str, session: u64) -> bool {
+        let mut subscribers = self.lock();
+        let (is_removed, is_empty) = match subscribers.get_mut(channel) {
+            Some(channel) => {
+                let len = channel.len();
+                channel.retain(|(id, _)| *id != session);
+                (channel.len() != len, channel.is_empty())
+            },
+            None => return false,
+        };
+
+        if is_empty {
+            subscribers.remove(channel);
+        }
+        is_removed
+    }
+
+    ///Removes all subscriptions of session.
+    pub fn close(&self, session: u64) {
+        self.lock().retain(|_, channel| {
+            channel.retain(|(id, _)| *id != session);
+            !channel.is_empty()
+        });
+    }
+
+    ///Sends message to all subscribers of channel, returning number of subscribers, which received it.
+    pub fn publish(&self, channel: &str, data: &str) -> usize {
+        let subscribers = self.lock();
+        let subscribers = match subscribers.get(channel) {
+            Some(subscribers) => subscribers,
+            None => return 0,
+        };
+
+        let message = notification(channel, data);
+        subscribers.iter().filter(|(_, outbox)| outbox.try_send(message.clone()).is_ok()).count()
+    }
+}
+
+fn notification(channel: &str, data: &str) -> Message {
+    let mut params = serde_json::Map::with_capacity(2);
+    params.insert("channel".to_owned(), channel.into());
+    params.insert("data".to_owned(), data.into());
+
+    let mut notification = serde_json::Map::with_capacity(3);
+    notification.insert("jsonrpc".to_owned(), "2.0".into());
+    notification.insert("method".to_owned(), METHOD.into());
+    notification.insert("params".to_owned(), params.into());
+
+    match serde_json::to_vec(&notification) {
+        Ok(message) => message.into(),
+        Err(_) => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_deliver_message_to_subscribers_of_channel() {
+        let channels = Channels::default();
+        let (first, mut first_outbox) = Session::new();
+        let (second, mut second_outbox) = Session::new();
+
+        assert!(channels.subscribe("news", &first));
+        assert!(!channels.subscribe("news", &first));
+        assert!(channels.subscribe("other", &second));
+
+        assert_eq!(channels.publish("news", "hello"), 1);
+        assert_eq!(channels.publish("missing", "hello"), 0);
+
+        let message = first_outbox.try_recv().expect("message");
+        assert_eq!(serde_json::from_slice::<serde_json::Value>(&message).expect("json"), serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": "message",
+            "params": {"channel": "news", "data": "hello"},
+        }));
+        assert!(first_outbox.try_recv().is_err());
+        assert!(second_outbox.try_recv().is_err());
+    }
+
+    #[test]
+    fn should_stop_delivery_once_unsubscribed() {
+        let channels = Channels::default();
+        let (session, _outbox) = Session::new();
+        channels.subscribe("news", &session);
+        channels.subscribe("other", &session);
+
+        assert!(channels.unsubscribe("news", session.id()));
+        assert!(!channels.unsubscribe("news", session.id()));
+        assert_eq!(channels.publish("news", "hello"), 0);
+
+        channels.close(session.id());
+        assert_eq!(channels.publish("other", "hello"), 0);
+        assert!(channels.lock().is_empty());
+    }
+}
@@ -0,0 +1,191 @@
+//! Durable queues, stored in dedicated tree.
+//!
+//! Message is stored under queue's name, followed by zero byte and sequence number in big endian,
+//! so that iteration over queue's prefix yields messages in order of push.
+//! Value of message is time, until which message is invisible, followed by data.
+
+use core::convert::TryFrom;
+
+use json_rpc_types::{Id, Version};
+
+use super::{int_err, internal_err, unix_time_ms, RESULT, SEQ, DATA};
+use crate::db;
+use crate::protocol::Response;
+
+pub enum QueueOp {
+    Push(String),
+    ///Pops first visible message, hiding it for specified time instead of removing, if it is not 0.
+    Pop {
+        visibility_ms: u64,
+    },
+    ///Removes message, hidden by pop.
+    Ack(u64),
+    Len,
+}
+
+impl QueueOp {
+    #[inline]
+    pub const fn name(&self) -> &'static str {
+        match self {
+            QueueOp::Push(_) => "queue_push",
+            QueueOp::Pop { .. } => "queue_pop",
+            QueueOp::Ack(_) => "queue_ack",
+            QueueOp::Len => "queue_len",
+        }
+    }
+}
+
+#[inline]
+fn prefix(name: &str) -> Vec<u8> {
+    let mut prefix = Vec::with_capacity(name.len() + 1);
+    prefix.extend_from_slice(name.as_bytes());
+    prefix.push(0);
+    prefix
+}
+
+#[inline]
+fn message_key(name: &str, seq: u64) -> Vec<u8> {
+    let mut key = prefix(name);
+    key.extend_from_slice(&seq.to_be_bytes());
+    key
+}
+
+fn encode(visible_at: u64, data: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(8 + data.len());
+    message.extend_from_slice(&visible_at.to_be_bytes());
+    message.extend_from_slice(data);
+    message
+}
+
+fn decode(message: &[u8]) -> Option<(u64, &[u8])> {
+    let (visible_at, data) = message.split_at_checked(8)?;
+    let visible_at = <[u8; 8]>::try_from(visible_at).ok()?;
+    Some((u64::from_be_bytes(visible_at), data))
+}
+
+#[inline]
+fn seq_of(key: &[u8]) -> Option<u64> {
+    let seq = key.len().checked_sub(8).and_then(|start| <[u8; 8]>::try_from(&key[start..]).ok())?;
+    Some(u64::from_be_bytes(seq))
+}
+
+fn pop(tree: &sled::Tree, name: &str, visibility_ms: u64) -> sled::Result<Option<(u64, sled::IVec)>> {
+    let now = unix_time_ms();
+
+    for message in tree.scan_prefix(prefix(name)) {
+        let (key, message) = message?;
+        let (seq, visible_at) = match (seq_of(&key), decode(&message)) {
+            (Some(seq), Some((visible_at, _))) => (seq, visible_at),
+            _ => continue,
+        };
+        if visible_at > now {
+            continue;
+        }
+
+        let new = match visibility_ms {
+            0 => None,
+            visibility_ms => Some(encode(now.saturating_add(visibility_ms), &message[8..])),
+        };
+        //Message can be taken concurrently, in which case we look for next one.
+        if tree.compare_and_swap(&key, Some(&message), new)?.is_ok() {
+            return Ok(Some((seq, message)));
+        }
+    }
+
+    Ok(None)
+}
+
+pub fn handle_queue_req(db: &db::DbView, op: QueueOp, name: &str, cid: Option<&str>, id: Option<Id>) -> Response {
+    let tree = &db.queues;
+    let op_name = op.name();
+
+    let result = match op {
+        QueueOp::Push(data) => db.generate_id().and_then(|seq| {
+            tree.insert(message_key(name, seq), encode(0, data.as_bytes()))?;
+            Ok(seq.into())
+        }),
+        QueueOp::Pop { visibility_ms } => match pop(tree, name, visibility_ms) {
+            Ok(Some((seq, message))) => match core::str::from_utf8(&message[8..]) {
+                Ok(data) => {
+                    let mut result = serde_json::map::Map::with_capacity(2);
+                    result.insert(SEQ.to_owned(), seq.into());
+                    result.insert(DATA.to_owned(), data.into());
+                    Ok(result.into())
+                },
+                Err(error) => {
+                    error!(cid: cid, "Data corruption in queue '{}'. Unexpected non-utf8 message {}: {}", name, seq, error);
+                    return internal_err(int_err::QUEUE_RSP_CORRUPT, id);
+                }
+            },
+            Ok(None) => Ok(serde_json::Value::Null),
+            Err(error) => Err(error),
+        },
+        QueueOp::Ack(seq) => tree.remove(message_key(name, seq)).map(|message| message.is_some().into()),
+        QueueOp::Len => tree.scan_prefix(prefix(name)).keys().try_fold(0u64, |len, key| key.map(|_| len + 1)).map(Into::into),
+    };
+
+    match result {
+        Ok(result) => {
+            let mut payload = serde_json::map::Map::with_capacity(1);
+            payload.insert(RESULT.to_owned(), result);
+            Response::result(Version::V2, payload.into(), id)
+        },
+        Err(error) => {
+            error!(cid: cid, "Unable to {} '{}': {}", op_name, name, error);
+            internal_err(int_err::QUEUE_FAIL, id)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(db: &db::DbView, op: QueueOp, name: &str) -> serde_json::Value {
+        handle_queue_req(db, op, name, None, None).payload.expect("queue")[RESULT].take()
+    }
+
+    #[test]
+    fn should_pop_messages_in_order_of_push() {
+        let db = db::Db::temporary().expect("open db").view();
+        let first = queue(&db, QueueOp::Push("first".to_owned()), "jobs");
+        queue(&db, QueueOp::Push("second".to_owned()), "jobs");
+        queue(&db, QueueOp::Push("other".to_owned()), "jobs2");
+        assert_eq!(queue(&db, QueueOp::Len, "jobs"), 2);
+
+        let message = queue(&db, QueueOp::Pop { visibility_ms: 0 }, "jobs");
+        assert_eq!(message, serde_json::json!({"seq": first, "data": "first"}));
+        assert_eq!(queue(&db, QueueOp::Pop { visibility_ms: 0 }, "jobs")["data"], "second");
+        assert_eq!(queue(&db, QueueOp::Pop { visibility_ms: 0 }, "jobs"), serde_json::Value::Null);
+        assert_eq!(queue(&db, QueueOp::Len, "jobs2"), 1);
+    }
+
+    #[test]
+    fn should_hide_popped_message_until_ack() {
+        let db = db::Db::temporary().expect("open db").view();
+        queue(&db, QueueOp::Push("first".to_owned()), "jobs");
+        queue(&db, QueueOp::Push("second".to_owned()), "jobs");
+
+        let message = queue(&db, QueueOp::Pop { visibility_ms: 60_000 }, "jobs");
+        assert_eq!(message["data"], "first");
+        assert_eq!(queue(&db, QueueOp::Pop { visibility_ms: 60_000 }, "jobs")["data"], "second");
+        assert_eq!(queue(&db, QueueOp::Pop { visibility_ms: 60_000 }, "jobs"), serde_json::Value::Null);
+        assert_eq!(queue(&db, QueueOp::Len, "jobs"), 2);
+
+        let seq = message["seq"].as_u64().expect("seq");
+        assert_eq!(queue(&db, QueueOp::Ack(seq), "jobs"), true);
+        assert_eq!(queue(&db, QueueOp::Ack(seq), "jobs"), false);
+        assert_eq!(queue(&db, QueueOp::Len, "jobs"), 1);
+    }
+
+    #[test]
+    fn should_return_message_once_visibility_expires() {
+        let db = db::Db::temporary().expect("open db").view();
+        queue(&db, QueueOp::Push("first".to_owned()), "jobs");
+
+        assert_eq!(queue(&db, QueueOp::Pop { visibility_ms: 1 }, "jobs")["data"], "first");
+        std::thread::sleep(core::time::Duration::from_millis(5));
+        assert_eq!(queue(&db, QueueOp::Pop { visibility_ms: 0 }, "jobs")["data"], "first");
+        assert_eq!(queue(&db, QueueOp::Len, "jobs"), 0);
+    }
+}
@@ -0,0 +1,325 @@
+//! Storage quotas of namespaces.
+//!
+//! Namespace of key is its part before first `/`, or empty for keys without it.
+//! Usage of namespace is size of its keys and values in bytes and number of keys,
+//! which is tracked in usage tree within the same transaction as write.
+//!
+//! Quota is only checked for writes, which increase usage, so namespace over its quota
+//! can still shrink.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use core::convert::TryFrom;
+
+use json_rpc_types::{Id, Version, Error, ErrorCode};
+use sled::transaction::{ConflictableTransactionError, ConflictableTransactionResult, TransactionalTree};
+
+use super::{int_err, internal_err, RESULT};
+use crate::db;
+use crate::protocol::Response;
+
+///Separator of namespace within key.
+const SEPARATOR: char = '/';
+
+#[inline]
+///Returns namespace of key.
+pub fn namespace(key: &str) -> &str {
+    key.split_once(SEPARATOR).map_or("", |(namespace, _)| namespace)
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct Limit {
+    ///Maximum size of keys and values in bytes, 0 disables it.
+    pub max_bytes: u64,
+    ///Maximum number of keys, 0 disables it.
+    pub max_keys: u64,
+}
+
+#[derive(Clone, Default)]
+///Limits of namespaces, which have quota.
+pub struct Quotas {
+    limits: Arc<BTreeMap<String, Limit>>,
+}
+
+impl Quotas {
+    #[inline]
+    pub fn get(&self, namespace: &str) -> Option<Limit> {
+        self.limits.get(namespace).copied()
+    }
+}
+
+impl core::fmt::Debug for Quotas {
+    fn fmt(&self, fmt: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        fmt.debug_map().entries(self.limits.iter().map(|(namespace, limit)| (namespace, (limit.max_bytes, limit.max_keys)))).finish()
+    }
+}
+
+impl core::str::FromStr for Quotas {
+    type Err = ();
+
+    ///Parses comma separated list of `<namespace>=<max_bytes>:<max_keys>`.
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut limits = BTreeMap::new();
+
+        for quota in text.split(',').map(str::trim).filter(|quota| !quota.is_empty()) {
+            let (namespace, limit) = quota.split_once('=').ok_or(())?;
+            let (max_bytes, max_keys) = limit.split_once(':').ok_or(())?;
+            let limit = Limit {
+                max_bytes: max_bytes.trim().parse().map_err(|_| ())?,
+                max_keys: max_keys.trim().parse().map_err(|_| ())?,
+            };
+            limits.insert(namespace.trim().to_owned(), limit);
+        }
+
+        Ok(Self {
+            limits: Arc::new(limits),
+        })
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct Usage {
+    pub bytes: u64,
+    pub keys: u64,
+}
+
+impl Usage {
+    #[inline]
+    fn decode(value: &[u8]) -> Self {
+        match <[u8; 16]>::try_from(value) {
+            Ok(value) => Self {
+                bytes: u64::from_be_bytes([value[0], value[1], value[2], value[3], value[4], value[5], value[6], value[7]]),
+                keys: u64::from_be_bytes([value[8], value[9], value[10], value[11], value[12], value[13], value[14], value[15]]),
+            },
+            Err(_) => Self::default(),
+        }
+    }
+
+    #[inline]
+    fn encode(&self) -> [u8; 16] {
+        let mut result = [0u8; 16];
+        result[..8].copy_from_slice(&self.bytes.to_be_bytes());
+        result[8..].copy_from_slice(&self.keys.to_be_bytes());
+        result
+    }
+
+    fn into_json(self, limit: Option<Limit>) -> serde_json::Value {
+        let limit = limit.unwrap_or_default();
+        let mut result = serde_json::Map::with_capacity(4);
+        result.insert("bytes".to_owned(), self.bytes.into());
+        result.insert("keys".to_owned(), self.keys.into());
+        result.insert("max_bytes".to_owned(), limit.max_bytes.into());
+        result.insert("max_keys".to_owned(), limit.max_keys.into());
+        result.into()
+    }
+}
+
+#[derive(Debug)]
+pub enum Exceeded {
+    Bytes,
+    Keys,
+}
+
+impl Exceeded {
+    #[inline]
+    ///Returns description, suitable for client.
+    pub const fn reason(&self) -> &'static str {
+        match self {
+            Exceeded::Bytes => "Namespace exceeds its quota of bytes",
+            Exceeded::Keys => "Namespace exceeds its quota of keys",
+        }
+    }
+
+    #[inline]
+    pub fn response(&self, id: Option<Id>) -> Response {
+        Response::error(Version::V2, Error::from_code(ErrorCode::ServerError(int_err::QUOTA_EXCEEDED)).set_data(self.reason()), id)
+    }
+}
+
+impl core::fmt::Display for Exceeded {
+    #[inline]
+    fn fmt(&self, fmt: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        fmt.write_str(self.reason())
+    }
+}
+
+///Returns usage after write of key, which replaces value of `old_len`, if it existed.
+fn after_write(mut current: Usage, limit: Option<Limit>, key: &str, old_len: Option<usize>, new_len: usize) -> Result<Usage, Exceeded> {
+    match old_len {
+        Some(old_len) => current.bytes = current.bytes.saturating_sub((key.len() + old_len) as u64),
+        None => current.keys += 1,
+    }
+    current.bytes += (key.len() + new_len) as u64;
+
+    if let Some(limit) = limit {
+        if old_len.is_none() && limit.max_keys > 0 && current.keys > limit.max_keys {
+            return Err(Exceeded::Keys);
+        }
+        if old_len.is_none_or(|old_len| new_len > old_len) && limit.max_bytes > 0 && current.bytes > limit.max_bytes {
+            return Err(Exceeded::Bytes);
+        }
+    }
+
+    Ok(current)
+}
+
+///Accounts write of key, which replaces value of `old_len`, if it existed.
+pub fn on_write(usage: &TransactionalTree, quotas: &Quotas, key: &str, old_len: Option<usize>, new_len: usize) -> ConflictableTransactionResult<(), Exceeded> {
+    let namespace = namespace(key);
+    let current = match usage.get(namespace.as_bytes())? {
+        Some(current) => Usage::decode(&current),
+        None => Usage::default(),
+    };
+
+    let current = after_write(current, quotas.get(namespace), key, old_len, new_len).map_err(ConflictableTransactionError::Abort)?;
+    usage.insert(namespace.as_bytes(), &current.encode())?;
+    Ok(())
+}
+
+///Checks whether write of key would fit into quota, without accounting it.
+pub fn check_write(db: &db::DbView, key: &str, old_len: Option<usize>, new_len: usize) -> Result<Result<(), Exceeded>, sled::Error> {
+    let namespace = namespace(key);
+    let current = usage_of(db, namespace)?;
+    Ok(after_write(current, db.quotas.get(namespace), key, old_len, new_len).map(|_| ()))
+}
+
+///Accounts removal of key with value of `old_len`.
+pub fn on_remove<E>(usage: &TransactionalTree, key: &str, old_len: usize) -> ConflictableTransactionResult<(), E> {
+    let namespace = namespace(key);
+    let mut current = match usage.get(namespace.as_bytes())? {
+        Some(current) => Usage::decode(&current),
+        None => return Ok(()),
+    };
+
+    current.bytes = current.bytes.saturating_sub((key.len() + old_len) as u64);
+    current.keys = current.keys.saturating_sub(1);
+    match current.keys {
+        0 => usage.remove(namespace.as_bytes())?,
+        _ => usage.insert(namespace.as_bytes(), &current.encode())?,
+    };
+    Ok(())
+}
+
+///Calculates usage of every namespace, unless it is already tracked.
+///
+///Needed once for database, written before usage was tracked.
+pub fn init_usage(db: &db::DbView) -> Result<(), sled::Error> {
+    if !db.usage.is_empty() {
+        return Ok(());
+    }
+
+    let mut usages = BTreeMap::<String, Usage>::new();
+    for key in db.config.iter().keys() {
+        let key = key?;
+        let len = match db.get_config(&key)? {
+            Some(value) => value.len(),
+            None => continue,
+        };
+        let key = String::from_utf8_lossy(&key);
+        let usage = usages.entry(namespace(&key).to_owned()).or_default();
+        usage.bytes += (key.len() + len) as u64;
+        usage.keys += 1;
+    }
+
+    for (namespace, usage) in usages {
+        db.usage.insert(namespace.as_bytes(), &usage.encode())?;
+    }
+    Ok(())
+}
+
+fn usage_of(db: &db::DbView, namespace: &str) -> Result<Usage, sled::Error> {
+    Ok(db.usage.get(namespace.as_bytes())?.map_or(Usage::default(), |usage| Usage::decode(&usage)))
+}
+
+///Returns usage of namespace or of every namespace with keys, when it is not specified.
+pub fn handle_usage_req(db: &db::DbView, namespace: Option<&str>, cid: Option<&str>, id: Option<Id>) -> Response {
+    let result = match namespace {
+        Some(namespace) => usage_of(db, namespace).map(|usage| usage.into_json(db.quotas.get(namespace))),
+        None => db.usage.iter().map(|entry| entry.map(|(namespace, usage)| {
+            let namespace = String::from_utf8_lossy(&namespace).into_owned();
+            let usage = Usage::decode(&usage).into_json(db.quotas.get(&namespace));
+            (namespace, usage)
+        })).collect::<Result<serde_json::Map<_, _>, _>>().map(Into::into),
+    };
+
+    match result {
+        Ok(result) => {
+            let mut payload = serde_json::map::Map::with_capacity(1);
+            payload.insert(RESULT.to_owned(), result);
+            Response::result(Version::V2, payload.into(), id)
+        },
+        Err(error) => {
+            error!(cid: cid, "Internal error accessing usage tree: {}", error);
+            internal_err(int_err::USAGE_FAIL_GET, id)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{handle_set_config_req, handle_delete_config_req, lease::Lease};
+
+    fn usage(db: &db::DbView, namespace: &str) -> serde_json::Value {
+        handle_usage_req(db, Some(namespace), None, None).payload.expect("usage")[RESULT].take()
+    }
+
+    #[test]
+    fn should_parse_quotas() {
+        let quotas = " app=100:2, =0:5 ".parse::<Quotas>().expect("quotas");
+        assert_eq!(format!("{:?}", quotas), r#"{"": (0, 5), "app": (100, 2)}"#);
+        assert!("".parse::<Quotas>().is_ok());
+        assert!("app=100".parse::<Quotas>().is_err());
+        assert!("app=100:x".parse::<Quotas>().is_err());
+
+        assert_eq!(namespace("app/db/host"), "app");
+        assert_eq!(namespace("key"), "");
+    }
+
+    #[test]
+    fn should_reject_writes_over_quota() {
+        let mut db = db::Db::temporary().expect("open db").view();
+        db.quotas = "app=20:2".parse().expect("quotas");
+
+        handle_set_config_req(&db, None, "app/a", "12345", Lease::Keep, None, None).payload.expect("set");
+        assert_eq!(usage(&db, "app"), serde_json::json!({"bytes": 10, "keys": 1, "max_bytes": 20, "max_keys": 2}));
+
+        let error = handle_set_config_req(&db, None, "app/b", "1234567", Lease::Keep, None, None).payload.expect_err("bytes");
+        assert_eq!(error.code.code(), int_err::QUOTA_EXCEEDED);
+        assert_eq!(error.data, Some(Exceeded::Bytes.reason()));
+        handle_set_config_req(&db, None, "app/b", "1", Lease::Keep, None, None).payload.expect("set");
+
+        let error = handle_set_config_req(&db, None, "app/c", "", Lease::Keep, None, None).payload.expect_err("keys");
+        assert_eq!(error.data, Some(Exceeded::Keys.reason()));
+
+        //Shrinking is allowed, as well as writes to other namespaces.
+        handle_set_config_req(&db, None, "app/a", "1", Lease::Keep, None, None).payload.expect("set");
+        handle_set_config_req(&db, None, "other/a", "1234567890123456789", Lease::Keep, None, None).payload.expect("set");
+        assert_eq!(usage(&db, "app"), serde_json::json!({"bytes": 12, "keys": 2, "max_bytes": 20, "max_keys": 2}));
+    }
+
+    #[test]
+    fn should_release_usage_of_deleted_keys() {
+        let db = db::Db::temporary().expect("open db").view();
+        handle_set_config_req(&db, None, "app/a", "value", Lease::Keep, None, None).payload.expect("set");
+        handle_set_config_req(&db, None, "key", "value", Lease::Keep, None, None).payload.expect("set");
+
+        let all = handle_usage_req(&db, None, None, None).payload.expect("usage")[RESULT].take();
+        assert_eq!(all["app"]["keys"], 1);
+        assert_eq!(all[""]["bytes"], 8);
+
+        handle_delete_config_req(&db, None, "app/a", Lease::Keep, None, None).payload.expect("delete");
+        assert_eq!(usage(&db, "app"), serde_json::json!({"bytes": 0, "keys": 0, "max_bytes": 0, "max_keys": 0}));
+        assert!(db.usage.get("app").expect("usage").is_none());
+    }
+
+    #[test]
+    fn should_calculate_usage_of_existing_keys() {
+        let db = db::Db::temporary().expect("open db").view();
+        handle_set_config_req(&db, None, "app/a", "value", Lease::Keep, None, None).payload.expect("set");
+        db.usage.clear().expect("clear");
+
+        init_usage(&db).expect("init");
+        assert_eq!(usage(&db, "app")["bytes"], 10);
+    }
+}
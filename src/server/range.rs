@@ -0,0 +1,124 @@
+//! Listing of keys within bounds in order of their bytes.
+//!
+//! Lower bound `from` is inclusive, while upper bound `to` is exclusive, either can be omitted.
+//! With descending order keys are listed starting from the greatest one, so that latest of
+//! time or sequence prefixed keys come first.
+
+use core::ops::Bound;
+
+use json_rpc_types::{Id, Version};
+
+use super::{int_err, internal_err, key_item, RESULT};
+use crate::db;
+use crate::protocol::Response;
+
+///Maximum number of keys listed by single request.
+pub const MAX_LIMIT: usize = 1000;
+
+pub struct Query {
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub descending: bool,
+    pub limit: usize,
+    pub with_values: bool,
+}
+
+fn range(db: &db::DbView, query: &Query) -> Result<serde_json::Map<String, serde_json::Value>, sled::Error> {
+    let from = query.from.as_ref().map_or(Bound::Unbounded, |from| Bound::Included(from.as_bytes()));
+    let to = query.to.as_ref().map_or(Bound::Unbounded, |to| Bound::Excluded(to.as_bytes()));
+    let entries = db.checksum.range::<&[u8], _>((from, to));
+    let entries: Box<dyn Iterator<Item = sled::Result<(sled::IVec, sled::IVec)>>> = match query.descending {
+        true => Box::new(entries.rev()),
+        false => Box::new(entries),
+    };
+
+    let mut keys = Vec::new();
+    let mut is_truncated = false;
+    for entry in entries {
+        if keys.len() >= query.limit {
+            is_truncated = true;
+            break;
+        }
+
+        let (key, checksum) = entry?;
+        keys.push(key_item(db, &String::from_utf8_lossy(&key), &checksum, query.with_values)?);
+    }
+
+    let mut result = serde_json::Map::with_capacity(2);
+    result.insert("keys".to_owned(), keys.into());
+    result.insert("truncated".to_owned(), is_truncated.into());
+    Ok(result)
+}
+
+pub fn handle_range_req(db: &db::DbView, query: &Query, cid: Option<&str>, id: Option<Id>) -> Response {
+    match range(db, query) {
+        Ok(result) => {
+            let mut payload = serde_json::map::Map::with_capacity(1);
+            payload.insert(RESULT.to_owned(), result.into());
+            Response::result(Version::V2, payload.into(), id)
+        },
+        Err(error) => {
+            error!(cid: cid, "Internal error listing range of config tree: {}", error);
+            internal_err(int_err::CONFIG_FAIL_GET, id)
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xxhash_rust::xxh3::xxh3_64;
+    use super::super::{handle_set_config_req, lease::Lease};
+
+    fn query(from: Option<&str>, to: Option<&str>, descending: bool, limit: usize) -> Query {
+        Query {
+            from: from.map(ToOwned::to_owned),
+            to: to.map(ToOwned::to_owned),
+            descending,
+            limit,
+            with_values: false,
+        }
+    }
+
+    fn keys(db: &db::DbView, query: &Query) -> (Vec<String>, bool) {
+        let payload = handle_range_req(db, query, None, None).payload.expect("range");
+        let keys = payload[RESULT]["keys"].as_array().expect("keys").iter().map(|item| item["id"].as_str().expect("id").to_owned()).collect();
+        (keys, payload[RESULT]["truncated"].as_bool().expect("truncated"))
+    }
+
+    fn db() -> db::DbView {
+        let db = db::Db::temporary().expect("open db").view();
+        for key in ["a", "b", "c", "d"] {
+            handle_set_config_req(&db, None, key, key, Lease::Keep, None, None).payload.expect("set");
+        }
+        db
+    }
+
+    #[test]
+    fn should_list_keys_within_bounds() {
+        let db = db();
+        assert_eq!(keys(&db, &query(Some("b"), Some("d"), false, MAX_LIMIT)), (vec!["b".to_owned(), "c".to_owned()], false));
+        assert_eq!(keys(&db, &query(None, Some("b"), false, MAX_LIMIT)), (vec!["a".to_owned()], false));
+        assert_eq!(keys(&db, &query(Some("c"), None, false, MAX_LIMIT)), (vec!["c".to_owned(), "d".to_owned()], false));
+        assert_eq!(keys(&db, &query(Some("b"), Some("b"), false, MAX_LIMIT)), (Vec::new(), false));
+    }
+
+    #[test]
+    fn should_list_greatest_keys_first_in_descending_order() {
+        let db = db();
+        assert_eq!(keys(&db, &query(None, Some("d"), true, 2)), (vec!["c".to_owned(), "b".to_owned()], true));
+        assert_eq!(keys(&db, &query(None, None, true, 4)), (vec!["d".to_owned(), "c".to_owned(), "b".to_owned(), "a".to_owned()], false));
+        assert_eq!(keys(&db, &query(None, None, false, 1)), (vec!["a".to_owned()], true));
+    }
+
+    #[test]
+    fn should_list_values_on_request() {
+        let db = db();
+        let query = Query {
+            with_values: true,
+            ..query(Some("a"), Some("b"), false, MAX_LIMIT)
+        };
+        let payload = handle_range_req(&db, &query, None, None).payload.expect("range");
+        assert_eq!(payload[RESULT]["keys"], serde_json::json!([{"id": "a", "checksum": xxh3_64(b"a"), "data": "a"}]));
+    }
+}
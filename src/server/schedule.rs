@@ -0,0 +1,150 @@
+//! Scheduled activation of configs.
+//!
+//! `set_config` with `activate_at` (milliseconds since unix epoch) stages value in pending tree
+//! under activation time followed by key, so that due values are always at the start of tree.
+//! Background task promotes due values, writing each together with removal of its pending entry,
+//! and announces them on channel `activated` with message `{"id": <key>, "checksum": <checksum>}`.
+//!
+//! Multiple values of the same key are activated in order of their time.
+
+use core::time::Duration;
+
+use json_rpc_types::{Id, Version};
+use xxhash_rust::xxh3::xxh3_64;
+
+use super::{int_err, internal_err, checksum_response, write_config, quota, cache, lease, RESULT};
+use crate::db;
+use crate::protocol::Response;
+
+///Channel, on which activations are announced.
+pub const CHANNEL: &str = "activated";
+///Interval between checks for due values.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(100);
+///Maximum number of values activated at once.
+const MAX_ACTIVATIONS: usize = 256;
+
+#[inline]
+fn pending_key(activate_at: u64, key: &str) -> Vec<u8> {
+    let mut result = Vec::with_capacity(8 + key.len());
+    result.extend_from_slice(&activate_at.to_be_bytes());
+    result.extend_from_slice(key.as_bytes());
+    result
+}
+
+pub fn handle_schedule_req(db: &db::DbView, key: &str, value: &str, activate_at: u64, cid: Option<&str>, id: Option<Id>) -> Response {
+    match db.pending.insert(pending_key(activate_at, key), value.as_bytes()) {
+        Ok(_) => checksum_response(xxh3_64(value.as_bytes()), id),
+        Err(error) => {
+            error!(cid: cid, "Unable to schedule config: {}", error);
+            internal_err(int_err::SET_CONFIG_FAIL, id)
+        }
+    }
+}
+
+fn activate(db: &db::DbView, cache: Option<&cache::Cache>, pending: &[u8], key: &str, value: &str) -> Result<Option<u64>, sled::Error> {
+    use sled::Transactional;
+    use sled::transaction::TransactionError;
+
+    let hash = xxh3_64(value.as_bytes());
+    let result: Result<(), TransactionError<quota::Exceeded>> = (&db.checksum, &db.config, &db.ephemeral, &db.blobs, &db.blob_refs, &db.usage, &db.pending).transaction(|(checksum, config, ephemeral, blobs, blob_refs, usage, pending_tree)| {
+        pending_tree.remove(pending)?;
+        //Scheduled value is no longer tied to connection, which could write key.
+        write_config(db, [checksum, config, ephemeral, blobs, blob_refs, usage], key, value, hash, lease::Lease::Detach)
+    });
+
+    match result {
+        Ok(()) => {
+            if let Some(cache) = cache {
+                cache.invalidate(key);
+            }
+            Ok(Some(hash))
+        },
+        Err(TransactionError::Abort(exceeded)) => {
+            warn!("Dropping scheduled value of '{}': {}", key, exceeded);
+            db.pending.remove(pending)?;
+            Ok(None)
+        },
+        Err(TransactionError::Storage(error)) => Err(error),
+    }
+}
+
+///Promotes values due at `now`, returning keys with their checksums.
+pub fn handle_activate_req(db: &db::DbView, cache: Option<&cache::Cache>, now: u64, id: Option<Id>) -> Response {
+    let mut activated = Vec::new();
+
+    for entry in db.pending.range(..now.saturating_add(1).to_be_bytes()).take(MAX_ACTIVATIONS) {
+        let result = entry.and_then(|(pending, value)| {
+            let key = String::from_utf8_lossy(pending.get(8..).unwrap_or_default()).into_owned();
+            let value = String::from_utf8_lossy(&value);
+            activate(db, cache, &pending, &key, &value).map(|hash| hash.map(|hash| (key, hash)))
+        });
+
+        match result {
+            Ok(Some((key, hash))) => {
+                let mut item = serde_json::Map::with_capacity(2);
+                item.insert("id".to_owned(), key.into());
+                item.insert("checksum".to_owned(), hash.into());
+                activated.push(serde_json::Value::from(item));
+            },
+            Ok(None) => (),
+            Err(error) => {
+                error!("Unable to activate scheduled config: {}", error);
+                break;
+            }
+        }
+    }
+
+    let mut payload = serde_json::map::Map::with_capacity(1);
+    payload.insert(RESULT.to_owned(), activated.into());
+    Response::result(Version::V2, payload.into(), id)
+}
+
+#[inline]
+///Returns key of activated value within result of `handle_activate_req`.
+pub fn activated_key(item: &serde_json::Value) -> Option<&str> {
+    item.get("id").and_then(serde_json::Value::as_str)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn activated(response: Response) -> Vec<(String, u64)> {
+        let payload = response.payload.expect("activate");
+        payload[RESULT].as_array().expect("array").iter().map(|item| {
+            (activated_key(item).expect("id").to_owned(), item["checksum"].as_u64().expect("checksum"))
+        }).collect()
+    }
+
+    #[test]
+    fn should_activate_only_due_values_in_order_of_time() {
+        let db = db::Db::temporary().expect("open db").view();
+        handle_schedule_req(&db, "key", "second", 20, None, None).payload.expect("schedule");
+        handle_schedule_req(&db, "key", "first", 10, None, None).payload.expect("schedule");
+        handle_schedule_req(&db, "later", "value", 30, None, None).payload.expect("schedule");
+
+        assert!(activated(handle_activate_req(&db, None, 5, None)).is_empty());
+        assert_eq!(db.get_config("key").expect("get"), None);
+
+        let result = activated(handle_activate_req(&db, None, 20, None));
+        assert_eq!(result, [("key".to_owned(), xxh3_64(b"first")), ("key".to_owned(), xxh3_64(b"second"))]);
+        assert_eq!(db.get_config("key").expect("get").as_deref(), Some(&b"second"[..]));
+        assert_eq!(db.get_config("later").expect("get"), None);
+        assert_eq!(db.pending.len(), 1);
+
+        assert_eq!(activated(handle_activate_req(&db, None, 30, None)), [("later".to_owned(), xxh3_64(b"value"))]);
+        assert!(db.pending.is_empty());
+        assert!(activated(handle_activate_req(&db, None, u64::MAX, None)).is_empty());
+    }
+
+    #[test]
+    fn should_detach_activated_value_from_lease() {
+        let db = db::Db::temporary().expect("open db").view();
+        super::super::handle_set_config_req(&db, None, "key", "ephemeral", lease::Lease::Attach, None, None).payload.expect("set");
+        handle_schedule_req(&db, "key", "durable", 1, None, None).payload.expect("schedule");
+
+        activated(handle_activate_req(&db, None, 1, None));
+        assert_eq!(db.get_config("key").expect("get").as_deref(), Some(&b"durable"[..]));
+        assert!(db.ephemeral.is_empty());
+    }
+}
@@ -0,0 +1,130 @@
+//! State of client's connection, shared by transport and handler.
+
+use std::sync::Arc;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+///Version of protocol, reported by `hello`.
+pub const PROTOCOL_VERSION: u64 = 1;
+///Framing of messages.
+pub const FRAMING: &str = "eot";
+///Encoding of messages.
+pub const ENCODING: &str = "json";
+
+///Features, which can be enabled via `hello`.
+pub mod feature {
+    ///Missing keys are reported as `null` result, as if every request sets `explicit_missing`.
+    pub const EXPLICIT_MISSING: u32 = 1;
+    ///Large values are sent in chunks, as if every request sets `chunked`.
+    pub const CHUNKED: u32 = 1 << 1;
+    ///Large responses are compressed with zstd.
+    ///
+    ///Compressed response is sent as byte `0x01`, followed by length of compressed data as big endian `u32` and data itself.
+    pub const ZSTD: u32 = 1 << 2;
+}
+
+///Maximum number of server-sent messages waiting to be written, newer messages are dropped.
+const OUTBOX_CAPACITY: usize = 64;
+
+///Serialized message, sent by server on its own.
+pub type Message = Arc<[u8]>;
+///Sender of messages to client's connection.
+pub type Outbox = tokio::sync::mpsc::Sender<Message>;
+
+const FEATURES: [(&str, u32); 3] = [
+    ("explicit_missing", feature::EXPLICIT_MISSING),
+    ("chunked", feature::CHUNKED),
+    ("zstd", feature::ZSTD),
+];
+
+pub struct Session {
+    id: u64,
+    features: AtomicU32,
+    outbox: Outbox,
+}
+
+impl Session {
+    ///Creates new session, returning it with receiver of messages to be written by transport.
+    pub fn new() -> (Self, tokio::sync::mpsc::Receiver<Message>) {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+        let (outbox, receiver) = tokio::sync::mpsc::channel(OUTBOX_CAPACITY);
+        let session = Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            features: AtomicU32::new(0),
+            outbox,
+        };
+        (session, receiver)
+    }
+
+    #[inline]
+    ///Returns unique identifier of session.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    #[inline]
+    ///Returns sender of messages to client.
+    pub fn outbox(&self) -> &Outbox {
+        &self.outbox
+    }
+
+    #[inline]
+    ///Returns whether feature is enabled for this connection.
+    pub fn has(&self, feature: u32) -> bool {
+        self.features.load(Ordering::Relaxed) & feature == feature
+    }
+
+    ///Enables requested features, returning names of features which are supported.
+    ///
+    ///Previously negotiated features are replaced.
+    pub fn negotiate<'a, I: IntoIterator<Item = &'a str>>(&self, requested: I) -> Vec<&'static str> {
+        let mut features = 0;
+        let mut result = Vec::new();
+
+        for name in requested {
+            if let Some((name, feature)) = FEATURES.iter().find(|(known, _)| *known == name) {
+                if features & feature == 0 {
+                    features |= feature;
+                    result.push(*name);
+                }
+            }
+        }
+
+        self.features.store(features, Ordering::Relaxed);
+        result
+    }
+
+    #[inline]
+    ///Returns names of all supported features.
+    pub fn supported() -> impl Iterator<Item = &'static str> {
+        FEATURES.iter().map(|(name, _)| *name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_negotiate_only_known_features() {
+        let (session, _outbox) = Session::new();
+        assert!(!session.has(feature::EXPLICIT_MISSING));
+
+        assert_eq!(session.negotiate(["zstd", "unknown", "explicit_missing", "zstd"]), ["zstd", "explicit_missing"]);
+        assert!(session.has(feature::ZSTD));
+        assert!(session.has(feature::EXPLICIT_MISSING));
+        assert!(!session.has(feature::CHUNKED));
+        assert!(!session.has(feature::ZSTD | feature::CHUNKED));
+
+        assert_eq!(session.negotiate(["chunked"]), ["chunked"]);
+        assert!(session.has(feature::CHUNKED));
+        assert!(!session.has(feature::ZSTD));
+    }
+
+    #[test]
+    fn should_give_sessions_unique_ids() {
+        let (first, _first_outbox) = Session::new();
+        let (second, _second_outbox) = Session::new();
+        assert_ne!(first.id(), second.id());
+    }
+}
@@ -0,0 +1,101 @@
+//! Reading of multiple keys as of single point in time.
+//!
+//! All keys are read within one transaction, which is retried on concurrent write,
+//! so that result never mixes values from before and after any single write.
+//! Values are returned raw, as resolving references would read outside of transaction.
+
+use json_rpc_types::{Id, Version};
+use xxhash_rust::xxh3::xxh3_64;
+use sled::Transactional;
+use sled::transaction::{ConflictableTransactionError, TransactionError};
+
+use super::{blob, int_err, internal_err, DATA, RESULT};
+use crate::db;
+use crate::protocol::Response;
+
+pub fn handle_read_snapshot_req(db: &db::DbView, keys: &[String], cid: Option<&str>, id: Option<Id>) -> Response {
+    let result: Result<Vec<Option<String>>, TransactionError<&str>> = (&db.config, &db.blobs).transaction(|(config, blobs)| {
+        let mut values = Vec::with_capacity(keys.len());
+        for key in keys {
+            let value = match config.get(key.as_bytes())? {
+                Some(value) => match blob::as_pointer(&value) {
+                    Some(hash) => blobs.get(hash)?,
+                    None => Some(value),
+                },
+                None => None,
+            };
+
+            match value.map(|value| String::from_utf8(value.to_vec())) {
+                Some(Ok(value)) => values.push(Some(value)),
+                Some(Err(_)) => return Err(ConflictableTransactionError::Abort(key.as_str())),
+                None => values.push(None),
+            }
+        }
+        Ok(values)
+    });
+
+    let values = match result {
+        Ok(values) => values,
+        Err(TransactionError::Abort(key)) => {
+            error!(cid: cid, "Data corruption in config. Unexpected non-utf8 config of '{}'", key);
+            return internal_err(int_err::CONFIG_RSP_CORRUPT, id);
+        },
+        Err(TransactionError::Storage(error)) => {
+            error!(cid: cid, "Internal error reading snapshot of config tree: {}", error);
+            return internal_err(int_err::CONFIG_FAIL_GET, id);
+        },
+    };
+
+    let mut snapshot = serde_json::Map::with_capacity(keys.len());
+    for (key, value) in keys.iter().zip(values) {
+        let value = match value {
+            Some(value) => {
+                let mut item = serde_json::Map::with_capacity(2);
+                item.insert("checksum".to_owned(), xxh3_64(value.as_bytes()).into());
+                item.insert(DATA.to_owned(), value.into());
+                item.into()
+            },
+            None => serde_json::Value::Null,
+        };
+        snapshot.insert(key.clone(), value);
+    }
+
+    let mut payload = serde_json::map::Map::with_capacity(1);
+    payload.insert(RESULT.to_owned(), snapshot.into());
+    Response::result(Version::V2, payload.into(), id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{handle_set_config_req, lease::Lease};
+
+    fn keys(keys: &[&str]) -> Vec<String> {
+        keys.iter().map(|key| key.to_string()).collect()
+    }
+
+    #[test]
+    fn should_read_values_together_with_blobs() {
+        let mut db = db::Db::temporary().expect("open db").view();
+        db.blob_threshold = 8;
+        handle_set_config_req(&db, None, "small", "value", Lease::Keep, None, None).payload.expect("set");
+        handle_set_config_req(&db, None, "large", "large value", Lease::Keep, None, None).payload.expect("set");
+        assert_eq!(db.blobs.len(), 1);
+
+        let payload = handle_read_snapshot_req(&db, &keys(&["small", "large", "missing"]), None, None).payload.expect("snapshot");
+        assert_eq!(payload[RESULT], serde_json::json!({
+            "small": {"checksum": xxh3_64(b"value"), "data": "value"},
+            "large": {"checksum": xxh3_64(b"large value"), "data": "large value"},
+            "missing": null,
+        }));
+    }
+
+    #[test]
+    fn should_reject_corrupted_value() {
+        let db = db::Db::temporary().expect("open db").view();
+        db.config.insert("key", &b"\xFF"[..]).expect("insert");
+
+        let response = handle_read_snapshot_req(&db, &keys(&["key"]), None, None);
+        assert_eq!(response.payload.expect_err("corrupt").code.code(), int_err::CONFIG_RSP_CORRUPT);
+    }
+}
@@ -0,0 +1,186 @@
+//! Synchronization of client's cache, by comparing its checksums against stored ones.
+//!
+//! Client submits checksums of keys it has, and gets back:
+//!
+//! - `stale` - keys, which checksum differs from stored one;
+//! - `deleted` - keys, which are no longer stored;
+//! - `missing` - keys under requested `prefix`, which client doesn't have.
+//!
+//! Alternatively client can check whole namespace at once via `tree_checksum`.
+
+use std::collections::BTreeMap;
+use core::convert::TryFrom;
+
+use json_rpc_types::{Id, Version};
+use xxhash_rust::xxh3::Xxh3;
+
+use super::{int_err, internal_err, RESULT};
+use crate::db;
+use crate::protocol::Response;
+
+///Maximum number of checksums in single request.
+pub const MAX_KEYS: usize = 4096;
+
+enum Error {
+    Db(sled::Error),
+    Corrupt(String),
+}
+
+impl From<sled::Error> for Error {
+    #[inline]
+    fn from(error: sled::Error) -> Self {
+        Error::Db(error)
+    }
+}
+
+#[inline]
+fn checksum_of(key: &str, value: &[u8]) -> Result<u64, Error> {
+    match <[u8; 8]>::try_from(value) {
+        Ok(bytes) => Ok(u64::from_be_bytes(bytes)),
+        Err(_) => Err(Error::Corrupt(key.to_owned())),
+    }
+}
+
+fn diff(db: &db::DbView, checksums: &BTreeMap<String, u64>, prefix: Option<&str>) -> Result<serde_json::Map<String, serde_json::Value>, Error> {
+    let mut stale = Vec::new();
+    let mut deleted = Vec::new();
+    let mut missing = Vec::new();
+
+    for (key, checksum) in checksums.iter() {
+        match db.checksum.get(key)? {
+            Some(stored) => if checksum_of(key, &stored)? != *checksum {
+                stale.push(serde_json::Value::from(key.as_str()));
+            },
+            None => deleted.push(serde_json::Value::from(key.as_str())),
+        }
+    }
+
+    if let Some(prefix) = prefix {
+        for key in db.checksum.scan_prefix(prefix).keys() {
+            let key = key?;
+            let key = String::from_utf8_lossy(&key);
+            if !checksums.contains_key(key.as_ref()) {
+                missing.push(serde_json::Value::from(key.into_owned()));
+            }
+        }
+    }
+
+    let mut result = serde_json::Map::with_capacity(3);
+    result.insert("stale".to_owned(), stale.into());
+    result.insert("missing".to_owned(), missing.into());
+    result.insert("deleted".to_owned(), deleted.into());
+    Ok(result)
+}
+
+fn response(result: Result<serde_json::Value, Error>, cid: Option<&str>, id: Option<Id>) -> Response {
+    match result {
+        Ok(result) => {
+            let mut payload = serde_json::map::Map::with_capacity(1);
+            payload.insert(RESULT.to_owned(), result);
+            Response::result(Version::V2, payload.into(), id)
+        },
+        Err(Error::Db(error)) => {
+            error!(cid: cid, "Internal error accessing checksum tree: {}", error);
+            internal_err(int_err::DIFF_FAIL_GET, id)
+        },
+        Err(Error::Corrupt(key)) => {
+            error!(cid: cid, "Data corruption in checksum of '{}'. Unexpected length, expected 8", key);
+            internal_err(int_err::DIFF_RSP_CORRUPT, id)
+        },
+    }
+}
+
+pub fn handle_diff_req(db: &db::DbView, checksums: &BTreeMap<String, u64>, prefix: Option<&str>, cid: Option<&str>, id: Option<Id>) -> Response {
+    response(diff(db, checksums, prefix).map(Into::into), cid, id)
+}
+
+///Hashes keys and checksums in order of keys, so that any change of namespace changes result.
+fn tree_checksum(db: &db::DbView, prefix: &str) -> Result<u64, Error> {
+    let mut hasher = Xxh3::new();
+
+    for entry in db.checksum.scan_prefix(prefix) {
+        let (key, checksum) = entry?;
+        //Length makes boundary between key and checksum unambiguous.
+        hasher.update(&(key.len() as u64).to_be_bytes());
+        hasher.update(&key);
+        hasher.update(&checksum_of(&String::from_utf8_lossy(&key), &checksum)?.to_be_bytes());
+    }
+
+    Ok(hasher.digest())
+}
+
+pub fn handle_tree_checksum_req(db: &db::DbView, prefix: &str, cid: Option<&str>, id: Option<Id>) -> Response {
+    response(tree_checksum(db, prefix).map(Into::into), cid, id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{handle_set_config_req, lease::Lease};
+
+    fn set(db: &db::DbView, key: &str, value: &str) -> u64 {
+        handle_set_config_req(db, None, key, value, Lease::Keep, None, None).payload.expect("set");
+        let checksum = db.checksum.get(key).expect("checksum").expect("stored checksum");
+        checksum_of(key, &checksum).ok().expect("checksum")
+    }
+
+    #[test]
+    fn should_report_stale_deleted_and_missing_keys() {
+        let db = db::Db::temporary().expect("open db").view();
+        let fresh = set(&db, "app/fresh", "value");
+        let stale = set(&db, "app/stale", "value");
+        set(&db, "app/new", "value");
+        set(&db, "other/new", "value");
+
+        let mut checksums = BTreeMap::new();
+        checksums.insert("app/fresh".to_owned(), fresh);
+        checksums.insert("app/stale".to_owned(), stale.wrapping_add(1));
+        checksums.insert("app/deleted".to_owned(), fresh);
+
+        let response = handle_diff_req(&db, &checksums, Some("app/"), None, None);
+        assert_eq!(response.payload.expect("diff")[RESULT], serde_json::json!({
+            "stale": ["app/stale"],
+            "missing": ["app/new"],
+            "deleted": ["app/deleted"],
+        }));
+
+        let response = handle_diff_req(&db, &checksums, None, None, None);
+        assert_eq!(response.payload.expect("diff")[RESULT]["missing"], serde_json::json!([]));
+    }
+
+    #[test]
+    fn should_report_corrupted_checksum_in_diff() {
+        let db = db::Db::temporary().expect("open db").view();
+        db.checksum.insert("key", &[1][..]).expect("insert");
+
+        let mut checksums = BTreeMap::new();
+        checksums.insert("key".to_owned(), 1);
+        let response = handle_diff_req(&db, &checksums, None, None, None);
+        assert_eq!(response.payload.expect_err("corrupted").code.code(), int_err::DIFF_RSP_CORRUPT);
+    }
+
+    fn tree(db: &db::DbView, prefix: &str) -> serde_json::Value {
+        handle_tree_checksum_req(db, prefix, None, None).payload.expect("tree_checksum")[RESULT].take()
+    }
+
+    #[test]
+    fn should_change_tree_checksum_with_namespace() {
+        let db = db::Db::temporary().expect("open db").view();
+        let empty = tree(&db, "app/");
+
+        set(&db, "app/a", "value");
+        let initial = tree(&db, "app/");
+        assert_ne!(initial, empty);
+
+        set(&db, "other/a", "value");
+        assert_eq!(tree(&db, "app/"), initial);
+
+        set(&db, "app/a", "changed");
+        let changed = tree(&db, "app/");
+        assert_ne!(changed, initial);
+
+        set(&db, "app/a", "value");
+        assert_eq!(tree(&db, "app/"), initial);
+        assert_ne!(tree(&db, ""), initial);
+    }
+}
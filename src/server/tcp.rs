@@ -1,43 +1,162 @@
-use std::io;
-use std::sync::Arc;
-use std::collections::HashSet;
+use std::io::{self, IoSlice};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Instant, SystemTime};
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use core::convert::TryFrom;
 use core::future::Future;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
 
-use tokio::net::{TcpStream, TcpListener};
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use rogu::{info, warn, trace};
+use tokio::net::TcpListener;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tracing::Instrument;
 
-use super::{Handler, LOCAL_HOST};
-use crate::protocol::{Request, EOT};
-use crate::db;
+use json_rpc_types::{Id, Version, Error, ErrorCode};
+use xxhash_rust::xxh3::xxh3_64;
 
-trait ErrorKindExt {
-    ///Returns true whether error can be ignored in context of `TcpListener::accept`
-    fn is_accept_error_ok(self) -> bool;
+use super::{RequestHandler, Handler, ErrorKindExt, ID, RESULT, CONNECTIONS, KICK, authorize, generate_correlation_id, invalid_req};
+use super::metrics::METRICS;
+use super::{pool, chunk, session, noise};
+use crate::protocol::{self, Field, Request, Response, EOT};
+
+///Maximum number of responses to accumulate before writing them out.
+const MAX_PENDING_RESPONSES: usize = 64;
+///Number of shards of connected set.
+const CONNECTED_SHARDS: usize = 16;
+
+#[inline]
+fn unix_time_ms(time: SystemTime) -> u64 {
+    match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(time) => time.as_millis() as u64,
+        Err(_) => 0,
+    }
+}
+
+#[derive(Default)]
+///Statistics updated by client's task.
+struct ConnectionStats {
+    requests: AtomicU64,
+    //Milliseconds since UNIX epoch
+    last_activity: AtomicU64,
+    //Notified to forcibly close connection.
+    kick: tokio::sync::Notify,
+}
+
+struct Connection {
+    addr: SocketAddr,
+    connected_at: SystemTime,
+    stats: Arc<ConnectionStats>,
+}
+
+///Set of connected clients grouped by IP, sharded by it to reduce lock contention on accept and disconnect.
+struct Connected {
+    shards: [Mutex<HashMap<IpAddr, Vec<Connection>>>; CONNECTED_SHARDS],
+}
+
+impl Connected {
+    fn new() -> Self {
+        Self {
+            shards: core::array::from_fn(|_| Mutex::new(HashMap::new())),
+        }
+    }
+
+    #[inline]
+    fn lock(shard: &Mutex<HashMap<IpAddr, Vec<Connection>>>) -> MutexGuard<'_, HashMap<IpAddr, Vec<Connection>>> {
+        shard.lock().unwrap_or_else(|error| error.into_inner())
+    }
+
+    fn shard(&self, ip: &IpAddr) -> MutexGuard<'_, HashMap<IpAddr, Vec<Connection>>> {
+        let hash = match ip {
+            IpAddr::V4(ip) => xxh3_64(&ip.octets()),
+            IpAddr::V6(ip) => xxh3_64(&ip.octets()),
+        };
+        Self::lock(&self.shards[(hash % CONNECTED_SHARDS as u64) as usize])
+    }
+
+    fn for_each<F: FnMut(&Connection)>(&self, mut cb: F) {
+        for shard in self.shards.iter() {
+            Self::lock(shard).values().flatten().for_each(&mut cb);
+        }
+    }
+}
+
+///Removes client from connected set once its task is finished, including on panic.
+struct ConnectionGuard<H: RequestHandler> {
+    server: Arc<Server<H>>,
+    addr: SocketAddr,
+    session: session::Session,
 }
 
-impl ErrorKindExt for io::ErrorKind {
-    #[inline(always)]
-    fn is_accept_error_ok(self) -> bool {
-        self == io::ErrorKind::ConnectionAborted ||
-        self == io::ErrorKind::ConnectionRefused ||
-        self == io::ErrorKind::ConnectionReset ||
-        self == io::ErrorKind::NotConnected ||
-        self == io::ErrorKind::WouldBlock ||
-        self == io::ErrorKind::TimedOut ||
-        self == io::ErrorKind::Interrupted
+impl<H: RequestHandler> Drop for ConnectionGuard<H> {
+    fn drop(&mut self) {
+        self.server.handler.close_session(&self.session);
+        let ip = self.addr.ip();
+        let mut shard = self.server.connected.shard(&ip);
+        if let Entry::Occupied(mut entry) = shard.entry(ip) {
+            entry.get_mut().retain(|connection| connection.addr != self.addr);
+            if entry.get().is_empty() {
+                entry.remove();
+            }
+        }
+        drop(shard);
+        METRICS.connection_close();
     }
 }
 
-pub struct Tcp {
-    server: Arc<Server>,
+#[derive(Clone)]
+///Options of TCP transport.
+pub struct Options {
+    ///Address on which connections are accepted.
+    pub bind: IpAddr,
+    ///Number of simultaneous connections from single IP, over which new connections are refused. 0 disables it.
+    pub max_connections_per_ip: usize,
+    ///Number of consecutive invalid frames, after which client is disconnected. 0 disables it.
+    pub max_invalid_frames: usize,
+    ///Interval without reads from client, after which server pings it. Writes, which client doesn't accept within it, fail as well. Disabled if `None`.
+    pub keepalive: Option<Duration>,
+    ///Size in bytes from which responses are compressed, once client negotiates it.
+    pub compression_threshold: usize,
+    ///Keys of Noise encryption, which every client must use if set.
+    pub noise: Option<noise::Config>,
+    ///Token, which `connections` and `kick` require within params. They are rejected if `None`.
+    pub admin_token: Option<String>,
+}
+
+///Request sent by server to idle client.
+const KEEPALIVE: &[u8] = br#"{"jsonrpc":"2.0","method":"ping","id":"keepalive"}"#;
+///Starts compressed response.
+const COMPRESSED_TAG: u8 = 0x01;
+const COMPRESSION_LEVEL: i32 = 3;
+
+///Number of unanswered keepalive pings, after which client is considered dead.
+const MAX_UNANSWERED_KEEPALIVE: usize = 2;
+
+fn is_present<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    <serde::de::IgnoredAny as serde::Deserialize>::deserialize(deserializer).map(|_| true)
+}
+
+#[derive(serde::Deserialize)]
+///Used to recover id of frame, which is not valid JSON-RPC request, or to recognize client's response.
+struct FrameInfo {
+    #[serde(default)]
+    id: Option<Id>,
+    #[serde(default, deserialize_with = "is_present")]
+    result: bool,
+    #[serde(default, deserialize_with = "is_present")]
+    error: bool,
+}
+
+pub struct Tcp<H: RequestHandler = Handler> {
+    server: Arc<Server<H>>,
 }
 
-impl Tcp {
+impl<H: RequestHandler> Tcp<H> {
     #[inline]
-    pub fn new(port: u16, db: db::DbView) -> Self {
+    pub fn new(port: u16, options: Options, handler: H) -> Self {
         Self {
-            server: Arc::new(Server::new(port, db)),
+            server: Arc::new(Server::new(port, options, handler)),
         }
     }
 
@@ -45,86 +164,394 @@ impl Tcp {
     pub fn start(&self) -> impl Future<Output=bool> {
         self.server.clone().start()
     }
+
+    ///Processes request outside of any client connection, within its own short lived session.
+    ///
+    ///Admin methods are available the same way as to connected clients.
+    pub async fn execute(&self, mut request: Request<'_>) -> Response {
+        let cid = generate_correlation_id();
+        if let Some(params) = request.params.as_mut() {
+            if protocol::correlation_id(params).is_none() {
+                params.correlation_id = Some(cid.into());
+            }
+        }
+
+        match self.server.handle_admin_request(&request) {
+            Some(response) => response,
+            None => {
+                let (session, _outbox) = session::Session::new();
+                let response = self.server.handler.handle_request(&session, request).await;
+                self.server.handler.close_session(&session);
+                response
+            }
+        }
+    }
 }
 
-pub struct Server {
+impl<H: RequestHandler> Clone for Tcp<H> {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self {
+            server: self.server.clone(),
+        }
+    }
+}
+
+pub struct Server<H: RequestHandler = Handler> {
     port: u16,
-    db: db::DbView,
-    connected: tokio::sync::RwLock<HashSet<std::net::IpAddr>>,
+    options: Options,
+    handler: H,
+    connected: Connected,
 }
 
-impl Server {
-    pub fn new(port: u16, db: db::DbView) -> Self {
+impl<H: RequestHandler> Server<H> {
+    pub fn new(port: u16, options: Options, handler: H) -> Self {
         Self {
             port,
-            db,
-            connected: tokio::sync::RwLock::new(HashSet::new()),
+            options,
+            handler,
+            connected: Connected::new(),
+        }
+    }
+
+    fn connections_response(&self, id: Option<Id>) -> Response {
+        let mut result = Vec::new();
+
+        self.connected.for_each(|connection| {
+            let mut info = serde_json::Map::with_capacity(4);
+            info.insert("addr".to_owned(), connection.addr.to_string().into());
+            info.insert("connected_at".to_owned(), unix_time_ms(connection.connected_at).into());
+            info.insert("requests".to_owned(), connection.stats.requests.load(Ordering::Relaxed).into());
+            info.insert("last_activity".to_owned(), connection.stats.last_activity.load(Ordering::Relaxed).into());
+            result.push(serde_json::Value::Object(info));
+        });
+
+        let mut payload = serde_json::Map::with_capacity(1);
+        payload.insert(RESULT.to_owned(), result.into());
+        Response::result(Version::V2, payload.into(), id)
+    }
+
+    ///Kicks connection with address, or every connection of IP.
+    fn kick_response(&self, request: &Request<'_>) -> Response {
+        let (ip, addr) = match request.params.as_ref().map_or(Field::Missing, |params| params.field(ID)) {
+            Field::Str(addr) => match addr.parse::<SocketAddr>() {
+                Ok(addr) => (addr.ip(), Some(addr)),
+                Err(_) => match addr.parse::<IpAddr>() {
+                    Ok(ip) => (ip, None),
+                    Err(_) => return invalid_req("Params field 'id' must be address of connection", request.id.clone()),
+                },
+            },
+            Field::Other(_) => return invalid_req("Params field 'id' must be a string", request.id.clone()),
+            Field::Missing => return invalid_req("Params is missing field 'id'", request.id.clone()),
+        };
+
+        let mut is_kicked = false;
+        if let Some(connections) = self.connected.shard(&ip).get(&ip) {
+            for connection in connections.iter().filter(|connection| addr.is_none_or(|addr| connection.addr == addr)) {
+                info!(peer: connection.addr, "Kicking client");
+                connection.stats.kick.notify_one();
+                is_kicked = true;
+            }
         }
+
+        let mut payload = serde_json::Map::with_capacity(1);
+        payload.insert(RESULT.to_owned(), is_kicked.into());
+        Response::result(Version::V2, payload.into(), request.id.clone())
     }
 
-    pub async fn handle_client(self: Arc<Self>, socket: TcpStream, addr: std::net::SocketAddr) {
-        let handler = Handler::new(self.db.clone());
+    ///Handles requests for connection management, returning back request otherwise.
+    fn handle_admin_request(&self, request: &Request<'_>) -> Option<Response> {
+        let method = xxh3_64(request.method.as_str().as_bytes());
+        if method != CONNECTIONS && method != KICK {
+            return None;
+        }
+        if let Err(response) = authorize(self.options.admin_token.as_deref(), request.params.as_ref(), &request.id) {
+            return Some(response);
+        }
+
+        match method {
+            CONNECTIONS => Some(self.connections_response(request.id.clone())),
+            _ => Some(self.kick_response(request)),
+        }
+    }
+
+    ///Reads rest of the frame, once client starts sending it.
+    async fn read_frame<S: AsyncRead + Unpin>(socket: &mut BufReader<S>) -> io::Result<pool::Buffer> {
+        let mut read_buf = pool::get();
+        socket.read_until(EOT, &mut read_buf).await?;
+        Ok(read_buf)
+    }
 
-        let mut serde_buf = Vec::<u8>::new();
-        let mut read_buf = Vec::new();
+    ///Limits write to `timeout`, failing it with `TimedOut` once client stops accepting data.
+    async fn write_within<F: Future<Output = io::Result<()>>>(timeout: Option<Duration>, write: F) -> io::Result<()> {
+        match timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, write).await {
+                Ok(result) => result,
+                Err(_) => Err(io::ErrorKind::TimedOut.into()),
+            },
+            None => write.await,
+        }
+    }
+
+    ///Writes all responses, using as few syscalls as possible.
+    async fn write_responses<S: AsyncWrite + Unpin>(socket: &mut S, responses: &[pool::Buffer]) -> io::Result<()> {
+        let mut slices: Vec<IoSlice<'_>> = responses.iter().map(|response| IoSlice::new(response)).collect();
+        let mut slices = &mut slices[..];
+
+        while !slices.is_empty() {
+            match socket.write_vectored(slices).await? {
+                0 => return Err(io::ErrorKind::WriteZero.into()),
+                written => IoSlice::advance_slices(&mut slices, written),
+            }
+        }
+
+        Ok(())
+    }
+
+    ///Creates error response to frame, which is not valid request.
+    ///
+    ///Returns `None` if frame is a response, which client sends to keepalive ping.
+    fn invalid_frame_response(frame: &[u8], error: &serde_json::Error) -> Option<Response> {
+        match error.classify() {
+            serde_json::error::Category::Data => match serde_json::from_slice::<FrameInfo>(frame) {
+                Ok(info) if info.result || info.error => None,
+                Ok(info) => Some(Response::error(Version::V2, Error::from_code(ErrorCode::InvalidRequest), info.id)),
+                Err(_) => Some(Response::error(Version::V2, Error::from_code(ErrorCode::InvalidRequest), None)),
+            },
+            _ => Some(Response::error(Version::V2, Error::from_code(ErrorCode::ParseError), None)),
+        }
+    }
+
+    fn serialize(response: &Response) -> pool::Buffer {
+        let mut serde_buf = pool::get();
+        match serde_json::to_writer(&mut *serde_buf, response) {
+            Ok(_) => (),
+            Err(_) => unreachable!(),
+        };
+        serde_buf
+    }
+
+    ///Serializes response, compressing it if client negotiated it and response is large enough.
+    fn encode(&self, session: &session::Session, response: &Response) -> pool::Buffer {
+        let serialized = Self::serialize(response);
+        if !session.has(session::feature::ZSTD) || serialized.len() < self.options.compression_threshold {
+            return serialized;
+        }
+
+        match zstd::block::compress(&serialized, COMPRESSION_LEVEL) {
+            Ok(compressed) => match u32::try_from(compressed.len()) {
+                Ok(len) => {
+                    let mut frame = pool::get();
+                    frame.push(COMPRESSED_TAG);
+                    frame.extend_from_slice(&len.to_be_bytes());
+                    frame.extend_from_slice(&compressed);
+                    frame
+                },
+                Err(_) => serialized,
+            },
+            Err(_error) => {
+                trace!("Unable to compress response: {}", _error);
+                serialized
+            }
+        }
+    }
+
+    async fn handle_client<S: AsyncRead + AsyncWrite + Unpin>(self: Arc<Self>, socket: S, addr: SocketAddr, stats: Arc<ConnectionStats>, guard: ConnectionGuard<H>, mut outbox: tokio::sync::mpsc::Receiver<session::Message>) {
+        let session = &guard.session;
         let mut socket = BufReader::new(socket);
+        let mut pending = Vec::new();
+        let mut invalid_frames = 0;
+        let mut unanswered_keepalive = 0;
+        let keepalive = self.options.keepalive;
+        //Only reads from client postpone ping, so that client receiving notifications is still checked.
+        let mut keepalive_at = tokio::time::Instant::now() + keepalive.unwrap_or_default();
 
         loop {
-            match socket.read_until(EOT, &mut read_buf).await {
-                Ok(0) => {
-                    trace!("{}: TCP disconnect", addr);
+            //Pipelined requests are answered together, once there is no complete frame left to process.
+            if !pending.is_empty() && (pending.len() >= MAX_PENDING_RESPONSES || !socket.buffer().contains(&EOT)) {
+                if let Err(_error) = Self::write_within(keepalive, Self::write_responses(socket.get_mut(), &pending)).await {
+                    trace!(peer: addr, "Unable to send response: {}", _error);
+                }
+                pending.clear();
+            }
+
+            //Waiting for data is cancel safe, as it stays in buffer.
+            let is_ready = tokio::select! {
+                read = socket.fill_buf() => read.map(|buf| !buf.is_empty()),
+                _ = stats.kick.notified() => {
+                    trace!(peer: addr, "Kicked");
                     break;
                 },
-                Ok(_) => (),
+                _ = tokio::time::sleep_until(keepalive_at), if keepalive.is_some() => {
+                    if unanswered_keepalive >= MAX_UNANSWERED_KEEPALIVE {
+                        info!(peer: addr, "Disconnecting client not answering to keepalive");
+                        break;
+                    }
+
+                    if let Err(_error) = Self::write_within(keepalive, socket.get_mut().write_all(KEEPALIVE)).await {
+                        trace!(peer: addr, "Unable to send keepalive: {}", _error);
+                        break;
+                    }
+                    unanswered_keepalive += 1;
+                    keepalive_at = tokio::time::Instant::now() + keepalive.unwrap_or_default();
+                    continue;
+                },
+                //Session keeps sender, so channel is never closed.
+                Some(message) = outbox.recv() => {
+                    if let Err(_error) = Self::write_within(keepalive, socket.get_mut().write_all(&message)).await {
+                        trace!(peer: addr, "Unable to send message: {}", _error);
+                        break;
+                    }
+                    continue;
+                },
+            };
+
+            let read = match is_ready {
+                Ok(true) => tokio::select! {
+                    read = Self::read_frame(&mut socket) => read,
+                    _ = stats.kick.notified() => {
+                        trace!(peer: addr, "Kicked");
+                        break;
+                    }
+                },
+                Ok(false) => {
+                    trace!(peer: addr, "TCP disconnect");
+                    break;
+                },
+                Err(error) => Err(error),
+            };
+
+            let read_buf = match read {
+                Ok(read_buf) => read_buf,
                 Err(_error) => {
-                    trace!("{}: TCP error: {}", addr, _error);
+                    trace!(peer: addr, "TCP error: {}", _error);
                     break;
                 }
             };
+            unanswered_keepalive = 0;
+            keepalive_at = tokio::time::Instant::now() + keepalive.unwrap_or_default();
 
-            match serde_json::from_slice::<Request>(&read_buf) {
-                Ok(request) => {
-                    if request.is_notification() {
-                        //Nothing to notify about right now.
-                        continue;
-                    }
+            //Frame includes EOT, unless client disconnected without sending it.
+            let frame = match read_buf.last() {
+                Some(&EOT) => &read_buf[..read_buf.len() - 1],
+                _ => &read_buf[..],
+            };
+
+            let span = tracing::info_span!("request", peer = %addr, cid = tracing::field::Empty, method = tracing::field::Empty);
+            let request = {
+                let _parse = tracing::info_span!(parent: &span, "parse").entered();
+                serde_json::from_slice::<Request>(frame)
+            };
 
-                    let response = handler.handle_request(request).await;
-                    match serde_json::to_writer(&mut serde_buf, &response) {
-                        Ok(_) => (),
-                        Err(_) => unreachable!(),
+            match request {
+                Ok(mut request) => {
+                    invalid_frames = 0;
+
+                    //Notifications are processed, but their responses are never sent.
+                    let is_notification = request.is_notification();
+
+                    //Generated id is passed to handler within params, but only client's own id is echoed back.
+                    let (cid, is_echo) = match request.params.as_mut() {
+                        Some(params) => match protocol::correlation_id(params) {
+                            Some(cid) => (cid.to_owned(), true),
+                            None => {
+                                let cid = generate_correlation_id();
+                                params.correlation_id = Some(cid.clone().into());
+                                (cid, false)
+                            }
+                        },
+                        None => (generate_correlation_id(), false),
+                    };
+
+                    let method = request.method;
+                    let is_chunked = session.has(session::feature::CHUNKED) || request.params.as_ref().is_some_and(chunk::is_requested);
+                    span.record("cid", cid.as_str());
+                    span.record("method", method.as_str());
+
+                    stats.requests.fetch_add(1, Ordering::Relaxed);
+                    stats.last_activity.store(unix_time_ms(SystemTime::now()), Ordering::Relaxed);
+
+                    let start = Instant::now();
+                    let admin_response = match is_notification {
+                        true => None,
+                        false => self.handle_admin_request(&request),
                     };
+                    let is_admin = admin_response.is_some();
+                    let mut response = match admin_response {
+                        Some(response) => response,
+                        None => self.handler.handle_request(session, request).instrument(tracing::info_span!(parent: &span, "dispatch")).await,
+                    };
+                    info!(peer: addr, cid: Some(&cid), method: method.as_str(), duration: start.elapsed(), "Request processed");
 
-                    match BufReader::get_mut(&mut socket).write_all(&serde_buf).await {
-                        Ok(_) => (),
-                        Err(_error) => {
-                            trace!("{}: Unable to send response: {}", addr, _error);
+                    if is_notification {
+                        continue;
+                    }
+                    if !is_admin && self.handler.is_lost(method.as_str()) {
+                        trace!(peer: addr, cid: Some(&cid), "Dropping response");
+                        continue;
+                    }
+
+                    if is_echo {
+                        if let Ok(serde_json::Value::Object(result)) = &mut response.payload {
+                            result.insert(protocol::CORRELATION_ID.to_owned(), cid.into());
                         }
                     }
 
-                    serde_buf.clear()
+                    match is_chunked.then(|| chunk::take_data(&mut response)).flatten() {
+                        Some(data) => {
+                            let mut chunks = chunk::split(&data).peekable();
+                            let mut seq = 0;
+                            while let Some(data) = chunks.next() {
+                                let chunk = chunk::response(&response, seq, chunks.peek().is_none(), data);
+                                pending.push(self.encode(session, &chunk));
+                                seq += 1;
+
+                                if pending.len() >= MAX_PENDING_RESPONSES {
+                                    if let Err(_error) = Self::write_within(keepalive, Self::write_responses(socket.get_mut(), &pending)).await {
+                                        trace!(peer: addr, "Unable to send response: {}", _error);
+                                    }
+                                    pending.clear();
+                                }
+                            }
+                        },
+                        None => {
+                            let _serialize = tracing::info_span!(parent: &span, "serialize").entered();
+                            pending.push(self.encode(session, &response));
+                        },
+                    }
                 },
-                Err(_error) => {
-                    trace!("{}: Invalid request: {}", addr, _error);
+                Err(error) => {
+                    let response = match Self::invalid_frame_response(frame, &error) {
+                        Some(response) => response,
+                        None => continue,
+                    };
+                    trace!(peer: addr, "Invalid request: {}", error);
+                    pending.push(Self::serialize(&response));
+
+                    invalid_frames += 1;
+                    if self.options.max_invalid_frames > 0 && invalid_frames >= self.options.max_invalid_frames {
+                        warn!(peer: addr, "Disconnecting after {} consecutive invalid frames", invalid_frames);
+                        if let Err(_error) = Self::write_within(keepalive, Self::write_responses(socket.get_mut(), &pending)).await {
+                            trace!(peer: addr, "Unable to send response: {}", _error);
+                        }
+                        break;
+                    }
                 },
             }
-
-            read_buf.clear();
         }
-
-        self.connected.write().await.remove(&addr.ip());
     }
 
     pub async fn start(self: Arc<Self>) -> bool {
-        let serv = match TcpListener::bind((LOCAL_HOST, self.port)).await {
+        let bind = self.options.bind;
+        let serv = match TcpListener::bind((bind, self.port)).await {
             Ok(serv) => serv,
             Err(error) => {
-                warn!("Unable to start TCP server on {}:{}. Error: {}", LOCAL_HOST, self.port, error);
+                warn!("Unable to start TCP server on {}:{}. Error: {}", bind, self.port, error);
                 return false;
             }
         };
 
-        info!("Start TCP on {}:{}", LOCAL_HOST, self.port);
+        info!("Start TCP on {}:{}", bind, self.port);
 
         loop {
             let (socket, addr) = match serv.accept().await {
@@ -141,14 +568,521 @@ impl Server {
                 }
             };
 
-            if self.connected.write().await.insert(addr.ip()) {
-                trace!("{}: Connected over TCP", addr);
+            let mut shard = self.connected.shard(&addr.ip());
+            let connections = shard.entry(addr.ip()).or_default();
+            match self.options.max_connections_per_ip > 0 && connections.len() >= self.options.max_connections_per_ip {
+                true => {
+                    drop(shard);
+                    drop(socket);
+                    trace!(peer: addr, "Already connected over TCP");
+                },
+                false => {
+                    trace!(peer: addr, "Connected over TCP");
+
+                    let now = SystemTime::now();
+                    let stats = Arc::new(ConnectionStats::default());
+                    stats.last_activity.store(unix_time_ms(now), Ordering::Relaxed);
+                    connections.push(Connection {
+                        addr,
+                        connected_at: now,
+                        stats: stats.clone(),
+                    });
+                    drop(shard);
 
-                tokio::spawn(self.clone().handle_client(socket, addr));
-            } else {
-                drop(socket);
-                trace!("{}: Already connected over TCP", addr);
+                    METRICS.connection_open();
+                    let (session, outbox) = session::Session::new();
+                    let guard = ConnectionGuard {
+                        server: self.clone(),
+                        addr,
+                        session,
+                    };
+                    let server = self.clone();
+                    tokio::spawn(async move {
+                        match server.options.noise.as_ref() {
+                            Some(config) => match noise::accept(socket, config).await {
+                                Ok(socket) => server.clone().handle_client(socket, addr, stats, guard, outbox).await,
+                                Err(error) => warn!(peer: addr, "Noise handshake failed: {}", error),
+                            },
+                            None => server.clone().handle_client(socket, addr, stats, guard, outbox).await,
+                        }
+                    });
+                }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::pin::Pin;
+    use core::task::{Context, Poll};
+    use tokio::io::{AsyncReadExt, DuplexStream};
+
+    fn options() -> Options {
+        Options {
+            bind: IpAddr::from([127, 0, 0, 1]),
+            max_connections_per_ip: 0,
+            max_invalid_frames: 0,
+            keepalive: None,
+            compression_threshold: usize::MAX,
+            noise: None,
+            admin_token: Some("secret".to_owned()),
+        }
+    }
+
+    ///Answers every request with its method.
+    struct Echo;
+
+    impl RequestHandler for Echo {
+        async fn handle_request(&self, _session: &session::Session, request: Request<'_>) -> Response {
+            let mut payload = serde_json::Map::with_capacity(1);
+            payload.insert("method".to_owned(), request.method.as_str().into());
+            Response::result(Version::V2, payload.into(), request.id)
+        }
+    }
+
+    ///Answers every request with correlation id, passed to handler.
+    struct Cid;
+
+    impl RequestHandler for Cid {
+        async fn handle_request(&self, _session: &session::Session, request: Request<'_>) -> Response {
+            let cid = request.params.as_ref().and_then(protocol::correlation_id).unwrap_or_default();
+            let mut payload = serde_json::Map::with_capacity(1);
+            payload.insert("seen".to_owned(), cid.into());
+            Response::result(Version::V2, payload.into(), request.id)
+        }
+    }
+
+    ///Serves connection from `addr` the same way as accepted one.
+    fn connect<H: RequestHandler>(server: &Arc<Server<H>>, addr: &str) -> (DuplexStream, tokio::task::JoinHandle<()>) {
+        let (client, _outbox, task) = connect_with_outbox(server, addr);
+        (client, task)
+    }
+
+    ///Serves connection as `connect`, returning sender of server's own messages to client.
+    fn connect_with_outbox<H: RequestHandler>(server: &Arc<Server<H>>, addr: &str) -> (DuplexStream, session::Outbox, tokio::task::JoinHandle<()>) {
+        let addr = addr.parse::<SocketAddr>().expect("addr");
+        let (client, socket) = tokio::io::duplex(64 * 1024);
+        let stats = Arc::new(ConnectionStats::default());
+        server.connected.shard(&addr.ip()).entry(addr.ip()).or_default().push(Connection {
+            addr,
+            connected_at: SystemTime::now(),
+            stats: stats.clone(),
+        });
+
+        let (session, outbox) = session::Session::new();
+        let sender = session.outbox().clone();
+        let guard = ConnectionGuard {
+            server: server.clone(),
+            addr,
+            session,
+        };
+        (client, sender, tokio::spawn(server.clone().handle_client(socket, addr, stats, guard, outbox)))
+    }
+
+    ///Reads every message sent by server until it closes connection.
+    async fn read_messages(client: &mut DuplexStream) -> Vec<serde_json::Value> {
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.expect("read");
+
+        let mut messages = Vec::new();
+        while let Some(message) = protocol::take_message(&mut buf).expect("message") {
+            messages.push(message);
+        }
+        assert!(buf.is_empty());
+        messages
+    }
+
+    #[tokio::test]
+    async fn should_serve_custom_handler_over_eot_frames() {
+        let server = Arc::new(Server::new(0, options(), Echo));
+        let (mut client, task) = connect(&server, "127.0.0.1:1000");
+
+        client.write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"first\",\"id\":1}\x04{\"jsonrpc\":\"2.0\",\"method\":\"second\",\"id\":2}").await.expect("write");
+        client.shutdown().await.expect("shutdown");
+
+        assert_eq!(read_messages(&mut client).await, [
+            serde_json::json!({"jsonrpc": "2.0", "result": {"method": "first"}, "id": 1}),
+            serde_json::json!({"jsonrpc": "2.0", "result": {"method": "second"}, "id": 2}),
+        ]);
+        task.await.expect("task");
+    }
+
+    #[tokio::test]
+    async fn should_execute_request_with_custom_handler() {
+        let tcp = Tcp::new(0, options(), Echo);
+        let request = serde_json::from_str::<Request>(r#"{"jsonrpc":"2.0","method":"get_config","params":{"id":"key"},"id":1}"#).expect("request");
+
+        let response = tcp.execute(request).await;
+        assert_eq!(serde_json::to_value(&response).expect("value"), serde_json::json!({"jsonrpc": "2.0", "result": {"method": "get_config"}, "id": 1}));
+    }
+
+    #[tokio::test]
+    async fn should_echo_only_client_correlation_id() {
+        let server = Arc::new(Server::new(0, options(), Cid));
+        let (mut client, task) = connect(&server, "127.0.0.1:1000");
+
+        client.write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"get_config\",\"params\":{\"correlation_id\":\"trace-1\"},\"id\":1}\x04").await.expect("write");
+        client.write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"get_config\",\"params\":{},\"id\":2}\x04").await.expect("write");
+        client.shutdown().await.expect("shutdown");
+
+        let messages = read_messages(&mut client).await;
+        task.await.expect("task");
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0]["result"], serde_json::json!({"seen": "trace-1", "correlation_id": "trace-1"}));
+
+        let generated = messages[1]["result"]["seen"].as_str().expect("generated id");
+        assert_eq!(generated.len(), 16);
+        assert!(messages[1]["result"].get(protocol::CORRELATION_ID).is_none());
+    }
+
+    #[tokio::test]
+    async fn should_pass_generated_correlation_id_to_executed_request() {
+        let tcp = Tcp::new(0, options(), Cid);
+        let request = serde_json::from_str::<Request>(r#"{"jsonrpc":"2.0","method":"get_config","params":{},"id":1}"#).expect("request");
+
+        let response = serde_json::to_value(&tcp.execute(request).await).expect("value");
+        assert_eq!(response["result"]["seen"].as_str().map(str::len), Some(16));
+    }
+
+    fn admin_request<H: RequestHandler>(server: &Server<H>, request: &str) -> Option<serde_json::Value> {
+        let request = serde_json::from_str::<Request>(request).expect("request");
+        server.handle_admin_request(&request).map(|response| serde_json::to_value(&response).expect("value"))
+    }
+
+    #[tokio::test]
+    async fn should_list_and_kick_connections() {
+        let server = Arc::new(Server::new(0, options(), Echo));
+        let (_first, first_task) = connect(&server, "127.0.0.1:1000");
+        let (_second, second_task) = connect(&server, "127.0.0.1:1001");
+        let (_other, other_task) = connect(&server, "127.0.0.2:1000");
+
+        let connections = admin_request(&server, r#"{"jsonrpc":"2.0","method":"connections","params":{"admin_token":"secret"},"id":1}"#).expect("connections");
+        let mut addrs: Vec<&str> = connections["result"]["result"].as_array().expect("connections").iter().map(|info| info["addr"].as_str().expect("addr")).collect();
+        addrs.sort_unstable();
+        assert_eq!(addrs, ["127.0.0.1:1000", "127.0.0.1:1001", "127.0.0.2:1000"]);
+
+        let kicked = admin_request(&server, r#"{"jsonrpc":"2.0","method":"kick","params":{"admin_token":"secret","id":"127.0.0.2:1000"},"id":2}"#).expect("kick");
+        assert_eq!(kicked["result"]["result"], true);
+        other_task.await.expect("task");
+
+        let kicked = admin_request(&server, r#"{"jsonrpc":"2.0","method":"kick","params":{"admin_token":"secret","id":"127.0.0.1"},"id":3}"#).expect("kick");
+        assert_eq!(kicked["result"]["result"], true);
+        first_task.await.expect("task");
+        second_task.await.expect("task");
+
+        let connections = admin_request(&server, r#"{"jsonrpc":"2.0","method":"connections","params":{"admin_token":"secret"},"id":4}"#).expect("connections");
+        assert_eq!(connections["result"]["result"], serde_json::json!([]));
+
+        let kicked = admin_request(&server, r#"{"jsonrpc":"2.0","method":"kick","params":{"admin_token":"secret","id":"127.0.0.1:1000"},"id":5}"#).expect("kick");
+        assert_eq!(kicked["result"]["result"], false);
+    }
+
+    #[test]
+    fn should_reject_kick_without_address() {
+        let server = Server::new(0, options(), Echo);
+
+        let response = admin_request(&server, r#"{"jsonrpc":"2.0","method":"kick","params":{"admin_token":"secret","id":"client"},"id":1}"#).expect("kick");
+        assert_eq!(response["error"]["code"], -32600);
+        let response = admin_request(&server, r#"{"jsonrpc":"2.0","method":"kick","params":{"admin_token":"secret"},"id":1}"#).expect("kick");
+        assert_eq!(response["error"]["code"], -32600);
+        assert!(admin_request(&server, r#"{"jsonrpc":"2.0","method":"get_config","params":{"id":"key"},"id":1}"#).is_none());
+    }
+
+    #[tokio::test]
+    async fn should_reject_admin_requests_without_token() {
+        let server = Arc::new(Server::new(0, options(), Echo));
+        let (_client, task) = connect(&server, "127.0.0.1:1000");
+
+        for request in [
+            r#"{"jsonrpc":"2.0","method":"connections","id":1}"#,
+            r#"{"jsonrpc":"2.0","method":"connections","params":{"admin_token":"secreT"},"id":1}"#,
+            r#"{"jsonrpc":"2.0","method":"kick","params":{"id":"127.0.0.1"},"id":1}"#,
+            r#"{"jsonrpc":"2.0","method":"kick","params":{"admin_token":1,"id":"127.0.0.1"},"id":1}"#,
+        ] {
+            let response = admin_request(&server, request).expect("admin");
+            assert_eq!(response["error"]["code"], super::super::int_err::UNAUTHORIZED);
+            assert_eq!(response["error"]["data"], "Params field 'admin_token' must be admin token");
+        }
+        assert_eq!(connected_addrs(&server), ["127.0.0.1:1000".parse::<SocketAddr>().expect("addr")]);
+
+        let server = Arc::new(Server::new(0, Options { admin_token: None, ..options() }, Echo));
+        let response = admin_request(&server, r#"{"jsonrpc":"2.0","method":"connections","params":{"admin_token":"secret"},"id":1}"#).expect("connections");
+        assert_eq!(response["error"]["data"], "Method requires admin token, which is not configured");
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn should_accept_admin_requests_with_token() {
+        let server = Arc::new(Server::new(0, options(), Echo));
+        let (_client, task) = connect(&server, "127.0.0.1:1000");
+
+        let connections = admin_request(&server, r#"{"jsonrpc":"2.0","method":"connections","params":{"admin_token":"secret"},"id":1}"#).expect("connections");
+        assert_eq!(connections["result"]["result"][0]["addr"], "127.0.0.1:1000");
+        let kicked = admin_request(&server, r#"{"jsonrpc":"2.0","method":"kick","params":{"admin_token":"secret","id":"127.0.0.1"},"id":2}"#).expect("kick");
+        assert_eq!(kicked["result"]["result"], true);
+        task.await.expect("task");
+    }
+
+    ///Writer accepting only few bytes per write, across as many slices as needed.
+    #[derive(Default)]
+    struct Partial {
+        written: Vec<u8>,
+        writes: usize,
+    }
+
+    impl AsyncWrite for Partial {
+        fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            self.poll_write_vectored(cx, &[IoSlice::new(buf)])
+        }
+
+        fn poll_write_vectored(mut self: Pin<&mut Self>, _: &mut Context<'_>, bufs: &[IoSlice<'_>]) -> Poll<io::Result<usize>> {
+            let mut written = 0;
+            for buf in bufs {
+                let len = buf.len().min(3 - written);
+                self.written.extend_from_slice(&buf[..len]);
+                written += len;
+                if written == 3 {
+                    break;
+                }
+            }
+            self.writes += 1;
+            Poll::Ready(Ok(written))
+        }
+
+        #[inline]
+        fn is_write_vectored(&self) -> bool {
+            true
+        }
+
+        #[inline]
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        #[inline]
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn should_write_every_response_across_partial_writes() {
+        let mut responses = Vec::new();
+        for response in [&b"first"[..], b"", b"second", b"third"] {
+            let mut buffer = pool::get();
+            buffer.extend_from_slice(response);
+            responses.push(buffer);
+        }
+
+        let mut socket = Partial::default();
+        Server::<Echo>::write_responses(&mut socket, &responses).await.expect("write");
+        assert_eq!(socket.written, b"firstsecondthird");
+        assert_eq!(socket.writes, 6);
+    }
+
+    fn connected_addrs<H: RequestHandler>(server: &Server<H>) -> Vec<SocketAddr> {
+        let mut addrs = Vec::new();
+        server.connected.for_each(|connection| addrs.push(connection.addr));
+        addrs.sort_unstable();
+        addrs
+    }
+
+    #[tokio::test]
+    async fn should_remove_only_disconnected_client() {
+        let server = Arc::new(Server::new(0, options(), Echo));
+        let (mut first, first_task) = connect(&server, "127.0.0.1:1000");
+        let (second, second_task) = connect(&server, "127.0.0.1:1001");
+        assert_eq!(connected_addrs(&server).len(), 2);
+
+        first.shutdown().await.expect("shutdown");
+        first_task.await.expect("task");
+        assert_eq!(connected_addrs(&server), ["127.0.0.1:1001".parse::<SocketAddr>().expect("addr")]);
+
+        drop(second);
+        second_task.await.expect("task");
+        assert!(connected_addrs(&server).is_empty());
+        assert!(server.connected.shard(&IpAddr::from([127, 0, 0, 1])).is_empty());
+    }
+
+    #[tokio::test]
+    async fn should_remove_client_once_its_task_panics() {
+        struct Panic;
+
+        impl RequestHandler for Panic {
+            async fn handle_request(&self, _session: &session::Session, _request: Request<'_>) -> Response {
+                panic!("handler failed")
+            }
+        }
+
+        let server = Arc::new(Server::new(0, options(), Panic));
+        let (mut client, task) = connect(&server, "127.0.0.1:1000");
+        client.write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"ping\",\"id\":1}\x04").await.expect("write");
+
+        assert!(task.await.expect_err("panic").is_panic());
+        assert!(connected_addrs(&server).is_empty());
+    }
+
+    fn invalid_frame(frame: &str) -> Option<serde_json::Value> {
+        let error = match serde_json::from_str::<Request>(frame) {
+            Ok(_) => panic!("frame must be invalid"),
+            Err(error) => error,
+        };
+        Server::<Echo>::invalid_frame_response(frame.as_bytes(), &error).map(|response| serde_json::to_value(&response).expect("value"))
+    }
+
+    #[test]
+    fn should_answer_invalid_frames_with_errors() {
+        let response = invalid_frame(r#"{"jsonrpc":"2.0","method":"config""#).expect("response");
+        assert_eq!(response["error"]["code"], -32700);
+        assert_eq!(response["id"], serde_json::Value::Null);
+
+        let response = invalid_frame(r#"{"jsonrpc":"2.0","method":1,"id":"request"}"#).expect("response");
+        assert_eq!(response["error"]["code"], -32600);
+        assert_eq!(response["id"], "request");
+
+        let response = invalid_frame(r#""request""#).expect("response");
+        assert_eq!(response["error"]["code"], -32600);
+        assert_eq!(response["id"], serde_json::Value::Null);
+
+        assert_eq!(invalid_frame(r#"{"jsonrpc":"2.0","result":null,"id":"keepalive"}"#), None);
+    }
+
+    #[tokio::test]
+    async fn should_disconnect_after_consecutive_invalid_frames() {
+        let mut options = options();
+        options.max_invalid_frames = 2;
+        let server = Arc::new(Server::new(0, options, Echo));
+        let (mut client, task) = connect(&server, "127.0.0.1:1000");
+
+        client.write_all(b"invalid\x04{\"jsonrpc\":\"2.0\",\"method\":\"ping\",\"id\":1}\x04invalid\x04invalid\x04").await.expect("write");
+        let messages = read_messages(&mut client).await;
+        task.await.expect("task");
+
+        let codes: Vec<&serde_json::Value> = messages.iter().map(|message| message.get("error").map_or(&message["result"], |error| &error["code"])).collect();
+        assert_eq!(codes, [&serde_json::json!(-32700), &serde_json::json!({"method": "ping"}), &serde_json::json!(-32700), &serde_json::json!(-32700)]);
+    }
+
+    #[tokio::test]
+    async fn should_perform_write_notifications_without_response() {
+        let handler = Handler::new(crate::db::Db::temporary().expect("open db").view(), Default::default());
+        let server = Arc::new(Server::new(0, options(), handler));
+        let (mut client, task) = connect(&server, "127.0.0.1:1000");
+
+        client.write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"set_config\",\"params\":{\"id\":\"kept\",\"data\":\"value\"}}\x04").await.expect("write");
+        client.write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"set_config\",\"params\":{\"id\":\"deleted\",\"data\":\"value\"}}\x04").await.expect("write");
+        client.write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"delete_config\",\"params\":{\"id\":\"deleted\"}}\x04").await.expect("write");
+        client.write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"config\",\"params\":{\"id\":\"kept\"}}\x04").await.expect("write");
+        client.write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"config\",\"params\":{\"id\":\"kept\"},\"id\":1}\x04").await.expect("write");
+        client.write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"config\",\"params\":{\"id\":\"deleted\",\"explicit_missing\":true},\"id\":2}").await.expect("write");
+        client.shutdown().await.expect("shutdown");
+
+        assert_eq!(read_messages(&mut client).await, [
+            serde_json::json!({"jsonrpc": "2.0", "result": {"result": "value"}, "id": 1}),
+            serde_json::json!({"jsonrpc": "2.0", "result": {"result": null}, "id": 2}),
+        ]);
+        task.await.expect("task");
+    }
+
+    #[tokio::test]
+    async fn should_disconnect_client_not_answering_keepalive() {
+        let mut options = options();
+        options.keepalive = Some(Duration::from_millis(10));
+        let server = Arc::new(Server::new(0, options, Echo));
+        let (mut client, task) = connect(&server, "127.0.0.1:1000");
+
+        let ping = serde_json::from_slice::<serde_json::Value>(KEEPALIVE).expect("ping");
+        assert_eq!(read_messages(&mut client).await, [ping.clone(), ping]);
+        task.await.expect("task");
+    }
+
+    #[tokio::test]
+    async fn should_disconnect_client_receiving_notifications_without_answering_keepalive() {
+        let mut options = options();
+        options.keepalive = Some(Duration::from_millis(30));
+        let server = Arc::new(Server::new(0, options, Echo));
+        let (mut client, outbox, task) = connect_with_outbox(&server, "127.0.0.1:1000");
+
+        let notify = tokio::spawn(async move {
+            let message: session::Message = Arc::from(&b"{\"jsonrpc\":\"2.0\",\"method\":\"notify\"}"[..]);
+            while outbox.send(message.clone()).await.is_ok() {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        });
+
+        let messages = read_messages(&mut client).await;
+        let ping = serde_json::from_slice::<serde_json::Value>(KEEPALIVE).expect("ping");
+        assert_eq!(messages.iter().filter(|message| **message == ping).count(), MAX_UNANSWERED_KEEPALIVE);
+        task.await.expect("task");
+        notify.abort();
+    }
+
+    #[tokio::test]
+    async fn should_time_out_write_to_client_not_reading() {
+        let mut options = options();
+        options.keepalive = Some(Duration::from_millis(10));
+        let server = Arc::new(Server::new(0, options, Echo));
+        let (client, outbox, task) = connect_with_outbox(&server, "127.0.0.1:1000");
+
+        let message: session::Message = Arc::from(vec![b' '; 128 * 1024]);
+        outbox.send(message).await.expect("send");
+        tokio::time::timeout(Duration::from_secs(1), task).await.expect("disconnect").expect("task");
+        drop(client);
+    }
+
+    #[tokio::test]
+    async fn should_keep_client_answering_keepalive() {
+        let mut options = options();
+        options.keepalive = Some(Duration::from_millis(10));
+        let server = Arc::new(Server::new(0, options, Echo));
+        let (client, task) = connect(&server, "127.0.0.1:1000");
+        let mut client = BufReader::new(client);
+
+        let mut buf = Vec::new();
+        for _ in 0..(MAX_UNANSWERED_KEEPALIVE + 2) {
+            let ping = loop {
+                if let Some(message) = protocol::take_message(&mut buf).expect("message") {
+                    break message;
+                }
+                let read = client.fill_buf().await.expect("read");
+                assert!(!read.is_empty(), "client must stay connected");
+                buf.extend_from_slice(read);
+                let len = read.len();
+                client.consume(len);
+            };
+
+            let answer = protocol::answer_server_request(&ping).expect("ping");
+            client.get_mut().write_all(&answer).await.expect("write");
+        }
+
+        drop(client);
+        task.await.expect("task");
+    }
+
+    #[test]
+    fn should_compress_large_responses_once_negotiated() {
+        let mut options = options();
+        options.compression_threshold = 64;
+        let server = Server::new(0, options, Echo);
+        let (session, _outbox) = session::Session::new();
+
+        let mut payload = serde_json::Map::with_capacity(1);
+        payload.insert(RESULT.to_owned(), "value".repeat(64).into());
+        let large = Response::result(Version::V2, payload.into(), Some(Id::Num(1)));
+        let small = Response::result(Version::V2, Default::default(), Some(Id::Num(2)));
+        let serialized = Server::<Echo>::serialize(&large);
+
+        assert_eq!(&server.encode(&session, &large)[..], &serialized[..]);
+
+        session.negotiate(["zstd"]);
+        assert_eq!(&server.encode(&session, &small)[..], &Server::<Echo>::serialize(&small)[..]);
+
+        let frame = server.encode(&session, &large);
+        assert_eq!(frame[0], COMPRESSED_TAG);
+        let len = u32::from_be_bytes(<[u8; 4]>::try_from(&frame[1..5]).expect("len")) as usize;
+        assert_eq!(len, frame.len() - 5);
+        assert!(len < serialized.len());
+        assert_eq!(zstd::block::decompress(&frame[5..], serialized.len()).expect("decompress"), &serialized[..]);
+    }
+}
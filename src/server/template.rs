@@ -0,0 +1,210 @@
+//! References to other keys within values, resolved on `config` reads.
+//!
+//! Reference `{{ref:other/key}}` is replaced with value of `other/key`, which is resolved recursively.
+//! Client can get value as it is stored by setting `raw: true`.
+//!
+//! Each referenced key is read and resolved once per value, while resolution is limited to `MAX_RESOLVED_SIZE` bytes.
+
+use std::collections::HashMap;
+
+use crate::db;
+
+///Start of reference.
+const START: &str = "{{ref:";
+///End of reference.
+const END: &str = "}}";
+///Maximum depth of nested references.
+const MAX_DEPTH: usize = 16;
+///Maximum number of bytes produced while resolving single value, counted on every level of nesting.
+pub const MAX_RESOLVED_SIZE: usize = 16 * 1024 * 1024;
+
+pub enum Error {
+    ///Reference to key, which is already being resolved.
+    Cycle(String),
+    ///Reference to key, which doesn't exist.
+    Missing(String),
+    ///Nesting is deeper than `MAX_DEPTH`.
+    TooDeep,
+    ///Resolution produces more than `MAX_RESOLVED_SIZE` bytes.
+    TooLarge,
+    Corrupt(String),
+    Db(sled::Error),
+}
+
+impl Error {
+    #[inline]
+    ///Returns description, suitable for client.
+    pub const fn reason(&self) -> &'static str {
+        match self {
+            Error::Cycle(_) => "Cyclic reference",
+            Error::Missing(_) => "Reference to missing key",
+            Error::TooDeep => "References are nested too deep",
+            Error::TooLarge => "Resolved value is too large",
+            Error::Corrupt(_) => "Referenced value is not utf-8",
+            Error::Db(_) => "Unable to read referenced key",
+        }
+    }
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, fmt: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::Cycle(key) | Error::Missing(key) | Error::Corrupt(key) => write!(fmt, "{} '{}'", self.reason(), key),
+            Error::TooDeep | Error::TooLarge => fmt.write_str(self.reason()),
+            Error::Db(error) => write!(fmt, "{}: {}", self.reason(), error),
+        }
+    }
+}
+
+#[inline]
+///Returns whether value contains references.
+pub fn has_refs(value: &[u8]) -> bool {
+    value.windows(START.len()).any(|window| window == START.as_bytes())
+}
+
+///Resolution of references within single value.
+struct Resolver<'a> {
+    db: &'a db::DbView,
+    ///Keys being resolved, from outermost one.
+    stack: Vec<String>,
+    ///Resolved values of referenced keys together with depth of references nested within them.
+    resolved: HashMap<String, (String, usize)>,
+    ///Number of bytes, which can still be produced.
+    budget: usize,
+}
+
+///Appends value to result, as long as it fits into budget.
+fn push(budget: &mut usize, result: &mut String, value: &str) -> Result<(), Error> {
+    match budget.checked_sub(value.len()) {
+        Some(rest) => {
+            *budget = rest;
+            result.push_str(value);
+            Ok(())
+        },
+        None => Err(Error::TooLarge),
+    }
+}
+
+impl Resolver<'_> {
+
+    ///Returns resolved value together with depth of references nested within it.
+    fn resolve_inner(&mut self, value: &str) -> Result<(String, usize), Error> {
+        let mut result = String::with_capacity(value.len().min(self.budget));
+        let mut depth = 0;
+        let mut rest = value;
+
+        while let Some(start) = rest.find(START) {
+            let end = match rest[start + START.len()..].find(END) {
+                Some(end) => start + START.len() + end,
+                //Unterminated reference is left as it is.
+                None => break,
+            };
+            let key = rest[start + START.len()..end].trim();
+
+            if self.stack.iter().any(|resolving| resolving == key) {
+                return Err(Error::Cycle(key.to_owned()));
+            }
+            if self.stack.len() >= MAX_DEPTH {
+                return Err(Error::TooDeep);
+            }
+
+            push(&mut self.budget, &mut result, &rest[..start])?;
+            //Resolved key cannot reach any key of stack, otherwise its resolution would fail, so it can be reused.
+            let nested = match self.resolved.get(key) {
+                Some((referenced, nested)) if self.stack.len() + 1 + nested > MAX_DEPTH => return Err(Error::TooDeep),
+                Some((referenced, nested)) => {
+                    push(&mut self.budget, &mut result, referenced)?;
+                    *nested
+                },
+                None => {
+                    let referenced = match self.db.get_config(key) {
+                        Ok(Some(referenced)) => referenced,
+                        Ok(None) => return Err(Error::Missing(key.to_owned())),
+                        Err(error) => return Err(Error::Db(error)),
+                    };
+                    let referenced = match core::str::from_utf8(&referenced) {
+                        Ok(referenced) => referenced,
+                        Err(_) => return Err(Error::Corrupt(key.to_owned())),
+                    };
+
+                    self.stack.push(key.to_owned());
+                    let (referenced, nested) = self.resolve_inner(referenced)?;
+                    self.stack.pop();
+                    push(&mut self.budget, &mut result, &referenced)?;
+                    self.resolved.insert(key.to_owned(), (referenced, nested));
+                    nested
+                },
+            };
+            depth = depth.max(nested + 1);
+            rest = &rest[end + END.len()..];
+        }
+
+        push(&mut self.budget, &mut result, rest)?;
+        Ok((result, depth))
+    }
+}
+
+///Resolves references within value of key.
+pub fn resolve(db: &db::DbView, key: &str, value: &str) -> Result<String, Error> {
+    let mut resolver = Resolver {
+        db,
+        stack: vec![key.to_owned()],
+        resolved: HashMap::new(),
+        budget: MAX_RESOLVED_SIZE,
+    };
+    resolver.resolve_inner(value).map(|(resolved, _)| resolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_resolve_nested_references() {
+        let db = db::Db::temporary().expect("open db").view();
+        db.config.insert("host", "localhost").expect("insert");
+        db.config.insert("url", "http://{{ref:host}}:{{ref: port}}").expect("insert");
+        db.config.insert("port", "80").expect("insert");
+
+        let resolved = resolve(&db, "service", "{{ref:url}}/{{ref:host}}").ok().expect("resolve");
+        assert_eq!(resolved, "http://localhost:80/localhost");
+    }
+
+    #[test]
+    fn should_reject_cycle() {
+        let db = db::Db::temporary().expect("open db").view();
+        db.config.insert("a", "{{ref:b}}").expect("insert");
+        db.config.insert("b", "{{ref:a}}").expect("insert");
+
+        assert!(matches!(resolve(&db, "a", "{{ref:b}}"), Err(Error::Cycle(key)) if key == "a"));
+    }
+
+    #[test]
+    fn should_reject_too_deep_reuse_of_resolved_key() {
+        let db = db::Db::temporary().expect("open db").view();
+        for level in 0..MAX_DEPTH - 1 {
+            db.config.insert(format!("key{}", level), format!("{{{{ref:key{}}}}}", level + 1).as_bytes()).expect("insert");
+        }
+        db.config.insert(format!("key{}", MAX_DEPTH - 1), "end").expect("insert");
+        db.config.insert("shallow", "{{ref:key2}}").expect("insert");
+
+        assert_eq!(resolve(&db, "root", "{{ref:shallow}}").ok().expect("resolve"), "end");
+        assert!(matches!(resolve(&db, "root", "{{ref:key0}}"), Err(Error::TooDeep)));
+
+        //key2 is resolved within shallow first, but must not be reused at deeper level.
+        assert!(matches!(resolve(&db, "root", "{{ref:shallow}}{{ref:key0}}"), Err(Error::TooDeep)));
+    }
+
+    #[test]
+    fn should_limit_expansion() {
+        let db = db::Db::temporary().expect("open db").view();
+        db.config.insert("lol0", "lollollollollollollollollollol").expect("insert");
+        for level in 1..10 {
+            let reference = format!("{{{{ref:lol{}}}}}", level - 1);
+            db.config.insert(format!("lol{}", level), reference.repeat(10).as_bytes()).expect("insert");
+        }
+
+        assert!(matches!(resolve(&db, "bomb", "{{ref:lol9}}"), Err(Error::TooLarge)));
+        assert!(resolve(&db, "small", "{{ref:lol3}}").ok().expect("resolve").len() == 30_000);
+    }
+}
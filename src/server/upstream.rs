@@ -0,0 +1,176 @@
+//! Read-through of keys from upstream dou-store.
+//!
+//! Key, missing locally, is requested from upstream as raw value and written locally the same way
+//! as by `set_config`, so that it is served with its checksum afterwards without contacting upstream.
+//! Key is fetched only once, hence later changes on upstream are not visible until key is deleted locally.
+//!
+//! Only plain TCP transport is supported, using single connection, which is re-established on failure.
+//! Upstream on another host must accept connections on its interface with `--bind`.
+
+use std::io;
+use core::time::Duration;
+
+use serde_json::Value;
+use tokio::net::TcpStream;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use super::{ID, RAW, RESULT, EXPLICIT_MISSING};
+use crate::protocol::{self, CORRELATION_ID, EOT};
+
+///Limit on duration of single request to upstream.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[inline]
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+struct Connection {
+    socket: TcpStream,
+    ///Received bytes, which are not yet taken as message, kept across requests.
+    buf: Vec<u8>,
+}
+
+impl Connection {
+    async fn send(&mut self, frame: &[u8]) -> io::Result<Value> {
+        self.socket.write_all(frame).await?;
+
+        loop {
+            while let Some(message) = protocol::take_message(&mut self.buf).map_err(invalid_data)? {
+                match message.get("method") {
+                    //Upstream's own request, such as keepalive ping.
+                    Some(_) => if let Some(answer) = protocol::answer_server_request(&message) {
+                        self.socket.write_all(&answer).await?;
+                    },
+                    None => return Ok(message),
+                }
+            }
+
+            if self.socket.read_buf(&mut self.buf).await? == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Upstream closed connection"));
+            }
+        }
+    }
+}
+
+pub struct Upstream {
+    addr: String,
+    connection: tokio::sync::Mutex<Option<Connection>>,
+}
+
+impl Upstream {
+    pub fn new(addr: String) -> Self {
+        Self {
+            addr,
+            connection: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    #[inline]
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    ///Sends request, returning its response.
+    async fn request(&self, request: &Value) -> io::Result<Value> {
+        let mut frame = request.to_string().into_bytes();
+        frame.push(EOT);
+
+        let mut connection = self.connection.lock().await;
+        //Existing connection might be closed by upstream in the meantime, so it is retried once over new one.
+        if let Some(existing) = connection.as_mut() {
+            match existing.send(&frame).await {
+                Ok(response) => return Ok(response),
+                Err(_) => *connection = None,
+            }
+        }
+
+        let mut new = Connection {
+            socket: TcpStream::connect(self.addr.as_str()).await?,
+            buf: Vec::new(),
+        };
+        let response = new.send(&frame).await?;
+        *connection = Some(new);
+        Ok(response)
+    }
+
+    ///Fetches raw value of key, returning `None` if upstream has no such key.
+    pub async fn fetch(&self, key: &str, cid: Option<&str>) -> io::Result<Option<String>> {
+        let mut params = serde_json::json!({
+            ID: key,
+            RAW: true,
+            EXPLICIT_MISSING: true,
+        });
+        if let Some(cid) = cid {
+            params[CORRELATION_ID] = cid.into();
+        }
+        let request = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "config",
+            "params": params,
+        });
+
+        let mut response = match tokio::time::timeout(TIMEOUT, self.request(&request)).await {
+            Ok(response) => response?,
+            Err(_) => {
+                //Response might still arrive, so connection cannot be used anymore.
+                *self.connection.lock().await = None;
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "Upstream timed out"));
+            },
+        };
+
+        if let Some(error) = response.get("error") {
+            return Err(io::Error::other(format!("Upstream responded with error {}", error)));
+        }
+        match response.get_mut(RESULT).and_then(|result| result.get_mut(RESULT)).map(Value::take) {
+            Some(Value::String(value)) => Ok(Some(value)),
+            Some(Value::Null) => Ok(None),
+            _ => Err(invalid_data("Upstream responded without value")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncBufReadExt;
+
+    const PING: &[u8] = br#"{"jsonrpc":"2.0","method":"ping","id":"keepalive"}"#;
+
+    #[tokio::test]
+    async fn should_keep_messages_received_after_response() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let upstream = Upstream::new(listener.local_addr().expect("addr").to_string());
+
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.expect("accept");
+            let mut socket = tokio::io::BufReader::new(socket);
+            let mut frames = Vec::new();
+
+            for value in ["first", "second"] {
+                let mut frame = Vec::new();
+                socket.read_until(EOT, &mut frame).await.expect("read");
+                frames.push(frame);
+
+                let mut response = format!(r#"{{"jsonrpc":"2.0","result":{{"result":"{}"}},"id":1}}"#, value).into_bytes();
+                if frames.len() == 1 {
+                    //Ping arrives together with response, so it is only handled by next request.
+                    response.extend_from_slice(PING);
+                }
+                socket.get_mut().write_all(&response).await.expect("write");
+            }
+
+            let mut answer = Vec::new();
+            socket.read_until(EOT, &mut answer).await.expect("read");
+            frames.push(answer);
+            frames
+        });
+
+        assert_eq!(upstream.fetch("key", None).await.expect("fetch").as_deref(), Some("first"));
+        assert_eq!(upstream.fetch("key", None).await.expect("fetch").as_deref(), Some("second"));
+
+        let frames = server.await.expect("server");
+        assert_eq!(frames[2], b"{\"jsonrpc\":\"2.0\",\"result\":null,\"id\":\"keepalive\"}\x04");
+    }
+}
@@ -0,0 +1,285 @@
+//! Notifications about changes of keys under prefix.
+//!
+//! Every mutation of config tree is delivered to sessions watching its prefix, regardless of what
+//! caused it, as JSON-RPC notification `changed` with params:
+//!
+//! - `{"id": <key>, "checksum": <checksum>, "data": <value>}` once key is written;
+//! - `{"id": <key>, "deleted": true}` once key is deleted.
+//!
+//! Config tree is only watched once first session asks for it.
+//! Watcher, which doesn't read fast enough, misses notifications once its outbox is full.
+//!
+//! `watch` mode of binary is client of it, printing changes with their difference to previous value.
+
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, TcpStream};
+use std::sync::{Arc, Mutex, Once};
+use std::collections::HashMap;
+
+use serde_json::Value;
+use xxhash_rust::xxh3::xxh3_64;
+
+use super::session::{Message, Outbox, Session};
+use super::{blob, compare, LOCAL_HOST, ID, PREFIX};
+use crate::db;
+use crate::protocol::{self, EOT};
+
+///Method of notification, carrying change of key.
+const METHOD: &str = "changed";
+
+struct Watcher {
+    session: u64,
+    prefix: String,
+    outbox: Outbox,
+}
+
+pub struct Watchers {
+    db: db::DbView,
+    started: Once,
+    watchers: Mutex<Vec<Watcher>>,
+}
+
+impl Watchers {
+    pub fn new(db: db::DbView) -> Self {
+        Self {
+            db,
+            started: Once::new(),
+            watchers: Mutex::new(Vec::new()),
+        }
+    }
+
+    #[inline]
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Watcher>> {
+        self.watchers.lock().unwrap_or_else(|error| error.into_inner())
+    }
+
+    ///Starts watching prefix for session, returning whether it wasn't watched already.
+    pub fn watch(self: &Arc<Self>, prefix: &str, session: &Session) -> bool {
+        self.started.call_once(|| {
+            let watchers = self.clone();
+            if let Err(error) = std::thread::Builder::new().name("config-watch".to_owned()).spawn(move || watchers.run()) {
+                error!("Unable to start config watch thread: {}", error);
+            }
+        });
+
+        let mut watchers = self.lock();
+        if watchers.iter().any(|watcher| watcher.session == session.id() && watcher.prefix == prefix) {
+            return false;
+        }
+        watchers.push(Watcher {
+            session: session.id(),
+            prefix: prefix.to_owned(),
+            outbox: session.outbox().clone(),
+        });
+        true
+    }
+
+    ///Stops watching prefix for session, returning whether it was watched.
+    pub fn unwatch(&self, prefix: &str, session: u64) -> bool {
+        let mut watchers = self.lock();
+        let len = watchers.len();
+        watchers.retain(|watcher| watcher.session != session || watcher.prefix != prefix);
+        watchers.len() != len
+    }
+
+    ///Removes all watched prefixes of session.
+    pub fn close(&self, session: u64) {
+        self.lock().retain(|watcher| watcher.session != session);
+    }
+
+    fn run(&self) {
+        for event in self.db.config.watch_prefix(Vec::new()) {
+            let key = event.key();
+            let is_watched = |watcher: &&Watcher| key.starts_with(watcher.prefix.as_bytes());
+
+            let watchers = self.lock();
+            if !watchers.iter().any(|watcher| is_watched(&watcher)) {
+                continue;
+            }
+
+            let notification = match &event {
+                sled::Event::Insert { value, .. } => match blob::as_pointer(value) {
+                    Some(hash) => match self.db.blobs.get(hash) {
+                        Ok(Some(value)) => notification(key, Some(&value)),
+                        //Blob is already released by later write.
+                        Ok(None) => continue,
+                        Err(error) => {
+                            error!("Unable to read blob of '{}' for watchers: {}", String::from_utf8_lossy(key), error);
+                            continue;
+                        }
+                    },
+                    None => notification(key, Some(value)),
+                },
+                sled::Event::Remove { .. } => notification(key, None),
+            };
+
+            for watcher in watchers.iter().filter(is_watched) {
+                let _ = watcher.outbox.try_send(notification.clone());
+            }
+        }
+    }
+}
+
+fn notification(key: &[u8], value: Option<&[u8]>) -> Message {
+    let mut params = serde_json::Map::with_capacity(3);
+    params.insert(ID.to_owned(), String::from_utf8_lossy(key).into());
+    match value {
+        Some(value) => {
+            params.insert("checksum".to_owned(), xxh3_64(value).into());
+            params.insert("data".to_owned(), String::from_utf8_lossy(value).into());
+        },
+        None => {
+            params.insert("deleted".to_owned(), true.into());
+        },
+    }
+
+    let mut notification = serde_json::Map::with_capacity(3);
+    notification.insert("jsonrpc".to_owned(), "2.0".into());
+    notification.insert("method".to_owned(), METHOD.into());
+    notification.insert("params".to_owned(), params.into());
+
+    match serde_json::to_vec(&notification) {
+        Ok(message) => message.into(),
+        Err(_) => unreachable!(),
+    }
+}
+
+///Prints change, carried by notification, comparing it against previously seen value of key.
+fn print_change(params: &Value, values: &mut HashMap<String, String>) {
+    let key = match params.get(ID).and_then(Value::as_str) {
+        Some(key) => key.to_owned(),
+        None => return,
+    };
+
+    let value = params.get("data").and_then(Value::as_str).map(ToOwned::to_owned);
+    match (&value, params.get("checksum")) {
+        (Some(_), Some(checksum)) => println!("set '{}' checksum={}", key, checksum),
+        _ => println!("delete '{}'", key),
+    }
+
+    //Value, which is not seen yet, is reported as added.
+    let old = values.remove(&key);
+    for change in compare::changes(old.as_deref().map(str::as_bytes), value.as_deref().map(str::as_bytes)) {
+        println!("  {}", change);
+    }
+
+    if let Some(value) = value {
+        values.insert(key, value);
+    }
+}
+
+fn watch(addr: IpAddr, port: u16, prefix: &str) -> io::Result<()> {
+    //Server, which accepts connections on every interface, is reachable on loopback.
+    let addr = match addr.is_unspecified() {
+        true => LOCAL_HOST,
+        false => addr,
+    };
+    let mut socket = TcpStream::connect((addr, port))?;
+
+    let request = serde_json::json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": "watch",
+        "params": {
+            PREFIX: prefix,
+        },
+    });
+    let mut frame = request.to_string().into_bytes();
+    frame.push(EOT);
+    socket.write_all(&frame)?;
+
+    let mut values = HashMap::new();
+    let mut buf = Vec::new();
+    let mut read_buf = [0u8; 8192];
+    loop {
+        while let Some(message) = protocol::take_message(&mut buf).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))? {
+            match message.get("method").and_then(Value::as_str) {
+                Some(METHOD) => if let Some(params) = message.get("params") {
+                    print_change(params, &mut values);
+                },
+                //Server's own request, such as keepalive ping.
+                Some(_) => if let Some(answer) = protocol::answer_server_request(&message) {
+                    socket.write_all(&answer)?;
+                },
+                None => match message.get("error") {
+                    Some(error) => return Err(io::Error::other(format!("Server responded with error {}", error))),
+                    None => eprintln!("Watching '{}'", prefix),
+                },
+            }
+        }
+
+        match socket.read(&mut read_buf)? {
+            0 => break Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Server closed connection")),
+            read => buf.extend_from_slice(&read_buf[..read]),
+        }
+    }
+}
+
+///Prints changes of keys under prefix until connection is closed, returning `true` on failure.
+pub fn run(addr: IpAddr, port: u16, prefix: &str) -> bool {
+    match watch(addr, port, prefix) {
+        Ok(()) => false,
+        Err(error) => {
+            eprintln!("Unable to watch '{}' on port {}: {}", prefix, port, error);
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::time::Duration;
+    use super::super::{handle_set_config_req, handle_delete_config_req, lease::Lease};
+
+    async fn next(receiver: &mut tokio::sync::mpsc::Receiver<Message>) -> Value {
+        let message = tokio::time::timeout(Duration::from_secs(5), receiver.recv()).await.expect("notification").expect("open");
+        serde_json::from_slice(&message).expect("json")
+    }
+
+    #[test]
+    fn should_describe_change_as_notification() {
+        let set: Value = serde_json::from_slice(&notification(b"key", Some(b"value"))).expect("json");
+        assert_eq!(set, serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": METHOD,
+            "params": {"id": "key", "checksum": xxh3_64(b"value"), "data": "value"},
+        }));
+
+        let delete: Value = serde_json::from_slice(&notification(b"key", None)).expect("json");
+        assert_eq!(delete["params"], serde_json::json!({"id": "key", "deleted": true}));
+    }
+
+    #[test]
+    fn should_watch_prefix_once_per_session() {
+        let watchers = Arc::new(Watchers::new(db::Db::temporary().expect("open db").view()));
+        let (session, _outbox) = Session::new();
+
+        assert!(watchers.watch("app/", &session));
+        assert!(!watchers.watch("app/", &session));
+        assert!(watchers.watch("other/", &session));
+
+        assert!(watchers.unwatch("app/", session.id()));
+        assert!(!watchers.unwatch("app/", session.id()));
+        watchers.close(session.id());
+        assert!(!watchers.unwatch("other/", session.id()));
+    }
+
+    #[tokio::test]
+    async fn should_notify_only_about_watched_prefix() {
+        let db = db::Db::temporary().expect("open db").view();
+        let watchers = Arc::new(Watchers::new(db.clone()));
+        let (session, mut outbox) = Session::new();
+        watchers.watch("app/", &session);
+        //Give watch thread time to subscribe.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        handle_set_config_req(&db, None, "other/key", "value", Lease::Keep, None, None).payload.expect("set");
+        handle_set_config_req(&db, None, "app/key", "value", Lease::Keep, None, None).payload.expect("set");
+        handle_delete_config_req(&db, None, "app/key", Lease::Keep, None, None).payload.expect("delete");
+
+        assert_eq!(next(&mut outbox).await["params"], serde_json::json!({"id": "app/key", "checksum": xxh3_64(b"value"), "data": "value"}));
+        assert_eq!(next(&mut outbox).await["params"], serde_json::json!({"id": "app/key", "deleted": true}));
+        assert!(outbox.try_recv().is_err());
+    }
+}
@@ -0,0 +1,362 @@
+//! Dedicated threads performing db operations.
+//!
+//! Requests are queued over channel instead of paying for `spawn_blocking` on each of them.
+
+use std::sync::{mpsc, Arc, Mutex};
+use std::panic::{self, AssertUnwindSafe};
+use std::collections::BTreeMap;
+
+use json_rpc_types::{Id, Version};
+
+use super::lease::Lease;
+use super::lock::{self, LockOp};
+use super::queue::{self, QueueOp};
+use super::{layer, sync, blob, quota, compare, schedule, snapshot, health, glob, range, idempotency, multipart};
+use super::{cache, int_err, internal_err, handle_checksum_req, handle_config_req, handle_set_config_req, handle_validate_config_req, handle_delete_config_req};
+use crate::db;
+use crate::protocol::Response;
+
+///Operation, performed by worker.
+pub enum Operation {
+    Checksum {
+        key: String,
+        explicit_missing: bool,
+    },
+    Config {
+        key: String,
+        explicit_missing: bool,
+        ///Whether to resolve references to other keys.
+        resolve: bool,
+    },
+    SetConfig {
+        key: String,
+        value: String,
+        lease: Lease,
+    },
+    DeleteConfig {
+        key: String,
+        lease: Lease,
+    },
+    Lock {
+        op: LockOp,
+        name: String,
+        owner: String,
+        ttl_ms: u64,
+    },
+    Queue {
+        op: QueueOp,
+        name: String,
+    },
+    Resolve {
+        keys: Vec<String>,
+        resolve: bool,
+    },
+    Diff {
+        checksums: BTreeMap<String, u64>,
+        prefix: Option<String>,
+    },
+    TreeChecksum {
+        prefix: String,
+    },
+    PutBlob {
+        value: String,
+    },
+    GetBlob {
+        hash: blob::Hash,
+        explicit_missing: bool,
+    },
+    Usage {
+        namespace: Option<String>,
+    },
+    ValidateConfig {
+        key: String,
+        value: String,
+    },
+    DiffConfig {
+        key: String,
+        target: compare::Target,
+    },
+    Schedule {
+        key: String,
+        value: String,
+        activate_at: u64,
+    },
+    Activate {
+        now: u64,
+    },
+    ReadSnapshot {
+        keys: Vec<String>,
+    },
+    Ready,
+    MatchKeys {
+        pattern: String,
+        with_values: bool,
+    },
+    Range {
+        query: range::Query,
+    },
+    Recall {
+        key: String,
+        fingerprint: u64,
+    },
+    Remember {
+        key: String,
+        fingerprint: u64,
+        result: serde_json::Value,
+    },
+    ExpireIdempotent {
+        now: u64,
+    },
+    UploadBegin {
+        session: u64,
+        key: String,
+    },
+    UploadChunk {
+        session: u64,
+        upload: u64,
+        seq: u64,
+        data: String,
+    },
+    UploadAssemble {
+        session: u64,
+        upload: u64,
+    },
+    UploadCommit {
+        assembled: multipart::Assembled,
+        lease: Lease,
+    },
+    DiscardUploads {
+        session: u64,
+    },
+    ExpireUploads {
+        now: u64,
+    },
+    ///Blocking work, which delivers its own result.
+    Call {
+        name: &'static str,
+        work: Box<dyn FnOnce() + Send>,
+    },
+}
+
+impl Operation {
+    #[inline]
+    const fn name(&self) -> &'static str {
+        match self {
+            Operation::Checksum { .. } => "checksum",
+            Operation::Config { .. } => "config",
+            Operation::SetConfig { .. } => "set_config",
+            Operation::DeleteConfig { .. } => "delete_config",
+            Operation::Lock { op, .. } => op.name(),
+            Operation::Queue { op, .. } => op.name(),
+            Operation::Resolve { .. } => "resolve",
+            Operation::Diff { .. } => "diff",
+            Operation::TreeChecksum { .. } => "tree_checksum",
+            Operation::PutBlob { .. } => "put_blob",
+            Operation::GetBlob { .. } => "get_blob_by_hash",
+            Operation::Usage { .. } => "usage",
+            Operation::ValidateConfig { .. } => "validate_config",
+            Operation::DiffConfig { .. } => "diff_config",
+            Operation::Schedule { .. } => "schedule_config",
+            Operation::Activate { .. } => "activate_scheduled",
+            Operation::ReadSnapshot { .. } => "read_snapshot",
+            Operation::Ready => "ready",
+            Operation::MatchKeys { .. } => "match_keys",
+            Operation::Range { .. } => "range",
+            Operation::Recall { .. } => "recall_idempotent",
+            Operation::Remember { .. } => "remember_idempotent",
+            Operation::ExpireIdempotent { .. } => "expire_idempotent",
+            Operation::UploadBegin { .. } => "upload_begin",
+            Operation::UploadChunk { .. } => "upload_chunk",
+            Operation::UploadAssemble { .. } => "upload_assemble",
+            Operation::UploadCommit { .. } => "upload_commit",
+            Operation::DiscardUploads { .. } => "discard_uploads",
+            Operation::ExpireUploads { .. } => "expire_uploads",
+            Operation::Call { name, .. } => name,
+        }
+    }
+}
+
+struct Task {
+    operation: Operation,
+    cid: Option<String>,
+    id: Option<Id>,
+    span: tracing::Span,
+    reply: tokio::sync::oneshot::Sender<Response>,
+}
+
+#[derive(Clone)]
+pub struct Worker {
+    sender: mpsc::Sender<Task>,
+}
+
+impl Worker {
+    ///Starts `threads` workers, which exit once all instances of `Worker` are dropped.
+    pub fn new(threads: usize, db: db::DbView, cache: Option<Arc<cache::Cache>>) -> Self {
+        let (sender, receiver) = mpsc::channel::<Task>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for idx in 0..threads.max(1) {
+            let receiver = receiver.clone();
+            let db = db.clone();
+            let cache = cache.clone();
+
+            let result = std::thread::Builder::new().name(format!("db-worker-{}", idx)).spawn(move || loop {
+                //Lock is only held to receive, so poisoned lock still guards intact receiver.
+                let task = match receiver.lock().unwrap_or_else(|error| error.into_inner()).recv() {
+                    Ok(task) => task,
+                    Err(_) => break,
+                };
+
+                let Task { operation, cid, id, span, reply } = task;
+                let name = operation.name();
+                let result = span.in_scope(|| panic::catch_unwind(AssertUnwindSafe(|| Self::execute(&db, cache.as_deref(), operation, cid.as_deref(), id.clone()))));
+                //Panic only fails its own operation, while worker keeps serving others.
+                let response = match result {
+                    Ok(response) => response,
+                    Err(_) => {
+                        error!(cid: cid.as_deref(), "Panic during {} operation", name);
+                        internal_err(int_err::TASK_PANIC, id)
+                    }
+                };
+                //Requester might be gone already.
+                let _ = reply.send(response);
+            });
+
+            if let Err(error) = result {
+                error!("Unable to start db worker: {}", error);
+            }
+        }
+
+        Self {
+            sender,
+        }
+    }
+
+    fn execute(db: &db::DbView, cache: Option<&cache::Cache>, operation: Operation, cid: Option<&str>, id: Option<Id>) -> Response {
+        match operation {
+            Operation::Checksum { key, explicit_missing } => handle_checksum_req(db, &key, explicit_missing, cid, id),
+            Operation::Config { key, explicit_missing, resolve } => handle_config_req(db, cache, &key, explicit_missing, resolve, cid, id),
+            Operation::SetConfig { key, value, lease } => handle_set_config_req(db, cache, &key, &value, lease, cid, id),
+            Operation::DeleteConfig { key, lease } => handle_delete_config_req(db, cache, &key, lease, cid, id),
+            Operation::Lock { op, name, owner, ttl_ms } => lock::handle_lock_req(db, op, &name, &owner, ttl_ms, cid, id),
+            Operation::Queue { op, name } => queue::handle_queue_req(db, op, &name, cid, id),
+            Operation::Resolve { keys, resolve } => layer::handle_resolve_req(db, &keys, resolve, cid, id),
+            Operation::Diff { checksums, prefix } => sync::handle_diff_req(db, &checksums, prefix.as_deref(), cid, id),
+            Operation::TreeChecksum { prefix } => sync::handle_tree_checksum_req(db, &prefix, cid, id),
+            Operation::PutBlob { value } => blob::handle_put_blob_req(db, &value, cid, id),
+            Operation::GetBlob { hash, explicit_missing } => blob::handle_get_blob_req(db, &hash, explicit_missing, cid, id),
+            Operation::Usage { namespace } => quota::handle_usage_req(db, namespace.as_deref(), cid, id),
+            Operation::ValidateConfig { key, value } => handle_validate_config_req(db, &key, &value, cid, id),
+            Operation::DiffConfig { key, target } => compare::handle_diff_config_req(db, &key, &target, cid, id),
+            Operation::Schedule { key, value, activate_at } => schedule::handle_schedule_req(db, &key, &value, activate_at, cid, id),
+            Operation::Activate { now } => schedule::handle_activate_req(db, cache, now, id),
+            Operation::ReadSnapshot { keys } => snapshot::handle_read_snapshot_req(db, &keys, cid, id),
+            Operation::Ready => health::handle_ready_req(db, id),
+            Operation::MatchKeys { pattern, with_values } => glob::handle_match_keys_req(db, &pattern, with_values, cid, id),
+            Operation::Range { query } => range::handle_range_req(db, &query, cid, id),
+            Operation::Recall { key, fingerprint } => idempotency::handle_recall_req(db, &key, fingerprint, cid, id),
+            Operation::Remember { key, fingerprint, result } => idempotency::handle_remember_req(db, &key, fingerprint, &result, cid, id),
+            Operation::ExpireIdempotent { now } => idempotency::handle_expire_req(db, now, id),
+            Operation::UploadBegin { session, key } => multipart::handle_begin_req(db, session, &key, cid, id),
+            Operation::UploadChunk { session, upload, seq, data } => multipart::handle_chunk_req(db, session, upload, seq, &data, cid, id),
+            Operation::UploadAssemble { session, upload } => multipart::handle_assemble_req(db, session, upload, cid, id),
+            Operation::UploadCommit { assembled, lease } => multipart::handle_commit_req(db, cache, &assembled, lease, cid, id),
+            Operation::DiscardUploads { session } => multipart::handle_discard_req(db, session, id),
+            Operation::ExpireUploads { now } => multipart::handle_expire_req(db, now, id),
+            Operation::Call { work, .. } => {
+                work();
+                Response::result(Version::V2, Default::default(), id)
+            },
+        }
+    }
+
+    ///Runs blocking work, such as hook script, waiting for its result.
+    ///
+    ///Returns `None` if work is not performed.
+    pub async fn call<R: Send + 'static>(&self, name: &'static str, cid: Option<&str>, work: impl FnOnce() -> R + Send + 'static) -> Option<R> {
+        let (sender, result) = tokio::sync::oneshot::channel();
+        let work = Box::new(move || {
+            let _ = sender.send(work());
+        });
+
+        //Failure is already logged.
+        self.run(Operation::Call { name, work }, cid, None).await;
+        result.await.ok()
+    }
+
+    ///Queues operation without waiting for its result.
+    pub fn spawn(&self, operation: Operation) {
+        let name = operation.name();
+        let (reply, _) = tokio::sync::oneshot::channel();
+        let task = Task {
+            operation,
+            cid: None,
+            id: None,
+            span: tracing::Span::none(),
+            reply,
+        };
+
+        if self.sender.send(task).is_err() {
+            error!("Failed to queue {} operation: db workers are not running", name);
+        }
+    }
+
+    ///Queues operation, waiting for its result.
+    pub async fn run(&self, operation: Operation, cid: Option<&str>, id: Option<Id>) -> Response {
+        let name = operation.name();
+        let (reply, result) = tokio::sync::oneshot::channel();
+        let task = Task {
+            operation,
+            cid: cid.map(ToOwned::to_owned),
+            id: id.clone(),
+            span: tracing::info_span!("db"),
+            reply,
+        };
+
+        if self.sender.send(task).is_err() {
+            error!(cid: cid, "Failed to queue {} operation: db workers are not running", name);
+            return internal_err(int_err::TASK_SPAWN_FAIL, id);
+        }
+
+        match result.await {
+            Ok(response) => response,
+            Err(error) => {
+                error!(cid: cid, "Failed to execute {} operation: {}", name, error);
+                internal_err(int_err::TASK_SPAWN_FAIL, id)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::lease::Lease;
+
+    fn worker() -> Worker {
+        Worker::new(1, db::Db::temporary().expect("open db").view(), None)
+    }
+
+    #[tokio::test]
+    async fn should_perform_operations() {
+        let worker = worker();
+
+        let response = worker.run(Operation::SetConfig { key: "key".to_owned(), value: "value".to_owned(), lease: Lease::Keep }, None, Some(Id::Num(1))).await;
+        assert!(response.payload.is_ok());
+
+        let response = worker.run(Operation::Config { key: "key".to_owned(), explicit_missing: false, resolve: false }, None, Some(Id::Num(2))).await;
+        assert_eq!(response.id, Some(Id::Num(2)));
+        assert_eq!(response.payload.expect("config")["result"], "value");
+    }
+
+    #[tokio::test]
+    async fn should_answer_panic_and_keep_serving() {
+        let worker = worker();
+
+        let response = worker.run(Operation::Call { name: "panic", work: Box::new(|| panic!("work failed")) }, None, Some(Id::Num(1))).await;
+        assert_eq!(response.payload.expect_err("panic").code.code(), int_err::TASK_PANIC);
+        assert_eq!(response.id, Some(Id::Num(1)));
+
+        assert_eq!(worker.call("call", None, || 42).await, Some(42));
+    }
+}
@@ -0,0 +1,60 @@
+//! Supervision of server by platform's service manager.
+//!
+//! Server runs in foreground, logging into stdout, and stops on SIGTERM, as launchd and systemd expect,
+//! so no special mode is needed there.
+//! On Windows it is registered as native service, which reports its state to service control manager
+//! and logs into event log.
+
+#[cfg(windows)]
+mod windows;
+#[cfg(windows)]
+pub use windows::{run, install, uninstall, RUN, INSTALL, UNINSTALL};
+
+#[cfg(any(windows, test))]
+///Joins program and its arguments into command line, quoting them as Windows programs parse it.
+fn command_line<I: IntoIterator<Item = String>>(program: &str, args: I) -> String {
+    let mut result = String::new();
+    quote(&mut result, program);
+    for arg in args {
+        result.push(' ');
+        quote(&mut result, &arg);
+    }
+    result
+}
+
+#[cfg(any(windows, test))]
+fn quote(out: &mut String, arg: &str) {
+    if !arg.is_empty() && !arg.contains([' ', '\t', '"']) {
+        out.push_str(arg);
+        return;
+    }
+
+    out.push('"');
+    //Backslashes are literal, unless they precede quote.
+    let mut backslashes = 0;
+    for ch in arg.chars() {
+        match ch {
+            '\\' => {
+                backslashes += 1;
+                continue;
+            },
+            '"' => out.extend(core::iter::repeat_n('\\', backslashes * 2 + 1)),
+            _ => out.extend(core::iter::repeat_n('\\', backslashes)),
+        }
+        out.push(ch);
+        backslashes = 0;
+    }
+    out.extend(core::iter::repeat_n('\\', backslashes * 2));
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_quote_command_line() {
+        let args = ["service", "--db", r"C:\Program Data\dou", "", r#"say "hi""#, r"C:\my dir\"].map(str::to_owned);
+        assert_eq!(command_line(r"C:\dou-store.exe", args), r#"C:\dou-store.exe service --db "C:\Program Data\dou" "" "say \"hi\"" "C:\my dir\\""#);
+    }
+}
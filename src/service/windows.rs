@@ -0,0 +1,248 @@
+//! Native Windows service.
+//!
+//! `service-install` registers server, started automatically with the rest of its arguments,
+//! while mode `service` is run by service control manager, which stops server on its request.
+//! Service runs in directory of its binary, so relative paths, such as of db, are resolved against it.
+//!
+//! Once run as service, logs are written into Application event log under source `dou-store`.
+
+use std::io;
+use std::path::Path;
+use std::ptr::{null, null_mut};
+use std::sync::Mutex;
+use core::ffi::c_void;
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+
+use windows_sys::core::PWSTR;
+use windows_sys::Win32::Foundation::{ERROR_CALL_NOT_IMPLEMENTED, ERROR_SERVICE_SPECIFIC_ERROR, NO_ERROR};
+use windows_sys::Win32::System::EventLog::{RegisterEventSourceW, ReportEventW, EVENTLOG_ERROR_TYPE, EVENTLOG_INFORMATION_TYPE, EVENTLOG_WARNING_TYPE};
+use windows_sys::Win32::System::Services::{
+    CloseServiceHandle, CreateServiceW, DeleteService, OpenSCManagerW, OpenServiceW, RegisterServiceCtrlHandlerExW, SetServiceStatus, StartServiceCtrlDispatcherW,
+    SC_HANDLE, SC_MANAGER_CONNECT, SC_MANAGER_CREATE_SERVICE, SERVICE_ACCEPT_SHUTDOWN, SERVICE_ACCEPT_STOP, SERVICE_AUTO_START, SERVICE_CONTROL_INTERROGATE,
+    SERVICE_CONTROL_SHUTDOWN, SERVICE_CONTROL_STOP, SERVICE_ERROR_NORMAL, SERVICE_QUERY_STATUS, SERVICE_RUNNING, SERVICE_STATUS, SERVICE_STATUS_CURRENT_STATE,
+    SERVICE_STOPPED, SERVICE_STOP_PENDING, SERVICE_TABLE_ENTRYW, SERVICE_WIN32_OWN_PROCESS,
+};
+
+use crate::cli;
+
+///Mode, in which service control manager runs server.
+pub const RUN: &str = "service";
+///Mode, which registers server with the rest of arguments as service.
+pub const INSTALL: &str = "service-install";
+///Mode, which removes registered service.
+pub const UNINSTALL: &str = "service-uninstall";
+
+///Name of service and source of its events.
+const NAME: &str = "dou-store";
+///Right to delete service.
+const DELETE: u32 = 0x0001_0000;
+///Time given to server to flush db once it is stopped.
+const STOP_WAIT_HINT_MS: u32 = 30_000;
+
+///Arguments of server, taken by service once it is started.
+static ARGS: Mutex<Option<cli::Cli>> = Mutex::new(None);
+static STATUS: AtomicPtr<c_void> = AtomicPtr::new(null_mut());
+static EVENT_SOURCE: AtomicPtr<c_void> = AtomicPtr::new(null_mut());
+static STOP: tokio::sync::Notify = tokio::sync::Notify::const_new();
+static IS_FAILED: AtomicBool = AtomicBool::new(false);
+
+fn wide(text: &str) -> Vec<u16> {
+    text.encode_utf16().chain(core::iter::once(0)).collect()
+}
+
+///Handle of service control manager or service, closed once dropped.
+struct ScHandle(SC_HANDLE);
+
+impl ScHandle {
+    #[inline]
+    fn new(handle: SC_HANDLE) -> io::Result<Self> {
+        match handle.is_null() {
+            true => Err(io::Error::last_os_error()),
+            false => Ok(Self(handle)),
+        }
+    }
+}
+
+impl Drop for ScHandle {
+    #[inline]
+    fn drop(&mut self) {
+        unsafe {
+            CloseServiceHandle(self.0);
+        }
+    }
+}
+
+fn set_status(state: SERVICE_STATUS_CURRENT_STATE, exit_code: u32) {
+    let status = SERVICE_STATUS {
+        dwServiceType: SERVICE_WIN32_OWN_PROCESS,
+        dwCurrentState: state,
+        dwControlsAccepted: match state {
+            SERVICE_RUNNING => SERVICE_ACCEPT_STOP | SERVICE_ACCEPT_SHUTDOWN,
+            _ => 0,
+        },
+        dwWin32ExitCode: exit_code,
+        dwServiceSpecificExitCode: match exit_code {
+            ERROR_SERVICE_SPECIFIC_ERROR => 1,
+            _ => 0,
+        },
+        dwCheckPoint: 0,
+        dwWaitHint: match state {
+            SERVICE_STOP_PENDING => STOP_WAIT_HINT_MS,
+            _ => 0,
+        },
+    };
+
+    unsafe {
+        SetServiceStatus(STATUS.load(Ordering::Acquire), &status);
+    }
+}
+
+///Writes log line into event log.
+fn report(level: rogu::Level, message: &str) {
+    let kind = match level {
+        rogu::Level::ERROR => EVENTLOG_ERROR_TYPE,
+        rogu::Level::WARN => EVENTLOG_WARNING_TYPE,
+        _ => EVENTLOG_INFORMATION_TYPE,
+    };
+    let message = wide(message);
+    let strings = [message.as_ptr()];
+
+    //Nothing to do if logging fails
+    unsafe {
+        ReportEventW(EVENT_SOURCE.load(Ordering::Acquire), kind, 0, 0, null_mut(), 1, 0, strings.as_ptr(), null());
+    }
+}
+
+extern "system" fn control_handler(control: u32, _event: u32, _data: *mut c_void, _context: *mut c_void) -> u32 {
+    match control {
+        SERVICE_CONTROL_STOP | SERVICE_CONTROL_SHUTDOWN => {
+            set_status(SERVICE_STOP_PENDING, NO_ERROR);
+            STOP.notify_one();
+            NO_ERROR
+        },
+        SERVICE_CONTROL_INTERROGATE => NO_ERROR,
+        _ => ERROR_CALL_NOT_IMPLEMENTED,
+    }
+}
+
+extern "system" fn service_main(_argc: u32, _argv: *mut PWSTR) {
+    let name = wide(NAME);
+    let status = unsafe {
+        RegisterServiceCtrlHandlerExW(name.as_ptr(), Some(control_handler), null())
+    };
+    if status.is_null() {
+        IS_FAILED.store(true, Ordering::Release);
+        return;
+    }
+    STATUS.store(status, Ordering::Release);
+
+    let source = unsafe {
+        RegisterEventSourceW(null(), name.as_ptr())
+    };
+    if !source.is_null() {
+        EVENT_SOURCE.store(source, Ordering::Release);
+        crate::log::set_sink(report);
+    }
+
+    if let Some(dir) = std::env::current_exe().ok().as_deref().and_then(Path::parent) {
+        if let Err(error) = std::env::set_current_dir(dir) {
+            warn!("Unable to run in directory of binary: {}", error);
+        }
+    }
+
+    set_status(SERVICE_RUNNING, NO_ERROR);
+    let is_failed = match ARGS.lock().unwrap_or_else(|error| error.into_inner()).take() {
+        Some(args) => crate::run(args, STOP.notified()),
+        None => true,
+    };
+    IS_FAILED.store(is_failed, Ordering::Release);
+    set_status(SERVICE_STOPPED, match is_failed {
+        true => ERROR_SERVICE_SPECIFIC_ERROR,
+        false => NO_ERROR,
+    });
+}
+
+///Runs server as service, returning once it is stopped.
+pub fn run(args: cli::Cli) -> bool {
+    *ARGS.lock().unwrap_or_else(|error| error.into_inner()) = Some(args);
+
+    let mut name = wide(NAME);
+    let table = [
+        SERVICE_TABLE_ENTRYW {
+            lpServiceName: name.as_mut_ptr(),
+            lpServiceProc: Some(service_main),
+        },
+        SERVICE_TABLE_ENTRYW {
+            lpServiceName: null_mut(),
+            lpServiceProc: None,
+        },
+    ];
+
+    //Service runs on its own thread, while dispatcher blocks until it is stopped.
+    if unsafe { StartServiceCtrlDispatcherW(table.as_ptr()) } == 0 {
+        eprintln!("Unable to run service, mode '{}' must be started by service control manager: {}", RUN, io::Error::last_os_error());
+        return true;
+    }
+    IS_FAILED.load(Ordering::Acquire)
+}
+
+///Registers service, which runs server with the same arguments, except mode.
+pub fn install() -> bool {
+    let exe = match std::env::current_exe() {
+        Ok(exe) => exe,
+        Err(error) => {
+            eprintln!("Unable to locate binary: {}", error);
+            return true;
+        }
+    };
+    let args = std::env::args().skip(1).map(|arg| match arg == INSTALL {
+        true => RUN.to_owned(),
+        false => arg,
+    });
+    let command = wide(&super::command_line(&exe.to_string_lossy(), args));
+    let name = wide(NAME);
+
+    let manager = unsafe {
+        ScHandle::new(OpenSCManagerW(null(), null(), SC_MANAGER_CREATE_SERVICE))
+    };
+    let result = manager.and_then(|manager| unsafe {
+        ScHandle::new(CreateServiceW(manager.0, name.as_ptr(), name.as_ptr(), SERVICE_QUERY_STATUS, SERVICE_WIN32_OWN_PROCESS, SERVICE_AUTO_START, SERVICE_ERROR_NORMAL, command.as_ptr(), null(), null_mut(), null(), null(), null()))
+    });
+
+    match result {
+        Ok(_) => {
+            println!("Installed service '{}'", NAME);
+            false
+        },
+        Err(error) => {
+            eprintln!("Unable to install service '{}': {}", NAME, error);
+            true
+        }
+    }
+}
+
+///Removes registered service, which is deleted once it is stopped.
+pub fn uninstall() -> bool {
+    let name = wide(NAME);
+
+    let manager = unsafe {
+        ScHandle::new(OpenSCManagerW(null(), null(), SC_MANAGER_CONNECT))
+    };
+    let result = manager.and_then(|manager| unsafe {
+        ScHandle::new(OpenServiceW(manager.0, name.as_ptr(), DELETE))
+    }).and_then(|service| match unsafe { DeleteService(service.0) } {
+        0 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    });
+
+    match result {
+        Ok(()) => {
+            println!("Uninstalled service '{}'", NAME);
+            false
+        },
+        Err(error) => {
+            eprintln!("Unable to uninstall service '{}': {}", NAME, error);
+            true
+        }
+    }
+}
@@ -0,0 +1,334 @@
+//! `tracing` subscriber, which logs timing breakdown of request spans.
+//!
+//! Each closed span reports its duration to its parent, until root span is closed.
+//! Root span then writes single log line with timings of all its descendants.
+//!
+//! Optionally closed spans are also queued for OTLP export.
+
+use std::sync::{Arc, Mutex, OnceLock};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::time::{Instant, SystemTime};
+use core::fmt;
+use core::num::NonZeroU64;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use tracing::{Event, Metadata, Subscriber};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use xxhash_rust::xxh3::xxh3_64_with_seed;
+
+use crate::otlp;
+
+///Generates random enough identifier for exported spans.
+fn generate_id() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    static SEED: OnceLock<u64> = OnceLock::new();
+
+    let seed = *SEED.get_or_init(|| match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(now) => now.as_nanos() as u64,
+        Err(_) => 0,
+    });
+    let counter = COUNTER.fetch_add(1, Ordering::Relaxed);
+
+    xxh3_64_with_seed(&counter.to_le_bytes(), seed)
+}
+
+thread_local! {
+    //Stack of entered spans on current thread.
+    static CURRENT: RefCell<Vec<Id>> = const { RefCell::new(Vec::new()) };
+}
+
+struct SpanData {
+    name: &'static str,
+    parent: Option<Id>,
+    trace_id: u128,
+    span_id: u64,
+    parent_span_id: Option<u64>,
+    start_time: SystemTime,
+    start: Instant,
+    refs: usize,
+    peer: Option<String>,
+    cid: Option<String>,
+    method: Option<String>,
+    timings: Vec<(&'static str, Duration)>,
+}
+
+struct FieldVisitor<'a>(&'a mut SpanData);
+
+impl Visit for FieldVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "peer" => self.0.peer = Some(value.to_owned()),
+            "cid" => self.0.cid = Some(value.to_owned()),
+            "method" => self.0.method = Some(value.to_owned()),
+            _ => (),
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        match field.name() {
+            "peer" => self.0.peer = Some(format!("{:?}", value)),
+            "cid" => self.0.cid = Some(format!("{:?}", value)),
+            "method" => self.0.method = Some(format!("{:?}", value)),
+            _ => (),
+        }
+    }
+}
+
+struct Timings<'a>(&'a [(&'static str, Duration)]);
+
+impl fmt::Display for Timings<'_> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, duration) in self.0.iter() {
+            write!(fmt, " {}={:?}", name, duration)?;
+        }
+        Ok(())
+    }
+}
+
+///Subscriber, logging timings of every root span at INFO level.
+pub struct SpanTimings {
+    next_id: AtomicU64,
+    spans: Mutex<HashMap<u64, SpanData>>,
+    is_log: bool,
+    exporter: Option<Arc<otlp::Exporter>>,
+}
+
+impl SpanTimings {
+    ///Creates new instance, which may log timings, export spans or both.
+    pub fn new(is_log: bool, exporter: Option<Arc<otlp::Exporter>>) -> Self {
+        Self {
+            next_id: AtomicU64::new(1),
+            spans: Mutex::new(HashMap::new()),
+            is_log,
+            exporter,
+        }
+    }
+
+    fn export(exporter: &otlp::Exporter, span: &SpanData, duration: Duration) {
+        let mut attributes = Vec::new();
+        if let Some(peer) = span.peer.as_ref() {
+            attributes.push(("peer", peer.clone()));
+        }
+        if let Some(cid) = span.cid.as_ref() {
+            attributes.push(("correlation_id", cid.clone()));
+        }
+        if let Some(method) = span.method.as_ref() {
+            attributes.push(("method", method.clone()));
+        }
+
+        exporter.push_span(otlp::SpanRecord {
+            trace_id: span.trace_id,
+            span_id: span.span_id,
+            parent_span_id: span.parent_span_id,
+            name: span.name,
+            start: span.start_time,
+            end: span.start_time + duration,
+            attributes,
+        });
+    }
+
+    ///Removes span, reporting its timings to the parent.
+    ///
+    ///Children keep reference to the parent, so parent might get closed too.
+    fn close(&self, id: &Id) {
+        let mut spans = self.spans.lock().unwrap_or_else(|error| error.into_inner());
+        let mut id = id.into_u64();
+
+        loop {
+            let span = match spans.remove(&id) {
+                Some(span) => span,
+                None => return,
+            };
+            let duration = span.start.elapsed();
+            if let Some(exporter) = self.exporter.as_ref() {
+                Self::export(exporter, &span, duration);
+            }
+
+            match span.parent.as_ref().and_then(|parent| spans.get_mut(&parent.into_u64()).map(|data| (parent, data))) {
+                Some((parent_id, parent)) => {
+                    parent.timings.extend_from_slice(&span.timings);
+                    parent.timings.push((span.name, duration));
+                    parent.refs -= 1;
+                    if parent.refs > 0 {
+                        return;
+                    }
+                    id = parent_id.into_u64();
+                },
+                None => {
+                    drop(spans);
+                    if !self.is_log {
+                        return;
+                    }
+
+                    let method = span.method.as_deref().unwrap_or("-");
+                    match span.peer {
+                        Some(peer) => info!(peer: peer, cid: span.cid.as_deref(), method: method, duration: duration, "{} timings:{}", span.name, Timings(&span.timings)),
+                        None => info!(cid: span.cid.as_deref(), method: method, duration: duration, "{} timings:{}", span.name, Timings(&span.timings)),
+                    }
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl Subscriber for SpanTimings {
+    #[inline]
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.is_span()
+    }
+
+    fn new_span(&self, attrs: &Attributes<'_>) -> Id {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let parent = match attrs.parent() {
+            Some(parent) => Some(parent.clone()),
+            None if attrs.is_contextual() => CURRENT.with(|current| current.borrow().last().cloned()),
+            None => None,
+        };
+
+        let mut span = SpanData {
+            name: attrs.metadata().name(),
+            parent,
+            trace_id: 0,
+            span_id: generate_id(),
+            parent_span_id: None,
+            start_time: SystemTime::now(),
+            start: Instant::now(),
+            refs: 1,
+            peer: None,
+            cid: None,
+            method: None,
+            timings: Vec::new(),
+        };
+        attrs.record(&mut FieldVisitor(&mut span));
+
+        let mut spans = self.spans.lock().unwrap_or_else(|error| error.into_inner());
+        //Child keeps parent alive until it is closed.
+        match span.parent.as_ref().and_then(|parent| spans.get_mut(&parent.into_u64())) {
+            Some(parent) => {
+                parent.refs += 1;
+                span.trace_id = parent.trace_id;
+                span.parent_span_id = Some(parent.span_id);
+            },
+            None => {
+                span.trace_id = (u128::from(generate_id()) << 64) | u128::from(generate_id());
+            }
+        }
+        spans.insert(id, span);
+        drop(spans);
+
+        //Counter starts from 1, so it is never zero.
+        Id::from_non_zero_u64(NonZeroU64::new(id).unwrap_or(NonZeroU64::MIN))
+    }
+
+    fn record(&self, id: &Id, values: &Record<'_>) {
+        let mut spans = self.spans.lock().unwrap_or_else(|error| error.into_inner());
+        if let Some(span) = spans.get_mut(&id.into_u64()) {
+            values.record(&mut FieldVisitor(span));
+        }
+    }
+
+    #[inline]
+    fn record_follows_from(&self, _: &Id, _: &Id) {
+    }
+
+    #[inline]
+    fn event(&self, _: &Event<'_>) {
+    }
+
+    fn enter(&self, id: &Id) {
+        CURRENT.with(|current| current.borrow_mut().push(id.clone()));
+    }
+
+    fn exit(&self, id: &Id) {
+        CURRENT.with(|current| {
+            let mut current = current.borrow_mut();
+            if let Some(idx) = current.iter().rposition(|entered| entered == id) {
+                current.remove(idx);
+            }
+        });
+    }
+
+    fn clone_span(&self, id: &Id) -> Id {
+        let mut spans = self.spans.lock().unwrap_or_else(|error| error.into_inner());
+        if let Some(span) = spans.get_mut(&id.into_u64()) {
+            span.refs += 1;
+        }
+        id.clone()
+    }
+
+    fn try_close(&self, id: Id) -> bool {
+        let is_closed = {
+            let mut spans = self.spans.lock().unwrap_or_else(|error| error.into_inner());
+            match spans.get_mut(&id.into_u64()) {
+                Some(span) => {
+                    span.refs -= 1;
+                    span.refs == 0
+                },
+                None => false,
+            }
+        };
+
+        if is_closed {
+            self.close(&id);
+        }
+
+        is_closed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timings(subscriber: &SpanTimings, name: &str) -> Option<Vec<&'static str>> {
+        let spans = subscriber.spans.lock().unwrap_or_else(|error| error.into_inner());
+        spans.values().find(|span| span.name == name).map(|span| span.timings.iter().map(|(name, _)| *name).collect())
+    }
+
+    #[test]
+    fn should_report_timings_of_children_to_root_span() {
+        let subscriber = Arc::new(SpanTimings::new(false, None));
+
+        tracing::subscriber::with_default(subscriber.clone(), || {
+            let span = tracing::info_span!("request", peer = "127.0.0.1:1000", method = tracing::field::Empty);
+            span.record("method", "get_config");
+
+            {
+                let _parse = tracing::info_span!(parent: &span, "parse").entered();
+                let _nested = tracing::info_span!("field").entered();
+            }
+            tracing::info_span!(parent: &span, "dispatch").in_scope(|| ());
+            assert_eq!(timings(&subscriber, "request"), Some(vec!["field", "parse", "dispatch"]));
+
+            {
+                let spans = subscriber.spans.lock().unwrap_or_else(|error| error.into_inner());
+                let request = spans.values().find(|span| span.name == "request").expect("request span");
+                assert_eq!(request.peer.as_deref(), Some("127.0.0.1:1000"));
+                assert_eq!(request.method.as_deref(), Some("get_config"));
+            }
+
+            drop(span);
+        });
+
+        assert!(subscriber.spans.lock().unwrap_or_else(|error| error.into_inner()).is_empty());
+    }
+
+    #[test]
+    fn should_keep_parent_until_children_are_closed() {
+        let subscriber = Arc::new(SpanTimings::new(false, None));
+
+        tracing::subscriber::with_default(subscriber.clone(), || {
+            let span = tracing::info_span!("request");
+            let child = tracing::info_span!(parent: &span, "dispatch");
+            drop(span);
+            assert_eq!(timings(&subscriber, "request"), Some(Vec::new()));
+
+            drop(child);
+            assert_eq!(timings(&subscriber, "request"), None);
+        });
+    }
+}